@@ -0,0 +1,33 @@
+-- Members queueing for a field slot that is already taken.
+CREATE TABLE IF NOT EXISTS "FieldWaitlist" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    field_id UUID NOT NULL REFERENCES "Field"(id),
+    user_id UUID NOT NULL REFERENCES "User"(id),
+    description VARCHAR(1024),
+    start_date TIMESTAMP WITH TIME ZONE NOT NULL,
+    end_date TIMESTAMP WITH TIME ZONE NOT NULL,
+    -- Set once the entry is promoted into a reservation.
+    reservation_id UUID REFERENCES "FieldReservation"(id),
+    deleted BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS field_waitlist_field_idx ON "FieldWaitlist" (field_id, start_date);
+
+CREATE TABLE IF NOT EXISTS "Notification" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES "User"(id),
+    message VARCHAR(1024) NOT NULL,
+    read BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "FieldWaitlist"
+EXECUTE FUNCTION update_updated_at_column();
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "Notification"
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1,2 @@
+ALTER TABLE "FieldReservation" DROP COLUMN IF EXISTS series_id;
+DROP TABLE IF EXISTS "FieldReservationSeries";
@@ -0,0 +1,24 @@
+-- Recurring reservations created by association admins, e.g. a training group
+-- using a court every Tuesday at 19:00.
+CREATE TABLE IF NOT EXISTS "FieldReservationSeries" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    field_id UUID NOT NULL REFERENCES "Field"(id),
+    creator_id UUID NOT NULL REFERENCES "User"(id),
+    description VARCHAR(1024),
+    -- ISO weekdays (1 = Monday, 7 = Sunday) on which the series repeats.
+    weekdays SMALLINT[] NOT NULL,
+    start_time TIME NOT NULL,
+    end_time TIME NOT NULL,
+    start_on DATE NOT NULL,
+    until DATE NOT NULL,
+    deleted BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Occurrences of a series are stored as regular reservations.
+ALTER TABLE "FieldReservation" ADD COLUMN series_id UUID REFERENCES "FieldReservationSeries"(id);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "FieldReservationSeries"
+EXECUTE FUNCTION update_updated_at_column();
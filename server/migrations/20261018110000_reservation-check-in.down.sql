@@ -0,0 +1,3 @@
+ALTER TABLE "FieldReservation" DROP COLUMN IF EXISTS no_show_excused;
+ALTER TABLE "FieldReservation" DROP COLUMN IF EXISTS no_show;
+ALTER TABLE "FieldReservation" DROP COLUMN IF EXISTS checked_in_at;
@@ -0,0 +1,5 @@
+ALTER TABLE "FieldReservation" ADD COLUMN checked_in_at TIMESTAMP WITH TIME ZONE;
+-- Set by the no-show job when a reservation ends without a check-in.
+ALTER TABLE "FieldReservation" ADD COLUMN no_show BOOLEAN NOT NULL DEFAULT FALSE;
+-- Set by admins lifting a no-show block, so the no-show stops counting.
+ALTER TABLE "FieldReservation" ADD COLUMN no_show_excused BOOLEAN NOT NULL DEFAULT FALSE;
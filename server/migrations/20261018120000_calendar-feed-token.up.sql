@@ -0,0 +1,12 @@
+-- Calendar apps cannot send JWTs, so iCalendar feeds are authenticated with a
+-- long-lived per-user token. Only its SHA-256 hash is stored.
+CREATE TABLE IF NOT EXISTS "CalendarFeedToken" (
+    user_id UUID PRIMARY KEY REFERENCES "User"(id),
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "CalendarFeedToken"
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1,5 @@
+ALTER TABLE "FieldReservation" DROP COLUMN IF EXISTS tentative;
+ALTER TABLE "FieldReservation" DROP COLUMN IF EXISTS charge_id;
+ALTER TABLE "Charge" DROP COLUMN IF EXISTS settled_at;
+ALTER TABLE "Charge" DROP COLUMN IF EXISTS expires_at;
+ALTER TABLE "Charge" DROP COLUMN IF EXISTS user_id;
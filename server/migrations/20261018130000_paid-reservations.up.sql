@@ -0,0 +1,9 @@
+-- Member being charged. Charges created by treasurers may leave it empty.
+ALTER TABLE "Charge" ADD COLUMN user_id UUID REFERENCES "User"(id);
+-- Unsettled charges past this date are released.
+ALTER TABLE "Charge" ADD COLUMN expires_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE "Charge" ADD COLUMN settled_at TIMESTAMP WITH TIME ZONE;
+
+ALTER TABLE "FieldReservation" ADD COLUMN charge_id UUID REFERENCES "Charge"(id);
+-- Paid reservations stay tentative until their charge is settled.
+ALTER TABLE "FieldReservation" ADD COLUMN tentative BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS "FieldReservationModeration";
+ALTER TABLE "FieldReservation" DROP COLUMN IF EXISTS blocked;
@@ -0,0 +1,24 @@
+-- Reservations made by admins to block a field on behalf of the association.
+ALTER TABLE "FieldReservation" ADD COLUMN blocked BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Admin actions on reservations, kept so affected members can see why.
+CREATE TABLE IF NOT EXISTS "FieldReservationModeration" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    reservation_id UUID NOT NULL REFERENCES "FieldReservation"(id),
+    admin_id UUID NOT NULL REFERENCES "User"(id),
+    -- One of 'cancelled', 'moved' or 'blocked'.
+    action VARCHAR(32) NOT NULL,
+    reason VARCHAR(1024) NOT NULL,
+    -- Slot before a move.
+    previous_start_date TIMESTAMP WITH TIME ZONE,
+    previous_end_date TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS field_reservation_moderation_reservation_idx
+    ON "FieldReservationModeration" (reservation_id);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "FieldReservationModeration"
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1 @@
+ALTER TABLE "Association" DROP COLUMN IF EXISTS timezone;
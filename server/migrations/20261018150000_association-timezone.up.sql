@@ -0,0 +1,2 @@
+-- IANA timezone used to evaluate reservation rules in local time.
+ALTER TABLE "Association" ADD COLUMN timezone VARCHAR(64) NOT NULL DEFAULT 'UTC';
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS "FieldReservationGuest";
+ALTER TABLE "Field" DROP COLUMN IF EXISTS capacity;
@@ -0,0 +1,20 @@
+-- Reservations a field holds at the same time.
+ALTER TABLE "Field" ADD COLUMN capacity INTEGER NOT NULL DEFAULT 1 CHECK (capacity > 0);
+
+-- Members invited to join a reservation. Invitations that were not declined
+-- count towards the guest's quotas.
+CREATE TABLE IF NOT EXISTS "FieldReservationGuest" (
+    reservation_id UUID NOT NULL REFERENCES "FieldReservation"(id),
+    user_id UUID NOT NULL REFERENCES "User"(id),
+    accepted_at TIMESTAMP WITH TIME ZONE,
+    declined_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (reservation_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS field_reservation_guest_user_idx ON "FieldReservationGuest" (user_id);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "FieldReservationGuest"
+EXECUTE FUNCTION update_updated_at_column();
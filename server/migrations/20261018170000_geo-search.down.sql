@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS field_location_idx;
+DROP FUNCTION IF EXISTS haversine_km(FLOAT8, FLOAT8, FLOAT8, FLOAT8);
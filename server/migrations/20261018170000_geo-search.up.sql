@@ -0,0 +1,11 @@
+-- Great-circle distance in kilometers between two points given in degrees.
+CREATE OR REPLACE FUNCTION haversine_km(lat1 FLOAT8, lng1 FLOAT8, lat2 FLOAT8, lng2 FLOAT8)
+RETURNS FLOAT8 AS $$
+    SELECT 2 * 6371 * asin(sqrt(
+        power(sin(radians(lat2 - lat1) / 2), 2)
+        + cos(radians(lat1)) * cos(radians(lat2)) * power(sin(radians(lng2 - lng1) / 2), 2)
+    ))
+$$ LANGUAGE SQL IMMUTABLE STRICT;
+
+-- Bounding box prefilter of nearby searches.
+CREATE INDEX IF NOT EXISTS field_location_idx ON "Field" (latitude, longitude);
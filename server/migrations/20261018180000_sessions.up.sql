@@ -0,0 +1,21 @@
+-- A login session. Access tokens carry the session id and stop working once
+-- the session is revoked. The refresh token is rotated on every use and only
+-- its SHA-256 hash is stored; the previous hash is kept to detect reuse of a
+-- stolen token.
+CREATE TABLE IF NOT EXISTS "Session" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES "User"(id),
+    refresh_token_hash VARCHAR(64) NOT NULL UNIQUE,
+    previous_refresh_token_hash VARCHAR(64),
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS session_user_idx ON "Session" (user_id);
+CREATE INDEX IF NOT EXISTS session_previous_refresh_token_idx ON "Session" (previous_refresh_token_hash);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "Session"
+EXECUTE FUNCTION update_updated_at_column();
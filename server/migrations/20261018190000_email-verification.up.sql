@@ -0,0 +1,18 @@
+-- Pending email/password registrations. The user is only created once the
+-- email is confirmed through the emailed token, whose SHA-256 hash is stored.
+CREATE TABLE IF NOT EXISTS "EmailVerification" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    email VARCHAR(255) NOT NULL,
+    password_hash VARCHAR(255) NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    verified_at TIMESTAMPTZ,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS email_verification_email_idx ON "EmailVerification" (email);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "EmailVerification"
+EXECUTE FUNCTION update_updated_at_column();
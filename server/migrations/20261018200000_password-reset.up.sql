@@ -0,0 +1,16 @@
+-- Single-use password reset tokens. Only their SHA-256 hash is stored.
+CREATE TABLE IF NOT EXISTS "PasswordReset" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES "User"(id),
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS password_reset_user_idx ON "PasswordReset" (user_id);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "PasswordReset"
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1,19 @@
+-- External login methods linked to a user, identified by the provider's
+-- subject id rather than by email.
+CREATE TABLE IF NOT EXISTS "UserIdentity" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES "User"(id),
+    provider VARCHAR(64) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    email VARCHAR(255),
+    linked_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (provider, subject)
+);
+
+CREATE INDEX IF NOT EXISTS user_identity_user_idx ON "UserIdentity" (user_id);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "UserIdentity"
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1,20 @@
+-- Audit trail of password logins, also used to throttle guessing. Failed
+-- attempts keep the reason; throttled attempts are recorded but not counted.
+CREATE TABLE IF NOT EXISTS "LoginAttempt" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    email VARCHAR(255) NOT NULL,
+    user_id UUID REFERENCES "User"(id),
+    ip VARCHAR(45),
+    succeeded BOOLEAN NOT NULL,
+    reason VARCHAR(32),
+    attempted_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS login_attempt_email_idx ON "LoginAttempt" (email, attempted_at);
+CREATE INDEX IF NOT EXISTS login_attempt_ip_idx ON "LoginAttempt" (ip, attempted_at);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "LoginAttempt"
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1,4 @@
+ALTER TABLE "Association" DROP COLUMN IF EXISTS require_two_factor;
+ALTER TABLE "Session" DROP COLUMN IF EXISTS two_factor;
+DROP TABLE IF EXISTS "RecoveryCode";
+DROP TABLE IF EXISTS "TwoFactor";
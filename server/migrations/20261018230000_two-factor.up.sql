@@ -0,0 +1,37 @@
+-- TOTP second factor. The secret is pending until the user confirms it with
+-- a first code; last_used_step keeps codes from being replayed.
+CREATE TABLE IF NOT EXISTS "TwoFactor" (
+    user_id UUID PRIMARY KEY REFERENCES "User"(id),
+    secret VARCHAR(64) NOT NULL,
+    enabled_at TIMESTAMPTZ,
+    last_used_step BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "TwoFactor"
+EXECUTE FUNCTION update_updated_at_column();
+
+-- Single-use codes for when the authenticator is lost. Only their SHA-256
+-- hash is stored.
+CREATE TABLE IF NOT EXISTS "RecoveryCode" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES "User"(id),
+    code_hash VARCHAR(64) NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS recovery_code_user_idx ON "RecoveryCode" (user_id);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "RecoveryCode"
+EXECUTE FUNCTION update_updated_at_column();
+
+-- Sessions started with a second factor.
+ALTER TABLE "Session" ADD COLUMN IF NOT EXISTS two_factor BOOLEAN NOT NULL DEFAULT false;
+
+-- Requires admins and treasurers of the association to use a second factor.
+ALTER TABLE "Association" ADD COLUMN IF NOT EXISTS require_two_factor BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,17 @@
+-- Single-use codes handed to the web app after an OAuth login, exchanged
+-- for tokens at /oauth/token. Only their SHA-256 hash is stored.
+CREATE TABLE IF NOT EXISTS "OAuthCode" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    code_hash VARCHAR(64) NOT NULL UNIQUE,
+    -- Unset for new users, who get a token to create their account.
+    user_id UUID REFERENCES "User"(id),
+    email VARCHAR(250),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "OAuthCode"
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1,25 @@
+-- Personal API tokens for scripts and integrations. Only their SHA-256 hash
+-- is stored. Tokens can be read-only, and limited to some roles in one
+-- association.
+CREATE TABLE IF NOT EXISTS "ApiToken" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES "User"(id),
+    name VARCHAR(100) NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    -- Start of the token, to tell tokens apart.
+    prefix VARCHAR(16) NOT NULL,
+    read_only BOOLEAN NOT NULL DEFAULT true,
+    association_id UUID REFERENCES "Association"(id),
+    roles association_role[] NOT NULL DEFAULT '{}',
+    expires_at TIMESTAMPTZ NOT NULL,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS api_token_user_idx ON "ApiToken" (user_id);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "ApiToken"
+EXECUTE FUNCTION update_updated_at_column();
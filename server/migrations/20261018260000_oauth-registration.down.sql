@@ -0,0 +1,5 @@
+ALTER TABLE "OAuthCode"
+    DROP COLUMN provider,
+    DROP COLUMN subject,
+    DROP COLUMN name,
+    DROP COLUMN picture_url;
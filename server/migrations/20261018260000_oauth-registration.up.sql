@@ -0,0 +1,6 @@
+-- What the provider told about new users, to prefill their registration.
+ALTER TABLE "OAuthCode"
+    ADD COLUMN provider VARCHAR(64),
+    ADD COLUMN subject VARCHAR(255),
+    ADD COLUMN name VARCHAR(250),
+    ADD COLUMN picture_url VARCHAR(128);
@@ -0,0 +1 @@
+ALTER TABLE "User" DROP COLUMN deleted_at;
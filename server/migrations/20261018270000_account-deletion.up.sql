@@ -0,0 +1,3 @@
+-- Deleted accounts are anonymized rather than removed, so financial records
+-- keep pointing at them.
+ALTER TABLE "User" ADD COLUMN deleted_at TIMESTAMPTZ;
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS "UserAddress";
+DROP TABLE IF EXISTS "UserDocument";
+DROP TYPE IF EXISTS document_kind;
@@ -0,0 +1,41 @@
+CREATE TYPE document_kind AS ENUM ('cpf', 'rg', 'cnh', 'passport');
+
+-- Identity documents of a user, replacing the free-text "User".identities.
+-- Numbers are stored normalized, e.g. CPFs as 11 digits.
+CREATE TABLE IF NOT EXISTS "UserDocument" (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES "User"(id),
+    kind document_kind NOT NULL,
+    number VARCHAR(32) NOT NULL,
+    -- Issuing country, ISO 3166-1 alpha-2.
+    country CHAR(2) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- One document of each kind per country, belonging to a single user.
+    UNIQUE (user_id, kind, country),
+    UNIQUE (kind, country, number)
+);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "UserDocument"
+EXECUTE FUNCTION update_updated_at_column();
+
+-- Structured home address, which associations use to check residency. The
+-- single-line "User".address is kept in sync for display.
+CREATE TABLE IF NOT EXISTS "UserAddress" (
+    user_id UUID PRIMARY KEY REFERENCES "User"(id),
+    street VARCHAR(120) NOT NULL,
+    number VARCHAR(10) NOT NULL,
+    unit VARCHAR(30),
+    neighborhood VARCHAR(60) NOT NULL,
+    city VARCHAR(60) NOT NULL,
+    state VARCHAR(32) NOT NULL,
+    postal_code VARCHAR(10) NOT NULL,
+    country CHAR(2) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "UserAddress"
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS trigger_name_before_update ON "FieldReservation";
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "FieldReservation"
+EXECUTE FUNCTION update_updated_at_column();
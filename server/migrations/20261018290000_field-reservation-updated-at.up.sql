@@ -0,0 +1,8 @@
+-- The trigger ran once per statement, so updated_at never changed. Calendar
+-- feeds derive the event sequence from it.
+DROP TRIGGER IF EXISTS trigger_name_before_update ON "FieldReservation";
+
+CREATE TRIGGER trigger_name_before_update
+BEFORE UPDATE ON "FieldReservation"
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at_column();
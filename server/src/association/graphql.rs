@@ -2,10 +2,14 @@ use async_graphql::{Context, FieldResult, Object};
 use uuid::Uuid;
 
 use crate::{
-    association::model::{AssocFilter, AssociationsPage}, relations::model::{Relations, Role}, token::Claims, DB
+    association::model::{AssocFilter, AssociationsPage},
+    geo::SearchArea,
+    relations::model::{Relations, Role},
+    token::Claims,
+    DB,
 };
 
-use super::model::{Association, AssociationInput, AssociationUpdate};
+use super::model::{Association, AssociationInput, AssociationUpdate, NearbyAssociation};
 
 #[derive(Default)]
 pub struct AssociationQuery;
@@ -39,8 +43,8 @@ impl AssociationQuery {
 
         let filter = AssocFilter {
             search,
-            member_only: member_only,
-            pending_only: pending_only,
+            member_only,
+            pending_only,
             user_id: if member_only || pending_only { Some(user_id) } else { None },
             page,
             page_size,
@@ -73,6 +77,23 @@ impl AssociationQuery {
             }
         }
     }
+
+    /// Associations with a field within `radius_km` of the given point,
+    /// closest first. Anonymous callers only see public associations.
+    async fn nearby_associations(
+        &self,
+        ctx: &Context<'_>,
+        lat: f64,
+        lng: f64,
+        radius_km: f64,
+    ) -> FieldResult<Vec<NearbyAssociation>> {
+        let user_id = ctx.data::<Claims>()?.sub;
+        let area = SearchArea::new(lat, lng, radius_km)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let associations = Association::nearby(pool, &area, user_id).await?;
+        Ok(associations)
+    }
 }
 
 #[derive(Default)]
@@ -1,14 +1,19 @@
-use async_graphql::{Context, InputObject, Object, SimpleObject};
+use std::sync::Arc;
+
+use async_graphql::{Context, FieldResult, InputObject, Object, SimpleObject};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, QueryBuilder};
 use uuid::Uuid;
 
 use crate::{
-    field::model::Field,
+    field::{model::Field, occupancy::OccupancyReport},
+    geo::{SearchArea, MAX_RESULTS},
     relations::model::{Relations, Role},
+    token::Claims,
     transaction::model::Transaction,
     user::model::User,
-    DB,
+    Clock, DB,
 };
 
 #[derive(Debug, FromRow, Deserialize, Serialize)]
@@ -25,6 +30,10 @@ pub struct Association {
     pub deleted: Option<bool>,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    // IANA timezone, e.g. America/Sao_Paulo.
+    pub timezone: String,
+    // Admins and treasurers must log in with a second factor.
+    pub require_two_factor: bool,
 }
 
 #[derive(InputObject)]
@@ -37,6 +46,8 @@ pub struct AssociationUpdate {
     pub identity: Option<String>,
     pub public: Option<bool>,
     pub deleted: Option<bool>,
+    pub timezone: Option<String>,
+    pub require_two_factor: Option<bool>,
 }
 
 #[derive(InputObject)]
@@ -49,6 +60,7 @@ pub struct AssociationInput {
     public: Option<bool>,
     deleted: Option<bool>,
     identity: Option<String>,
+    timezone: Option<String>,
 }
 
 #[derive(Debug)]
@@ -61,6 +73,14 @@ pub struct AssocFilter {
     pub page_size:   i64,
 }
 
+#[derive(SimpleObject, FromRow)]
+pub struct NearbyAssociation {
+    #[sqlx(flatten)]
+    pub association: Association,
+    // Distance to the association's closest field.
+    pub distance_km: f64,
+}
+
 #[derive(SimpleObject)]
 pub struct AssociationsPage {
     pub total_size: i64,
@@ -106,6 +126,14 @@ impl Association {
         self.public
     }
 
+    pub async fn timezone(&self) -> String {
+        self.timezone.to_owned()
+    }
+
+    pub async fn require_two_factor(&self) -> bool {
+        self.require_two_factor
+    }
+
     pub async fn created_at(&self) -> chrono::NaiveDateTime {
         self.created_at
     }
@@ -166,10 +194,45 @@ impl Association {
             r#"SELECT * FROM "Field" WHERE association_id = $1"#,
             self.id
         )
-        .fetch_all(&*pool)
+        .fetch_all(pool)
         .await?;
         Ok(fields)
     }
+
+    /// Usage statistics of all fields between `from` and `to`, for admins.
+    async fn occupancy(
+        &self,
+        ctx: &Context<'_>,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> FieldResult<OccupancyReport> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        let admin_role = Relations::get_role(ctx, &user_id, self.id, Role::Admin).await?;
+        if admin_role.is_none() {
+            Err(anyhow::Error::msg("Only admins can view occupancy reports"))?
+        }
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let fields = sqlx::query_as!(
+            Field,
+            r#"SELECT * FROM "Field" WHERE association_id = $1 AND deleted IS NOT TRUE"#,
+            self.id
+        )
+        .fetch_all(pool)
+        .await?;
+        let report = OccupancyReport::for_fields(pool, &self.id, &fields, from, to, now).await?;
+        Ok(report)
+    }
+}
+
+pub fn parse_timezone(timezone: &str) -> Result<Tz, anyhow::Error> {
+    timezone
+        .parse::<Tz>()
+        .map_err(|_| anyhow::anyhow!("Unknown timezone {}", timezone))
 }
 
 impl Association {
@@ -178,12 +241,15 @@ impl Association {
         user_id: Uuid,
         association: AssociationInput,
     ) -> Result<Association, anyhow::Error> {
+        if let Some(timezone) = &association.timezone {
+            parse_timezone(timezone)?;
+        }
         let mut tx = db.begin().await?;
         let association = sqlx::query_as!(
             Association,
             r#"INSERT INTO "Association" (name, neighborhood, country, state, address,
-                identity)
-                VALUES ($1, $2, $3, $4, $5, $6)
+                identity, timezone)
+                VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7, 'UTC'))
                 RETURNING *"#,
             association.name,
             association.neighborhood,
@@ -191,6 +257,7 @@ impl Association {
             association.state,
             association.address,
             association.identity,
+            association.timezone,
         )
         .fetch_one(&mut *tx)
         .await?;
@@ -336,6 +403,9 @@ impl Association {
         id: &Uuid,
         association: AssociationUpdate,
     ) -> Result<Association, anyhow::Error> {
+        if let Some(timezone) = &association.timezone {
+            parse_timezone(timezone)?;
+        }
         let mut tx = db.begin().await?;
 
         let association = sqlx::query_as!(
@@ -348,7 +418,9 @@ impl Association {
                     address = COALESCE($5, address),
                     identity = COALESCE($6, identity),
                     public = COALESCE($7, public),
-                    deleted = COALESCE($8, deleted)
+                    deleted = COALESCE($8, deleted),
+                    timezone = COALESCE($10, timezone),
+                    require_two_factor = COALESCE($11, require_two_factor)
                 WHERE id = $9 RETURNING *"#,
             association.name,
             association.neighborhood,
@@ -358,11 +430,51 @@ impl Association {
             association.identity,
             association.public,
             association.deleted,
-            id
+            id,
+            association.timezone,
+            association.require_two_factor
         )
         .fetch_one(&mut *tx)
         .await?;
         tx.commit().await?;
         Ok(association)
     }
+
+    /// Associations with a field inside the search area, closest first. Only
+    /// public associations or ones the user belongs to are returned.
+    pub async fn nearby(
+        db: &DB,
+        area: &SearchArea,
+        user_id: Option<Uuid>,
+    ) -> Result<Vec<NearbyAssociation>, anyhow::Error> {
+        // Not checked at compile time: `query_as!` cannot fill the
+        // `#[sqlx(flatten)]` association of `NearbyAssociation`.
+        let associations = sqlx::query_as::<_, NearbyAssociation>(
+            r#"SELECT a.*, min(haversine_km($1, $2, f.latitude::float8, f.longitude::float8)) AS distance_km
+            FROM "Association" a INNER JOIN "Field" f ON f.association_id = a.id
+            WHERE f.deleted IS NOT TRUE AND a.deleted IS NOT TRUE
+                AND f.latitude BETWEEN $4::numeric AND $5::numeric
+                AND f.longitude BETWEEN $6::numeric AND $7::numeric
+                AND (a.public OR EXISTS (
+                    SELECT 1 FROM "AssociationRoles" ar
+                    WHERE ar.association_id = a.id AND ar.user_id = $8
+                ))
+            GROUP BY a.id
+            HAVING min(haversine_km($1, $2, f.latitude::float8, f.longitude::float8)) <= $3
+            ORDER BY distance_km
+            LIMIT $9"#,
+        )
+        .bind(area.latitude)
+        .bind(area.longitude)
+        .bind(area.radius_km)
+        .bind(area.min_latitude)
+        .bind(area.max_latitude)
+        .bind(area.min_longitude)
+        .bind(area.max_longitude)
+        .bind(user_id)
+        .bind(MAX_RESULTS)
+        .fetch_all(db)
+        .await?;
+        Ok(associations)
+    }
 }
@@ -0,0 +1,25 @@
+use async_graphql::{Context, FieldResult, Object};
+
+use crate::{token::Claims, user::api_token::ApiTokenScope, DB};
+
+use super::model::CalendarFeedToken;
+
+#[derive(Default)]
+pub struct CalendarMutation;
+
+#[Object(extends)]
+impl CalendarMutation {
+    /// Returns a new token for the user's iCalendar feeds. Previously issued
+    /// feed URLs stop working.
+    async fn regenerate_calendar_feed_token(&self, ctx: &Context<'_>) -> FieldResult<String> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let token = CalendarFeedToken::regenerate(pool, &user_id).await?;
+        Ok(token)
+    }
+}
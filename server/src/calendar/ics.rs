@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query},
+    http::header,
+    response::IntoResponse,
+    Extension,
+};
+use chrono::{Duration, Utc};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    field::model::Field,
+    relations::model::{Relations, Role},
+    Clock, DB,
+};
+
+use super::model::{CalendarEvent, CalendarFeedToken};
+
+/// Feeds include reservations starting up to this many days ago...
+const FEED_PAST_DAYS: i64 = 30;
+/// ...and up to this many days ahead.
+const FEED_FUTURE_DAYS: i64 = 365;
+
+#[derive(Debug, Deserialize)]
+pub struct FeedParams {
+    token: String,
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Folds content lines longer than 75 octets, as required by RFC 5545.
+fn fold_line(line: &str, out: &mut String) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn format_date_time(date_time: chrono::DateTime<Utc>) -> String {
+    date_time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Renders reservations as an iCalendar document. Cancelled reservations are
+/// kept as cancelled events so subscribed calendars remove them. The sequence
+/// grows with every change, as calendars ignore updates that keep it.
+pub fn render_calendar(name: &str, events: &[CalendarEvent]) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_owned(),
+        "VERSION:2.0".to_owned(),
+        "PRODID:-//MyHood//Field reservations//EN".to_owned(),
+        "CALSCALE:GREGORIAN".to_owned(),
+        "METHOD:PUBLISH".to_owned(),
+        format!("X-WR-CALNAME:{}", escape_text(name)),
+    ];
+    for event in events {
+        let updated_at = format_date_time(event.updated_at.and_utc());
+        lines.push("BEGIN:VEVENT".to_owned());
+        lines.push(format!("UID:{}@myhood", event.id));
+        lines.push(format!("DTSTAMP:{}", updated_at));
+        lines.push(format!("LAST-MODIFIED:{}", updated_at));
+        lines.push(format!("DTSTART:{}", format_date_time(event.start_date)));
+        lines.push(format!("DTEND:{}", format_date_time(event.end_date)));
+        lines.push(format!("SUMMARY:{}", escape_text(&event.field_name)));
+        if let Some(description) = &event.description {
+            lines.push(format!("DESCRIPTION:{}", escape_text(description)));
+        }
+        if event.cancelled {
+            lines.push("STATUS:CANCELLED".to_owned());
+        } else {
+            lines.push("STATUS:CONFIRMED".to_owned());
+        }
+        lines.push(format!(
+            "SEQUENCE:{}",
+            event.updated_at.and_utc().timestamp()
+        ));
+        lines.push("END:VEVENT".to_owned());
+    }
+    lines.push("END:VCALENDAR".to_owned());
+
+    let mut calendar = String::new();
+    for line in lines {
+        fold_line(&line, &mut calendar);
+    }
+    calendar
+}
+
+fn calendar_response(calendar: String) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        calendar,
+    )
+}
+
+async fn feed_user_id(db: &DB, token: &str) -> Result<Uuid, (StatusCode, &'static str)> {
+    CalendarFeedToken::user_id(db, token)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?
+        .ok_or((StatusCode::UNAUTHORIZED, "Invalid feed token"))
+}
+
+/// Reservations of a field, for members of its association.
+pub async fn field_calendar_handler(
+    Path(field_id): Path<Uuid>,
+    Query(params): Query<FeedParams>,
+    Extension(db): Extension<DB>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let user_id = feed_user_id(&db, &params.token).await?;
+    let field = Field::get(&db, &field_id)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "Field not found"))?;
+    let member = Relations::read_role(&db, &user_id, field.association_id, Role::Member)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?;
+    if member.is_none() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "User is not a member of the association",
+        ));
+    }
+
+    let now = clock.now();
+    let events = CalendarEvent::for_field(
+        &db,
+        &field_id,
+        now - Duration::days(FEED_PAST_DAYS),
+        now + Duration::days(FEED_FUTURE_DAYS),
+    )
+    .await
+    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?;
+    Ok(calendar_response(render_calendar(&field.name, &events)))
+}
+
+/// Reservations of the feed token owner.
+pub async fn user_calendar_handler(
+    Query(params): Query<FeedParams>,
+    Extension(db): Extension<DB>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let user_id = feed_user_id(&db, &params.token).await?;
+
+    let now = clock.now();
+    let events = CalendarEvent::for_user(
+        &db,
+        &user_id,
+        now - Duration::days(FEED_PAST_DAYS),
+        now + Duration::days(FEED_FUTURE_DAYS),
+    )
+    .await
+    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?;
+    Ok(calendar_response(render_calendar(
+        "My reservations",
+        &events,
+    )))
+}
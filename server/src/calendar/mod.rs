@@ -0,0 +1,3 @@
+pub mod graphql;
+pub mod ics;
+pub mod model;
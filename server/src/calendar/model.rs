@@ -0,0 +1,105 @@
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::{
+    token::{generate_opaque_token, hash_opaque_token},
+    DB,
+};
+
+pub struct CalendarFeedToken;
+
+impl CalendarFeedToken {
+    /// Creates a new feed token for the user, invalidating the previous one.
+    /// The token is only returned here, the database keeps its hash.
+    pub async fn regenerate(db: &DB, user_id: &Uuid) -> Result<String, anyhow::Error> {
+        let token = generate_opaque_token();
+        let mut tx = db.begin().await?;
+        sqlx::query!(
+            r#"INSERT INTO "CalendarFeedToken" (user_id, token_hash) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET token_hash = EXCLUDED.token_hash"#,
+            user_id,
+            hash_opaque_token(&token)
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    pub async fn user_id(db: &DB, token: &str) -> Result<Option<Uuid>, anyhow::Error> {
+        let user_id = sqlx::query_scalar!(
+            r#"SELECT t.user_id FROM "CalendarFeedToken" t
+            INNER JOIN "User" u ON u.id = t.user_id
+            WHERE t.token_hash = $1 AND u.deleted = false"#,
+            hash_opaque_token(token)
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(user_id)
+    }
+}
+
+/// A reservation as shown in a calendar feed.
+#[derive(Debug)]
+pub struct CalendarEvent {
+    pub id: Uuid,
+    pub field_name: String,
+    pub description: Option<String>,
+    pub start_date: chrono::DateTime<Utc>,
+    pub end_date: chrono::DateTime<Utc>,
+    pub cancelled: bool,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl CalendarEvent {
+    /// Reservations of a field starting in `[from, to)`, cancelled ones included.
+    pub async fn for_field(
+        db: &DB,
+        field_id: &Uuid,
+        from: chrono::DateTime<Utc>,
+        to: chrono::DateTime<Utc>,
+    ) -> Result<Vec<CalendarEvent>, anyhow::Error> {
+        let events = sqlx::query_as!(
+            CalendarEvent,
+            r#"SELECT r.id, f.name AS field_name, r.description, r.start_date, r.end_date,
+                r.deleted AS cancelled, r.updated_at
+            FROM "FieldReservation" r INNER JOIN "Field" f ON f.id = r.field_id
+            WHERE r.field_id = $1 AND r.start_date >= $2 AND r.start_date < $3
+            ORDER BY r.start_date"#,
+            field_id,
+            from,
+            to
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(events)
+    }
+
+    /// Reservations a user owns or joined as a guest starting in `[from, to)`,
+    /// cancelled ones included.
+    pub async fn for_user(
+        db: &DB,
+        user_id: &Uuid,
+        from: chrono::DateTime<Utc>,
+        to: chrono::DateTime<Utc>,
+    ) -> Result<Vec<CalendarEvent>, anyhow::Error> {
+        let events = sqlx::query_as!(
+            CalendarEvent,
+            r#"SELECT r.id, f.name AS field_name, r.description, r.start_date, r.end_date,
+                r.deleted AS cancelled, r.updated_at
+            FROM "FieldReservation" r INNER JOIN "Field" f ON f.id = r.field_id
+            WHERE r.start_date >= $2 AND r.start_date < $3
+                AND (r.user_id = $1 OR EXISTS (
+                    SELECT 1 FROM "FieldReservationGuest" g
+                    WHERE g.reservation_id = r.id AND g.user_id = $1 AND g.accepted_at IS NOT NULL
+                ))
+            ORDER BY r.start_date"#,
+            user_id,
+            from,
+            to
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(events)
+    }
+}
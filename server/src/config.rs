@@ -1,40 +1,335 @@
-#[derive(Debug, Clone, Default)]
+use std::{collections::HashMap, fmt};
+
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+
+#[derive(Debug, Clone)]
 pub struct Config {
-    pub jwt_secret: String,
-    pub jwt_expires_in: String,
+    pub jwt_keys: JwtKeys,
+    // Lifetime of access tokens.
+    pub jwt_expires_in: chrono::Duration,
+    // Max age of the auth cookie, in minutes.
     pub jwt_max_age: i64,
-    pub google_oauth_client_id: String,
-    pub google_oauth_client_secret: String,
-    pub google_oauth_redirect_url: String,
+    pub oauth_providers: Vec<OidcProviderConfig>,
     pub client_origin: String,
+    // Origins of the web app, allowed for CORS and as post-login redirects.
+    pub allowed_origins: Vec<String>,
+    // Where OAuth logins return to when no redirect was asked for.
+    pub post_login_url: String,
+    // Only send auth cookies over HTTPS. Disable for local development.
+    pub secure_cookies: bool,
+    pub reservation_job_interval_secs: u64,
+    // "stdout" or "file", see `mail::mailer_from_config`.
+    pub mailer: String,
+    pub mail_dir: String,
+    // Page of the web app that confirms an email, receiving the token.
+    pub email_verification_url: String,
+    // Page of the web app that sets a new password, receiving the token.
+    pub password_reset_url: String,
+    // Failed password logins allowed per email, then per IP, before a
+    // lockout. Repeated failures on an email double the wait between
+    // attempts, starting at `login_backoff`.
+    pub login_max_attempts: i64,
+    pub login_ip_max_attempts: i64,
+    pub login_backoff: chrono::Duration,
+    pub login_lockout: chrono::Duration,
+    // Take the client IP from X-Forwarded-For, when behind a reverse proxy.
+    pub trust_forwarded_for: bool,
+    // Requests accepted on the GraphQL route per period, from each logged in
+    // user or else each client IP.
+    pub graphql_rate_limit: u64,
+    pub graphql_rate_limit_period: chrono::Duration,
+}
+
+/// Keys for signing and verifying JWTs. Tokens are signed with the current
+/// key and name it in their `kid` header. Keys listed in `JWT_ACCEPTED_KEYS`
+/// are only used for verification, so tokens signed before a key rotation
+/// keep working until they expire.
+#[derive(Clone)]
+pub struct JwtKeys {
+    pub algorithm: Algorithm,
+    pub kid: String,
+    encoding_key: EncodingKey,
+    decoding_keys: HashMap<String, DecodingKey>,
+}
+
+impl fmt::Debug for JwtKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtKeys")
+            .field("algorithm", &self.algorithm)
+            .field("kid", &self.kid)
+            .field("accepted", &self.decoding_keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl JwtKeys {
+    /// HS256 keys sharing one secret.
+    pub fn hmac(kid: &str, secret: &str) -> Self {
+        JwtKeys {
+            algorithm: Algorithm::HS256,
+            kid: kid.to_owned(),
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_keys: HashMap::from([(
+                kid.to_owned(),
+                DecodingKey::from_secret(secret.as_bytes()),
+            )]),
+        }
+    }
+
+    /// RS256 or EdDSA keys from a PKCS#8 private key and its public key, in PEM.
+    pub fn from_pem(
+        algorithm: Algorithm,
+        kid: &str,
+        private_pem: &[u8],
+        public_pem: &[u8],
+    ) -> Result<Self, anyhow::Error> {
+        let (encoding_key, decoding_key) = match algorithm {
+            Algorithm::RS256 => (
+                EncodingKey::from_rsa_pem(private_pem)?,
+                DecodingKey::from_rsa_pem(public_pem)?,
+            ),
+            Algorithm::EdDSA => (
+                EncodingKey::from_ed_pem(private_pem)?,
+                DecodingKey::from_ed_pem(public_pem)?,
+            ),
+            _ => return Err(anyhow::anyhow!("Unsupported JWT algorithm {:?}", algorithm)),
+        };
+        Ok(JwtKeys {
+            algorithm,
+            kid: kid.to_owned(),
+            encoding_key,
+            decoding_keys: HashMap::from([(kid.to_owned(), decoding_key)]),
+        })
+    }
+
+    /// Also accepts tokens signed with a previous key.
+    pub fn with_accepted_key(mut self, kid: &str, decoding_key: DecodingKey) -> Self {
+        self.decoding_keys.insert(kid.to_owned(), decoding_key);
+        self
+    }
+
+    /// Reads the keys from `JWT_ALGORITHM` (HS256, RS256 or EdDSA, default
+    /// HS256), `JWT_KEY_ID`, and either `JWT_SECRET` or `JWT_PRIVATE_KEY_FILE`
+    /// and `JWT_PUBLIC_KEY_FILE`. `JWT_ACCEPTED_KEYS` lists previous keys as
+    /// comma separated `kid:secret` or `kid:public key file` pairs.
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let algorithm = match std::env::var("JWT_ALGORITHM").as_deref() {
+            Err(_) | Ok("HS256") => Algorithm::HS256,
+            Ok("RS256") => Algorithm::RS256,
+            Ok("EdDSA") => Algorithm::EdDSA,
+            Ok(other) => return Err(anyhow::anyhow!("Unsupported JWT algorithm {}", other)),
+        };
+        let kid = std::env::var("JWT_KEY_ID").unwrap_or("default".to_owned());
+
+        let mut keys = if algorithm == Algorithm::HS256 {
+            JwtKeys::hmac(&kid, &std::env::var("JWT_SECRET")?)
+        } else {
+            let private_pem = std::fs::read(std::env::var("JWT_PRIVATE_KEY_FILE")?)?;
+            let public_pem = std::fs::read(std::env::var("JWT_PUBLIC_KEY_FILE")?)?;
+            JwtKeys::from_pem(algorithm, &kid, &private_pem, &public_pem)?
+        };
+
+        let accepted = std::env::var("JWT_ACCEPTED_KEYS").unwrap_or_default();
+        for entry in accepted.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (kid, value) = entry
+                .split_once(':')
+                .ok_or(anyhow::anyhow!("JWT_ACCEPTED_KEYS entries must be kid:key"))?;
+            let decoding_key = match algorithm {
+                Algorithm::HS256 => DecodingKey::from_secret(value.as_bytes()),
+                Algorithm::RS256 => DecodingKey::from_rsa_pem(&std::fs::read(value)?)?,
+                _ => DecodingKey::from_ed_pem(&std::fs::read(value)?)?,
+            };
+            keys = keys.with_accepted_key(kid, decoding_key);
+        }
+        Ok(keys)
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding_key
+    }
+
+    /// Key for a token's `kid`. Tokens issued before key ids were introduced
+    /// have none and are checked against the current key.
+    pub fn decoding_key(&self, kid: Option<&str>) -> Option<&DecodingKey> {
+        self.decoding_keys.get(kid.unwrap_or(&self.kid))
+    }
+}
+
+/// An OpenID Connect provider users can log in with, at
+/// `/oauth/{name}/login`. Endpoints and signing keys are discovered from the
+/// issuer.
+#[derive(Debug, Clone)]
+pub struct OidcProviderConfig {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: Vec<String>,
+    pub redirect_url: String,
+}
+
+impl OidcProviderConfig {
+    /// Reads the providers named in `OAUTH_PROVIDERS`, each configured by
+    /// `OAUTH_<NAME>_ISSUER`, `_CLIENT_ID`, `_CLIENT_SECRET`, and optionally
+    /// `_SCOPES` and `_REDIRECT_URL`. Google is also enabled by the
+    /// `GOOGLE_OAUTH_*` variables.
+    pub fn from_env(server_url: &str) -> Result<Vec<Self>, anyhow::Error> {
+        let default_redirect_url = |name: &str| format!("{}/oauth/{}/callback", server_url, name);
+        let mut providers = vec![];
+
+        if let Ok(client_id) = std::env::var("GOOGLE_OAUTH_CLIENT_ID") {
+            providers.push(OidcProviderConfig {
+                name: "google".to_owned(),
+                issuer: "https://accounts.google.com".to_owned(),
+                client_id,
+                client_secret: std::env::var("GOOGLE_OAUTH_CLIENT_SECRET")?,
+                scopes: vec!["openid".to_owned(), "email".to_owned()],
+                redirect_url: std::env::var("GOOGLE_OAUTH_REDIRECT_URL")
+                    .unwrap_or(default_redirect_url("google")),
+            });
+        }
+
+        let names = std::env::var("OAUTH_PROVIDERS").unwrap_or_default();
+        for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let var = |key: &str| {
+                let var = format!("OAUTH_{}_{}", name.to_uppercase(), key);
+                std::env::var(&var).map_err(|_| anyhow::anyhow!("{} must be set", var))
+            };
+            let name = name.to_lowercase();
+            if providers.iter().any(|provider| provider.name == name) {
+                return Err(anyhow::anyhow!(
+                    "OAuth provider {} is configured twice",
+                    name
+                ));
+            }
+            providers.push(OidcProviderConfig {
+                issuer: var("ISSUER")?,
+                client_id: var("CLIENT_ID")?,
+                client_secret: var("CLIENT_SECRET")?,
+                scopes: var("SCOPES")
+                    .unwrap_or("openid email".to_owned())
+                    .split_whitespace()
+                    .map(str::to_owned)
+                    .collect(),
+                redirect_url: var("REDIRECT_URL").unwrap_or(default_redirect_url(&name)),
+                name,
+            });
+        }
+        Ok(providers)
+    }
+}
+
+/// Parses durations such as `90s`, `60m`, `24h` or `7d`. Plain numbers are
+/// minutes.
+pub fn parse_duration(value: &str) -> Result<chrono::Duration, anyhow::Error> {
+    let value = value.trim();
+    let (amount, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "m"),
+    };
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid duration {}", value))?;
+    match unit {
+        "s" => Ok(chrono::Duration::seconds(amount)),
+        "m" => Ok(chrono::Duration::minutes(amount)),
+        "h" => Ok(chrono::Duration::hours(amount)),
+        "d" => Ok(chrono::Duration::days(amount)),
+        _ => Err(anyhow::anyhow!("Invalid duration {}", value)),
+    }
+}
+
+impl Config {
+    /// Whether a post-login redirect goes to one of the allowed origins.
+    pub fn is_allowed_redirect(&self, url: &str) -> bool {
+        let Ok(url) = reqwest::Url::parse(url) else {
+            return false;
+        };
+        let origin = url.origin().ascii_serialization();
+        self.allowed_origins.contains(&origin)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Config {
     pub fn new() -> Self {
-        let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+        let jwt_keys = JwtKeys::from_env().expect("Invalid JWT key configuration");
         let jwt_expires_in =
             std::env::var("TOKEN_EXPIRED_IN").expect("TOKEN_EXPIRED_IN must be set");
         let jwt_max_age = std::env::var("TOKEN_MAXAGE").expect("TOKEN_MAXAGE must be set");
-        let google_oauth_client_id =
-            std::env::var("GOOGLE_OAUTH_CLIENT_ID").expect("GOOGLE_OAUTH_CLIENT_ID must be set");
-        let google_oauth_client_secret = std::env::var("GOOGLE_OAUTH_CLIENT_SECRET")
-            .expect("GOOGLE_OAUTH_CLIENT_SECRET must be set");
-        let google_oauth_redirect_url = std::env::var("GOOGLE_OAUTH_REDIRECT_URL")
-            .expect("GOOGLE_OAUTH_REDIRECT_URL must be set");
+        let reservation_job_interval_secs = std::env::var("RESERVATION_JOB_INTERVAL_SECS")
+            .map(|secs| {
+                secs.parse::<u64>()
+                    .expect("RESERVATION_JOB_INTERVAL_SECS must be a number")
+            })
+            .unwrap_or(300);
+
+        let mailer = std::env::var("MAILER").unwrap_or("stdout".to_owned());
+        if !["stdout", "file"].contains(&mailer.as_str()) {
+            panic!("MAILER must be stdout or file");
+        }
+        let mail_dir = std::env::var("MAIL_DIR").unwrap_or("mail".to_owned());
+        let email_verification_url = std::env::var("EMAIL_VERIFICATION_URL")
+            .unwrap_or("http://localhost:8081/verify-email".to_owned());
+        let password_reset_url = std::env::var("PASSWORD_RESET_URL")
+            .unwrap_or("http://localhost:8081/reset-password".to_owned());
+
+        let env_number = |name: &str, default: i64| {
+            std::env::var(name)
+                .map(|value| {
+                    value
+                        .parse::<i64>()
+                        .unwrap_or_else(|_| panic!("{} must be a number", name))
+                })
+                .unwrap_or(default)
+        };
+        let env_duration = |name: &str, default: &str| {
+            parse_duration(&std::env::var(name).unwrap_or(default.to_owned()))
+                .unwrap_or_else(|_| panic!("{} must be a duration such as 15m", name))
+        };
 
         let client_origin = format!(
             "http://{}:{}",
             std::env::var("HOST").expect("HOST must be set"),
             std::env::var("PORT").expect("PORT must be set")
         );
+        let oauth_providers =
+            OidcProviderConfig::from_env(&client_origin).expect("Invalid OAuth configuration");
+        let allowed_origins = std::env::var("ALLOWED_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_owned())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        let post_login_url =
+            std::env::var("WEB_POST_LOGIN_URL").expect("WEB_POST_LOGIN_URL must be set");
         Config {
-            jwt_secret,
-            jwt_expires_in,
+            jwt_keys,
+            jwt_expires_in: parse_duration(&jwt_expires_in)
+                .expect("TOKEN_EXPIRED_IN must be a duration such as 60m"),
             jwt_max_age: jwt_max_age.parse::<i64>().unwrap(),
-            google_oauth_client_id,
-            google_oauth_client_secret,
-            google_oauth_redirect_url,
+            oauth_providers,
             client_origin,
+            allowed_origins,
+            post_login_url,
+            secure_cookies: std::env::var("COOKIE_SECURE").as_deref() != Ok("false"),
+            reservation_job_interval_secs,
+            mailer,
+            mail_dir,
+            email_verification_url,
+            password_reset_url,
+            login_max_attempts: env_number("LOGIN_MAX_ATTEMPTS", 5),
+            login_ip_max_attempts: env_number("LOGIN_IP_MAX_ATTEMPTS", 50),
+            login_backoff: env_duration("LOGIN_BACKOFF", "1s"),
+            login_lockout: env_duration("LOGIN_LOCKOUT", "15m"),
+            trust_forwarded_for: std::env::var("TRUST_FORWARDED_FOR").as_deref() == Ok("true"),
+            graphql_rate_limit: env_number("GRAPHQL_RATE_LIMIT", 100) as u64,
+            graphql_rate_limit_period: env_duration("GRAPHQL_RATE_LIMIT_PERIOD", "1s"),
         }
     }
 }
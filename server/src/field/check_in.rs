@@ -0,0 +1,97 @@
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::DB;
+
+use super::{
+    model::{Field, FieldReservation},
+    rules::DEFAULT_CHECK_IN_WINDOW_MINUTES,
+};
+
+impl FieldReservation {
+    /// Checks the member in if `now` is within the field's check-in window
+    /// around the reservation start.
+    pub async fn check_in(
+        db: &DB,
+        field: &Field,
+        field_reservation: &FieldReservation,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<FieldReservation, anyhow::Error> {
+        if field_reservation.deleted {
+            return Err(anyhow::anyhow!("Reservation was cancelled"));
+        }
+        if field_reservation.checked_in_at.is_some() {
+            return Err(anyhow::anyhow!("Reservation is already checked in"));
+        }
+
+        let window = field
+            .rules()?
+            .map(|rules| rules.check_in_window())
+            .unwrap_or(chrono::Duration::minutes(
+                DEFAULT_CHECK_IN_WINDOW_MINUTES as i64,
+            ));
+        if now < field_reservation.start_date - window
+            || now > field_reservation.start_date + window
+        {
+            return Err(anyhow::anyhow!(
+                "Check-in is only possible {} minutes around the reservation start",
+                window.num_minutes()
+            ));
+        }
+
+        let mut tx = db.begin().await?;
+        let field_reservation = sqlx::query_as!(
+            FieldReservation,
+            r#"UPDATE "FieldReservation" SET checked_in_at = $1 WHERE id = $2 RETURNING *"#,
+            now,
+            field_reservation.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(field_reservation)
+    }
+
+    /// Marks reservations that ended without a check-in as no-shows.
+    /// Occurrences of recurring series and association blocks are not tracked.
+    pub async fn mark_no_shows(
+        db: &DB,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Vec<FieldReservation>, anyhow::Error> {
+        let mut tx = db.begin().await?;
+        let no_shows = sqlx::query_as!(
+            FieldReservation,
+            r#"UPDATE "FieldReservation" SET no_show = true
+            WHERE deleted = false AND series_id IS NULL AND blocked = false
+                AND checked_in_at IS NULL AND no_show = false AND end_date <= $1
+            RETURNING *"#,
+            now
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(no_shows)
+    }
+
+    /// Excuses all the member's no-shows in the association, lifting any
+    /// no-show block. Returns how many no-shows were excused.
+    pub async fn excuse_no_shows(
+        db: &DB,
+        association_id: &Uuid,
+        user_id: &Uuid,
+    ) -> Result<u64, anyhow::Error> {
+        let mut tx = db.begin().await?;
+        let result = sqlx::query!(
+            r#"UPDATE "FieldReservation" r SET no_show_excused = true
+            FROM "Field" f
+            WHERE f.id = r.field_id AND f.association_id = $1 AND r.user_id = $2
+                AND r.no_show = true AND r.no_show_excused = false"#,
+            association_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+}
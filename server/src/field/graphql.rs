@@ -4,12 +4,20 @@ use async_graphql::{Context, FieldResult, Object};
 use uuid::Uuid;
 
 use crate::{
+    geo::SearchArea,
     relations::model::{Relations, Role},
     token::Claims,
     Clock, DB,
 };
 
-use super::model::{Field, FieldInput, FieldReservation, FieldReservationInput};
+use super::{
+    guests::FieldReservationGuest,
+    model::{Field, FieldInput, FieldReservation, FieldReservationInput, FieldReservationUpdate},
+    nearby::NearbyField,
+    rules::{ReservationRequest, ReservationRules, ReservationRulesValidation},
+    series::{FieldReservationSeries, FieldReservationSeriesInput},
+    waitlist::FieldWaitlist,
+};
 
 #[derive(Default)]
 pub struct FieldQuery;
@@ -21,6 +29,95 @@ impl FieldQuery {
     async fn field(&self, _ctx: &Context<'_>, _id: Uuid) -> FieldResult<u32> {
         todo!()
     }
+
+    /// Fields within `radius_km` of the given point, closest first. Anonymous
+    /// callers only see fields of public associations.
+    async fn nearby_fields(
+        &self,
+        ctx: &Context<'_>,
+        lat: f64,
+        lng: f64,
+        radius_km: f64,
+    ) -> FieldResult<Vec<NearbyField>> {
+        let user_id = ctx.data::<Claims>()?.sub;
+        let area = SearchArea::new(lat, lng, radius_km)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let fields = Field::nearby(pool, &area, user_id).await?;
+        Ok(fields)
+    }
+
+    /// Upcoming reservations the user was invited to and has not answered.
+    async fn field_reservation_invitations(
+        &self,
+        ctx: &Context<'_>,
+    ) -> FieldResult<Vec<FieldReservation>> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let invitations = FieldReservationGuest::pending_invitations(pool, &user_id, now).await?;
+        Ok(invitations)
+    }
+
+    /// Parses and checks reservation rules before they are saved. If a sample
+    /// reservation is given, also reports every rule it would break.
+    async fn validate_reservation_rules(
+        &self,
+        ctx: &Context<'_>,
+        reservation_rules: String,
+        field_reservation_input: Option<FieldReservationInput>,
+    ) -> FieldResult<ReservationRulesValidation> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let rules = match ReservationRules::from_json(&reservation_rules) {
+            Ok(rules) => rules,
+            Err(e) => {
+                return Ok(ReservationRulesValidation {
+                    valid: false,
+                    errors: vec![format!("Failed to parse reservation rules: {}", e)],
+                    normalized: None,
+                    violations: vec![],
+                })
+            }
+        };
+        let errors = rules.validate();
+
+        let mut violations = vec![];
+        if let Some(input) = field_reservation_input {
+            let pool = ctx.data::<DB>().expect("DB pool not found");
+            let field = Field::get(pool, &input.field_id).await?;
+            let is_admin =
+                Relations::get_role(ctx, &user_id, field.association_id, Role::Admin).await?;
+            if is_admin.is_none() {
+                return Err(anyhow::Error::msg("User is not an admin of the association").into());
+            }
+
+            let now = ctx.data::<Arc<dyn Clock>>()?.now();
+            let request = ReservationRequest {
+                field: &field,
+                user_id: &user_id,
+                start_date: input.start_date,
+                end_date: input.end_date,
+                now,
+            };
+            let mut conn = pool.acquire().await?;
+            violations = rules.can_reserve(&mut conn, &request).await?;
+        }
+
+        Ok(ReservationRulesValidation {
+            valid: errors.is_empty(),
+            errors,
+            normalized: Some(rules.to_json()?),
+            violations,
+        })
+    }
 }
 
 #[Object(extends)]
@@ -70,11 +167,132 @@ impl FieldMutation {
         Ok(field_reservation)
     }
 
+    /// Lets the owner change the description or slot of a reservation.
+    async fn update_field_reservation(
+        &self,
+        ctx: &Context<'_>,
+        field_reservation_update: FieldReservationUpdate,
+    ) -> FieldResult<FieldReservation> {
+        let clock = ctx.data::<Arc<dyn Clock>>()?;
+        let now: chrono::DateTime<chrono::Utc> = clock.now();
+
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let field_reservation = FieldReservation::get(pool, &field_reservation_update.id).await?;
+        if field_reservation.user_id != user_id {
+            return Err(
+                anyhow::Error::msg("User id does not match field reservation input id").into(),
+            );
+        }
+        if field_reservation.series_id.is_some() || field_reservation.blocked {
+            return Err(anyhow::Error::msg("Reservation is managed by admins").into());
+        }
+        let field = Field::get(pool, &field_reservation.field_id).await?;
+        let member = Relations::get_role(ctx, &user_id, field.association_id, Role::Member).await?;
+        if member.is_none() {
+            return Err(anyhow::Error::msg("User is not a member of the association").into());
+        }
+
+        let field_reservation = FieldReservation::update(
+            pool,
+            &field,
+            &field_reservation,
+            field_reservation_update,
+            now,
+        )
+        .await?;
+        Ok(field_reservation)
+    }
+
     async fn delete_field_reservation(
         &self,
         ctx: &Context<'_>,
         id: Uuid,
     ) -> FieldResult<FieldReservation> {
+        let clock = ctx.data::<Arc<dyn Clock>>()?;
+        let now: chrono::DateTime<chrono::Utc> = clock.now();
+
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let field_reservation = FieldReservation::get(pool, &id).await?;
+        let field = Field::get(pool, &field_reservation.field_id).await?;
+
+        if field_reservation.series_id.is_some() || field_reservation.blocked {
+            // Series occurrences and association blocks are managed by admins.
+            let is_admin =
+                Relations::get_role(ctx, &user_id, field.association_id, Role::Admin).await?;
+            if is_admin.is_none() {
+                return Err(anyhow::Error::msg("User is not an admin of the association").into());
+            }
+        } else {
+            if field_reservation.user_id != user_id {
+                return Err(anyhow::Error::msg(
+                    "User id does not match field reservation input id",
+                )
+                .into());
+            }
+            let member =
+                Relations::get_role(ctx, &user_id, field.association_id, Role::Member).await?;
+            if member.is_none() {
+                return Err(anyhow::Error::msg("User is not a member of the association").into());
+            }
+        }
+
+        let field_reservation = FieldReservation::delete(pool, &id, now).await?;
+        Ok(field_reservation)
+    }
+
+    /// Cancels any reservation on the association's fields. The reason is
+    /// recorded and sent to the member.
+    async fn cancel_field_reservation_as_admin(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+        reason: String,
+    ) -> FieldResult<FieldReservation> {
+        let clock = ctx.data::<Arc<dyn Clock>>()?;
+        let now: chrono::DateTime<chrono::Utc> = clock.now();
+
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let field_reservation = FieldReservation::get(pool, &id).await?;
+        let field = Field::get(pool, &field_reservation.field_id).await?;
+        let is_admin =
+            Relations::get_role(ctx, &user_id, field.association_id, Role::Admin).await?;
+        if is_admin.is_none() {
+            return Err(anyhow::Error::msg("User is not an admin of the association").into());
+        }
+
+        let field_reservation =
+            FieldReservation::cancel_as_admin(pool, &user_id, &field, &id, &reason, now).await?;
+        Ok(field_reservation)
+    }
+
+    /// Moves any reservation on the association's fields to a free slot. The
+    /// reason is recorded and sent to the member.
+    async fn move_field_reservation(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+        start_date: chrono::DateTime<chrono::Utc>,
+        end_date: chrono::DateTime<chrono::Utc>,
+        reason: String,
+    ) -> FieldResult<FieldReservation> {
+        let clock = ctx.data::<Arc<dyn Clock>>()?;
+        let now: chrono::DateTime<chrono::Utc> = clock.now();
+
         let claims = ctx.data::<Claims>()?;
         let user_id = claims
             .sub
@@ -82,19 +300,231 @@ impl FieldMutation {
 
         let pool = ctx.data::<DB>().expect("DB pool not found");
         let field_reservation = FieldReservation::get(pool, &id).await?;
+        let field = Field::get(pool, &field_reservation.field_id).await?;
+        let is_admin =
+            Relations::get_role(ctx, &user_id, field.association_id, Role::Admin).await?;
+        if is_admin.is_none() {
+            return Err(anyhow::Error::msg("User is not an admin of the association").into());
+        }
+
+        let field_reservation = FieldReservation::move_as_admin(
+            pool,
+            &user_id,
+            &field_reservation,
+            start_date,
+            end_date,
+            &reason,
+            now,
+        )
+        .await?;
+        Ok(field_reservation)
+    }
+
+    /// Blocks a field slot on behalf of the association, cancelling member
+    /// reservations in it. Blocks do not count towards quotas.
+    async fn block_field(
+        &self,
+        ctx: &Context<'_>,
+        field_reservation_input: FieldReservationInput,
+        reason: String,
+    ) -> FieldResult<FieldReservation> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let field = Field::get(pool, &field_reservation_input.field_id).await?;
+        let is_admin =
+            Relations::get_role(ctx, &user_id, field.association_id, Role::Admin).await?;
+        if is_admin.is_none() {
+            return Err(anyhow::Error::msg("User is not an admin of the association").into());
+        }
+
+        let block =
+            FieldReservation::block(pool, &user_id, &field, field_reservation_input, &reason)
+                .await?;
+        Ok(block)
+    }
+
+    /// Accepts or declines an invitation to join a reservation.
+    async fn respond_to_field_reservation_invitation(
+        &self,
+        ctx: &Context<'_>,
+        reservation_id: Uuid,
+        accept: bool,
+    ) -> FieldResult<FieldReservationGuest> {
+        let clock = ctx.data::<Arc<dyn Clock>>()?;
+        let now: chrono::DateTime<chrono::Utc> = clock.now();
+
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let field_reservation = FieldReservation::get(pool, &reservation_id).await?;
+        let guest =
+            FieldReservationGuest::respond(pool, &field_reservation, &user_id, accept, now).await?;
+        Ok(guest)
+    }
+
+    /// Checks the owner in within the check-in window around the reservation
+    /// start. Reservations that end without a check-in become no-shows.
+    async fn check_in_field_reservation(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+    ) -> FieldResult<FieldReservation> {
+        let clock = ctx.data::<Arc<dyn Clock>>()?;
+        let now: chrono::DateTime<chrono::Utc> = clock.now();
+
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
 
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let field_reservation = FieldReservation::get(pool, &id).await?;
         if field_reservation.user_id != user_id {
             return Err(
                 anyhow::Error::msg("User id does not match field reservation input id").into(),
             );
         }
         let field = Field::get(pool, &field_reservation.field_id).await?;
+
+        let field_reservation =
+            FieldReservation::check_in(pool, &field, &field_reservation, now).await?;
+        Ok(field_reservation)
+    }
+
+    /// Excuses a member's no-shows in the association, lifting the no-show
+    /// block. Returns the number of excused no-shows.
+    async fn lift_no_show_block(
+        &self,
+        ctx: &Context<'_>,
+        association_id: Uuid,
+        target_user_id: Uuid,
+    ) -> FieldResult<u64> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let is_admin = Relations::get_role(ctx, &user_id, association_id, Role::Admin).await?;
+        if is_admin.is_none() {
+            return Err(anyhow::Error::msg("User is not an admin of the association").into());
+        }
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let excused =
+            FieldReservation::excuse_no_shows(pool, &association_id, &target_user_id).await?;
+        Ok(excused)
+    }
+
+    /// Creates a weekly recurring reservation. Occurrences bypass member quotas
+    /// but block conflicting member bookings.
+    async fn create_field_reservation_series(
+        &self,
+        ctx: &Context<'_>,
+        field_reservation_series_input: FieldReservationSeriesInput,
+    ) -> FieldResult<FieldReservationSeries> {
+        let clock = ctx.data::<Arc<dyn Clock>>()?;
+        let now: chrono::DateTime<chrono::Utc> = clock.now();
+
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let field = Field::get(pool, &field_reservation_series_input.field_id).await?;
+        let is_admin =
+            Relations::get_role(ctx, &user_id, field.association_id, Role::Admin).await?;
+        if is_admin.is_none() {
+            return Err(anyhow::Error::msg("User is not an admin of the association").into());
+        }
+
+        let series = FieldReservationSeries::create(
+            pool,
+            &user_id,
+            &field,
+            field_reservation_series_input,
+            now,
+        )
+        .await?;
+        Ok(series)
+    }
+
+    async fn delete_field_reservation_series(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+    ) -> FieldResult<FieldReservationSeries> {
+        let clock = ctx.data::<Arc<dyn Clock>>()?;
+        let now: chrono::DateTime<chrono::Utc> = clock.now();
+
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let series = FieldReservationSeries::get(pool, &id).await?;
+        let field = Field::get(pool, &series.field_id).await?;
+        let is_admin =
+            Relations::get_role(ctx, &user_id, field.association_id, Role::Admin).await?;
+        if is_admin.is_none() {
+            return Err(anyhow::Error::msg("User is not an admin of the association").into());
+        }
+
+        let series = FieldReservationSeries::delete(pool, &id, now).await?;
+        Ok(series)
+    }
+
+    /// Queues the user for a slot that is already reserved. The user is
+    /// promoted into a reservation as soon as the slot is freed.
+    async fn join_field_waitlist(
+        &self,
+        ctx: &Context<'_>,
+        field_reservation_input: FieldReservationInput,
+    ) -> FieldResult<FieldWaitlist> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let field = Field::get(pool, &field_reservation_input.field_id).await?;
         let member = Relations::get_role(ctx, &user_id, field.association_id, Role::Member).await?;
         if member.is_none() {
             return Err(anyhow::Error::msg("User is not a member of the association").into());
         }
 
-        let field_reservation = FieldReservation::delete(pool, &id).await?;
-        Ok(field_reservation)
+        let entry = FieldWaitlist::create(pool, &user_id, &field, field_reservation_input).await?;
+        Ok(entry)
+    }
+
+    async fn leave_field_waitlist(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+    ) -> FieldResult<FieldWaitlist> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let entry = FieldWaitlist::get(pool, &id).await?;
+        if entry.user_id != user_id {
+            return Err(anyhow::Error::msg("User id does not match waitlist entry user id").into());
+        }
+        if entry.reservation_id.is_some() {
+            return Err(anyhow::Error::msg("Waitlist entry was already promoted").into());
+        }
+
+        let entry = FieldWaitlist::delete(pool, &id).await?;
+        Ok(entry)
     }
 }
@@ -0,0 +1,190 @@
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use uuid::Uuid;
+
+use crate::{notification::model::Notification, DB};
+
+use super::model::{Field, FieldReservation};
+
+/// A member invited to join a reservation. Invitations count towards the
+/// guest's quotas until they are declined.
+#[derive(Debug, SimpleObject, FromRow, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldReservationGuest {
+    pub reservation_id: Uuid,
+    pub user_id: Uuid,
+    pub accepted_at: Option<chrono::DateTime<Utc>>,
+    pub declined_at: Option<chrono::DateTime<Utc>>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl FieldReservationGuest {
+    pub async fn read_by_reservation(
+        db: &DB,
+        reservation_id: &Uuid,
+    ) -> Result<Vec<FieldReservationGuest>, anyhow::Error> {
+        let guests = sqlx::query_as!(
+            FieldReservationGuest,
+            r#"SELECT * FROM "FieldReservationGuest" WHERE reservation_id = $1 ORDER BY created_at"#,
+            reservation_id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(guests)
+    }
+
+    /// Guests that did not decline.
+    pub(crate) async fn active_guest_ids(
+        conn: &mut PgConnection,
+        reservation_id: &Uuid,
+    ) -> Result<Vec<Uuid>, anyhow::Error> {
+        let guest_ids = sqlx::query_scalar!(
+            r#"SELECT user_id FROM "FieldReservationGuest"
+            WHERE reservation_id = $1 AND declined_at IS NULL"#,
+            reservation_id
+        )
+        .fetch_all(conn)
+        .await?;
+        Ok(guest_ids)
+    }
+
+    /// Deduplicates the guest list and checks every guest is a member of the
+    /// field's association other than the owner.
+    pub(crate) async fn check_guests(
+        conn: &mut PgConnection,
+        field: &Field,
+        owner_id: &Uuid,
+        guest_ids: &[Uuid],
+    ) -> Result<Vec<Uuid>, anyhow::Error> {
+        let mut guest_ids = guest_ids.to_vec();
+        guest_ids.sort();
+        guest_ids.dedup();
+        if guest_ids.contains(owner_id) {
+            return Err(anyhow::anyhow!("Owner cannot be invited as a guest"));
+        }
+
+        let members = sqlx::query_scalar!(
+            r#"SELECT count(*) AS "count!" FROM "AssociationRoles"
+            WHERE association_id = $1 AND role = 'member' AND user_id = ANY($2)"#,
+            field.association_id,
+            &guest_ids
+        )
+        .fetch_one(conn)
+        .await?;
+        if members as usize != guest_ids.len() {
+            return Err(anyhow::anyhow!("Guests must be members of the association"));
+        }
+        Ok(guest_ids)
+    }
+
+    pub(crate) async fn invite(
+        conn: &mut PgConnection,
+        field: &Field,
+        reservation: &FieldReservation,
+        guest_ids: &[Uuid],
+    ) -> Result<(), anyhow::Error> {
+        for guest_id in guest_ids {
+            sqlx::query!(
+                r#"INSERT INTO "FieldReservationGuest" (reservation_id, user_id) VALUES ($1, $2)"#,
+                reservation.id,
+                guest_id
+            )
+            .execute(&mut *conn)
+            .await?;
+            Notification::create(
+                &mut *conn,
+                guest_id,
+                format!(
+                    "You were invited to a reservation of {} from {} to {}.",
+                    field.name, reservation.start_date, reservation.end_date
+                ),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Accepts or declines an invitation. Pending invitations can be
+    /// accepted, and guests can decline until the reservation starts.
+    pub async fn respond(
+        db: &DB,
+        reservation: &FieldReservation,
+        user_id: &Uuid,
+        accept: bool,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<FieldReservationGuest, anyhow::Error> {
+        if reservation.deleted {
+            return Err(anyhow::anyhow!("Reservation was cancelled"));
+        }
+        if reservation.start_date <= now {
+            return Err(anyhow::anyhow!("Reservation already started"));
+        }
+
+        let mut tx = db.begin().await?;
+        let guest = if accept {
+            sqlx::query_as!(
+                FieldReservationGuest,
+                r#"UPDATE "FieldReservationGuest" SET accepted_at = $3
+                WHERE reservation_id = $1 AND user_id = $2
+                    AND accepted_at IS NULL AND declined_at IS NULL
+                RETURNING *"#,
+                reservation.id,
+                user_id,
+                now
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+        } else {
+            sqlx::query_as!(
+                FieldReservationGuest,
+                r#"UPDATE "FieldReservationGuest" SET declined_at = $3
+                WHERE reservation_id = $1 AND user_id = $2 AND declined_at IS NULL
+                RETURNING *"#,
+                reservation.id,
+                user_id,
+                now
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+        }
+        .ok_or(anyhow::anyhow!("No open invitation for this reservation"))?;
+
+        Notification::create(
+            &mut tx,
+            &reservation.user_id,
+            format!(
+                "A guest {} your reservation from {} to {}.",
+                if accept { "joined" } else { "declined" },
+                reservation.start_date,
+                reservation.end_date
+            ),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(guest)
+    }
+
+    /// Upcoming reservations the user was invited to and has not answered.
+    pub async fn pending_invitations(
+        db: &DB,
+        user_id: &Uuid,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Vec<FieldReservation>, anyhow::Error> {
+        let reservations = sqlx::query_as!(
+            FieldReservation,
+            r#"SELECT r.* FROM "FieldReservation" r
+            INNER JOIN "FieldReservationGuest" g ON g.reservation_id = r.id
+            WHERE g.user_id = $1 AND g.accepted_at IS NULL AND g.declined_at IS NULL
+                AND r.deleted = false AND r.start_date > $2
+            ORDER BY r.start_date"#,
+            user_id,
+            now
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(reservations)
+    }
+}
@@ -0,0 +1,20 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{Clock, DB};
+
+use super::model::FieldReservation;
+
+/// Periodically marks no-shows and releases reservations that were not paid
+/// in time. Runs until the process exits.
+pub async fn reservation_job(db: DB, clock: Arc<dyn Clock>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(err) = FieldReservation::mark_no_shows(&db, clock.now()).await {
+            eprintln!("Error marking no-shows: {}", err);
+        }
+        if let Err(err) = FieldReservation::release_expired(&db, clock.now()).await {
+            eprintln!("Error releasing unpaid reservations: {}", err);
+        }
+    }
+}
@@ -1,3 +1,12 @@
+pub mod check_in;
 pub mod graphql;
+pub mod guests;
+pub mod jobs;
 pub mod model;
+pub mod moderation;
+pub mod nearby;
+pub mod occupancy;
+pub mod payment;
 pub mod rules;
+pub mod series;
+pub mod waitlist;
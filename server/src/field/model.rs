@@ -1,13 +1,27 @@
-use async_graphql::{Context, InputObject, Object};
+use std::sync::Arc;
+
+use async_graphql::{Context, Enum, FieldResult, InputObject, Object};
 use bigdecimal::BigDecimal;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{FromRow, PgConnection};
 use uuid::Uuid;
 
-use crate::{user::model::User, DB};
-
-use super::rules::ReservationRules;
+use crate::{
+    relations::model::{Relations, Role},
+    token::Claims,
+    transaction::model::Charge,
+    user::model::User,
+    Clock, DB,
+};
+
+use super::{
+    guests::FieldReservationGuest,
+    moderation::FieldReservationModeration,
+    occupancy::OccupancyReport,
+    rules::{ReservationRequest, ReservationRules},
+    waitlist::FieldWaitlist,
+};
 
 #[derive(Debug, FromRow, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -23,6 +37,8 @@ pub struct Field {
     pub deleted: Option<bool>,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    // Reservations the field holds at the same time, e.g. barbecue spots.
+    pub capacity: i32,
 }
 
 #[derive(Debug, InputObject, Deserialize)]
@@ -34,6 +50,7 @@ pub struct FieldInput {
     // Latitude and longitude of the field.
     pub latitude: BigDecimal,
     pub longitude: BigDecimal,
+    pub capacity: Option<i32>,
 }
 
 #[derive(InputObject)]
@@ -62,6 +79,29 @@ pub struct FieldReservation {
     pub deleted: bool,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    // Set when the reservation is an occurrence of a recurring series.
+    pub series_id: Option<Uuid>,
+    pub checked_in_at: Option<chrono::DateTime<Utc>>,
+    #[serde(default)]
+    pub no_show: bool,
+    #[serde(default)]
+    pub no_show_excused: bool,
+    // Charge for reservations on paid fields. The reservation stays tentative
+    // until the charge is settled.
+    pub charge_id: Option<Uuid>,
+    #[serde(default)]
+    pub tentative: bool,
+    // Set on reservations blocking the field on behalf of the association.
+    #[serde(default)]
+    pub blocked: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Enum)]
+pub enum FieldReservationStatus {
+    // Waiting for payment.
+    Tentative,
+    Confirmed,
+    Cancelled,
 }
 
 #[derive(Debug, InputObject, Deserialize)]
@@ -70,17 +110,16 @@ pub struct FieldReservationInput {
     pub description: Option<String>,
     pub start_date: chrono::DateTime<chrono::Utc>,
     pub end_date: chrono::DateTime<chrono::Utc>,
+    // Members invited to join the reservation.
+    pub guest_ids: Option<Vec<Uuid>>,
 }
 
 #[derive(InputObject)]
 pub struct FieldReservationUpdate {
     pub id: Uuid,
-    pub field_id: Uuid,
-    pub user_id: Uuid,
     pub description: Option<String>,
-    pub start_date: Option<chrono::NaiveDateTime>,
-    pub end_date: Option<chrono::NaiveDateTime>,
-    pub deleted: Option<bool>,
+    pub start_date: Option<chrono::DateTime<chrono::Utc>>,
+    pub end_date: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[Object]
@@ -113,6 +152,10 @@ impl Field {
         self.longitude.clone()
     }
 
+    pub async fn capacity(&self) -> i32 {
+        self.capacity
+    }
+
     pub async fn created_at(&self) -> chrono::NaiveDateTime {
         self.created_at
     }
@@ -130,7 +173,7 @@ impl Field {
         let pool = ctx.data::<DB>().unwrap();
 
         if to_date_time - from_date_time >= chrono::Duration::days(30) {
-            return Err(anyhow::Error::msg("Date range too large").into());
+            return Err(anyhow::Error::msg("Date range too large"));
         }
 
         let field_reservations = sqlx::query_as!(
@@ -142,29 +185,88 @@ impl Field {
             from_date_time,
             to_date_time
         )
-        .fetch_all(&*pool)
+        .fetch_all(pool)
         .await?;
         Ok(field_reservations)
     }
+
+    async fn waitlist(
+        &self,
+        ctx: &Context<'_>,
+        from_date_time: chrono::DateTime<chrono::Utc>,
+        to_date_time: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<FieldWaitlist>, anyhow::Error> {
+        let pool = ctx.data::<DB>().unwrap();
+
+        if to_date_time - from_date_time >= chrono::Duration::days(30) {
+            return Err(anyhow::Error::msg("Date range too large"));
+        }
+
+        FieldWaitlist::read_pending(pool, &self.id, from_date_time, to_date_time).await
+    }
+
+    /// Usage statistics between `from` and `to`, for association admins.
+    async fn occupancy(
+        &self,
+        ctx: &Context<'_>,
+        from: chrono::DateTime<Utc>,
+        to: chrono::DateTime<Utc>,
+    ) -> FieldResult<OccupancyReport> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        let admin_role =
+            Relations::get_role(ctx, &user_id, self.association_id, Role::Admin).await?;
+        if admin_role.is_none() {
+            Err(anyhow::Error::msg("Only admins can view occupancy reports"))?
+        }
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let report = OccupancyReport::for_fields(
+            pool,
+            &self.association_id,
+            std::slice::from_ref(self),
+            from,
+            to,
+            now,
+        )
+        .await?;
+        Ok(report)
+    }
 }
 
 impl Field {
     pub async fn create(db: &DB, field: FieldInput) -> Result<Field, anyhow::Error> {
         let mut tx = db.begin().await?;
         let rules_opt = &field.reservation_rules;
-        rules_opt
+        let rules = rules_opt
             .clone()
             .map(|json| {
                 ReservationRules::from_json(&json)
                     .map_err(|e| anyhow::anyhow!("Failed to parse reservation rules: {}", e))
             })
             .transpose()?;
+        if let Some(rules) = rules {
+            let errors = rules.validate();
+            if !errors.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Invalid reservation rules: {}",
+                    errors.join("; ")
+                ));
+            }
+        }
+
+        if field.capacity.is_some_and(|capacity| capacity < 1) {
+            return Err(anyhow::anyhow!("Capacity must be at least 1"));
+        }
 
         let field = sqlx::query_as!(
             Field,
             r#"
-            INSERT INTO "Field" (association_id, name, description, reservation_rules, latitude, longitude)
-            VALUES ($1, $2, $3, $4, $5, $6)
+            INSERT INTO "Field" (association_id, name, description, reservation_rules, latitude, longitude, capacity)
+            VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7, 1))
             RETURNING *
             "#,
             field.association_id,
@@ -173,6 +275,7 @@ impl Field {
             field.reservation_rules,
             field.latitude,
             field.longitude,
+            field.capacity,
         )
         .fetch_one(&mut *tx)
         .await?;
@@ -182,10 +285,52 @@ impl Field {
 
     pub async fn get(db: &DB, id: &Uuid) -> Result<Field, anyhow::Error> {
         let field = sqlx::query_as!(Field, r#"SELECT * FROM "Field" WHERE id = $1"#, id)
-            .fetch_one(&*db)
+            .fetch_one(db)
             .await?;
         Ok(field)
     }
+
+    /// Whether the field is full at some point of `[start_date, end_date)`
+    /// given the reservations overlapping it. Blocks always fill the field.
+    pub fn is_full(
+        &self,
+        overlapping: &[FieldReservation],
+        start_date: chrono::DateTime<Utc>,
+        end_date: chrono::DateTime<Utc>,
+    ) -> bool {
+        if overlapping.iter().any(|r| r.blocked) {
+            return true;
+        }
+        // Ends sort before starts at the same instant, so back-to-back
+        // reservations are not concurrent.
+        let mut boundaries: Vec<(chrono::DateTime<Utc>, i32)> = overlapping
+            .iter()
+            .flat_map(|r| {
+                [
+                    (r.start_date.max(start_date), 1),
+                    (r.end_date.min(end_date), -1),
+                ]
+            })
+            .collect();
+        boundaries.sort();
+        let mut concurrent = 0;
+        for (_, delta) in boundaries {
+            concurrent += delta;
+            if concurrent >= self.capacity {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn rules(&self) -> Result<Option<ReservationRules>, anyhow::Error> {
+        let rules = self
+            .reservation_rules
+            .as_deref()
+            .map(ReservationRules::from_json)
+            .transpose()?;
+        Ok(rules)
+    }
 }
 
 #[Object]
@@ -222,10 +367,66 @@ impl FieldReservation {
         self.updated_at
     }
 
+    pub async fn series_id(&self) -> Option<Uuid> {
+        self.series_id
+    }
+
+    pub async fn checked_in_at(&self) -> Option<chrono::DateTime<Utc>> {
+        self.checked_in_at
+    }
+
+    pub async fn no_show(&self) -> bool {
+        self.no_show
+    }
+
+    pub async fn blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// Members invited to the reservation.
+    pub async fn guests(
+        &self,
+        ctx: &Context<'_>,
+    ) -> Result<Vec<FieldReservationGuest>, anyhow::Error> {
+        let pool = ctx.data::<DB>().unwrap();
+        FieldReservationGuest::read_by_reservation(pool, &self.id).await
+    }
+
+    /// Admin actions taken on the reservation.
+    pub async fn moderations(
+        &self,
+        ctx: &Context<'_>,
+    ) -> Result<Vec<FieldReservationModeration>, anyhow::Error> {
+        let pool = ctx.data::<DB>().unwrap();
+        FieldReservationModeration::read_by_reservation(pool, &self.id).await
+    }
+
+    pub async fn charge_id(&self) -> Option<Uuid> {
+        self.charge_id
+    }
+
+    pub async fn status(&self) -> FieldReservationStatus {
+        if self.deleted {
+            FieldReservationStatus::Cancelled
+        } else if self.tentative {
+            FieldReservationStatus::Tentative
+        } else {
+            FieldReservationStatus::Confirmed
+        }
+    }
+
+    pub async fn charge(&self, ctx: &Context<'_>) -> Result<Option<Charge>, anyhow::Error> {
+        let pool = ctx.data::<DB>().unwrap();
+        match self.charge_id {
+            Some(charge_id) => Ok(Some(Charge::read_one(pool, &charge_id).await?)),
+            None => Ok(None),
+        }
+    }
+
     pub async fn user(&self, ctx: &Context<'_>) -> Result<User, anyhow::Error> {
         let pool = ctx.data::<DB>().unwrap();
         let user = sqlx::query_as!(User, r#"SELECT * FROM "User" WHERE id = $1"#, self.user_id)
-            .fetch_one(&*pool)
+            .fetch_one(pool)
             .await?;
         Ok(user)
     }
@@ -241,28 +442,203 @@ impl FieldReservation {
             r#"SELECT * FROM "FieldReservation" WHERE id = $1"#,
             field_reservation_id
         )
-        .fetch_one(&*db)
+        .fetch_one(db)
         .await?;
         Ok(field_reservation)
     }
 
+    /// Active reservations on `field_id` that intersect `[start_date, end_date)`.
+    pub async fn overlapping(
+        conn: &mut PgConnection,
+        field_id: &Uuid,
+        start_date: chrono::DateTime<Utc>,
+        end_date: chrono::DateTime<Utc>,
+    ) -> Result<Vec<FieldReservation>, anyhow::Error> {
+        let field_reservations = sqlx::query_as!(
+            FieldReservation,
+            r#"SELECT * FROM "FieldReservation"
+            WHERE field_id = $1 AND deleted = false AND start_date < $3 AND end_date > $2
+            ORDER BY start_date ASC, end_date ASC"#,
+            field_id,
+            start_date,
+            end_date,
+        )
+        .fetch_all(conn)
+        .await?;
+        Ok(field_reservations)
+    }
+
+    /// Cancels a reservation and, in the same transaction, promotes waitlisted
+    /// members into the freed slot.
     pub async fn delete(
         db: &DB,
         field_reservation_id: &Uuid,
+        now: chrono::DateTime<chrono::Utc>,
     ) -> Result<FieldReservation, anyhow::Error> {
         let mut tx = db.begin().await?;
+        let field_reservation = Self::cancel(&mut tx, field_reservation_id).await?;
+        let field = sqlx::query_as!(
+            Field,
+            r#"SELECT * FROM "Field" WHERE id = $1"#,
+            field_reservation.field_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        FieldWaitlist::promote(&mut tx, &field, &field_reservation, now).await?;
+        tx.commit().await?;
+
+        Ok(field_reservation)
+    }
+
+    /// Marks the reservation deleted and voids its charge if it was not paid.
+    /// Reservations that were already cancelled are refused.
+    pub(crate) async fn cancel(
+        conn: &mut PgConnection,
+        field_reservation_id: &Uuid,
+    ) -> Result<FieldReservation, anyhow::Error> {
         let field_reservation = sqlx::query_as!(
             FieldReservation,
             r#"
-            UPDATE "FieldReservation" SET deleted = true WHERE id = $1 RETURNING *
+            UPDATE "FieldReservation" SET deleted = true
+            WHERE id = $1 AND deleted = false
+            RETURNING *
             "#,
             field_reservation_id
         )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(anyhow::anyhow!("Reservation was cancelled"))?;
+        if let Some(charge_id) = field_reservation.charge_id {
+            sqlx::query!(
+                r#"UPDATE "Charge" SET deleted = true WHERE id = $1 AND settled_at IS NULL"#,
+                charge_id
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(field_reservation)
+    }
+
+    /// Changes the description or slot of a reservation. The new slot is
+    /// checked against the field rules as if the reservation did not exist,
+    /// and the original slot is kept if the change is rejected.
+    pub async fn update(
+        db: &DB,
+        field: &Field,
+        field_reservation: &FieldReservation,
+        update: FieldReservationUpdate,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<FieldReservation, anyhow::Error> {
+        if field_reservation.deleted {
+            return Err(anyhow::anyhow!("Reservation was cancelled"));
+        }
+        let start_date = update.start_date.unwrap_or(field_reservation.start_date);
+        let end_date = update.end_date.unwrap_or(field_reservation.end_date);
+        let slot_changed =
+            start_date != field_reservation.start_date || end_date != field_reservation.end_date;
+        if slot_changed && field_reservation.start_date <= now {
+            return Err(anyhow::anyhow!("Reservation already started"));
+        }
+        if start_date >= end_date {
+            return Err(anyhow::anyhow!("Start date must be before end date"));
+        }
+
+        let mut tx = db.begin().await?;
+        // Hide the reservation so it counts neither as an overlap nor towards
+        // quotas. Rolling back restores it.
+        let hidden = sqlx::query!(
+            r#"UPDATE "FieldReservation" SET deleted = true WHERE id = $1 AND deleted = false"#,
+            field_reservation.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if hidden.rows_affected() != 1 {
+            return Err(anyhow::anyhow!("Reservation was cancelled"));
+        }
+
+        if slot_changed {
+            let rules = field.rules()?;
+            if let Some(rules) = &rules {
+                let guest_ids =
+                    FieldReservationGuest::active_guest_ids(&mut tx, &field_reservation.id).await?;
+                let request = ReservationRequest {
+                    field,
+                    user_id: &field_reservation.user_id,
+                    start_date,
+                    end_date,
+                    now,
+                };
+                rules.check(&mut tx, &request, &guest_ids).await?;
+            }
+            Self::check_slot(
+                &mut tx,
+                field,
+                &field_reservation.user_id,
+                start_date,
+                end_date,
+            )
+            .await?;
+
+            if let Some(charge_id) = &field_reservation.charge_id {
+                let price = rules
+                    .as_ref()
+                    .and_then(|rules| rules.price(start_date, end_date))
+                    .unwrap_or_default();
+                Self::reprice_charge(&mut tx, charge_id, price).await?;
+            }
+        }
+
+        let updated = sqlx::query_as!(
+            FieldReservation,
+            r#"UPDATE "FieldReservation"
+            SET deleted = false, description = COALESCE($2, description), start_date = $3, end_date = $4
+            WHERE id = $1
+            RETURNING *"#,
+            field_reservation.id,
+            update.description,
+            start_date,
+            end_date
+        )
         .fetch_one(&mut *tx)
         .await?;
+        if slot_changed {
+            FieldWaitlist::promote(&mut tx, field, field_reservation, now).await?;
+        }
         tx.commit().await?;
 
-        Ok(field_reservation)
+        Ok(updated)
+    }
+
+    /// Sets the reservation's charge to the price of its new slot. Paid
+    /// charges cannot change.
+    pub(crate) async fn reprice_charge(
+        conn: &mut PgConnection,
+        charge_id: &Uuid,
+        price: BigDecimal,
+    ) -> Result<(), anyhow::Error> {
+        let charge = sqlx::query_as!(
+            Charge,
+            r#"SELECT * FROM "Charge" WHERE id = $1 FOR UPDATE"#,
+            charge_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        if charge.amount == price {
+            return Ok(());
+        }
+        if charge.settled_at.is_some() {
+            return Err(anyhow::anyhow!(
+                "Reservation is already paid, cancel it to book a different price"
+            ));
+        }
+        sqlx::query!(
+            r#"UPDATE "Charge" SET amount = $2 WHERE id = $1"#,
+            charge_id,
+            price
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
     }
 
     pub async fn create(
@@ -272,39 +648,115 @@ impl FieldReservation {
         field_reservation: FieldReservationInput,
         now: chrono::DateTime<chrono::Utc>,
     ) -> Result<FieldReservation, anyhow::Error> {
-        let rules = &field.reservation_rules;
+        let mut tx = db.begin().await?;
+        let field_reservation =
+            Self::insert_checked(&mut tx, user_id, field, field_reservation, now).await?;
+        tx.commit().await?;
 
-        if let Some(rules) = rules {
-            let rules: ReservationRules = serde_json::from_str(&rules)?;
-            rules
-                .can_reserve(
-                    db,
+        Ok(field_reservation)
+    }
+
+    /// Checks the field's reservation rules and inserts the reservation. On
+    /// paid fields the member is charged and the reservation stays tentative
+    /// until the charge is settled.
+    pub(crate) async fn insert_checked(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        field: &Field,
+        field_reservation: FieldReservationInput,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<FieldReservation, anyhow::Error> {
+        let guest_ids = FieldReservationGuest::check_guests(
+            &mut *conn,
+            field,
+            user_id,
+            field_reservation.guest_ids.as_deref().unwrap_or_default(),
+        )
+        .await?;
+
+        let rules = field.rules()?;
+        if let Some(rules) = &rules {
+            let request = ReservationRequest {
+                field,
+                user_id,
+                start_date: field_reservation.start_date,
+                end_date: field_reservation.end_date,
+                now,
+            };
+            rules.check(&mut *conn, &request, &guest_ids).await?;
+        }
+
+        Self::check_slot(
+            &mut *conn,
+            field,
+            user_id,
+            field_reservation.start_date,
+            field_reservation.end_date,
+        )
+        .await?;
+
+        let mut charge_id = None;
+        if let Some(rules) = &rules {
+            if let Some(price) =
+                rules.price(field_reservation.start_date, field_reservation.end_date)
+            {
+                let charge = Charge::create_for_member(
+                    &mut *conn,
+                    &field.association_id,
                     user_id,
-                    field_reservation.start_date,
-                    field_reservation.end_date,
-                    now,
+                    format!(
+                        "Reservation of {} from {} to {}",
+                        field.name, field_reservation.start_date, field_reservation.end_date
+                    ),
+                    price,
+                    field_reservation.start_date.date_naive(),
+                    now + rules.payment_window(),
                 )
                 .await?;
+                charge_id = Some(charge.id);
+            }
         }
 
-        let mut tx = db.begin().await?;
         let field_reservation = sqlx::query_as!(
             FieldReservation,
             r#"
-            INSERT INTO "FieldReservation" (field_id, user_id, description, start_date, end_date)
-            VALUES ($1, $2, $3, $4, $5)
+            INSERT INTO "FieldReservation" (field_id, user_id, description, start_date, end_date, charge_id, tentative)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
             RETURNING *
             "#,
             field.id,
             user_id,
             field_reservation.description,
             field_reservation.start_date,
-            field_reservation.end_date
+            field_reservation.end_date,
+            charge_id,
+            charge_id.is_some()
         )
-        .fetch_one(&mut *tx)
+        .fetch_one(&mut *conn)
         .await?;
-        tx.commit().await?;
+        FieldReservationGuest::invite(conn, field, &field_reservation, &guest_ids).await?;
 
         Ok(field_reservation)
     }
+
+    /// Fails if the field has no room left in `[start_date, end_date)` or the
+    /// user already holds a reservation there.
+    async fn check_slot(
+        conn: &mut PgConnection,
+        field: &Field,
+        user_id: &Uuid,
+        start_date: chrono::DateTime<Utc>,
+        end_date: chrono::DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        let overlapping = Self::overlapping(conn, &field.id, start_date, end_date).await?;
+        if field.is_full(&overlapping, start_date, end_date) {
+            return Err(anyhow::anyhow!("Field overlaps with another reservation"));
+        }
+        if overlapping.iter().any(|r| &r.user_id == user_id) {
+            return Err(anyhow::anyhow!(
+                "User already has a reservation in this slot"
+            ));
+        }
+        Ok(())
+    }
 }
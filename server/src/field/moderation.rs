@@ -0,0 +1,262 @@
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use uuid::Uuid;
+
+use crate::{notification::model::Notification, DB};
+
+use super::{
+    model::{Field, FieldReservation, FieldReservationInput},
+    waitlist::FieldWaitlist,
+};
+
+pub const ACTION_CANCELLED: &str = "cancelled";
+pub const ACTION_MOVED: &str = "moved";
+pub const ACTION_BLOCKED: &str = "blocked";
+
+/// An admin action on a reservation, with the reason given to the member.
+#[derive(Debug, SimpleObject, FromRow, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldReservationModeration {
+    pub id: Uuid,
+    pub reservation_id: Uuid,
+    pub admin_id: Uuid,
+    // One of `cancelled`, `moved` or `blocked`.
+    pub action: String,
+    pub reason: String,
+    // Slot the reservation had before being moved.
+    pub previous_start_date: Option<chrono::DateTime<Utc>>,
+    pub previous_end_date: Option<chrono::DateTime<Utc>>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+fn check_reason(reason: &str) -> Result<&str, anyhow::Error> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(anyhow::anyhow!("A reason is required"));
+    }
+    Ok(reason)
+}
+
+impl FieldReservationModeration {
+    pub async fn read_by_reservation(
+        db: &DB,
+        reservation_id: &Uuid,
+    ) -> Result<Vec<FieldReservationModeration>, anyhow::Error> {
+        let moderations = sqlx::query_as!(
+            FieldReservationModeration,
+            r#"SELECT * FROM "FieldReservationModeration"
+            WHERE reservation_id = $1
+            ORDER BY created_at ASC"#,
+            reservation_id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(moderations)
+    }
+
+    async fn record(
+        conn: &mut PgConnection,
+        reservation: &FieldReservation,
+        admin_id: &Uuid,
+        action: &str,
+        reason: &str,
+        previous: Option<(chrono::DateTime<Utc>, chrono::DateTime<Utc>)>,
+    ) -> Result<FieldReservationModeration, anyhow::Error> {
+        let moderation = sqlx::query_as!(
+            FieldReservationModeration,
+            r#"INSERT INTO "FieldReservationModeration"
+                (reservation_id, admin_id, action, reason, previous_start_date, previous_end_date)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *"#,
+            reservation.id,
+            admin_id,
+            action,
+            reason,
+            previous.map(|(start, _)| start),
+            previous.map(|(_, end)| end)
+        )
+        .fetch_one(conn)
+        .await?;
+        Ok(moderation)
+    }
+}
+
+impl FieldReservation {
+    /// Cancels any reservation on behalf of the association and tells the
+    /// member why. The freed slot goes to the waitlist.
+    pub async fn cancel_as_admin(
+        db: &DB,
+        admin_id: &Uuid,
+        field: &Field,
+        field_reservation_id: &Uuid,
+        reason: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<FieldReservation, anyhow::Error> {
+        let reason = check_reason(reason)?;
+
+        let mut tx = db.begin().await?;
+        let field_reservation = Self::cancel(&mut tx, field_reservation_id).await?;
+        Self::notify_cancelled(&mut tx, admin_id, field, &field_reservation, reason).await?;
+        FieldWaitlist::promote(&mut tx, field, &field_reservation, now).await?;
+        tx.commit().await?;
+        Ok(field_reservation)
+    }
+
+    /// Moves a reservation to another, upcoming slot, bypassing the field
+    /// rules. The new slot must be free. The unpaid charge of a reservation
+    /// on a paid field gets the price of the new slot.
+    pub async fn move_as_admin(
+        db: &DB,
+        admin_id: &Uuid,
+        field_reservation: &FieldReservation,
+        start_date: chrono::DateTime<Utc>,
+        end_date: chrono::DateTime<Utc>,
+        reason: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<FieldReservation, anyhow::Error> {
+        let reason = check_reason(reason)?;
+        if field_reservation.deleted {
+            return Err(anyhow::anyhow!("Reservation was cancelled"));
+        }
+        if start_date >= end_date {
+            return Err(anyhow::anyhow!("Start date must be before end date"));
+        }
+        if start_date <= now {
+            return Err(anyhow::anyhow!("Reservations cannot be moved to the past"));
+        }
+
+        let mut tx = db.begin().await?;
+        let field = sqlx::query_as!(
+            Field,
+            r#"SELECT * FROM "Field" WHERE id = $1"#,
+            field_reservation.field_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let mut overlapping = Self::overlapping(&mut tx, &field.id, start_date, end_date).await?;
+        overlapping.retain(|r| r.id != field_reservation.id);
+        if field.is_full(&overlapping, start_date, end_date) {
+            return Err(anyhow::anyhow!("Field overlaps with another reservation"));
+        }
+        if let Some(charge_id) = &field_reservation.charge_id {
+            let price = field
+                .rules()?
+                .and_then(|rules| rules.price(start_date, end_date))
+                .unwrap_or_default();
+            Self::reprice_charge(&mut tx, charge_id, price).await?;
+        }
+
+        let moved = sqlx::query_as!(
+            FieldReservation,
+            r#"UPDATE "FieldReservation" SET start_date = $2, end_date = $3
+            WHERE id = $1 AND deleted = false
+            RETURNING *"#,
+            field_reservation.id,
+            start_date,
+            end_date
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        FieldReservationModeration::record(
+            &mut tx,
+            &moved,
+            admin_id,
+            ACTION_MOVED,
+            reason,
+            Some((field_reservation.start_date, field_reservation.end_date)),
+        )
+        .await?;
+        Notification::create(
+            &mut tx,
+            &moved.user_id,
+            format!(
+                "Your reservation of {} was moved from {} - {} to {} - {}: {}",
+                field.name,
+                field_reservation.start_date,
+                field_reservation.end_date,
+                moved.start_date,
+                moved.end_date,
+                reason
+            ),
+        )
+        .await?;
+        FieldWaitlist::promote(&mut tx, &field, field_reservation, now).await?;
+        tx.commit().await?;
+        Ok(moved)
+    }
+
+    /// Blocks the field on behalf of the association, e.g. for a tournament.
+    /// Member reservations in the slot are cancelled with the given reason.
+    pub async fn block(
+        db: &DB,
+        admin_id: &Uuid,
+        field: &Field,
+        input: FieldReservationInput,
+        reason: &str,
+    ) -> Result<FieldReservation, anyhow::Error> {
+        let reason = check_reason(reason)?;
+        if input.start_date >= input.end_date {
+            return Err(anyhow::anyhow!("Start date must be before end date"));
+        }
+
+        let mut tx = db.begin().await?;
+        let overlapping =
+            Self::overlapping(&mut tx, &field.id, input.start_date, input.end_date).await?;
+        if overlapping.iter().any(|r| r.blocked) {
+            return Err(anyhow::anyhow!("Field is already blocked in this slot"));
+        }
+        for reservation in overlapping {
+            let cancelled = Self::cancel(&mut tx, &reservation.id).await?;
+            Self::notify_cancelled(&mut tx, admin_id, field, &cancelled, reason).await?;
+        }
+
+        let block = sqlx::query_as!(
+            FieldReservation,
+            r#"INSERT INTO "FieldReservation" (field_id, user_id, description, start_date, end_date, blocked)
+            VALUES ($1, $2, $3, $4, $5, true)
+            RETURNING *"#,
+            field.id,
+            admin_id,
+            input.description,
+            input.start_date,
+            input.end_date
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        FieldReservationModeration::record(&mut tx, &block, admin_id, ACTION_BLOCKED, reason, None)
+            .await?;
+        tx.commit().await?;
+        Ok(block)
+    }
+
+    async fn notify_cancelled(
+        conn: &mut PgConnection,
+        admin_id: &Uuid,
+        field: &Field,
+        cancelled: &FieldReservation,
+        reason: &str,
+    ) -> Result<(), anyhow::Error> {
+        FieldReservationModeration::record(
+            &mut *conn,
+            cancelled,
+            admin_id,
+            ACTION_CANCELLED,
+            reason,
+            None,
+        )
+        .await?;
+        Notification::create(
+            &mut *conn,
+            &cancelled.user_id,
+            format!(
+                "Your reservation of {} from {} to {} was cancelled by an admin: {}",
+                field.name, cancelled.start_date, cancelled.end_date, reason
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use async_graphql::SimpleObject;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::{
+    geo::{SearchArea, MAX_RESULTS},
+    DB,
+};
+
+use super::model::Field;
+
+#[derive(Debug, SimpleObject, FromRow)]
+pub struct NearbyField {
+    #[sqlx(flatten)]
+    pub field: Field,
+    pub distance_km: f64,
+}
+
+impl Field {
+    /// Fields within the search area, closest first. Only fields of public
+    /// associations or associations the user belongs to are returned.
+    pub async fn nearby(
+        db: &DB,
+        area: &SearchArea,
+        user_id: Option<Uuid>,
+    ) -> Result<Vec<NearbyField>, anyhow::Error> {
+        // Not checked at compile time: `query_as!` cannot fill the
+        // `#[sqlx(flatten)]` field of `NearbyField`.
+        let fields = sqlx::query_as::<_, NearbyField>(
+            r#"SELECT * FROM (
+                SELECT f.*, haversine_km($1, $2, f.latitude::float8, f.longitude::float8) AS distance_km
+                FROM "Field" f INNER JOIN "Association" a ON a.id = f.association_id
+                WHERE f.deleted IS NOT TRUE AND a.deleted IS NOT TRUE
+                    AND f.latitude BETWEEN $4::numeric AND $5::numeric
+                    AND f.longitude BETWEEN $6::numeric AND $7::numeric
+                    AND (a.public OR EXISTS (
+                        SELECT 1 FROM "AssociationRoles" ar
+                        WHERE ar.association_id = a.id AND ar.user_id = $8
+                    ))
+            ) nearby
+            WHERE distance_km <= $3
+            ORDER BY distance_km
+            LIMIT $9"#,
+        )
+        .bind(area.latitude)
+        .bind(area.longitude)
+        .bind(area.radius_km)
+        .bind(area.min_latitude)
+        .bind(area.max_latitude)
+        .bind(area.min_longitude)
+        .bind(area.max_longitude)
+        .bind(user_id)
+        .bind(MAX_RESULTS)
+        .fetch_all(db)
+        .await?;
+        Ok(fields)
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+use async_graphql::SimpleObject;
+use chrono::{Datelike, Duration, Timelike, Utc};
+use chrono_tz::Tz;
+use uuid::Uuid;
+
+use crate::{association::model::parse_timezone, DB};
+
+use super::{
+    model::{Field, FieldReservation},
+    series::DayOfWeek,
+};
+
+/// Longest period an occupancy report may cover.
+const MAX_OCCUPANCY_DAYS: i64 = 366;
+/// Number of members listed in `top_users`.
+const TOP_USERS: usize = 10;
+
+/// Booked hours in one hour of the week, in the association's timezone.
+#[derive(Debug, SimpleObject)]
+pub struct OccupancyHour {
+    pub weekday: DayOfWeek,
+    pub hour: u32,
+    pub booked_hours: f64,
+}
+
+#[derive(Debug, SimpleObject)]
+pub struct OccupancyUser {
+    pub user_id: Uuid,
+    pub reservations: i64,
+    pub booked_hours: f64,
+}
+
+/// Usage of one or more fields between `from` and `to`. Available hours are
+/// the period times the field capacity, less the time the association
+/// blocked the field. Recurring series count as booked time but not towards
+/// no-shows, which are not tracked for them.
+#[derive(Debug, SimpleObject)]
+pub struct OccupancyReport {
+    pub from: chrono::DateTime<Utc>,
+    pub to: chrono::DateTime<Utc>,
+    pub booked_hours: f64,
+    pub available_hours: f64,
+    pub occupancy_rate: f64,
+    pub reservations: i64,
+    pub cancellations: i64,
+    pub cancellation_rate: f64,
+    pub no_shows: i64,
+    pub no_show_rate: f64,
+    // Only hours with bookings are listed, by weekday and hour.
+    pub peak_hours: Vec<OccupancyHour>,
+    pub top_users: Vec<OccupancyUser>,
+}
+
+fn hours(duration: Duration) -> f64 {
+    duration.num_seconds() as f64 / 3600.0
+}
+
+fn rate(part: f64, total: f64) -> f64 {
+    if total > 0.0 {
+        part / total
+    } else {
+        0.0
+    }
+}
+
+impl OccupancyReport {
+    /// Builds the report for fields of the same association.
+    pub async fn for_fields(
+        db: &DB,
+        association_id: &Uuid,
+        fields: &[Field],
+        from: chrono::DateTime<Utc>,
+        to: chrono::DateTime<Utc>,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<OccupancyReport, anyhow::Error> {
+        if from >= to {
+            return Err(anyhow::anyhow!("Start must be before end"));
+        }
+        if to - from > Duration::days(MAX_OCCUPANCY_DAYS) {
+            return Err(anyhow::anyhow!(
+                "Reports can span at most {} days",
+                MAX_OCCUPANCY_DAYS
+            ));
+        }
+
+        let timezone = sqlx::query_scalar!(
+            r#"SELECT timezone FROM "Association" WHERE id = $1"#,
+            association_id
+        )
+        .fetch_one(db)
+        .await?;
+        let tz = parse_timezone(&timezone)?;
+
+        let field_ids: Vec<Uuid> = fields.iter().map(|field| field.id).collect();
+        let reservations = sqlx::query_as!(
+            FieldReservation,
+            r#"SELECT * FROM "FieldReservation"
+            WHERE field_id = ANY($1) AND start_date < $3 AND end_date > $2
+            ORDER BY start_date"#,
+            &field_ids,
+            from,
+            to
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(Self::compute(fields, &reservations, from, to, now, tz))
+    }
+
+    fn compute(
+        fields: &[Field],
+        reservations: &[FieldReservation],
+        from: chrono::DateTime<Utc>,
+        to: chrono::DateTime<Utc>,
+        now: chrono::DateTime<Utc>,
+        tz: Tz,
+    ) -> OccupancyReport {
+        let capacities: HashMap<Uuid, i32> = fields
+            .iter()
+            .map(|field| (field.id, field.capacity))
+            .collect();
+        let mut available_hours: f64 = fields
+            .iter()
+            .map(|field| field.capacity as f64 * hours(to - from))
+            .sum();
+
+        let mut booked_hours = 0.0;
+        let mut count = 0;
+        let mut cancellations = 0;
+        let mut no_shows = 0;
+        let mut tracked = 0;
+        let mut heatmap: HashMap<(u32, u32), f64> = HashMap::new();
+        let mut users: HashMap<Uuid, (i64, f64)> = HashMap::new();
+
+        for reservation in reservations {
+            let start = reservation.start_date.max(from);
+            let end = reservation.end_date.min(to);
+            if reservation.blocked {
+                if !reservation.deleted {
+                    let capacity = capacities.get(&reservation.field_id).copied().unwrap_or(1);
+                    available_hours -= capacity as f64 * hours(end - start);
+                }
+                continue;
+            }
+            if reservation.deleted {
+                cancellations += 1;
+                continue;
+            }
+
+            count += 1;
+            booked_hours += hours(end - start);
+            if reservation.series_id.is_none() && reservation.end_date <= now {
+                tracked += 1;
+                if reservation.no_show {
+                    no_shows += 1;
+                }
+            }
+            let user = users.entry(reservation.user_id).or_default();
+            user.0 += 1;
+            user.1 += hours(end - start);
+
+            // Split the reservation into local clock hours.
+            let mut time = start;
+            while time < end {
+                let local = time.with_timezone(&tz);
+                let into_hour =
+                    Duration::seconds(local.minute() as i64 * 60 + local.second() as i64);
+                let next = (time - into_hour + Duration::hours(1)).min(end);
+                *heatmap
+                    .entry((local.weekday().num_days_from_monday(), local.hour()))
+                    .or_default() += hours(next - time);
+                time = next;
+            }
+        }
+
+        let mut peak_hours: Vec<OccupancyHour> = heatmap
+            .into_iter()
+            .map(|((weekday, hour), booked_hours)| OccupancyHour {
+                weekday: chrono::Weekday::try_from(weekday as u8)
+                    .expect("weekday is in range")
+                    .into(),
+                hour,
+                booked_hours,
+            })
+            .collect();
+        peak_hours.sort_by_key(|cell| {
+            (
+                chrono::Weekday::from(cell.weekday).num_days_from_monday(),
+                cell.hour,
+            )
+        });
+
+        let mut top_users: Vec<OccupancyUser> = users
+            .into_iter()
+            .map(|(user_id, (reservations, booked_hours))| OccupancyUser {
+                user_id,
+                reservations,
+                booked_hours,
+            })
+            .collect();
+        top_users.sort_by(|a, b| {
+            b.booked_hours
+                .total_cmp(&a.booked_hours)
+                .then(b.reservations.cmp(&a.reservations))
+                .then(a.user_id.cmp(&b.user_id))
+        });
+        top_users.truncate(TOP_USERS);
+
+        OccupancyReport {
+            from,
+            to,
+            booked_hours,
+            available_hours,
+            occupancy_rate: rate(booked_hours, available_hours),
+            reservations: count,
+            cancellations,
+            cancellation_rate: rate(cancellations as f64, (count + cancellations) as f64),
+            no_shows,
+            no_show_rate: rate(no_shows as f64, tracked as f64),
+            peak_hours,
+            top_users,
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use chrono::Utc;
+
+use crate::{notification::model::Notification, DB};
+
+use super::{
+    model::{Field, FieldReservation},
+    waitlist::FieldWaitlist,
+};
+
+impl FieldReservation {
+    /// Cancels tentative reservations whose charge expired unpaid, voids the
+    /// charges and promotes waitlisted members into the freed slots.
+    pub async fn release_expired(
+        db: &DB,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Vec<FieldReservation>, anyhow::Error> {
+        let mut tx = db.begin().await?;
+        let released = sqlx::query_as!(
+            FieldReservation,
+            r#"UPDATE "FieldReservation" r SET deleted = true
+            FROM "Charge" c
+            WHERE c.id = r.charge_id AND r.deleted = false AND r.tentative = true
+                AND c.settled_at IS NULL AND c.expires_at <= $1
+            RETURNING r.*"#,
+            now
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for reservation in &released {
+            sqlx::query!(
+                r#"UPDATE "Charge" SET deleted = true WHERE id = $1"#,
+                reservation.charge_id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            let field = sqlx::query_as!(
+                Field,
+                r#"SELECT * FROM "Field" WHERE id = $1"#,
+                reservation.field_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            Notification::create(
+                &mut tx,
+                &reservation.user_id,
+                format!(
+                    "Your reservation of {} from {} to {} was cancelled because it was not paid in time.",
+                    field.name, reservation.start_date, reservation.end_date
+                ),
+            )
+            .await?;
+            FieldWaitlist::promote(&mut tx, &field, reservation, now).await?;
+        }
+        tx.commit().await?;
+        Ok(released)
+    }
+}
@@ -1,107 +1,613 @@
-use chrono::NaiveTime;
+use async_graphql::SimpleObject;
+use bigdecimal::{BigDecimal, Zero};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use uuid::Uuid;
 
-use crate::{field::model::FieldReservation, DB};
+use crate::{association::model::parse_timezone, field::model::Field, relations::model::Role};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Version written by `to_json`. Rules without a version are the flat format
+/// used before rule lists.
+pub const RULES_VERSION: u32 = 2;
+pub const DEFAULT_CHECK_IN_WINDOW_MINUTES: u32 = 15;
+const DEFAULT_PAYMENT_WINDOW_MINUTES: u32 = 60;
+const DEFAULT_NO_SHOW_PERIOD_DAYS: u32 = 30;
+
+fn default_check_in_window_minutes() -> u32 {
+    DEFAULT_CHECK_IN_WINDOW_MINUTES
+}
+
+fn default_no_show_period_days() -> u32 {
+    DEFAULT_NO_SHOW_PERIOD_DAYS
+}
+
+fn default_payment_window_minutes() -> u32 {
+    DEFAULT_PAYMENT_WINDOW_MINUTES
+}
+
+/// First instant at or after a local time. Times in a DST gap are moved to
+/// when the clocks jump forward.
+pub(crate) fn local_instant(tz: &Tz, local: NaiveDateTime) -> chrono::DateTime<chrono::Utc> {
+    (0..=24 * 4)
+        .map(|quarters| local + chrono::Duration::minutes(15 * quarters))
+        .find_map(|local| tz.from_local_datetime(&local).earliest())
+        .expect("Day should have a valid local time")
+        .with_timezone(&chrono::Utc)
+}
+
+/// First instant of a local day.
+fn local_day_start(tz: &Tz, day: NaiveDate) -> chrono::DateTime<chrono::Utc> {
+    local_instant(tz, day.and_hms_opt(0, 0, 0).expect("Should be valid time"))
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum ReservationPeriod {
     Daily,
+    // Monday to Sunday.
+    Weekly,
+    Monthly,
+}
+
+impl ReservationPeriod {
+    /// Local days the period containing `day` starts and ends (exclusive) on.
+    fn bounds(&self, day: NaiveDate) -> (NaiveDate, NaiveDate) {
+        match self {
+            ReservationPeriod::Daily => (day, day + chrono::Days::new(1)),
+            ReservationPeriod::Weekly => {
+                let monday = day - chrono::Days::new(day.weekday().num_days_from_monday() as u64);
+                (monday, monday + chrono::Days::new(7))
+            }
+            ReservationPeriod::Monthly => {
+                let first = day.with_day(1).expect("Should be valid date");
+                (first, first + chrono::Months::new(1))
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ReservationPeriod::Daily => "day",
+            ReservationPeriod::Weekly => "week",
+            ReservationPeriod::Monthly => "month",
+        }
+    }
+}
+
+/// A single reservation policy. Times of day and periods are local to the
+/// association's timezone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ReservationRule {
+    /// Reservations can only be made for the current day.
+    SameDayOnly,
+    /// Bookings are only accepted from this time of day on.
+    OpensAt {
+        time: NaiveTime,
+    },
+    MaxDuration {
+        minutes: u32,
+    },
+    /// Reservations must start at least this long after booking.
+    MinNotice {
+        minutes: u32,
+    },
+    /// Reservations can start at most this many days ahead.
+    MaxAdvance {
+        days: u32,
+    },
+    /// Reservations per member in the period containing the reservation
+    /// start, as owner or invited guest. Series occurrences and association
+    /// blocks are not counted.
+    QuotaPerPeriod {
+        max_reservations: u32,
+        period: ReservationPeriod,
+    },
+    /// Pending members cannot reserve.
+    MembersOnly,
+    /// Only users holding one of these roles in the association can reserve.
+    RoleRestricted {
+        roles: Vec<Role>,
+    },
+    /// Days since the member joined the association.
+    MinMembershipAge {
+        days: u32,
+    },
+    /// Reservations that have not ended yet a member may hold across the
+    /// association's fields.
+    MaxConcurrentFutureBookings {
+        max: u32,
+    },
+    /// Unexcused no-shows within `period_days` that block a member.
+    NoShowLimit {
+        max_no_shows: u32,
+        period_days: u32,
+    },
+}
+
+impl ReservationRule {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReservationRule::SameDayOnly => "same_day_only",
+            ReservationRule::OpensAt { .. } => "opens_at",
+            ReservationRule::MaxDuration { .. } => "max_duration",
+            ReservationRule::MinNotice { .. } => "min_notice",
+            ReservationRule::MaxAdvance { .. } => "max_advance",
+            ReservationRule::QuotaPerPeriod { .. } => "quota_per_period",
+            ReservationRule::MembersOnly => "members_only",
+            ReservationRule::RoleRestricted { .. } => "role_restricted",
+            ReservationRule::MinMembershipAge { .. } => "min_membership_age",
+            ReservationRule::MaxConcurrentFutureBookings { .. } => "max_concurrent_future_bookings",
+            ReservationRule::NoShowLimit { .. } => "no_show_limit",
+        }
+    }
+
+    /// Whether the rule depends on who reserves, so it also applies to
+    /// invited guests.
+    fn applies_to_guests(&self) -> bool {
+        matches!(
+            self,
+            ReservationRule::QuotaPerPeriod { .. }
+                | ReservationRule::MembersOnly
+                | ReservationRule::RoleRestricted { .. }
+                | ReservationRule::MinMembershipAge { .. }
+                | ReservationRule::MaxConcurrentFutureBookings { .. }
+                | ReservationRule::NoShowLimit { .. }
+        )
+    }
+
+    fn validate(&self) -> Option<String> {
+        match self {
+            ReservationRule::MaxDuration { minutes: 0 } => {
+                Some("max_duration: minutes must be positive".to_owned())
+            }
+            ReservationRule::RoleRestricted { roles } if roles.is_empty() => {
+                Some("role_restricted: at least one role is required".to_owned())
+            }
+            ReservationRule::NoShowLimit { period_days: 0, .. } => {
+                Some("no_show_limit: period_days must be positive".to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns a message if the reservation breaks this rule.
+    async fn check(
+        &self,
+        conn: &mut PgConnection,
+        request: &ReservationRequest<'_>,
+        tz: &Tz,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let association_id = request.field.association_id;
+        let local_now = request.now.with_timezone(tz);
+        let local_start = request.start_date.with_timezone(tz);
+
+        let violation = match self {
+            ReservationRule::SameDayOnly => (local_start.date_naive() != local_now.date_naive())
+                .then(|| "Reservations can only be made for today".to_owned()),
+            ReservationRule::OpensAt { time } => (local_now.time() < *time)
+                .then(|| format!("Reservations can only be made after {}", time)),
+            ReservationRule::MaxDuration { minutes } => {
+                let duration = (request.end_date - request.start_date).num_minutes();
+                (duration > *minutes as i64).then(|| {
+                    format!(
+                        "Reservations can only be made for a maximum of {} minutes",
+                        minutes
+                    )
+                })
+            }
+            ReservationRule::MinNotice { minutes } => {
+                let notice = (request.start_date - request.now).num_minutes();
+                (notice < *minutes as i64).then(|| {
+                    format!(
+                        "Reservations must be made at least {} minutes in advance",
+                        minutes
+                    )
+                })
+            }
+            ReservationRule::MaxAdvance { days } => {
+                let limit = request.now + chrono::Duration::days(*days as i64);
+                (request.start_date > limit)
+                    .then(|| format!("Reservations can be made at most {} days in advance", days))
+            }
+            ReservationRule::QuotaPerPeriod {
+                max_reservations,
+                period,
+            } => {
+                let (first_day, end_day) = period.bounds(local_start.date_naive());
+                let count = sqlx::query_scalar!(
+                    r#"SELECT count(*) AS "count!" FROM "FieldReservation" r
+                    INNER JOIN "Field" f ON f.id = r.field_id
+                    WHERE f.association_id = $1 AND r.deleted = false
+                        AND r.series_id IS NULL AND r.blocked = false
+                        AND r.start_date >= $3 AND r.start_date < $4
+                        AND (r.user_id = $2 OR EXISTS (
+                            SELECT 1 FROM "FieldReservationGuest" g
+                            WHERE g.reservation_id = r.id AND g.user_id = $2 AND g.declined_at IS NULL
+                        ))"#,
+                    association_id,
+                    request.user_id,
+                    local_day_start(tz, first_day),
+                    local_day_start(tz, end_day),
+                )
+                .fetch_one(&mut *conn)
+                .await?;
+                (count as u32 >= *max_reservations).then(|| {
+                    format!(
+                        "User has reached the maximum number of reservations for the {}",
+                        period.name()
+                    )
+                })
+            }
+            ReservationRule::MembersOnly => {
+                let pending = sqlx::query_scalar!(
+                    r#"SELECT pending FROM "AssociationRoles"
+                    WHERE user_id = $1 AND association_id = $2 AND role = 'member'"#,
+                    request.user_id,
+                    association_id
+                )
+                .fetch_optional(&mut *conn)
+                .await?;
+                (pending != Some(false))
+                    .then(|| "Only approved members can make reservations".to_owned())
+            }
+            ReservationRule::RoleRestricted { roles } => {
+                let held = sqlx::query_scalar!(
+                    r#"SELECT role AS "role: Role" FROM "AssociationRoles"
+                    WHERE user_id = $1 AND association_id = $2 AND pending = false"#,
+                    request.user_id,
+                    association_id
+                )
+                .fetch_all(&mut *conn)
+                .await?;
+                (!held.iter().any(|role| roles.contains(role))).then(|| {
+                    let roles: Vec<String> =
+                        roles.iter().map(|role| format!("{:?}", role)).collect();
+                    format!("Reservations are restricted to {}", roles.join(", "))
+                })
+            }
+            ReservationRule::MinMembershipAge { days } => {
+                let joined_at = sqlx::query_scalar!(
+                    r#"SELECT created_at FROM "AssociationRoles"
+                    WHERE user_id = $1 AND association_id = $2 AND role = 'member'"#,
+                    request.user_id,
+                    association_id
+                )
+                .fetch_optional(&mut *conn)
+                .await?;
+                let old_enough = joined_at.is_some_and(|joined_at| {
+                    request.now - joined_at.and_utc() >= chrono::Duration::days(*days as i64)
+                });
+                (!old_enough).then(|| {
+                    format!(
+                        "Only members for at least {} days can make reservations",
+                        days
+                    )
+                })
+            }
+            ReservationRule::MaxConcurrentFutureBookings { max } => {
+                let count = sqlx::query_scalar!(
+                    r#"SELECT count(*) AS "count!" FROM "FieldReservation" r
+                    INNER JOIN "Field" f ON f.id = r.field_id
+                    WHERE f.association_id = $1 AND r.deleted = false
+                        AND r.series_id IS NULL AND r.blocked = false AND r.end_date > $3
+                        AND (r.user_id = $2 OR EXISTS (
+                            SELECT 1 FROM "FieldReservationGuest" g
+                            WHERE g.reservation_id = r.id AND g.user_id = $2 AND g.declined_at IS NULL
+                        ))"#,
+                    association_id,
+                    request.user_id,
+                    request.now
+                )
+                .fetch_one(&mut *conn)
+                .await?;
+                (count as u32 >= *max).then(|| {
+                    format!(
+                        "User already has {} upcoming reservations, the maximum is {}",
+                        count, max
+                    )
+                })
+            }
+            ReservationRule::NoShowLimit {
+                max_no_shows,
+                period_days,
+            } => {
+                let since = request.now - chrono::Duration::days(*period_days as i64);
+                let no_shows = sqlx::query_scalar!(
+                    r#"SELECT count(*) AS "count!" FROM "FieldReservation" r
+                    INNER JOIN "Field" f ON f.id = r.field_id
+                    WHERE f.association_id = $1 AND r.user_id = $2 AND r.no_show = true
+                        AND r.no_show_excused = false AND r.start_date >= $3"#,
+                    association_id,
+                    request.user_id,
+                    since,
+                )
+                .fetch_one(&mut *conn)
+                .await?;
+                (no_shows as u32 >= *max_no_shows).then(|| {
+                    format!(
+                        "User is blocked from reserving after {} no-shows in the last {} days",
+                        no_shows, period_days
+                    )
+                })
+            }
+        };
+        Ok(violation)
+    }
+}
+
+#[derive(Debug, SimpleObject)]
+pub struct RuleViolation {
+    pub rule: String,
+    pub message: String,
+}
+
+#[derive(Debug, SimpleObject)]
+pub struct ReservationRulesValidation {
+    pub valid: bool,
+    pub errors: Vec<String>,
+    // Rules in the current format, ready to be saved.
+    pub normalized: Option<String>,
+    // Rules a sample reservation would break.
+    pub violations: Vec<RuleViolation>,
+}
+
+/// A reservation being checked against the field rules.
+pub struct ReservationRequest<'a> {
+    pub field: &'a Field,
+    pub user_id: &'a Uuid,
+    pub start_date: chrono::DateTime<chrono::Utc>,
+    pub end_date: chrono::DateTime<chrono::Utc>,
+    pub now: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ReservationRules {
-    reservations_start_at_time_utc: chrono::NaiveTime,
+    version: u32,
+    rules: Vec<ReservationRule>,
+    // Minutes before and after the reservation start during which members can check in.
+    #[serde(default = "default_check_in_window_minutes")]
+    check_in_window_minutes: u32,
+    // Paid fields charge a flat fee plus an hourly rate. Reservations stay
+    // tentative until the charge is settled within `payment_window_minutes`.
+    #[serde(default)]
+    price_per_reservation: Option<BigDecimal>,
+    #[serde(default)]
+    price_per_hour: Option<BigDecimal>,
+    #[serde(default = "default_payment_window_minutes")]
+    payment_window_minutes: u32,
+}
+
+/// Flat rules stored before versioning, equivalent to a same-day policy.
+#[derive(Debug, Deserialize)]
+struct LegacyReservationRules {
+    #[serde(alias = "reservations_start_at_time_utc")]
+    reservations_start_at_time: chrono::NaiveTime,
     max_duration_minutes: u32,
     max_reservations_per_period: u32,
     reservation_period: ReservationPeriod,
+    #[serde(default = "default_check_in_window_minutes")]
+    check_in_window_minutes: u32,
+    #[serde(default)]
+    max_no_shows: Option<u32>,
+    #[serde(default = "default_no_show_period_days")]
+    no_show_period_days: u32,
+    #[serde(default)]
+    price_per_reservation: Option<BigDecimal>,
+    #[serde(default)]
+    price_per_hour: Option<BigDecimal>,
+    #[serde(default = "default_payment_window_minutes")]
+    payment_window_minutes: u32,
+}
+
+impl From<LegacyReservationRules> for ReservationRules {
+    fn from(legacy: LegacyReservationRules) -> Self {
+        let mut rules = ReservationRules::new(
+            legacy.reservations_start_at_time,
+            legacy.max_duration_minutes,
+            legacy.max_reservations_per_period,
+            legacy.reservation_period,
+        );
+        if let Some(max_no_shows) = legacy.max_no_shows {
+            rules = rules.with_no_show_limit(max_no_shows, legacy.no_show_period_days);
+        }
+        rules.check_in_window_minutes = legacy.check_in_window_minutes;
+        rules.with_price(
+            legacy.price_per_reservation,
+            legacy.price_per_hour,
+            legacy.payment_window_minutes,
+        )
+    }
 }
 
 impl ReservationRules {
+    /// Same-day booking opening at `reservations_start_at_time`, with a
+    /// maximum duration and a quota per period.
     pub fn new(
-        reservations_start_at_time_utc: chrono::NaiveTime,
+        reservations_start_at_time: chrono::NaiveTime,
         max_duration_minutes: u32,
         max_reservations_per_period: u32,
         reservation_period: ReservationPeriod,
     ) -> Self {
+        Self::from_rules(vec![
+            ReservationRule::SameDayOnly,
+            ReservationRule::OpensAt {
+                time: reservations_start_at_time,
+            },
+            ReservationRule::MaxDuration {
+                minutes: max_duration_minutes,
+            },
+            ReservationRule::QuotaPerPeriod {
+                max_reservations: max_reservations_per_period,
+                period: reservation_period,
+            },
+        ])
+    }
+
+    pub fn from_rules(rules: Vec<ReservationRule>) -> Self {
         Self {
-            reservations_start_at_time_utc,
-            max_duration_minutes,
-            max_reservations_per_period,
-            reservation_period,
+            version: RULES_VERSION,
+            rules,
+            check_in_window_minutes: DEFAULT_CHECK_IN_WINDOW_MINUTES,
+            price_per_reservation: None,
+            price_per_hour: None,
+            payment_window_minutes: DEFAULT_PAYMENT_WINDOW_MINUTES,
+        }
+    }
+
+    pub fn with_rule(mut self, rule: ReservationRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn with_no_show_limit(self, max_no_shows: u32, no_show_period_days: u32) -> Self {
+        self.with_rule(ReservationRule::NoShowLimit {
+            max_no_shows,
+            period_days: no_show_period_days,
+        })
+    }
+
+    pub fn with_price(
+        mut self,
+        price_per_reservation: Option<BigDecimal>,
+        price_per_hour: Option<BigDecimal>,
+        payment_window_minutes: u32,
+    ) -> Self {
+        self.price_per_reservation = price_per_reservation;
+        self.price_per_hour = price_per_hour;
+        self.payment_window_minutes = payment_window_minutes;
+        self
+    }
+
+    pub fn rules(&self) -> &[ReservationRule] {
+        &self.rules
+    }
+
+    pub fn check_in_window(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.check_in_window_minutes as i64)
+    }
+
+    pub fn payment_window(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.payment_window_minutes as i64)
+    }
+
+    /// Price of a reservation, or `None` if reserving is free.
+    pub fn price(
+        &self,
+        start_date_time: chrono::DateTime<chrono::Utc>,
+        end_date_time: chrono::DateTime<chrono::Utc>,
+    ) -> Option<BigDecimal> {
+        let mut price = self.price_per_reservation.clone().unwrap_or_default();
+        if let Some(price_per_hour) = &self.price_per_hour {
+            let minutes = (end_date_time - start_date_time).num_minutes();
+            price += price_per_hour * BigDecimal::from(minutes) / BigDecimal::from(60);
         }
+        let price = price.round(2);
+        (price > BigDecimal::zero()).then_some(price)
     }
-    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(json)
+
+    /// Parses versioned rules, or the flat format used before versioning.
+    pub fn from_json(json: &str) -> Result<Self, anyhow::Error> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        let rules = match value.get("version").and_then(|version| version.as_u64()) {
+            None => serde_json::from_value::<LegacyReservationRules>(value)?.into(),
+            Some(version) if version == RULES_VERSION as u64 => serde_json::from_value(value)?,
+            Some(version) => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported reservation rules version {}",
+                    version
+                ))
+            }
+        };
+        Ok(rules)
     }
 
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
     }
 
+    /// Problems that would make the rules misbehave, e.g. an empty role list.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors: Vec<String> = self.rules.iter().filter_map(|r| r.validate()).collect();
+        if self.payment_window_minutes == 0 {
+            errors.push("payment_window_minutes must be positive".to_owned());
+        }
+        for price in [&self.price_per_reservation, &self.price_per_hour]
+            .into_iter()
+            .flatten()
+        {
+            if price < &BigDecimal::zero() {
+                errors.push("Prices cannot be negative".to_owned());
+            }
+        }
+        errors
+    }
+
+    /// Checks every rule and returns all the ones the reservation breaks.
     pub async fn can_reserve(
         &self,
-        db: &DB,
-        user_id: &uuid::Uuid,
-        start_date_time: chrono::DateTime<chrono::Utc>,
-        end_date_time: chrono::DateTime<chrono::Utc>,
-        now: chrono::DateTime<chrono::Utc>,
+        conn: &mut PgConnection,
+        request: &ReservationRequest<'_>,
+    ) -> Result<Vec<RuleViolation>, anyhow::Error> {
+        self.violations(conn, request, false).await
+    }
+
+    async fn violations(
+        &self,
+        conn: &mut PgConnection,
+        request: &ReservationRequest<'_>,
+        guest: bool,
+    ) -> Result<Vec<RuleViolation>, anyhow::Error> {
+        let timezone = sqlx::query_scalar!(
+            r#"SELECT timezone FROM "Association" WHERE id = $1"#,
+            request.field.association_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        let tz = parse_timezone(&timezone)?;
+
+        let mut violations = vec![];
+        for rule in &self.rules {
+            if guest && !rule.applies_to_guests() {
+                continue;
+            }
+            if let Some(message) = rule.check(&mut *conn, request, &tz).await? {
+                violations.push(RuleViolation {
+                    rule: rule.kind().to_owned(),
+                    message,
+                });
+            }
+        }
+        Ok(violations)
+    }
+
+    /// Fails with every broken rule if the reservation is not allowed for the
+    /// owner or any of the guests.
+    pub async fn check(
+        &self,
+        conn: &mut PgConnection,
+        request: &ReservationRequest<'_>,
+        guest_ids: &[Uuid],
     ) -> Result<(), anyhow::Error> {
-        match self.reservation_period {
-            ReservationPeriod::Daily => {
-                if now.date_naive() != start_date_time.date_naive() {
-                    return Err(anyhow::anyhow!("Reservations can only be made for today"));
-                }
-                if now.time() < self.reservations_start_at_time_utc {
-                    return Err(anyhow::anyhow!(
-                        "Reservations can only be made after {}",
-                        self.reservations_start_at_time_utc
-                    ));
-                }
-                let duration_minutes = (end_date_time - start_date_time).num_minutes() as u32;
-                if duration_minutes > self.max_duration_minutes {
-                    return Err(anyhow::anyhow!(
-                        "Reservations can only be made for a maximum of {} minutes",
-                        self.max_duration_minutes
-                    ));
-                }
-
-                let reservations = sqlx::query_as!(
-                    FieldReservation,
-                    r#"SELECT * FROM "FieldReservation" WHERE deleted = false AND start_date >= $1 AND end_date <= $2
-                    ORDER BY start_date ASC, end_date ASC;"#,
-                    start_date_time,
-                    end_date_time,
-                )
-                .fetch_optional(&*db)
-                .await?;
-                if reservations.is_some() {
-                    return Err(anyhow::anyhow!("Field overlaps with another reservation"));
-                }
-
-                let today_start = now
-                    .with_time(NaiveTime::from_hms_opt(0, 0, 0).expect("Should be valid time"))
-                    .unwrap();
-                let today_end = now
-                    .with_time(NaiveTime::from_hms_opt(23, 59, 59).expect("Should be valid time"))
-                    .unwrap();
-                let user_reservations = sqlx::query!(
-                    r#"SELECT count(*) FROM "FieldReservation" WHERE deleted = false AND user_id = $1 AND start_date >= $2 AND end_date <= $3"#,
-                    user_id,
-                    today_start,
-                    today_end,
-                ).fetch_optional(&*db).await?;
-
-                if let Some(user_reservations) = user_reservations {
-                    if user_reservations.count.unwrap_or(0) as u32
-                        >= self.max_reservations_per_period
-                    {
-                        return Err(anyhow::anyhow!(
-                            "User has reached the maximum number of reservations for today"
-                        ));
-                    }
-                }
+        let mut messages: Vec<String> = self
+            .can_reserve(&mut *conn, request)
+            .await?
+            .into_iter()
+            .map(|v| v.message)
+            .collect();
+        for guest_id in guest_ids {
+            let guest_request = ReservationRequest {
+                user_id: guest_id,
+                ..*request
+            };
+            for violation in self.violations(&mut *conn, &guest_request, true).await? {
+                messages.push(format!("Guest {}: {}", guest_id, violation.message));
             }
         }
-        Ok(())
+        if messages.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!(messages.join("; ")))
     }
 }
@@ -0,0 +1,298 @@
+use async_graphql::{Enum, InputObject, Object};
+use chrono::{Datelike, NaiveDate, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::{association::model::parse_timezone, DB};
+
+use super::{
+    model::{Field, FieldReservation},
+    rules::local_instant,
+    waitlist::FieldWaitlist,
+};
+
+/// Longest span a series may cover, so occurrences stay bounded.
+const MAX_SERIES_DAYS: i64 = 366;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Enum, Deserialize, Serialize)]
+pub enum DayOfWeek {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl From<DayOfWeek> for Weekday {
+    fn from(day: DayOfWeek) -> Self {
+        match day {
+            DayOfWeek::Monday => Weekday::Mon,
+            DayOfWeek::Tuesday => Weekday::Tue,
+            DayOfWeek::Wednesday => Weekday::Wed,
+            DayOfWeek::Thursday => Weekday::Thu,
+            DayOfWeek::Friday => Weekday::Fri,
+            DayOfWeek::Saturday => Weekday::Sat,
+            DayOfWeek::Sunday => Weekday::Sun,
+        }
+    }
+}
+
+impl From<Weekday> for DayOfWeek {
+    fn from(day: Weekday) -> Self {
+        match day {
+            Weekday::Mon => DayOfWeek::Monday,
+            Weekday::Tue => DayOfWeek::Tuesday,
+            Weekday::Wed => DayOfWeek::Wednesday,
+            Weekday::Thu => DayOfWeek::Thursday,
+            Weekday::Fri => DayOfWeek::Friday,
+            Weekday::Sat => DayOfWeek::Saturday,
+            Weekday::Sun => DayOfWeek::Sunday,
+        }
+    }
+}
+
+/// A weekly recurring reservation. Each occurrence is stored as a
+/// `FieldReservation` with `series_id` set, so it blocks member bookings like
+/// any other reservation but does not count towards member quotas.
+#[derive(Debug, FromRow, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldReservationSeries {
+    pub id: Uuid,
+    pub field_id: Uuid,
+    pub creator_id: Uuid,
+    pub description: Option<String>,
+    // ISO weekdays, 1 = Monday.
+    pub weekdays: Vec<i16>,
+    // Times of day in the association's timezone.
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub start_on: NaiveDate,
+    pub until: NaiveDate,
+    #[serde(skip)]
+    pub deleted: bool,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, InputObject, Deserialize)]
+pub struct FieldReservationSeriesInput {
+    pub field_id: Uuid,
+    pub description: Option<String>,
+    pub weekdays: Vec<DayOfWeek>,
+    // Local times of day, in the association's timezone.
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub start_on: NaiveDate,
+    pub until: NaiveDate,
+}
+
+#[Object]
+impl FieldReservationSeries {
+    pub async fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub async fn field_id(&self) -> Uuid {
+        self.field_id
+    }
+
+    pub async fn creator_id(&self) -> Uuid {
+        self.creator_id
+    }
+
+    pub async fn description(&self) -> Option<String> {
+        self.description.clone()
+    }
+
+    pub async fn weekdays(&self) -> Vec<DayOfWeek> {
+        self.weekdays
+            .iter()
+            .filter_map(|day| Weekday::try_from(*day as u8 - 1).ok())
+            .map(DayOfWeek::from)
+            .collect()
+    }
+
+    /// Local time of day, in the association's timezone.
+    pub async fn start_time(&self) -> NaiveTime {
+        self.start_time
+    }
+
+    /// Local time of day, in the association's timezone.
+    pub async fn end_time(&self) -> NaiveTime {
+        self.end_time
+    }
+
+    pub async fn start_on(&self) -> NaiveDate {
+        self.start_on
+    }
+
+    pub async fn until(&self) -> NaiveDate {
+        self.until
+    }
+
+    pub async fn created_at(&self) -> chrono::NaiveDateTime {
+        self.created_at
+    }
+
+    pub async fn updated_at(&self) -> chrono::NaiveDateTime {
+        self.updated_at
+    }
+}
+
+impl FieldReservationSeries {
+    pub async fn get(db: &DB, id: &Uuid) -> Result<FieldReservationSeries, anyhow::Error> {
+        let series = sqlx::query_as!(
+            FieldReservationSeries,
+            r#"SELECT * FROM "FieldReservationSeries" WHERE id = $1"#,
+            id
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(series)
+    }
+
+    /// Creates the series and all its occurrences from today on, at the given
+    /// local times in the association's timezone. Fails without creating
+    /// anything if any occurrence conflicts with an existing reservation.
+    pub async fn create(
+        db: &DB,
+        creator_id: &Uuid,
+        field: &Field,
+        input: FieldReservationSeriesInput,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<FieldReservationSeries, anyhow::Error> {
+        if input.weekdays.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Series must repeat on at least one weekday"
+            ));
+        }
+        if input.start_time >= input.end_time {
+            return Err(anyhow::anyhow!("Start time must be before end time"));
+        }
+        if input.until < input.start_on {
+            return Err(anyhow::anyhow!("Series must end after it starts"));
+        }
+        if (input.until - input.start_on).num_days() > MAX_SERIES_DAYS {
+            return Err(anyhow::anyhow!(
+                "Series can span at most {} days",
+                MAX_SERIES_DAYS
+            ));
+        }
+
+        let weekdays: Vec<Weekday> = input.weekdays.iter().map(|d| (*d).into()).collect();
+        let mut iso_weekdays: Vec<i16> = weekdays
+            .iter()
+            .map(|d| d.number_from_monday() as i16)
+            .collect();
+        iso_weekdays.sort();
+        iso_weekdays.dedup();
+
+        let mut tx = db.begin().await?;
+        let timezone = sqlx::query_scalar!(
+            r#"SELECT timezone FROM "Association" WHERE id = $1"#,
+            field.association_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let tz = parse_timezone(&timezone)?;
+
+        let series = sqlx::query_as!(
+            FieldReservationSeries,
+            r#"INSERT INTO "FieldReservationSeries"
+                (field_id, creator_id, description, weekdays, start_time, end_time, start_on, until)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING *"#,
+            field.id,
+            creator_id,
+            input.description,
+            &iso_weekdays,
+            input.start_time,
+            input.end_time,
+            input.start_on,
+            input.until,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let first_day = input.start_on.max(now.with_timezone(&tz).date_naive());
+        for day in first_day.iter_days().take_while(|day| *day <= input.until) {
+            if !weekdays.contains(&day.weekday()) {
+                continue;
+            }
+            let start_date = local_instant(&tz, day.and_time(input.start_time));
+            let end_date = local_instant(&tz, day.and_time(input.end_time));
+            if end_date <= now {
+                continue;
+            }
+
+            let overlapping =
+                FieldReservation::overlapping(&mut tx, &field.id, start_date, end_date).await?;
+            if field.is_full(&overlapping, start_date, end_date) {
+                return Err(anyhow::anyhow!(
+                    "Occurrence on {} overlaps with another reservation",
+                    day
+                ));
+            }
+
+            sqlx::query!(
+                r#"INSERT INTO "FieldReservation" (field_id, user_id, description, start_date, end_date, series_id)
+                VALUES ($1, $2, $3, $4, $5, $6)"#,
+                field.id,
+                creator_id,
+                input.description,
+                start_date,
+                end_date,
+                series.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(series)
+    }
+
+    /// Cancels the whole series and promotes waitlisted members into the freed
+    /// occurrences. Occurrences that already started are kept.
+    pub async fn delete(
+        db: &DB,
+        id: &Uuid,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<FieldReservationSeries, anyhow::Error> {
+        let mut tx = db.begin().await?;
+        let series = sqlx::query_as!(
+            FieldReservationSeries,
+            r#"UPDATE "FieldReservationSeries" SET deleted = true WHERE id = $1 RETURNING *"#,
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let cancelled = sqlx::query_as!(
+            FieldReservation,
+            r#"UPDATE "FieldReservation" SET deleted = true
+            WHERE series_id = $1 AND deleted = false AND start_date >= $2
+            RETURNING *"#,
+            id,
+            now
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let field = sqlx::query_as!(
+            Field,
+            r#"SELECT * FROM "Field" WHERE id = $1"#,
+            series.field_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        for occurrence in &cancelled {
+            FieldWaitlist::promote(&mut tx, &field, occurrence, now).await?;
+        }
+        tx.commit().await?;
+
+        Ok(series)
+    }
+}
@@ -0,0 +1,217 @@
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, FromRow, PgConnection};
+use uuid::Uuid;
+
+use crate::{notification::model::Notification, DB};
+
+use super::model::{Field, FieldReservation, FieldReservationInput};
+
+/// A member queueing for a slot that is already reserved. Once the slot is
+/// freed the entry is promoted and `reservation_id` points to the new
+/// reservation.
+#[derive(Debug, SimpleObject, FromRow, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldWaitlist {
+    pub id: Uuid,
+    pub field_id: Uuid,
+    pub user_id: Uuid,
+    pub description: Option<String>,
+    pub start_date: chrono::DateTime<Utc>,
+    pub end_date: chrono::DateTime<Utc>,
+    pub reservation_id: Option<Uuid>,
+    #[graphql(skip)]
+    #[serde(skip)]
+    pub deleted: bool,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl FieldWaitlist {
+    pub async fn get(db: &DB, id: &Uuid) -> Result<FieldWaitlist, anyhow::Error> {
+        let entry = sqlx::query_as!(
+            FieldWaitlist,
+            r#"SELECT * FROM "FieldWaitlist" WHERE id = $1"#,
+            id
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(entry)
+    }
+
+    /// Pending entries of a field in the given range, in queue order.
+    pub async fn read_pending(
+        db: &DB,
+        field_id: &Uuid,
+        from_date_time: chrono::DateTime<Utc>,
+        to_date_time: chrono::DateTime<Utc>,
+    ) -> Result<Vec<FieldWaitlist>, anyhow::Error> {
+        let entries = sqlx::query_as!(
+            FieldWaitlist,
+            r#"SELECT * FROM "FieldWaitlist"
+            WHERE field_id = $1 AND deleted = false AND reservation_id IS NULL
+                AND start_date >= $2 AND end_date <= $3
+            ORDER BY created_at ASC"#,
+            field_id,
+            from_date_time,
+            to_date_time
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(entries)
+    }
+
+    pub async fn create(
+        db: &DB,
+        user_id: &Uuid,
+        field: &Field,
+        input: FieldReservationInput,
+    ) -> Result<FieldWaitlist, anyhow::Error> {
+        if input.start_date >= input.end_date {
+            return Err(anyhow::anyhow!("Start date must be before end date"));
+        }
+
+        let mut tx = db.begin().await?;
+        let overlapping =
+            FieldReservation::overlapping(&mut tx, &field.id, input.start_date, input.end_date)
+                .await?;
+        if !field.is_full(&overlapping, input.start_date, input.end_date) {
+            return Err(anyhow::anyhow!("Slot is available, reserve it instead"));
+        }
+        if overlapping.iter().any(|r| &r.user_id == user_id) {
+            return Err(anyhow::anyhow!(
+                "User already has a reservation in this slot"
+            ));
+        }
+
+        let already_waiting = sqlx::query_scalar!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM "FieldWaitlist"
+                WHERE field_id = $1 AND user_id = $2 AND deleted = false AND reservation_id IS NULL
+                    AND start_date < $4 AND end_date > $3
+            ) AS "exists!""#,
+            field.id,
+            user_id,
+            input.start_date,
+            input.end_date
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if already_waiting {
+            return Err(anyhow::anyhow!(
+                "User is already on the waitlist for this slot"
+            ));
+        }
+
+        let entry = sqlx::query_as!(
+            FieldWaitlist,
+            r#"INSERT INTO "FieldWaitlist" (field_id, user_id, description, start_date, end_date)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING *"#,
+            field.id,
+            user_id,
+            input.description,
+            input.start_date,
+            input.end_date
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(entry)
+    }
+
+    pub async fn delete(db: &DB, id: &Uuid) -> Result<FieldWaitlist, anyhow::Error> {
+        let mut tx = db.begin().await?;
+        let entry = sqlx::query_as!(
+            FieldWaitlist,
+            r#"UPDATE "FieldWaitlist" SET deleted = true WHERE id = $1 RETURNING *"#,
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(entry)
+    }
+
+    /// Promotes waitlisted members into the slot freed by `freed`, in queue
+    /// order. Entries whose owner is no longer a member or no longer satisfies
+    /// the field rules stay on the waitlist. Must run in the transaction that
+    /// cancelled `freed`; each candidate runs in a savepoint so a rejected
+    /// one leaves nothing behind.
+    pub async fn promote(
+        conn: &mut PgConnection,
+        field: &Field,
+        freed: &FieldReservation,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Vec<FieldReservation>, anyhow::Error> {
+        let candidates = sqlx::query_as!(
+            FieldWaitlist,
+            r#"SELECT w.* FROM "FieldWaitlist" w
+            WHERE w.field_id = $1 AND w.deleted = false AND w.reservation_id IS NULL
+                AND w.start_date < $3 AND w.end_date > $2
+                AND EXISTS (
+                    SELECT 1 FROM "AssociationRoles" ar
+                    WHERE ar.user_id = w.user_id AND ar.association_id = $4 AND ar.role = 'member'
+                )
+            ORDER BY w.created_at ASC
+            FOR UPDATE"#,
+            field.id,
+            freed.start_date,
+            freed.end_date,
+            field.association_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut promoted = vec![];
+        for entry in candidates {
+            let input = FieldReservationInput {
+                field_id: entry.field_id,
+                description: entry.description.clone(),
+                start_date: entry.start_date,
+                end_date: entry.end_date,
+                guest_ids: None,
+            };
+            let mut savepoint = conn.begin().await?;
+            let reservation = match FieldReservation::insert_checked(
+                &mut savepoint,
+                &entry.user_id,
+                field,
+                input,
+                now,
+            )
+            .await
+            {
+                Ok(reservation) => reservation,
+                // Database errors are not a rejection of the candidate.
+                Err(err) if err.downcast_ref::<sqlx::Error>().is_some() => return Err(err),
+                Err(_) => {
+                    savepoint.rollback().await?;
+                    continue;
+                }
+            };
+
+            sqlx::query!(
+                r#"UPDATE "FieldWaitlist" SET reservation_id = $1 WHERE id = $2"#,
+                reservation.id,
+                entry.id
+            )
+            .execute(&mut *savepoint)
+            .await?;
+
+            Notification::create(
+                &mut savepoint,
+                &entry.user_id,
+                format!(
+                    "A spot opened on {}: your reservation from {} to {} is confirmed.",
+                    field.name, reservation.start_date, reservation.end_date
+                ),
+            )
+            .await?;
+            savepoint.commit().await?;
+            promoted.push(reservation);
+        }
+        Ok(promoted)
+    }
+}
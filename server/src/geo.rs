@@ -0,0 +1,58 @@
+use anyhow::anyhow;
+
+/// Largest radius accepted by nearby searches.
+pub const MAX_RADIUS_KM: f64 = 200.0;
+/// Most results returned by a nearby search.
+pub const MAX_RESULTS: i64 = 100;
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Circle around a point, with the bounding box used to narrow down
+/// candidates before computing exact distances in the database.
+#[derive(Debug)]
+pub struct SearchArea {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_km: f64,
+    pub min_latitude: f64,
+    pub max_latitude: f64,
+    pub min_longitude: f64,
+    pub max_longitude: f64,
+}
+
+impl SearchArea {
+    pub fn new(latitude: f64, longitude: f64, radius_km: f64) -> Result<Self, anyhow::Error> {
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(anyhow!("Invalid coordinates"));
+        }
+        if !(radius_km > 0.0 && radius_km <= MAX_RADIUS_KM) {
+            return Err(anyhow!("Radius must be between 0 and {} km", MAX_RADIUS_KM));
+        }
+
+        let latitude_delta = (radius_km / EARTH_RADIUS_KM).to_degrees();
+        let min_latitude = latitude - latitude_delta;
+        let max_latitude = latitude + latitude_delta;
+        // Near the poles or across the antimeridian the box would wrap, so
+        // only latitude narrows the search there.
+        let (min_longitude, max_longitude) = if min_latitude <= -90.0 || max_latitude >= 90.0 {
+            (-180.0, 180.0)
+        } else {
+            let longitude_delta =
+                (radius_km / (EARTH_RADIUS_KM * latitude.to_radians().cos())).to_degrees();
+            if longitude - longitude_delta < -180.0 || longitude + longitude_delta > 180.0 {
+                (-180.0, 180.0)
+            } else {
+                (longitude - longitude_delta, longitude + longitude_delta)
+            }
+        };
+
+        Ok(Self {
+            latitude,
+            longitude,
+            radius_km,
+            min_latitude,
+            max_latitude,
+            min_longitude,
+            max_longitude,
+        })
+    }
+}
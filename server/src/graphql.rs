@@ -2,21 +2,34 @@ use std::sync::Arc;
 
 use crate::{
     association::graphql::{AssociationMutation, AssociationQuery},
+    calendar::graphql::CalendarMutation,
     config::Config,
     field::graphql::{FieldMutation, FieldQuery},
+    notification::graphql::{NotificationMutation, NotificationQuery},
     relations::graphql::RelationsMutation,
+    session::graphql::SessionMutation,
     token::Claims,
     transaction::graphql::{TransactionMutation, TransactionQuery},
-    user::graphql::{UserMutation, UserQuery},
+    user::{
+        api_token::ApiTokenScope,
+        graphql::{UserMutation, UserQuery},
+        registration::Registration,
+    },
     Clock, SystemClock, DB,
 };
 
-use async_graphql::{EmptySubscription, MergedObject, Schema};
+use async_graphql::{EmptySubscription, MergedObject, Schema, ServerError};
 use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
 use axum::{response::IntoResponse, Extension};
 
 #[derive(MergedObject, Default)]
-pub struct Query(UserQuery, AssociationQuery, TransactionQuery, FieldQuery);
+pub struct Query(
+    UserQuery,
+    AssociationQuery,
+    TransactionQuery,
+    FieldQuery,
+    NotificationQuery,
+);
 
 #[derive(MergedObject, Default)]
 pub struct Mutation(
@@ -25,6 +38,9 @@ pub struct Mutation(
     TransactionMutation,
     RelationsMutation,
     FieldMutation,
+    NotificationMutation,
+    CalendarMutation,
+    SessionMutation,
 );
 pub type AppSchema = Schema<Query, Mutation, EmptySubscription>;
 
@@ -38,12 +54,26 @@ pub fn get_schema(db: DB, config: Config) -> Schema<Query, Mutation, EmptySubscr
 pub async fn graphql_handler(
     Extension(schema): Extension<AppSchema>,
     claims: Claims,
+    scope: Option<ApiTokenScope>,
+    registration: Option<Registration>,
     req: GraphQLRequest,
 ) -> impl IntoResponse {
     // Turn the incoming request into an async-graphql `Request`
     let mut request = req.into_inner();
     // Insert claims so that resolvers can access them via Context
     request = request.data(claims);
+    // Requests with an API token are limited to its scope.
+    if let Some(scope) = scope {
+        if !scope.allows_request(&request) {
+            let error = ServerError::new("This API token is read-only", None);
+            return GraphQLResponse::from(async_graphql::Response::from_errors(vec![error]));
+        }
+        request = request.data(scope);
+    }
+    // Registration tokens only allow completing the registration.
+    if let Some(registration) = registration {
+        request = request.data(registration);
+    }
     request = request.data(Arc::new(SystemClock) as Arc<dyn Clock>);
 
     let response = schema.execute(request).await;
@@ -3,12 +3,19 @@ use chrono::{DateTime, Utc};
 use sqlx::{Pool, Postgres};
 
 pub mod association;
+pub mod calendar;
 pub mod config;
 pub mod error;
 pub mod field;
+pub mod geo;
 pub mod graphql;
+pub mod mail;
+pub mod notification;
 pub mod oauth;
+pub mod oidc;
+pub mod rate_limit;
 pub mod relations;
+pub mod session;
 pub mod token;
 pub mod transaction;
 pub mod user;
@@ -0,0 +1,67 @@
+use std::{path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::Utc;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sends emails. Production deployments plug in a real transport; the file
+/// and stdout mailers are meant for local development.
+#[async_trait]
+pub trait Mailer: Sync + Send {
+    async fn send(&self, email: Email) -> Result<(), anyhow::Error>;
+}
+
+/// Prints emails to stdout.
+pub struct StdoutMailer;
+
+#[async_trait]
+impl Mailer for StdoutMailer {
+    async fn send(&self, email: Email) -> Result<(), anyhow::Error> {
+        println!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            email.to, email.subject, email.body
+        );
+        Ok(())
+    }
+}
+
+/// Writes each email to its own file in a directory.
+pub struct FileMailer {
+    pub dir: PathBuf,
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), anyhow::Error> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S"),
+            uuid::Uuid::new_v4().simple()
+        ));
+        let contents = format!(
+            "To: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            email.to, email.subject, email.body
+        );
+        tokio::fs::write(path, contents).await?;
+        Ok(())
+    }
+}
+
+/// Mailer selected by `MAILER`.
+pub fn mailer_from_config(config: &Config) -> Arc<dyn Mailer> {
+    match config.mailer.as_str() {
+        "file" => Arc::new(FileMailer {
+            dir: PathBuf::from(&config.mail_dir),
+        }),
+        _ => Arc::new(StdoutMailer),
+    }
+}
@@ -1,4 +1,4 @@
-use std::env;
+use std::{env, net::SocketAddr, sync::Arc, time::Duration};
 
 use async_graphql::http::GraphiQLSource;
 use axum::{
@@ -6,18 +6,29 @@ use axum::{
     routing::{get, post},
     Extension, Router,
 };
-use clap::{command, Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use dotenv::dotenv;
 use http::{HeaderValue, Method};
 use my_hood_server::{
     association::model::Association,
+    calendar::ics::{field_calendar_handler, user_calendar_handler},
     config::Config,
+    field::jobs::reservation_job,
     graphql::{get_schema, graphql_handler},
-    oauth::{callback_handler, google_oauth_client},
+    mail::mailer_from_config,
+    oauth::{oauth_callback_handler, oauth_login_handler, oauth_token_handler},
+    oidc::OidcRegistry,
+    rate_limit::rate_limited,
     relations::model::{Relations, Role},
-    token::login_handler,
-    user::model::{User, UserInput},
-    DB,
+    token::{
+        forgot_password_handler, login_handler, logout_handler, refresh_handler, register_handler,
+        reset_password_handler, two_factor_handler, verify_email_handler,
+    },
+    user::{
+        model::{User, UserInput},
+        personal_data::export_handler,
+    },
+    Clock, SystemClock, DB,
 };
 use tokio::net::TcpListener;
 use tower_cookies::CookieManagerLayer;
@@ -63,11 +74,17 @@ async fn run() -> anyhow::Result<()> {
 
     let schema = get_schema(db.clone(), config.clone());
 
+    tokio::spawn(reservation_job(
+        db.clone(),
+        Arc::new(SystemClock) as Arc<dyn Clock>,
+        Duration::from_secs(config.reservation_job_interval_secs),
+    ));
+
     async fn graphql_playground() -> impl IntoResponse {
         response::Html(GraphiQLSource::build().endpoint("/").finish())
     }
 
-    let allowed_origins = get_allowed_origins();
+    let allowed_origins = get_allowed_origins(&config);
 
     // CORS middleware
     let cors = CorsLayer::new()
@@ -87,32 +104,50 @@ async fn run() -> anyhow::Result<()> {
         ]);
 
     let app = Router::new()
-        .route("/", get(graphql_playground).post(post(graphql_handler)))
+        .route(
+            "/",
+            get(graphql_playground).merge(rate_limited(post(graphql_handler), &config)),
+        )
         .route("/auth", post(login_handler))
-        .route("/oauth/google/login", get(google_oauth_client))
-        .route("/oauth/google/callback", get(callback_handler))
+        .route("/auth/two-factor", post(two_factor_handler))
+        .route("/auth/register", post(register_handler))
+        .route("/auth/verify-email", post(verify_email_handler))
+        .route("/auth/forgot-password", post(forgot_password_handler))
+        .route("/auth/reset-password", post(reset_password_handler))
+        .route("/auth/refresh", post(refresh_handler))
+        .route("/auth/logout", post(logout_handler))
+        .route("/oauth/{provider}/login", get(oauth_login_handler))
+        .route("/oauth/{provider}/callback", get(oauth_callback_handler))
+        .route("/oauth/token", post(oauth_token_handler))
+        .route(
+            "/calendar/fields/{field_id}/reservations.ics",
+            get(field_calendar_handler),
+        )
+        .route("/calendar/me/reservations.ics", get(user_calendar_handler))
+        .route("/me/export.json", get(export_handler))
         .layer(Extension(schema))
         .layer(Extension(db))
+        .layer(Extension(mailer_from_config(&config)))
+        .layer(Extension(OidcRegistry::new(&config.oauth_providers)))
+        .layer(Extension(config))
+        .layer(Extension(Arc::new(SystemClock) as Arc<dyn Clock>))
         .layer(cors)
         .layer(CookieManagerLayer::new());
 
     println!("Serving on http://{host}:{port}");
     axum::serve(
         TcpListener::bind(format!("{host}:{port}")).await.unwrap(),
-        app,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
     )
     .await
     .expect("Error spawning server");
     Ok(())
 }
 
-fn get_allowed_origins() -> Vec<HeaderValue> {
-    let origins = env::var("ALLOWED_ORIGINS").expect("ALLOWED_ORIGINS must be set");
-
-    // split string by comma, trim spaces and try to convert each item to HeaderValue
-    origins
-        .split(',')
-        .map(|origin| origin.trim())
+fn get_allowed_origins(config: &Config) -> Vec<HeaderValue> {
+    config
+        .allowed_origins
+        .iter()
         .filter_map(|origin| HeaderValue::from_str(origin).ok())
         .collect()
 }
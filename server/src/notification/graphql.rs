@@ -0,0 +1,50 @@
+use async_graphql::{Context, FieldResult, Object};
+use uuid::Uuid;
+
+use crate::{token::Claims, DB};
+
+use super::model::Notification;
+
+#[derive(Default)]
+pub struct NotificationQuery;
+
+#[Object(extends)]
+impl NotificationQuery {
+    /// Notifications addressed to the logged in user, newest first.
+    async fn notifications(
+        &self,
+        ctx: &Context<'_>,
+        unread_only: Option<bool>,
+    ) -> FieldResult<Vec<Notification>> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let notifications =
+            Notification::read_all_by_user(pool, &user_id, unread_only.unwrap_or(false)).await?;
+        Ok(notifications)
+    }
+}
+
+#[derive(Default)]
+pub struct NotificationMutation;
+
+#[Object(extends)]
+impl NotificationMutation {
+    async fn mark_notification_read(
+        &self,
+        ctx: &Context<'_>,
+        id: Uuid,
+    ) -> FieldResult<Notification> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let notification = Notification::mark_read(pool, &id, &user_id).await?;
+        Ok(notification)
+    }
+}
@@ -0,0 +1,70 @@
+use async_graphql::SimpleObject;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use uuid::Uuid;
+
+use crate::DB;
+
+#[derive(Debug, SimpleObject, FromRow, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub message: String,
+    pub read: bool,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl Notification {
+    pub async fn create(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        message: String,
+    ) -> Result<Notification, anyhow::Error> {
+        let notification = sqlx::query_as!(
+            Notification,
+            r#"INSERT INTO "Notification" (user_id, message) VALUES ($1, $2) RETURNING *"#,
+            user_id,
+            message
+        )
+        .fetch_one(conn)
+        .await?;
+        Ok(notification)
+    }
+
+    pub async fn read_all_by_user(
+        db: &DB,
+        user_id: &Uuid,
+        unread_only: bool,
+    ) -> Result<Vec<Notification>, anyhow::Error> {
+        let notifications = sqlx::query_as!(
+            Notification,
+            r#"SELECT * FROM "Notification" WHERE user_id = $1 AND ($2::bool IS FALSE OR read = false)
+            ORDER BY created_at DESC"#,
+            user_id,
+            unread_only
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(notifications)
+    }
+
+    pub async fn mark_read(
+        db: &DB,
+        id: &Uuid,
+        user_id: &Uuid,
+    ) -> Result<Notification, anyhow::Error> {
+        let mut tx = db.begin().await?;
+        let notification = sqlx::query_as!(
+            Notification,
+            r#"UPDATE "Notification" SET read = true WHERE id = $1 AND user_id = $2 RETURNING *"#,
+            id,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(notification)
+    }
+}
@@ -1,233 +1,373 @@
-use std::env;
+use std::sync::Arc;
 
 use axum::{
-    extract::Query,
-    response::{IntoResponse, Redirect},
-    Extension,
+    extract::{Path, Query},
+    response::{IntoResponse, Redirect, Response},
+    Extension, Json,
 };
-use jsonwebtoken::{encode, EncodingKey, Header};
-use oauth2::{
-    basic::{BasicClient}, 
-    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, 
-    EndpointNotSet, EndpointSet, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, 
-    TokenResponse, TokenUrl
-};
-use reqwest::{ClientBuilder, StatusCode};
-use serde::Deserialize;
+use jsonwebtoken::{encode, Header};
+use oauth2::{AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, Scope};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 use tower_cookies::{cookie, Cookie, Cookies};
 use uuid::Uuid;
 
 use crate::{
-    token::{get_token_exp, Claims},
-    user::model::User,
-    DB,
+    config::Config,
+    oidc::OidcRegistry,
+    session::model::Session,
+    token::{
+        decode_purpose_token, encode_purpose_token, get_token_exp, Claims, LoginResponse,
+        RegistrationResponse, TwoFactorChallenge,
+    },
+    user::{
+        api_token::ApiTokenScope, identity::UserIdentity, model::User, oauth_code::OAuthCode,
+        registration::Registration, two_factor::TwoFactor,
+    },
+    Clock, DB,
 };
 
 #[derive(Debug, Deserialize)]
 pub struct OAuthRequest {
     code: String,
-    #[allow(dead_code)]
     state: String,
 }
 
 #[derive(Deserialize)]
-pub struct LoginParams { redirect: Option<String> }
-
-type GoogleClient = BasicClient<
-    EndpointSet,
-    EndpointNotSet,
-    EndpointNotSet,
-    EndpointNotSet,
-    EndpointSet
->;
-
-fn get_oauth_client() -> GoogleClient {
-    let client_id = ClientId::new(env::var("GOOGLE_OAUTH_CLIENT_ID").expect("Missing client ID"));
-    let client_secret =
-        ClientSecret::new(env::var("GOOGLE_OAUTH_CLIENT_SECRET").expect("Missing client secret"));
-    let auth_url = AuthUrl::new("https://accounts.google.com/o/oauth2/auth".to_string())
-        .expect("Invalid authorization URL");
-    let token_url = TokenUrl::new("https://oauth2.googleapis.com/token".to_string())
-        .expect("Invalid token URL");
-    let rediret_url = RedirectUrl::new(
-        env::var("GOOGLE_OAUTH_REDIRECT_URL").expect("Missing oauth redirect url"),
-    )
-    .expect("Invalid redirect URL");
-
-    BasicClient::new(client_id)
-        .set_client_secret(client_secret)
-        .set_auth_uri(auth_url)
-        .set_token_uri(token_url)
-        .set_redirect_uri(rediret_url)
+pub struct LoginParams {
+    redirect: Option<String>,
+    // Links the provider account to the logged in user instead of logging in.
+    link: Option<bool>,
+}
+
+const LINK_AUDIENCE: &str = "oauth-link";
+/// Minutes a logged in user has to finish linking a provider account.
+const LINK_MINUTES: i64 = 10;
+
+/// Names the user linking a provider account, kept in a cookie until the
+/// provider redirects back. Access tokens are never put in login URLs.
+#[derive(Serialize, Deserialize)]
+struct LinkClaims {
+    sub: Uuid,
+    sid: Uuid,
+    aud: String,
+    exp: usize,
+}
+
+/// Cookies keeping the login state until the provider redirects back.
+const LOGIN_COOKIES: [&str; 5] = [
+    "post_oauth_redirect",
+    "oauth_link_token",
+    "pkce_verifier",
+    "oauth_nonce",
+    "oauth_state",
+];
+
+fn login_cookie(config: &Config, name: &'static str, value: String) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .http_only(true)
+        .secure(config.secure_cookies)
+        .same_site(cookie::SameSite::Lax)
+        .path("/")
+        .build()
 }
 
-pub async fn google_oauth_client(Query(params): Query<LoginParams>, cookies: Cookies) -> Redirect {
-    let client = get_oauth_client();
-
-    // If a redirect URL is provided, store it in a cookie to redirect after login.
-    if let Some(r) = &params.redirect {
-        cookies.add(
-            Cookie::build(("post_oauth_redirect", r.clone()))
-                .http_only(true)
-                .same_site(cookie::SameSite::Lax)
-                .path("/")
-                .build(),
-        );
+/// Longest picture URL kept as the profile URL of new users.
+const MAX_PICTURE_URL_LENGTH: usize = 128;
+
+/// Request payload exchanging the code of an OAuth login.
+#[derive(Debug, Deserialize)]
+pub struct OAuthCodeRequest {
+    code: String,
+}
+
+/// Redirects to the provider's authorization page.
+pub async fn oauth_login_handler(
+    Path(provider): Path<String>,
+    Query(params): Query<LoginParams>,
+    claims: Result<Claims, (StatusCode, &'static str)>,
+    scope: Option<ApiTokenScope>,
+    Extension(config): Extension<Config>,
+    Extension(oidc): Extension<OidcRegistry>,
+    cookies: Cookies,
+) -> Result<Redirect, (StatusCode, &'static str)> {
+    let provider = oidc
+        .get(&provider)
+        .ok_or((StatusCode::NOT_FOUND, "Unknown OAuth provider"))?;
+    let client = provider.client().await.map_err(|err| {
+        eprintln!("Error discovering {}: {}", provider.config.name, err);
+        (StatusCode::BAD_GATEWAY, "OAuth provider unavailable")
+    })?;
+
+    // If a redirect URL is provided, store it in a cookie to redirect after
+    // login. Only pages of the web app are allowed.
+    match params.redirect {
+        Some(redirect) if !config.is_allowed_redirect(&redirect) => {
+            return Err((StatusCode::BAD_REQUEST, "Redirect URL not allowed"));
+        }
+        Some(redirect) => cookies.add(login_cookie(&config, "post_oauth_redirect", redirect)),
+        None => cookies.remove(login_cookie(&config, "post_oauth_redirect", String::new())),
+    }
+
+    // Linking needs a logged in user, from the auth cookie or header. The
+    // session is checked again in the callback, when the account is linked.
+    if params.link.unwrap_or(false) {
+        let unauthorized = (StatusCode::UNAUTHORIZED, "Log in to link an account");
+        let claims = claims.map_err(|_| unauthorized)?;
+        if scope.is_some() {
+            return Err((StatusCode::FORBIDDEN, "Not allowed with an API token"));
+        }
+        let expires_at = chrono::Utc::now() + chrono::Duration::minutes(LINK_MINUTES);
+        let link_claims = LinkClaims {
+            sub: claims.sub.ok_or(unauthorized)?,
+            sid: claims.sid.ok_or(unauthorized)?,
+            aud: LINK_AUDIENCE.to_owned(),
+            exp: expires_at.timestamp() as usize,
+        };
+        let link_token = encode_purpose_token(&config, &link_claims)
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Token creation failed"))?;
+        cookies.add(login_cookie(&config, "oauth_link_token", link_token));
+    } else {
+        cookies.remove(login_cookie(&config, "oauth_link_token", String::new()));
     }
 
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
     // Store the PKCE verifier in a cookie for later use.
-    cookies.add(
-        Cookie::build(("pkce_verifier", pkce_verifier.secret().to_string()))
-            .http_only(true)
-            .same_site(cookie::SameSite::Lax)
-            .path("/")
-            .build(),
-    );
+    cookies.add(login_cookie(
+        &config,
+        "pkce_verifier",
+        pkce_verifier.secret().to_string(),
+    ));
 
+    // The nonce ties the ID token to this login.
+    let nonce = CsrfToken::new_random();
     let (auth_url, csrf_state) = client
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("email".to_owned()))
+        .add_scopes(provider.config.scopes.iter().cloned().map(Scope::new))
+        .add_extra_param("nonce", nonce.secret())
         .set_pkce_challenge(pkce_challenge)
         .url();
+    cookies.add(login_cookie(
+        &config,
+        "oauth_nonce",
+        nonce.secret().to_string(),
+    ));
 
     // Store the CSRF state in a cookie to verify later.
-    cookies.add(
-        Cookie::build(("oauth_state", csrf_state.secret().to_string()))
-            .http_only(true)
-            .same_site(cookie::SameSite::Lax)
-            .path("/")
-            .build(),
-    );
+    cookies.add(login_cookie(
+        &config,
+        "oauth_state",
+        csrf_state.secret().to_string(),
+    ));
 
-    Redirect::to(auth_url.as_ref())
+    Ok(Redirect::to(auth_url.as_ref()))
 }
 
 /// Handler to receive the callback from the OAuth provider.
-pub async fn callback_handler(
+pub async fn oauth_callback_handler(
+    Path(provider): Path<String>,
     Query(params): Query<OAuthRequest>,
     db: Extension<DB>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+    Extension(config): Extension<Config>,
+    Extension(oidc): Extension<OidcRegistry>,
     cookies: Cookies,
 ) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
-    let client = get_oauth_client();
+    let provider = oidc
+        .get(&provider)
+        .ok_or((StatusCode::NOT_FOUND, "Unknown OAuth provider"))?;
 
-    // TODO: Verify that `params.state` matches the previously stored CSRF token.
-    
-    let cookie_state = cookies
-        .get("oauth_state")
-        .ok_or((StatusCode::BAD_REQUEST, "Missing state cookie"))?;
+    // The login state is only used once, whatever the outcome.
+    let [redirect, link_token, pkce_verifier, nonce, state] =
+        LOGIN_COOKIES.map(|name| cookies.get(name).map(|cookie| cookie.value().to_owned()));
+    for name in LOGIN_COOKIES {
+        cookies.remove(login_cookie(&config, name, String::new()));
+    }
 
-    if cookie_state.value() != params.state {
+    let state = state.ok_or((StatusCode::BAD_REQUEST, "Missing state cookie"))?;
+    if state != params.state {
         return Err((StatusCode::BAD_REQUEST, "Invalid CSRF state"));
     }
+    let pkce_verifier = PkceCodeVerifier::new(
+        pkce_verifier.ok_or((StatusCode::BAD_REQUEST, "Missing PKCE verifier"))?,
+    );
+    let nonce = nonce.ok_or((StatusCode::BAD_REQUEST, "Missing nonce"))?;
 
-    // Retrieve the PKCE verifier from the cookie.
-    let pkce_verifier_cookie = cookies
-        .get("pkce_verifier")
-        .ok_or((StatusCode::BAD_REQUEST, "Missing PKCE verifier"))?;
-
-    let pkce_verifier = PkceCodeVerifier::new(pkce_verifier_cookie.value().to_string());
-
-    let http_client = ClientBuilder::new()
-        // Following redirects opens the client up to SSRF vulnerabilities.
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .expect("Client should build");
+    let client = provider.client().await.map_err(|err| {
+        eprintln!("Error discovering {}: {}", provider.config.name, err);
+        (StatusCode::BAD_GATEWAY, "OAuth provider unavailable")
+    })?;
 
-    // Exchange the authorization code for an access token.
-    let token_result = client
+    // Exchange the authorization code for the tokens.
+    let token = client
         .exchange_code(AuthorizationCode::new(params.code))
         .set_pkce_verifier(pkce_verifier)
-        .request_async(&http_client)
-        .await;
-
-    match token_result {
-        Ok(token) => {
-            let userinfo_endpoint = "https://www.googleapis.com/oauth2/v3/userinfo";
-            let access_token = token.access_token().secret();
-            let client_reqwest = reqwest::Client::new();
-            let response = client_reqwest
-                .get(userinfo_endpoint)
-                .bearer_auth(access_token)
-                .send()
-                .await;
-            match response {
-                Ok(response) => {
-                    let user_info: serde_json::Value = response.json().await.map_err(|_| {
-                        (StatusCode::INTERNAL_SERVER_ERROR, "Error parsing user info")
-                    })?;
-
-                    // Craft a new JWT token so user can create an account.
-                    let email_opt = user_info
-                        .get("email")
-                        .map(|email| email.as_str().map(|email| email.to_owned()))
-                        .flatten();
-
-                    let (id, email) = if let Some(email) = &email_opt {
-                        let user = User::read_one_by_email(&db, email).await.map_err(|_| {
-                            (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                "Error reading user from DB",
-                            )
-                        })?;
-                        match user {
-                            Some(user) => (Some(user.id), user.email),
-                            None => (None, Some(email.clone())),
-                        }
-                    } else {
-                        (None, None)
-                    };
-
-                    let token = get_token(id, email)?;
-                    let mut auth_cookie = Cookie::new("auth_token", token.clone());
-                    auth_cookie.set_path("/");
-                    auth_cookie.set_http_only(false);
-                    cookies.add(auth_cookie);
-
-                    // Redirect to the post-login URL with the token.
-                    let redirect_target = cookies
-                        .get("post_oauth_redirect")
-                        .map(|c| c.value().to_string())
-                        .unwrap_or_else(|| env::var("WEB_POST_LOGIN_URL").unwrap());
-
-                    let location = format!("{}?token={}", redirect_target, token);
-
-                    Ok(Redirect::to(&location))
-                }
-                Err(err) => {
-                    eprintln!("Error obtaining user info: {}", err);
-                    Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "Error obtaining user info",
-                    ))
-                }
+        .request_async(provider.http_client())
+        .await
+        .map_err(|err| {
+            eprintln!("Error obtaining token: {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Error obtaining token")
+        })?;
+    let id_token = token
+        .extra_fields()
+        .id_token
+        .as_deref()
+        .ok_or((StatusCode::BAD_GATEWAY, "Missing ID token"))?;
+    let id_claims = provider
+        .validate_id_token(id_token, &nonce)
+        .await
+        .map_err(|err| {
+            eprintln!("Invalid ID token from {}: {}", provider.config.name, err);
+            (StatusCode::UNAUTHORIZED, "Invalid ID token")
+        })?;
+
+    let db_error = |_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error");
+    let provider_name = &provider.config.name;
+    let email = id_claims.verified_email();
+
+    // A logged in user started the login to link this account.
+    let linking_user = match link_token {
+        Some(link_token) => {
+            let invalid = (StatusCode::UNAUTHORIZED, "Invalid link token");
+            let claims: LinkClaims =
+                decode_purpose_token(&config, &link_token, LINK_AUDIENCE).map_err(|_| invalid)?;
+            if !Session::is_active(&db, &claims.sid, clock.now())
+                .await
+                .map_err(db_error)?
+            {
+                return Err(invalid);
             }
+            Some(claims.sub)
         }
-        Err(err) => {
-            eprintln!("Error obtaining token: {}", err);
-            Err((StatusCode::INTERNAL_SERVER_ERROR, "Error obtaining token"))
+        None => None,
+    };
+
+    // Users are found by the provider's subject id only. Registered users
+    // link other accounts while logged in, so a provider vouching for their
+    // email cannot take over their account.
+    let identity = UserIdentity::find(&db, provider_name, &id_claims.sub)
+        .await
+        .map_err(db_error)?;
+    let user_id = match (identity, linking_user) {
+        (Some(identity), Some(user_id)) if identity.user_id != user_id => {
+            return Err((
+                StatusCode::CONFLICT,
+                "This account is linked to another user",
+            ));
+        }
+        (Some(identity), _) => Some(identity.user_id),
+        (None, linking_user) => linking_user,
+    };
+    if let Some(user_id) = &user_id {
+        let mut conn = db
+            .acquire()
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?;
+        UserIdentity::link(
+            &mut conn,
+            user_id,
+            provider_name,
+            &id_claims.sub,
+            email.as_deref(),
+            clock.now(),
+        )
+        .await
+        .map_err(db_error)?;
+    }
+
+    // New users complete their registration with what the provider told.
+    // Pictures too long for a profile URL are left out.
+    let registration = Registration {
+        email,
+        provider: user_id.is_none().then(|| provider_name.clone()),
+        subject: user_id.is_none().then(|| id_claims.sub.clone()),
+        name: id_claims.name.clone(),
+        picture_url: id_claims
+            .picture
+            .clone()
+            .filter(|picture| picture.len() <= MAX_PICTURE_URL_LENGTH),
+    };
+
+    // The web app exchanges the code for tokens at /oauth/token, so none
+    // end up in the browser history or server logs.
+    let code = OAuthCode::create(&db, user_id.as_ref(), &registration, clock.now())
+        .await
+        .map_err(db_error)?;
+    let mut location = redirect
+        .filter(|redirect| config.is_allowed_redirect(redirect))
+        .unwrap_or_else(|| config.post_login_url.clone())
+        .parse::<reqwest::Url>()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Invalid redirect URL"))?;
+    location.query_pairs_mut().append_pair("code", &code);
+
+    Ok(Redirect::to(location.as_str()))
+}
+
+/// Exchanges the code of an OAuth login. Registered users get a session, or
+/// a two-factor challenge; new users a registration token to create their
+/// account.
+pub async fn oauth_token_handler(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Config>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+    cookies: Cookies,
+    Json(payload): Json<OAuthCodeRequest>,
+) -> Result<Response, Response> {
+    let db_error = |_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error").into_response();
+    let now = clock.now();
+    let code = OAuthCode::exchange(&db, &payload.code, now)
+        .await
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid or expired code").into_response())?;
+
+    let user_id = match code.user_id {
+        Some(user_id) => user_id,
+        None => {
+            let registration = RegistrationResponse::new(&config, &code.registration())
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Json(registration).into_response());
         }
+    };
+    // The account may have been deleted since the provider redirected back.
+    let user = User::read_one(&db, &user_id).await.map_err(db_error)?;
+    if user.deleted == Some(true) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid credentials").into_response());
+    }
+    if TwoFactor::is_enabled(&db, &user_id)
+        .await
+        .map_err(db_error)?
+    {
+        return Ok(Json(
+            TwoFactorChallenge::new(&config, user_id).map_err(IntoResponse::into_response)?,
+        )
+        .into_response());
     }
+    let login = LoginResponse::start_session(&db, &config, user, false, now)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    login.set_cookies(&config, &cookies);
+    Ok(Json(login).into_response())
 }
 
+/// Signs a token with the current key from `Config`.
 pub fn get_token(
+    config: &Config,
     sub: Option<Uuid>,
     email: Option<String>,
+    sid: Option<Uuid>,
 ) -> Result<String, (StatusCode, &'static str)> {
-    let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    let exp = get_token_exp();
+    let keys = &config.jwt_keys;
+    let exp = get_token_exp(config);
     let claims = Claims {
         sub,
         exp,
-        email: email,
+        email,
+        sid,
     };
 
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
-    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Token creation failed"))?;
+    let mut header = Header::new(keys.algorithm);
+    header.kid = Some(keys.kid.clone());
+    let token = encode(&header, &claims, keys.encoding_key())
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Token creation failed"))?;
     Ok(token)
 }
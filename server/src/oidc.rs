@@ -0,0 +1,222 @@
+use std::{collections::HashMap, sync::Arc};
+
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use oauth2::{
+    basic::{
+        BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+        BasicTokenType,
+    },
+    AuthUrl, Client, ClientId, ClientSecret, EndpointNotSet, EndpointSet, ExtraTokenFields,
+    RedirectUrl, StandardRevocableToken, StandardTokenResponse, TokenUrl,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OnceCell, RwLock};
+
+use crate::config::OidcProviderConfig;
+
+/// Algorithms accepted for ID tokens. Symmetric algorithms are left out, as
+/// they would make the client secret a signing key.
+const ID_TOKEN_ALGORITHMS: [Algorithm; 7] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// The parts of `.well-known/openid-configuration` used for logging in.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// Token endpoint fields added by OpenID Connect.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IdTokenFields {
+    pub id_token: Option<String>,
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
+pub type OidcTokenResponse = StandardTokenResponse<IdTokenFields, BasicTokenType>;
+
+pub type OidcClient = Client<
+    BasicErrorResponse,
+    OidcTokenResponse,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointSet,
+>;
+
+/// Claims read from a validated ID token.
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub nonce: Option<String>,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+}
+
+impl IdTokenClaims {
+    /// The email, if the provider vouches for it. Providers that do not send
+    /// `email_verified` are not trusted with emails.
+    pub fn verified_email(&self) -> Option<String> {
+        match self.email_verified {
+            Some(true) => self.email.clone(),
+            _ => None,
+        }
+    }
+}
+
+pub struct OidcProvider {
+    pub config: OidcProviderConfig,
+    http: reqwest::Client,
+    metadata: OnceCell<ProviderMetadata>,
+    jwks: RwLock<JwkSet>,
+}
+
+impl OidcProvider {
+    /// Discovers the provider endpoints on first use.
+    pub async fn metadata(&self) -> Result<&ProviderMetadata, anyhow::Error> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let metadata: ProviderMetadata = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                if metadata.issuer != self.config.issuer {
+                    return Err(anyhow::anyhow!(
+                        "Discovered issuer {} does not match {}",
+                        metadata.issuer,
+                        self.config.issuer
+                    ));
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    pub async fn client(&self) -> Result<OidcClient, anyhow::Error> {
+        let metadata = self.metadata().await?;
+        Ok(Client::new(ClientId::new(self.config.client_id.clone()))
+            .set_client_secret(ClientSecret::new(self.config.client_secret.clone()))
+            .set_auth_uri(AuthUrl::new(metadata.authorization_endpoint.clone())?)
+            .set_token_uri(TokenUrl::new(metadata.token_endpoint.clone())?)
+            .set_redirect_uri(RedirectUrl::new(self.config.redirect_url.clone())?))
+    }
+
+    /// Client for the token endpoint. Following redirects would open it up
+    /// to SSRF.
+    pub fn http_client(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// Signing key for `kid`. The key set is fetched again when the key is
+    /// unknown, as providers rotate their keys.
+    async fn signing_key(&self, kid: Option<&str>) -> Result<Jwk, anyhow::Error> {
+        let find = |jwks: &JwkSet| match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+        if let Some(jwk) = find(&*self.jwks.read().await) {
+            return Ok(jwk);
+        }
+
+        let metadata = self.metadata().await?;
+        let jwks: JwkSet = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let jwk = find(&jwks);
+        *self.jwks.write().await = jwks;
+        jwk.ok_or(anyhow::anyhow!("Unknown ID token signing key"))
+    }
+
+    /// Checks the ID token signature, issuer, audience, expiry and nonce.
+    pub async fn validate_id_token(
+        &self,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, anyhow::Error> {
+        let header = decode_header(id_token)?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            return Err(anyhow::anyhow!("Unsupported ID token algorithm"));
+        }
+        let jwk = self.signing_key(header.kid.as_deref()).await?;
+        let key = DecodingKey::from_jwk(&jwk)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = decode::<IdTokenClaims>(id_token, &key, &validation)?.claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(anyhow::anyhow!("Invalid ID token nonce"));
+        }
+        Ok(claims)
+    }
+}
+
+/// The configured OpenID Connect providers, by name.
+#[derive(Clone)]
+pub struct OidcRegistry {
+    providers: Arc<HashMap<String, Arc<OidcProvider>>>,
+}
+
+impl OidcRegistry {
+    pub fn new(configs: &[OidcProviderConfig]) -> Self {
+        let http = reqwest::ClientBuilder::new()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Client should build");
+        let providers = configs
+            .iter()
+            .map(|config| {
+                let provider = OidcProvider {
+                    config: config.clone(),
+                    http: http.clone(),
+                    metadata: OnceCell::new(),
+                    jwks: RwLock::new(JwkSet { keys: vec![] }),
+                };
+                (config.name.clone(), Arc::new(provider))
+            })
+            .collect();
+        OidcRegistry {
+            providers: Arc::new(providers),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<OidcProvider>> {
+        self.providers.get(name).cloned()
+    }
+}
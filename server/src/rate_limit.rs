@@ -0,0 +1,150 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::{header, request::Parts, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+};
+use uuid::Uuid;
+
+use crate::{config::Config, token::Claims};
+
+/// IP address of the client, when known. Behind a reverse proxy, set
+/// `TRUST_FORWARDED_FOR` so it is read from `X-Forwarded-For`.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let trust_forwarded_for = parts
+            .extensions
+            .get::<Config>()
+            .is_some_and(|config| config.trust_forwarded_for);
+        let forwarded = parts
+            .headers
+            .get("x-forwarded-for")
+            .filter(|_| trust_forwarded_for)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        let peer = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .ok()
+            .map(|ConnectInfo(addr)| addr.ip());
+        Ok(ClientIp(forwarded.or(peer)))
+    }
+}
+
+/// Who a request counts against: the logged in user, or else the client
+/// IP. Requests from unknown clients share one limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RateLimitKey {
+    User(Uuid),
+    Ip(IpAddr),
+    Unknown,
+}
+
+/// Requests counted since the window of a key started.
+struct Window {
+    started_at: Instant,
+    requests: u64,
+}
+
+/// Windows kept before the expired ones are dropped.
+const MAX_WINDOWS: usize = 10_000;
+
+#[derive(Clone)]
+struct RateLimiter {
+    limit: u64,
+    period: Duration,
+    windows: Arc<Mutex<HashMap<RateLimitKey, Window>>>,
+}
+
+impl RateLimiter {
+    /// Counts a request, returning how long to wait when over the limit.
+    fn check(&self, key: RateLimitKey, now: Instant) -> Option<Duration> {
+        let mut windows = self.windows.lock().expect("Rate limiter lock poisoned");
+        if windows.len() >= MAX_WINDOWS {
+            windows.retain(|_, window| now - window.started_at < self.period);
+        }
+        let window = windows.entry(key).or_insert(Window {
+            started_at: now,
+            requests: 0,
+        });
+        if now - window.started_at >= self.period {
+            *window = Window {
+                started_at: now,
+                requests: 0,
+            };
+        }
+        if window.requests >= self.limit {
+            return Some(self.period - (now - window.started_at));
+        }
+        window.requests += 1;
+        None
+    }
+}
+
+async fn limit_requests(
+    State(limiter): State<RateLimiter>,
+    ClientIp(ip): ClientIp,
+    claims: Result<Claims, (StatusCode, &'static str)>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let key = match (&claims, ip) {
+        (
+            Ok(Claims {
+                sub: Some(user_id), ..
+            }),
+            _,
+        ) => RateLimitKey::User(*user_id),
+        (_, Some(ip)) => RateLimitKey::Ip(ip),
+        (_, None) => RateLimitKey::Unknown,
+    };
+    if let Some(retry_after) = limiter.check(key, Instant::now()) {
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, seconds.to_string())],
+            "Too many requests",
+        )
+            .into_response();
+    }
+    // The handler reuses the claims instead of authenticating again.
+    if let Ok(claims) = claims {
+        request.extensions_mut().insert(claims);
+    }
+    next.run(request).await
+}
+
+/// Limits each client to `graphql_rate_limit` requests per period, counted
+/// per logged in user or else per client IP. Requests over the limit are
+/// refused with 429 until the period ends.
+pub fn rate_limited<S>(route: MethodRouter<S>, config: &Config) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let limiter = RateLimiter {
+        limit: config.graphql_rate_limit.max(1),
+        period: config
+            .graphql_rate_limit_period
+            .to_std()
+            .expect("Rate limit period must be positive"),
+        windows: Arc::default(),
+    };
+    route.layer(middleware::from_fn_with_state(limiter, limit_requests))
+}
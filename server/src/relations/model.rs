@@ -1,14 +1,20 @@
 use std::ops::Range;
 
 use async_graphql::{Context, Enum, SimpleObject};
+use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 use uuid::Uuid;
 
-use crate::DB;
+use crate::{
+    token::Claims,
+    user::{api_token::ApiTokenScope, two_factor::TwoFactor},
+    DB,
+};
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Enum, sqlx::Type)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Enum, sqlx::Type, Deserialize, Serialize)]
 #[sqlx(type_name = "association_role")]
 #[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum Role {
     Admin,
     Treasurer,
@@ -77,6 +83,32 @@ impl Relations {
         role: Role,
     ) -> Result<Option<AssociationRoles>, anyhow::Error> {
         let pool = ctx.data::<DB>().unwrap();
+        let claims = ctx.data_opt::<Claims>();
+        let own_role = claims.is_some_and(|claims| claims.sub.as_ref() == Some(user_id));
+        // API tokens only act with the roles they were given.
+        if let Some(scope) = ctx.data_opt::<ApiTokenScope>() {
+            if own_role && !scope.allows_role(&association_id, role) {
+                return Ok(None);
+            }
+        }
+        let association_roles = Self::read_role(pool, user_id, association_id, role).await?;
+        // Admins and treasurers acting in an association that requires it
+        // must have logged in with a second factor.
+        if association_roles.is_some() && matches!(role, Role::Admin | Role::Treasurer) && own_role
+        {
+            TwoFactor::check_policy(pool, &association_id, claims.and_then(|claims| claims.sid))
+                .await?;
+        }
+        Ok(association_roles)
+    }
+
+    /// Same as `get_role`, for callers outside of GraphQL resolvers.
+    pub async fn read_role(
+        pool: &DB,
+        user_id: &Uuid,
+        association_id: Uuid,
+        role: Role,
+    ) -> Result<Option<AssociationRoles>, anyhow::Error> {
         let association_roles = sqlx::query_as::<_, AssociationRoles>(
             r#"SELECT * FROM "AssociationRoles" WHERE
             user_id = $1 AND 
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use async_graphql::{Context, FieldResult, Object};
+
+use crate::{token::Claims, user::api_token::ApiTokenScope, Clock, DB};
+
+use super::model::Session;
+
+#[derive(Default)]
+pub struct SessionMutation;
+
+#[Object(extends)]
+impl SessionMutation {
+    /// Revokes the session of the current token.
+    async fn logout(&self, ctx: &Context<'_>) -> FieldResult<bool> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        let session_id = claims
+            .sid
+            .ok_or(anyhow::Error::msg("Token has no session"))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let revoked = Session::revoke(pool, &user_id, &session_id, now).await?;
+        Ok(revoked)
+    }
+
+    /// Revokes every session of the user, including the current one. Returns
+    /// how many sessions were logged out.
+    async fn logout_all_devices(&self, ctx: &Context<'_>) -> FieldResult<u64> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let mut conn = pool.acquire().await?;
+        let revoked = Session::revoke_all(&mut conn, &user_id, now).await?;
+        Ok(revoked)
+    }
+}
@@ -0,0 +1,196 @@
+use chrono::{Duration, Utc};
+use sqlx::{FromRow, PgConnection};
+use uuid::Uuid;
+
+use crate::{
+    token::{generate_opaque_token, hash_opaque_token},
+    DB,
+};
+
+/// How long a session can go without being refreshed.
+pub const REFRESH_TOKEN_DAYS: i64 = 30;
+
+/// A login session, shared by the access tokens issued for it.
+#[derive(Debug, FromRow)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub refresh_token_hash: String,
+    pub previous_refresh_token_hash: Option<String>,
+    pub expires_at: chrono::DateTime<Utc>,
+    pub revoked_at: Option<chrono::DateTime<Utc>>,
+    // Started with a second factor, see `user::two_factor`.
+    pub two_factor: bool,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl Session {
+    /// Starts a session for the user. The refresh token is only returned
+    /// here, the database keeps its hash.
+    pub async fn create(
+        db: &DB,
+        user_id: &Uuid,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<(Session, String), anyhow::Error> {
+        let refresh_token = generate_opaque_token();
+        let mut tx = db.begin().await?;
+        let session = sqlx::query_as!(
+            Session,
+            r#"INSERT INTO "Session" (user_id, refresh_token_hash, expires_at)
+            VALUES ($1, $2, $3) RETURNING *"#,
+            user_id,
+            hash_opaque_token(&refresh_token),
+            now + Duration::days(REFRESH_TOKEN_DAYS)
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok((session, refresh_token))
+    }
+
+    /// Exchanges a refresh token for a new one, extending the session.
+    /// Presenting a token that was already exchanged revokes the session, as
+    /// it means the token leaked.
+    pub async fn refresh(
+        db: &DB,
+        refresh_token: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<(Session, String), anyhow::Error> {
+        let token_hash = hash_opaque_token(refresh_token);
+        let mut tx = db.begin().await?;
+
+        let reused = sqlx::query_scalar!(
+            r#"UPDATE "Session" SET revoked_at = COALESCE(revoked_at, $2)
+            WHERE previous_refresh_token_hash = $1 RETURNING id"#,
+            token_hash,
+            now
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if reused.is_some() {
+            tx.commit().await?;
+            return Err(anyhow::anyhow!("Invalid refresh token"));
+        }
+
+        let new_token = generate_opaque_token();
+        let session = sqlx::query_as!(
+            Session,
+            r#"UPDATE "Session" s
+            SET refresh_token_hash = $2, previous_refresh_token_hash = $1, expires_at = $4
+            FROM "User" u
+            WHERE u.id = s.user_id AND u.deleted IS NOT TRUE
+                AND s.refresh_token_hash = $1 AND s.revoked_at IS NULL AND s.expires_at > $3
+            RETURNING s.*"#,
+            token_hash,
+            hash_opaque_token(&new_token),
+            now,
+            now + Duration::days(REFRESH_TOKEN_DAYS)
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(anyhow::anyhow!("Invalid refresh token"))?;
+        tx.commit().await?;
+        Ok((session, new_token))
+    }
+
+    /// Whether the session was neither revoked nor left to expire, and its
+    /// user still has an account.
+    pub async fn is_active(
+        db: &DB,
+        id: &Uuid,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<bool, anyhow::Error> {
+        let active = sqlx::query_scalar!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM "Session" s
+                INNER JOIN "User" u ON u.id = s.user_id
+                WHERE s.id = $1 AND s.revoked_at IS NULL AND s.expires_at > $2
+                    AND u.deleted IS NOT TRUE
+            ) AS "active!""#,
+            id,
+            now
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(active)
+    }
+
+    /// Revokes one of the user's sessions. Returns whether it was active.
+    pub async fn revoke(
+        db: &DB,
+        user_id: &Uuid,
+        id: &Uuid,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<bool, anyhow::Error> {
+        let mut tx = db.begin().await?;
+        let result = sqlx::query!(
+            r#"UPDATE "Session" SET revoked_at = $3
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL"#,
+            id,
+            user_id,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Revokes all the user's sessions. Returns how many were active.
+    pub async fn revoke_all(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<u64, anyhow::Error> {
+        let result = sqlx::query!(
+            r#"UPDATE "Session" SET revoked_at = $2 WHERE user_id = $1 AND revoked_at IS NULL"#,
+            user_id,
+            now
+        )
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Revokes the user's sessions except `current`, e.g. after a password
+    /// change from that session.
+    pub async fn revoke_others(
+        db: &DB,
+        user_id: &Uuid,
+        current: Option<Uuid>,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<u64, anyhow::Error> {
+        let mut tx = db.begin().await?;
+        let result = sqlx::query!(
+            r#"UPDATE "Session" SET revoked_at = $3
+            WHERE user_id = $1 AND revoked_at IS NULL AND id IS DISTINCT FROM $2"#,
+            user_id,
+            current,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Marks the session as started with a second factor.
+    pub async fn set_two_factor(db: &DB, id: &Uuid) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"UPDATE "Session" SET two_factor = true WHERE id = $1"#,
+            id
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn is_two_factor(db: &DB, id: &Uuid) -> Result<bool, anyhow::Error> {
+        let two_factor =
+            sqlx::query_scalar!(r#"SELECT two_factor FROM "Session" WHERE id = $1"#, id)
+                .fetch_optional(db)
+                .await?;
+        Ok(two_factor.unwrap_or(false))
+    }
+}
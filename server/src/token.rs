@@ -1,18 +1,37 @@
-use std::{env, future::Future};
+use std::{
+    future::Future,
+    sync::{Arc, LazyLock},
+};
 
-use crate::{oauth::get_token, user::model::User};
+use crate::{
+    config::Config,
+    mail::{Email, Mailer},
+    oauth::get_token,
+    rate_limit::ClientIp,
+    session::model::Session,
+    user::{
+        api_token::{ApiToken, ApiTokenScope, API_TOKEN_PREFIX},
+        login_attempt::{LoginAttempt, LoginFailure},
+        model::User,
+        password::{hash_password, PasswordReset},
+        registration::{check_email, check_password, EmailVerification, Registration},
+        two_factor::{get_pre_auth_token, validate_pre_auth_token, TwoFactor},
+    },
+    Clock,
+};
 use anyhow::Error;
 use axum::{
     extract::{FromRequest, FromRequestParts, Request},
-    http::{request::Parts, HeaderMap},
-    response::IntoResponse,
+    http::{header, request::Parts, HeaderMap},
+    response::{IntoResponse, Response},
     Extension, Json,
 };
-use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, DecodingKey, TokenData, Validation};
+use chrono::Utc;
+use jsonwebtoken::{decode, decode_header, encode, Header, TokenData, Validation};
 use reqwest::StatusCode;
-use serde::{Deserialize, Serialize};
-use tower_cookies::Cookies;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tower_cookies::{cookie, Cookie, Cookies};
 
 use crate::DB;
 
@@ -27,6 +46,9 @@ pub struct Claims {
     pub sub: Option<uuid::Uuid>,
     pub email: Option<String>,
     pub exp: usize,
+    // Session the token was issued for. Tokens of revoked sessions are rejected.
+    #[serde(default)]
+    pub sid: Option<uuid::Uuid>,
 }
 
 // A small extractor that pulls Claims from Request.extensions()
@@ -57,48 +79,162 @@ where
     type Rejection = (StatusCode, &'static str);
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // Already authenticated, e.g. by the rate limit.
+        if let Some(claims) = parts.extensions.get::<Claims>() {
+            return Ok(claims.clone());
+        }
         let cookies = Cookies::from_request_parts(parts, state).await.unwrap();
-        let claims = extract_claims_from_request(&parts.headers, &cookies);
-        match claims {
-            Some(claims) => Ok(claims),
+        let db = parts
+            .extensions
+            .get::<DB>()
+            .cloned()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "DB pool not found"))?;
+        let config = parts
+            .extensions
+            .get::<Config>()
+            .cloned()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Config not found"))?;
+        let now = parts
+            .extensions
+            .get::<Arc<dyn Clock>>()
+            .map_or_else(Utc::now, |clock| clock.now());
+        let claims = extract_claims_from_request(&config, &db, &parts.headers, &cookies, now).await;
+        if let Some((claims, scope)) = claims {
+            // Read by handlers that restrict API tokens, such as GraphQL.
+            if let Some(scope) = scope {
+                parts.extensions.insert(scope);
+            }
+            return Ok(claims);
+        }
+
+        // Registration tokens carry no user, only what `completeRegistration`
+        // needs to create one.
+        let registration = bearer_token(&parts.headers)
+            .and_then(|token| Registration::from_token(&config, token).ok());
+        match registration {
+            Some(registration) => {
+                parts.extensions.insert(registration);
+                Ok(Claims {
+                    sub: None,
+                    email: None,
+                    exp: 0,
+                    sid: None,
+                })
+            }
             None => Err((StatusCode::UNAUTHORIZED, "Missing or invalid token")),
         }
     }
 }
 
-pub fn extract_claims_from_request(headers: &HeaderMap, cookies: &Cookies) -> Option<Claims> {
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Authenticates the request with a JWT or personal API token. Requests with
+/// an API token also get its scope.
+pub async fn extract_claims_from_request(
+    config: &Config,
+    db: &DB,
+    headers: &HeaderMap,
+    cookies: &Cookies,
+    now: chrono::DateTime<Utc>,
+) -> Option<(Claims, Option<ApiTokenScope>)> {
     // First, try Authorization header
-    if let Some(auth_header) = headers.get("Authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            if let Some(token) = auth_str.strip_prefix("Bearer ") {
-                if let Ok(claims) = validate_token(token) {
-                    return Some(claims);
-                }
+    if let Some(token) = bearer_token(headers) {
+        if token.starts_with(API_TOKEN_PREFIX) {
+            if let Ok(Some(api_token)) = ApiToken::authenticate(db, token, now).await {
+                let claims = Claims {
+                    sub: Some(api_token.user_id),
+                    email: None,
+                    exp: api_token.expires_at.timestamp() as usize,
+                    sid: None,
+                };
+                return Some((claims, Some(api_token.scope())));
             }
+        } else if let Ok(claims) = validate_token(config, db, token, now).await {
+            return Some((claims, None));
         }
     }
 
     // If no header, fallback to auth_token cookie
     if let Some(cookie) = cookies.get("auth_token") {
-        if let Ok(claims) = validate_token(cookie.value()) {
-            return Some(claims);
+        if let Ok(claims) = validate_token(config, db, cookie.value(), now).await {
+            return Some((claims, None));
         }
     }
 
     None
 }
 
-// Function to validate JWT token
-fn validate_token(token: &str) -> Result<Claims, Error> {
-    let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    let token: TokenData<Claims> = decode(
-        token,
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &Validation::default(),
-    )?;
+/// Validates a JWT against the key named in its `kid` header and checks its
+/// session was neither revoked nor expired. Access tokens belong to a user
+/// and a session.
+pub async fn validate_token(
+    config: &Config,
+    db: &DB,
+    token: &str,
+    now: chrono::DateTime<Utc>,
+) -> Result<Claims, Error> {
+    let keys = &config.jwt_keys;
+    let header = decode_header(token)?;
+    let decoding_key = keys
+        .decoding_key(header.kid.as_deref())
+        .ok_or(anyhow::anyhow!("Unknown signing key"))?;
+    let token: TokenData<Claims> = decode(token, decoding_key, &Validation::new(keys.algorithm))?;
+    match (token.claims.sub, token.claims.sid) {
+        (_, Some(sid)) => {
+            if !Session::is_active(db, &sid, now).await? {
+                return Err(anyhow::anyhow!("Session was revoked or expired"));
+            }
+        }
+        (Some(_), None) => return Err(anyhow::anyhow!("Token has no session")),
+        (None, None) => return Err(anyhow::anyhow!("Token has no user")),
+    }
     Ok(token.claims)
 }
 
+/// Signs a token for a single purpose, named by its `aud` claim. Tokens
+/// with an audience are not accepted as access tokens.
+pub fn encode_purpose_token<T: Serialize>(config: &Config, claims: &T) -> Result<String, Error> {
+    let keys = &config.jwt_keys;
+    let mut header = Header::new(keys.algorithm);
+    header.kid = Some(keys.kid.clone());
+    Ok(encode(&header, claims, keys.encoding_key())?)
+}
+
+pub fn decode_purpose_token<T: DeserializeOwned>(
+    config: &Config,
+    token: &str,
+    audience: &str,
+) -> Result<T, Error> {
+    let keys = &config.jwt_keys;
+    let header = decode_header(token)?;
+    let decoding_key = keys
+        .decoding_key(header.kid.as_deref())
+        .ok_or(anyhow::anyhow!("Unknown signing key"))?;
+    let mut validation = Validation::new(keys.algorithm);
+    validation.set_audience(&[audience]);
+    Ok(decode::<T>(token, decoding_key, &validation)?.claims)
+}
+
+/// Generates a random opaque token, for secrets that are not JWTs.
+pub fn generate_opaque_token() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Opaque tokens are only stored hashed.
+pub fn hash_opaque_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
 /// Request payload for logging in.
 #[derive(Debug, Deserialize)]
 pub struct LoginOrCreateRequest {
@@ -108,67 +244,473 @@ pub struct LoginOrCreateRequest {
 
 /// Response payload for login containing the token.
 #[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct LoginResponse {
     token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+}
+
+impl LoginResponse {
+    /// Logs the user in with a new session.
+    pub async fn start_session(
+        db: &DB,
+        config: &Config,
+        user: User,
+        two_factor: bool,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Self, (StatusCode, &'static str)> {
+        let db_error = |_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error");
+        let (session, refresh_token) =
+            Session::create(db, &user.id, now).await.map_err(db_error)?;
+        if two_factor {
+            Session::set_two_factor(db, &session.id)
+                .await
+                .map_err(db_error)?;
+        }
+        let token = get_token(config, Some(user.id), user.email, Some(session.id))?;
+        Ok(LoginResponse {
+            token,
+            refresh_token: Some(refresh_token),
+        })
+    }
+
+    /// Sets the tokens as cookies, for browsers.
+    pub fn set_cookies(&self, config: &Config, cookies: &Cookies) {
+        cookies.add(auth_token_cookie(config, self.token.clone()));
+        if let Some(refresh_token) = &self.refresh_token {
+            cookies.add(refresh_token_cookie(config, refresh_token.clone()));
+        }
+    }
+}
+
+/// Response payload for someone without a user yet, to send as bearer token
+/// to `completeRegistration`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationResponse {
+    registration_required: bool,
+    registration_token: String,
+}
+
+impl RegistrationResponse {
+    pub fn new(
+        config: &Config,
+        registration: &Registration,
+    ) -> Result<Self, (StatusCode, &'static str)> {
+        let registration_token = registration
+            .token(config)
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Token creation failed"))?;
+        Ok(RegistrationResponse {
+            registration_required: true,
+            registration_token,
+        })
+    }
 }
 
-pub fn get_token_exp() -> usize {
+/// Request payload for refreshing a session. Without a token in the body,
+/// the `refresh_token` cookie set by OAuth logins is used.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshRequest {
+    refresh_token: Option<String>,
+}
+
+/// Refresh tokens are also handed out as an http-only cookie to browsers.
+pub fn refresh_token_cookie(config: &Config, refresh_token: String) -> Cookie<'static> {
+    Cookie::build(("refresh_token", refresh_token))
+        .http_only(true)
+        .secure(config.secure_cookies)
+        .same_site(cookie::SameSite::Lax)
+        .path("/auth")
+        .build()
+}
+
+/// Access token cookie for browsers, out of reach of scripts.
+pub fn auth_token_cookie(config: &Config, token: String) -> Cookie<'static> {
+    Cookie::build(("auth_token", token))
+        .http_only(true)
+        .secure(config.secure_cookies)
+        .same_site(cookie::SameSite::Lax)
+        .path("/")
+        .max_age(cookie::time::Duration::minutes(config.jwt_max_age))
+        .build()
+}
+
+pub fn get_token_exp(config: &Config) -> usize {
     Utc::now()
-        .checked_add_signed(Duration::hours(24))
+        .checked_add_signed(config.jwt_expires_in)
         .expect("valid timestamp")
         .timestamp() as usize
 }
 
+/// Checked instead when the user is unknown or has no password, so the time
+/// a login takes does not tell which emails are registered.
+static DUMMY_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| {
+    bcrypt::hash("not a real password", bcrypt::DEFAULT_COST).expect("Should hash password")
+});
+
+fn too_many_attempts(retry_after: chrono::Duration) -> Response {
+    let seconds = (retry_after.num_milliseconds() + 999) / 1000;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, seconds.to_string())],
+        "Too many login attempts, try again later",
+    )
+        .into_response()
+}
+
 pub async fn login_handler(
     Extension(db): Extension<DB>,
+    Extension(config): Extension<Config>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+    ClientIp(ip): ClientIp,
     Json(payload): Json<LoginOrCreateRequest>,
-) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
-    let mut tx = db
-        .begin()
+) -> Result<Response, Response> {
+    let db_error = |_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error").into_response();
+    let now = clock.now();
+    let email = payload.email.trim().to_lowercase();
+
+    // Throttled attempts are refused before checking the password.
+    let retry_after = LoginAttempt::retry_after(&db, &config, &email, ip, now)
         .await
-        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?;
-    let user = sqlx::query_as!(
-        User,
-        r#"SELECT * FROM "User" WHERE email = $1"#,
-        payload.email
-    )
-    .fetch_optional(&mut *tx)
-    .await
-    .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid credentials"))?;
-
-    // Create a token that expires in 24 hours.
-    let exp = get_token_exp();
-
-    let claims = match user {
-        Some(user) => {
-            let sub = Some(user.id);
-            if let Some(user_password_hash) = user.password_hash {
-                // Verify the password.
-                let verify = bcrypt::verify(payload.password.clone(), &user_password_hash)
-                    .map_err(|_| {
-                        (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            "Password verification failed",
-                        )
-                    })?;
-                if verify == false {
-                    return Err((StatusCode::UNAUTHORIZED, "Invalid credentials"));
-                }
-            }
+        .map_err(db_error)?;
+    if let Some(retry_after) = retry_after {
+        LoginAttempt::record(&db, &email, None, ip, Some(LoginFailure::Throttled), now)
+            .await
+            .map_err(db_error)?;
+        return Err(too_many_attempts(retry_after));
+    }
 
-            Claims {
-                sub,
-                email: user.email,
-                exp,
-            }
+    let user = User::read_one_by_email(&db, &email)
+        .await
+        .map_err(db_error)?
+        .filter(|user| user.deleted != Some(true));
+    let password_hash = user
+        .as_ref()
+        .and_then(|user| user.password_hash.as_deref())
+        .unwrap_or(&DUMMY_PASSWORD_HASH);
+    let verify = bcrypt::verify(payload.password.clone(), password_hash).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Password verification failed",
+        )
+            .into_response()
+    })?;
+    let failure = match &user {
+        None => Some(LoginFailure::UnknownUser),
+        // Users created through OAuth have no password to log in with.
+        Some(User {
+            password_hash: None,
+            ..
+        }) => Some(LoginFailure::NoPassword),
+        Some(_) => (!verify).then_some(LoginFailure::WrongPassword),
+    };
+    let user_id = user.as_ref().map(|user| user.id);
+    let user = match (user, failure) {
+        (Some(user), None) => user,
+        _ => {
+            LoginAttempt::record(&db, &email, user_id.as_ref(), ip, failure, now)
+                .await
+                .map_err(db_error)?;
+            return Err((StatusCode::UNAUTHORIZED, "Invalid credentials").into_response());
+        }
+    };
+
+    // The login only succeeds once the second factor is checked.
+    if TwoFactor::is_enabled(&db, &user.id)
+        .await
+        .map_err(db_error)?
+    {
+        return Ok(Json(
+            TwoFactorChallenge::new(&config, user.id).map_err(IntoResponse::into_response)?,
+        )
+        .into_response());
+    }
+    LoginAttempt::record(&db, &email, Some(&user.id), ip, None, now)
+        .await
+        .map_err(db_error)?;
+
+    let login = LoginResponse::start_session(&db, &config, user, false, now)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    Ok(Json(login).into_response())
+}
+
+/// Response payload for a password login of a user with two-factor enabled.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFactorChallenge {
+    two_factor_required: bool,
+    pre_auth_token: String,
+}
+
+impl TwoFactorChallenge {
+    pub fn new(config: &Config, user_id: uuid::Uuid) -> Result<Self, (StatusCode, &'static str)> {
+        let pre_auth_token = get_pre_auth_token(config, user_id)
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Token creation failed"))?;
+        Ok(TwoFactorChallenge {
+            two_factor_required: true,
+            pre_auth_token,
+        })
+    }
+}
+
+/// Request payload completing a login with a TOTP or recovery code.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFactorRequest {
+    pre_auth_token: String,
+    code: String,
+}
+
+/// Second step of a login, after the password or OAuth provider was checked.
+/// Wrong codes count towards the same throttling as wrong passwords.
+pub async fn two_factor_handler(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Config>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<TwoFactorRequest>,
+) -> Result<Json<LoginResponse>, Response> {
+    let db_error = |_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error").into_response();
+    let now = clock.now();
+    let user_id = validate_pre_auth_token(&config, &payload.pre_auth_token)
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid or expired token").into_response())?;
+    let user = User::read_one(&db, &user_id)
+        .await
+        .ok()
+        .filter(|user| user.deleted != Some(true))
+        .ok_or((StatusCode::UNAUTHORIZED, "Invalid credentials").into_response())?;
+    let email = user.email.clone().unwrap_or_default().to_lowercase();
+
+    let retry_after = LoginAttempt::retry_after(&db, &config, &email, ip, now)
+        .await
+        .map_err(db_error)?;
+    if let Some(retry_after) = retry_after {
+        LoginAttempt::record(
+            &db,
+            &email,
+            Some(&user.id),
+            ip,
+            Some(LoginFailure::Throttled),
+            now,
+        )
+        .await
+        .map_err(db_error)?;
+        return Err(too_many_attempts(retry_after));
+    }
+
+    let verified = TwoFactor::verify(&db, &user.id, &payload.code, now)
+        .await
+        .map_err(db_error)?;
+    let failure = (!verified).then_some(LoginFailure::WrongCode);
+    LoginAttempt::record(&db, &email, Some(&user.id), ip, failure, now)
+        .await
+        .map_err(db_error)?;
+    if !verified {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid code").into_response());
+    }
+
+    let login = LoginResponse::start_session(&db, &config, user, true, now)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    Ok(Json(login))
+}
+
+/// Starts an email/password registration by emailing a verification link.
+/// The response is the same whether or not the email is already registered,
+/// so it cannot be used to find out who has an account.
+pub async fn register_handler(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Config>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Json(payload): Json<LoginOrCreateRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let bad_request = |e: anyhow::Error| (StatusCode::BAD_REQUEST, e.to_string());
+    let internal_error = |_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Registration failed".to_owned(),
+        )
+    };
+    let email = payload.email.trim().to_lowercase();
+    check_email(&email).map_err(bad_request)?;
+    check_password(&payload.password).map_err(bad_request)?;
+
+    let existing = User::read_one_by_email(&db, &email)
+        .await
+        .map_err(internal_error)?;
+    let message = if existing.is_some() {
+        Email {
+            to: email,
+            subject: "You already have an account".to_owned(),
+            body: "Someone tried to register with this email, which already has an account. \
+                If it was you, log in or reset your password instead."
+                .to_owned(),
+        }
+    } else {
+        let password_hash = hash_password(&payload.password).map_err(internal_error)?;
+        let token = EmailVerification::create(&db, &email, &password_hash, clock.now())
+            .await
+            .map_err(internal_error)?;
+        Email {
+            to: email,
+            subject: "Confirm your email".to_owned(),
+            body: format!(
+                "Open this link to confirm your email and finish signing up: {}?token={}",
+                config.email_verification_url, token
+            ),
         }
-        None => Claims {
-            sub: None,
-            email: None,
-            exp,
-        },
     };
+    mailer.send(message).await.map_err(internal_error)?;
+    Ok(StatusCode::ACCEPTED)
+}
 
-    let token = get_token(claims.sub, claims.email.clone())?;
-    return Ok(Json(LoginResponse { token }));
+/// Request payload with a token received by email.
+#[derive(Debug, Deserialize)]
+pub struct EmailTokenRequest {
+    token: String,
+}
+
+/// Confirms a registration. The returned registration token carries the
+/// verified email, which lets `completeRegistration` create the account.
+pub async fn verify_email_handler(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Config>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+    Json(payload): Json<EmailTokenRequest>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let verification = EmailVerification::verify(&db, &payload.token, clock.now())
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                "Invalid or expired verification token",
+            )
+        })?;
+    let registration = Registration {
+        email: Some(verification.email),
+        provider: None,
+        subject: None,
+        name: None,
+        picture_url: None,
+    };
+    Ok(Json(RegistrationResponse::new(&config, &registration)?))
+}
+
+/// Request payload for starting a password reset.
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    email: String,
+}
+
+/// Emails a password reset link. Like registration, the response does not
+/// tell whether the email has an account.
+pub async fn forgot_password_handler(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Config>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Json(payload): Json<ForgotPasswordRequest>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let internal_error = |_| (StatusCode::INTERNAL_SERVER_ERROR, "Password reset failed");
+    let email = payload.email.trim().to_lowercase();
+    let user = User::read_one_by_email(&db, &email)
+        .await
+        .map_err(internal_error)?
+        .filter(|user| user.deleted != Some(true));
+    if let Some(user) = user {
+        let token = PasswordReset::create(&db, &user.id, clock.now())
+            .await
+            .map_err(internal_error)?;
+        mailer
+            .send(Email {
+                to: email,
+                subject: "Reset your password".to_owned(),
+                body: format!(
+                    "Open this link to choose a new password: {}?token={}\n\
+                    If you did not ask for it, you can ignore this email.",
+                    config.password_reset_url, token
+                ),
+            })
+            .await
+            .map_err(internal_error)?;
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Request payload for setting a new password with a reset token.
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    token: String,
+    password: String,
+}
+
+/// Sets a new password with a reset token. All sessions are logged out.
+pub async fn reset_password_handler(
+    Extension(db): Extension<DB>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_password(&payload.password).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    PasswordReset::reset(&db, &payload.token, &payload.password, clock.now())
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                "Invalid or expired reset token".to_owned(),
+            )
+        })?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Exchanges a refresh token for a new access token and refresh token.
+pub async fn refresh_handler(
+    Extension(db): Extension<DB>,
+    Extension(config): Extension<Config>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+    cookies: Cookies,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let from_cookie = payload.refresh_token.is_none();
+    let refresh_token = payload
+        .refresh_token
+        .or_else(|| cookies.get("refresh_token").map(|c| c.value().to_owned()))
+        .ok_or((StatusCode::UNAUTHORIZED, "Missing refresh token"))?;
+
+    let (session, refresh_token) = Session::refresh(&db, &refresh_token, clock.now())
+        .await
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid refresh token"))?;
+    let user = User::read_one(&db, &session.user_id)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?;
+
+    let token = get_token(&config, Some(user.id), user.email, Some(session.id))?;
+    if from_cookie {
+        cookies.add(auth_token_cookie(&config, token.clone()));
+        cookies.add(refresh_token_cookie(&config, refresh_token.clone()));
+    }
+    Ok(Json(LoginResponse {
+        token,
+        refresh_token: Some(refresh_token),
+    }))
+}
+
+/// Revokes the session of the presented token.
+pub async fn logout_handler(
+    Extension(db): Extension<DB>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+    claims: Claims,
+    cookies: Cookies,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    if let (Some(user_id), Some(sid)) = (claims.sub, claims.sid) {
+        Session::revoke(&db, &user_id, &sid, clock.now())
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?;
+    }
+    cookies.remove(Cookie::build(("auth_token", "")).path("/").build());
+    cookies.remove(Cookie::build(("refresh_token", "")).path("/auth").build());
+    Ok(StatusCode::NO_CONTENT)
 }
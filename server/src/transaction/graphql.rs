@@ -1,9 +1,11 @@
+use std::sync::Arc;
+
 use async_graphql::{Context, FieldResult, Object};
 use uuid::Uuid;
 
-use crate::{token::Claims, user::model::User, DB};
+use crate::{token::Claims, user::model::User, Clock, DB};
 
-use super::model::{Transaction, TransactionInput};
+use super::model::{Charge, Transaction, TransactionInput};
 
 #[derive(Default)]
 pub struct TransactionQuery;
@@ -15,6 +17,22 @@ impl TransactionQuery {
         let user = Transaction::read_one(pool, &id).await?;
         Ok(user)
     }
+
+    async fn charge(&self, ctx: &Context<'_>, id: Uuid) -> FieldResult<Charge> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().unwrap();
+        let charge = Charge::read_one(pool, &id).await?;
+        let user = User::read_one(pool, &user_id).await?;
+        if charge.user_id != Some(user_id) && !user.is_treasurer(ctx, charge.association_id).await?
+        {
+            Err(anyhow::Error::msg("Unauthorized"))?
+        }
+        Ok(charge)
+    }
 }
 
 #[derive(Default)]
@@ -42,4 +60,25 @@ impl TransactionMutation {
         let user = Transaction::create(pool, transaction).await?;
         Ok(user)
     }
+
+    /// Records the payment of a charge. Tentative reservations linked to it
+    /// are confirmed.
+    async fn settle_charge(&self, ctx: &Context<'_>, id: Uuid) -> FieldResult<Charge> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+
+        let pool = ctx.data::<DB>().unwrap();
+        let charge = Charge::read_one(pool, &id).await?;
+        let user = User::read_one(pool, &user_id).await?;
+        if !user.is_treasurer(ctx, charge.association_id).await? {
+            Err(anyhow::Error::msg(
+                "Unauthorized, only treasurers can settle charges",
+            ))?
+        }
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let charge = Charge::settle(pool, &id, &user_id, now).await?;
+        Ok(charge)
+    }
 }
@@ -1,7 +1,8 @@
 use async_graphql::{InputObject, SimpleObject};
 use bigdecimal::BigDecimal;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{FromRow, PgConnection};
 use uuid::Uuid;
 
 use crate::DB;
@@ -66,14 +67,14 @@ impl Transaction {
             r#"SELECT * FROM "Transaction" WHERE id = $1"#,
             id
         )
-        .fetch_one(&*db)
+        .fetch_one(db)
         .await?;
         Ok(transaction)
     }
 
     pub async fn read_all(db: &DB) -> Result<Vec<Transaction>, anyhow::Error> {
         let transactions = sqlx::query_as!(Transaction, r#"SELECT * FROM "Transaction""#)
-            .fetch_all(&*db)
+            .fetch_all(db)
             .await?;
         Ok(transactions)
     }
@@ -101,13 +102,17 @@ pub struct Charge {
     pub id: Uuid,
     pub association_id: Uuid,
     pub creator_id: Uuid,
-    pub details: String,
+    pub details: Option<String>,
     pub amount: BigDecimal,
     pub file_url: Option<String>,
     pub reference_date: chrono::NaiveDate,
     pub deleted: bool,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    // Member being charged.
+    pub user_id: Option<Uuid>,
+    pub expires_at: Option<chrono::DateTime<Utc>>,
+    pub settled_at: Option<chrono::DateTime<Utc>>,
 }
 
 #[derive(InputObject)]
@@ -145,4 +150,86 @@ impl Charge {
     //     tx.commit().await?;
     //     Ok(transaction)
     // }
+
+    /// Charges a member, e.g. for a paid field reservation. Unsettled charges
+    /// expire at `expires_at`.
+    pub async fn create_for_member(
+        conn: &mut PgConnection,
+        association_id: &Uuid,
+        user_id: &Uuid,
+        details: String,
+        amount: BigDecimal,
+        reference_date: chrono::NaiveDate,
+        expires_at: chrono::DateTime<Utc>,
+    ) -> Result<Charge, anyhow::Error> {
+        let charge = sqlx::query_as!(
+            Charge,
+            r#"INSERT INTO "Charge" (association_id, creator_id, user_id, details, amount, reference_date, expires_at)
+            VALUES ($1, $2, $2, $3, $4, $5, $6)
+            RETURNING *"#,
+            association_id,
+            user_id,
+            details,
+            amount,
+            reference_date,
+            expires_at
+        )
+        .fetch_one(conn)
+        .await?;
+        Ok(charge)
+    }
+
+    pub async fn read_one(db: &DB, id: &Uuid) -> Result<Charge, anyhow::Error> {
+        let charge = sqlx::query_as!(Charge, r#"SELECT * FROM "Charge" WHERE id = $1"#, id)
+            .fetch_one(db)
+            .await?;
+        Ok(charge)
+    }
+
+    /// Marks the charge as paid, records the income and confirms the
+    /// reservations waiting for it.
+    pub async fn settle(
+        db: &DB,
+        id: &Uuid,
+        treasurer_id: &Uuid,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Charge, anyhow::Error> {
+        let mut tx = db.begin().await?;
+        let charge = sqlx::query_as!(
+            Charge,
+            r#"UPDATE "Charge" SET settled_at = $2
+            WHERE id = $1 AND deleted = false AND settled_at IS NULL
+                AND (expires_at IS NULL OR expires_at > $2)
+            RETURNING *"#,
+            id,
+            now
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(anyhow::Error::msg("Charge is already settled or expired"))?;
+
+        sqlx::query!(
+            r#"INSERT INTO "Transaction" (association_id, creator_id, details, amount, reference_date)
+            VALUES ($1, $2, $3, $4, $5)"#,
+            charge.association_id,
+            treasurer_id,
+            charge
+                .details
+                .clone()
+                .unwrap_or_else(|| format!("Charge {}", charge.id)),
+            charge.amount,
+            now.date_naive()
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"UPDATE "FieldReservation" SET tentative = false WHERE charge_id = $1 AND deleted = false"#,
+            charge.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(charge)
+    }
 }
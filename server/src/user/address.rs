@@ -0,0 +1,196 @@
+use async_graphql::{InputObject, SimpleObject};
+use serde::Serialize;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::{association::model::Association, DB};
+
+/// Brazilian states, as used in addresses.
+const BR_STATES: [&str; 27] = [
+    "AC", "AL", "AP", "AM", "BA", "CE", "DF", "ES", "GO", "MA", "MT", "MS", "MG", "PA", "PB", "PR",
+    "PE", "PI", "RJ", "RN", "RS", "RO", "RR", "SC", "SP", "SE", "TO",
+];
+
+/// The home address of a user.
+#[derive(Debug, FromRow, SimpleObject, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserAddress {
+    #[graphql(skip)]
+    pub user_id: Uuid,
+    pub street: String,
+    pub number: String,
+    pub unit: Option<String>,
+    pub neighborhood: String,
+    pub city: String,
+    pub state: String,
+    pub postal_code: String,
+    pub country: String,
+    #[graphql(skip)]
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, InputObject)]
+pub struct AddressInput {
+    pub street: String,
+    pub number: String,
+    pub unit: Option<String>,
+    pub neighborhood: String,
+    pub city: String,
+    pub state: String,
+    pub postal_code: String,
+    // Defaults to BR.
+    pub country: Option<String>,
+}
+
+/// Compares names ignoring case and spacing, e.g. of neighborhoods.
+fn same_name(a: &str, b: &str) -> bool {
+    let words = |name: &str| {
+        name.split_whitespace()
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+    };
+    words(a) == words(b)
+}
+
+fn required(value: &str, field: &str, max_len: usize) -> Result<String, anyhow::Error> {
+    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    if value.is_empty() || value.chars().count() > max_len {
+        return Err(anyhow::anyhow!(
+            "The {} must have 1 to {} characters",
+            field,
+            max_len
+        ));
+    }
+    Ok(value)
+}
+
+impl AddressInput {
+    /// Validates the address, normalizing the state and postal code.
+    fn normalize(self) -> Result<AddressInput, anyhow::Error> {
+        let country = self
+            .country
+            .as_deref()
+            .unwrap_or("BR")
+            .trim()
+            .to_ascii_uppercase();
+        if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(anyhow::anyhow!("Invalid country code"));
+        }
+        let mut state = required(&self.state, "state", 32)?;
+        let mut postal_code = required(&self.postal_code, "postal code", 10)?;
+        if country == "BR" {
+            state = state.to_ascii_uppercase();
+            if !BR_STATES.contains(&state.as_str()) {
+                return Err(anyhow::anyhow!("Invalid state, use its abbreviation"));
+            }
+            // CEPs have 8 digits, written as 40000-000.
+            let digits: String = postal_code.chars().filter(char::is_ascii_digit).collect();
+            if digits.len() != 8 || postal_code.chars().any(|c| !c.is_ascii_digit() && c != '-') {
+                return Err(anyhow::anyhow!("Invalid CEP"));
+            }
+            postal_code = format!("{}-{}", &digits[..5], &digits[5..]);
+        }
+        let unit = match self.unit.as_deref().map(str::trim) {
+            Some("") | None => None,
+            Some(unit) => Some(required(unit, "unit", 30)?),
+        };
+        Ok(AddressInput {
+            street: required(&self.street, "street", 120)?,
+            number: required(&self.number, "number", 10)?,
+            unit,
+            neighborhood: required(&self.neighborhood, "neighborhood", 60)?,
+            city: required(&self.city, "city", 60)?,
+            state,
+            postal_code,
+            country: Some(country),
+        })
+    }
+}
+
+impl UserAddress {
+    /// The address on a single line, as kept in `User.address`.
+    pub fn single_line(&self) -> String {
+        let unit = self
+            .unit
+            .as_ref()
+            .map(|unit| format!(" {}", unit))
+            .unwrap_or_default();
+        format!(
+            "{}, {}{}, {}, {} - {}, {}",
+            self.street,
+            self.number,
+            unit,
+            self.neighborhood,
+            self.city,
+            self.state,
+            self.postal_code
+        )
+    }
+
+    /// Whether the address is in the association's neighborhood.
+    pub fn in_neighborhood(&self, association: &Association) -> bool {
+        self.country.eq_ignore_ascii_case(&association.country)
+            && same_name(&self.state, &association.state)
+            && same_name(&self.neighborhood, &association.neighborhood)
+    }
+
+    pub async fn read(db: &DB, user_id: &Uuid) -> Result<Option<UserAddress>, anyhow::Error> {
+        let address = sqlx::query_as!(
+            UserAddress,
+            r#"SELECT * FROM "UserAddress" WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(address)
+    }
+
+    /// Sets the user's address, also updating `User.address`.
+    pub async fn set(
+        db: &DB,
+        user_id: &Uuid,
+        input: AddressInput,
+    ) -> Result<UserAddress, anyhow::Error> {
+        let address = input.normalize()?;
+        let mut tx = db.begin().await?;
+        let address = sqlx::query_as!(
+            UserAddress,
+            r#"INSERT INTO "UserAddress"
+                (user_id, street, number, unit, neighborhood, city, state, postal_code, country)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (user_id) DO UPDATE SET
+                street = EXCLUDED.street,
+                number = EXCLUDED.number,
+                unit = EXCLUDED.unit,
+                neighborhood = EXCLUDED.neighborhood,
+                city = EXCLUDED.city,
+                state = EXCLUDED.state,
+                postal_code = EXCLUDED.postal_code,
+                country = EXCLUDED.country
+            RETURNING *"#,
+            user_id,
+            address.street,
+            address.number,
+            address.unit,
+            address.neighborhood,
+            address.city,
+            address.state,
+            address.postal_code,
+            address.country
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        // Long addresses are cut to fit the single-line column.
+        let single_line: String = address.single_line().chars().take(250).collect();
+        sqlx::query!(
+            r#"UPDATE "User" SET address = $2 WHERE id = $1"#,
+            user_id,
+            single_line
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(address)
+    }
+}
@@ -0,0 +1,246 @@
+use std::convert::Infallible;
+
+use async_graphql::{Context, InputObject, SimpleObject};
+use axum::{extract::OptionalFromRequestParts, http::request::Parts};
+use chrono::{Duration, Utc};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::{
+    relations::model::Role,
+    token::{generate_opaque_token, hash_opaque_token},
+    DB,
+};
+
+/// API tokens start with this, so they are not mistaken for JWTs.
+pub const API_TOKEN_PREFIX: &str = "mhk_";
+const DEFAULT_EXPIRY_DAYS: i64 = 90;
+const MAX_EXPIRY_DAYS: i64 = 365;
+
+/// A personal API token. The token itself is only shown once, on creation.
+#[derive(Debug, FromRow, SimpleObject)]
+pub struct ApiToken {
+    pub id: Uuid,
+    #[graphql(skip)]
+    pub user_id: Uuid,
+    pub name: String,
+    #[graphql(skip)]
+    pub token_hash: String,
+    pub prefix: String,
+    pub read_only: bool,
+    // Limits the token to roles in one association.
+    pub association_id: Option<Uuid>,
+    // Roles the token can act with in the association. Empty for all.
+    pub roles: Vec<Role>,
+    pub expires_at: chrono::DateTime<Utc>,
+    pub last_used_at: Option<chrono::DateTime<Utc>>,
+    pub revoked_at: Option<chrono::DateTime<Utc>>,
+    pub created_at: chrono::NaiveDateTime,
+    #[graphql(skip)]
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, InputObject)]
+pub struct ApiTokenInput {
+    pub name: String,
+    #[graphql(default = true)]
+    pub read_only: bool,
+    pub association_id: Option<Uuid>,
+    pub roles: Option<Vec<Role>>,
+    // Defaults to 90 days from now, and can be at most a year away.
+    pub expires_at: Option<chrono::DateTime<Utc>>,
+}
+
+#[derive(Debug, SimpleObject)]
+pub struct CreatedApiToken {
+    pub token: String,
+    pub api_token: ApiToken,
+}
+
+/// What a request authenticated with an API token may do. Requests with a
+/// session have no scope.
+#[derive(Debug, Clone)]
+pub struct ApiTokenScope {
+    pub token_id: Uuid,
+    pub read_only: bool,
+    pub association_id: Option<Uuid>,
+    pub roles: Vec<Role>,
+}
+
+/// Optional extractor of the scope, set by the `Claims` extractor.
+impl<S> OptionalFromRequestParts<S> for ApiTokenScope
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<ApiTokenScope>().cloned())
+    }
+}
+
+impl ApiTokenScope {
+    /// Whether the token may act with the role in the association.
+    pub fn allows_role(&self, association_id: &Uuid, role: Role) -> bool {
+        match self.association_id {
+            Some(scope_association) => {
+                scope_association == *association_id
+                    && (self.roles.is_empty() || self.roles.contains(&role))
+            }
+            None => true,
+        }
+    }
+
+    /// Whether the operation to run in a GraphQL request is allowed.
+    pub fn allows_request(&self, request: &async_graphql::Request) -> bool {
+        use async_graphql::parser::types::{DocumentOperations, OperationType};
+
+        if !self.read_only {
+            return true;
+        }
+        let Ok(document) = async_graphql::parser::parse_query(&request.query) else {
+            // Invalid queries are refused by the schema.
+            return true;
+        };
+        let mutation = |ty: OperationType| ty == OperationType::Mutation;
+        match &document.operations {
+            DocumentOperations::Single(operation) => !mutation(operation.node.ty),
+            DocumentOperations::Multiple(operations) => match &request.operation_name {
+                Some(name) => operations
+                    .get(name.as_str())
+                    .is_none_or(|operation| !mutation(operation.node.ty)),
+                None => operations
+                    .values()
+                    .all(|operation| !mutation(operation.node.ty)),
+            },
+        }
+    }
+
+    /// Errors if the request was authenticated with an API token. Account
+    /// settings and credentials can only be changed with a session.
+    pub fn deny(ctx: &Context<'_>) -> Result<(), anyhow::Error> {
+        if ctx.data_opt::<ApiTokenScope>().is_some() {
+            return Err(anyhow::anyhow!("Not allowed with an API token"));
+        }
+        Ok(())
+    }
+}
+
+impl ApiToken {
+    pub fn scope(&self) -> ApiTokenScope {
+        ApiTokenScope {
+            token_id: self.id,
+            read_only: self.read_only,
+            association_id: self.association_id,
+            roles: self.roles.clone(),
+        }
+    }
+
+    /// Creates a token for the user. The token is only returned here.
+    pub async fn create(
+        db: &DB,
+        user_id: &Uuid,
+        input: ApiTokenInput,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<CreatedApiToken, anyhow::Error> {
+        let name = input.name.trim();
+        if name.is_empty() || name.chars().count() > 100 {
+            return Err(anyhow::anyhow!("Token name must have 1 to 100 characters"));
+        }
+        let expires_at = input
+            .expires_at
+            .unwrap_or(now + Duration::days(DEFAULT_EXPIRY_DAYS));
+        if expires_at <= now || expires_at > now + Duration::days(MAX_EXPIRY_DAYS) {
+            return Err(anyhow::anyhow!(
+                "Tokens must expire within {} days",
+                MAX_EXPIRY_DAYS
+            ));
+        }
+        let roles = input.roles.unwrap_or_default();
+        if input.association_id.is_none() && !roles.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Roles can only be limited in an association"
+            ));
+        }
+
+        let token = format!("{}{}", API_TOKEN_PREFIX, generate_opaque_token());
+        let api_token = sqlx::query_as!(
+            ApiToken,
+            r#"INSERT INTO "ApiToken"
+                (user_id, name, token_hash, prefix, read_only, association_id, roles, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, user_id, name, token_hash, prefix, read_only, association_id,
+                roles AS "roles: Vec<Role>", expires_at, last_used_at, revoked_at,
+                created_at, updated_at"#,
+            user_id,
+            name,
+            hash_opaque_token(&token),
+            &token[..API_TOKEN_PREFIX.len() + 8],
+            input.read_only,
+            input.association_id,
+            &roles as &[Role],
+            expires_at
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(CreatedApiToken { token, api_token })
+    }
+
+    pub async fn read_for_user(db: &DB, user_id: &Uuid) -> Result<Vec<ApiToken>, anyhow::Error> {
+        let api_tokens = sqlx::query_as!(
+            ApiToken,
+            r#"SELECT id, user_id, name, token_hash, prefix, read_only, association_id,
+                roles AS "roles: Vec<Role>", expires_at, last_used_at, revoked_at,
+                created_at, updated_at
+            FROM "ApiToken" WHERE user_id = $1 ORDER BY created_at"#,
+            user_id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(api_tokens)
+    }
+
+    pub async fn revoke(
+        db: &DB,
+        user_id: &Uuid,
+        id: &Uuid,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query!(
+            r#"UPDATE "ApiToken" SET revoked_at = $3
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL"#,
+            id,
+            user_id,
+            now
+        )
+        .execute(db)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Finds the active token of a user with an account, recording it was
+    /// used.
+    pub async fn authenticate(
+        db: &DB,
+        token: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Option<ApiToken>, anyhow::Error> {
+        let api_token = sqlx::query_as!(
+            ApiToken,
+            r#"UPDATE "ApiToken" SET last_used_at = $2
+            WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > $2
+                AND user_id IN (SELECT id FROM "User" WHERE deleted IS NOT TRUE)
+            RETURNING id, user_id, name, token_hash, prefix, read_only, association_id,
+                roles AS "roles: Vec<Role>", expires_at, last_used_at, revoked_at,
+                created_at, updated_at"#,
+            hash_opaque_token(token),
+            now
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(api_token)
+    }
+}
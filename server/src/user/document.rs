@@ -0,0 +1,170 @@
+use async_graphql::{Enum, InputObject, SimpleObject};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::DB;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Enum, sqlx::Type, Deserialize, Serialize)]
+#[sqlx(type_name = "document_kind")]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentKind {
+    // Brazilian taxpayer id.
+    Cpf,
+    // Brazilian identity card.
+    Rg,
+    // Brazilian driver's license.
+    Cnh,
+    Passport,
+}
+
+/// An identity document of a user.
+#[derive(Debug, FromRow, SimpleObject, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserDocument {
+    pub id: Uuid,
+    #[graphql(skip)]
+    pub user_id: Uuid,
+    pub kind: DocumentKind,
+    pub number: String,
+    pub country: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, InputObject)]
+pub struct DocumentInput {
+    pub kind: DocumentKind,
+    pub number: String,
+    // Issuing country. Brazilian documents are always from BR.
+    pub country: Option<String>,
+}
+
+/// Checks the two check digits of a CPF, given as 11 digits.
+pub fn is_valid_cpf(cpf: &str) -> bool {
+    let digits: Vec<u32> = cpf.chars().filter_map(|c| c.to_digit(10)).collect();
+    if digits.len() != 11 || cpf.len() != 11 || digits.iter().all(|d| *d == digits[0]) {
+        return false;
+    }
+    let check_digit = |len: usize| {
+        let sum: u32 = digits[..len]
+            .iter()
+            .zip((2..=len as u32 + 1).rev())
+            .map(|(digit, weight)| digit * weight)
+            .sum();
+        match sum % 11 {
+            0 | 1 => 0,
+            rest => 11 - rest,
+        }
+    };
+    check_digit(9) == digits[9] && check_digit(10) == digits[10]
+}
+
+impl DocumentInput {
+    /// Validates the number, returning the normalized number and country.
+    pub fn normalize(&self) -> Result<(String, String), anyhow::Error> {
+        let country = match self.kind {
+            DocumentKind::Cpf | DocumentKind::Rg | DocumentKind::Cnh => "BR".to_owned(),
+            DocumentKind::Passport => self
+                .country
+                .as_deref()
+                .map(|country| country.trim().to_ascii_uppercase())
+                .filter(|country| {
+                    country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic())
+                })
+                .ok_or(anyhow::anyhow!("Passports need the issuing country code"))?,
+        };
+        // Punctuation like "123.456.789-09" is dropped.
+        let number: String = self
+            .number
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let valid = match self.kind {
+            DocumentKind::Cpf => is_valid_cpf(&number),
+            DocumentKind::Cnh => number.len() == 11 && number.chars().all(|c| c.is_ascii_digit()),
+            DocumentKind::Rg => (5..=14).contains(&number.len()),
+            DocumentKind::Passport => (6..=9).contains(&number.len()),
+        };
+        if !valid {
+            let name = match self.kind {
+                DocumentKind::Cpf => "CPF",
+                DocumentKind::Rg => "RG",
+                DocumentKind::Cnh => "CNH",
+                DocumentKind::Passport => "passport",
+            };
+            return Err(anyhow::anyhow!("Invalid {} number", name));
+        }
+        Ok((number, country))
+    }
+}
+
+impl UserDocument {
+    pub async fn read_for_user(
+        db: &DB,
+        user_id: &Uuid,
+    ) -> Result<Vec<UserDocument>, anyhow::Error> {
+        let documents = sqlx::query_as!(
+            UserDocument,
+            r#"SELECT id, user_id, kind AS "kind: DocumentKind", number, country, created_at, updated_at
+            FROM "UserDocument" WHERE user_id = $1 ORDER BY created_at"#,
+            user_id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(documents)
+    }
+
+    /// Adds a document, replacing the user's one of the same kind and
+    /// country. Documents of other users cannot be added.
+    pub async fn set(
+        db: &DB,
+        user_id: &Uuid,
+        input: DocumentInput,
+    ) -> Result<UserDocument, anyhow::Error> {
+        let (number, country) = input.normalize()?;
+        let taken = sqlx::query_scalar!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM "UserDocument"
+                WHERE kind = $1 AND country = $2 AND number = $3 AND user_id <> $4
+            ) AS "taken!""#,
+            input.kind as DocumentKind,
+            country,
+            number,
+            user_id
+        )
+        .fetch_one(db)
+        .await?;
+        if taken {
+            return Err(anyhow::anyhow!("This document belongs to another user"));
+        }
+        let document = sqlx::query_as!(
+            UserDocument,
+            r#"INSERT INTO "UserDocument" (user_id, kind, number, country)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, kind, country) DO UPDATE SET number = EXCLUDED.number
+            RETURNING id, user_id, kind AS "kind: DocumentKind", number, country, created_at,
+                updated_at"#,
+            user_id,
+            input.kind as DocumentKind,
+            number,
+            country
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(document)
+    }
+
+    pub async fn remove(db: &DB, user_id: &Uuid, id: &Uuid) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query!(
+            r#"DELETE FROM "UserDocument" WHERE id = $1 AND user_id = $2"#,
+            id,
+            user_id
+        )
+        .execute(db)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+}
@@ -1,20 +1,41 @@
-use async_graphql::{Context, FieldResult, Object, SimpleObject};
+use std::sync::Arc;
+
+use async_graphql::{Context, FieldResult, Json, Object, SimpleObject};
 use uuid::Uuid;
 
 use crate::{
+    config::Config,
     oauth::get_token,
     relations::model::{Relations, Role},
+    session::model::Session,
     token::Claims,
-    DB,
+    Clock, DB,
 };
 
-use super::model::{User, UserInput, UserUpdate};
+use super::{
+    address::{AddressInput, UserAddress},
+    api_token::{ApiToken, ApiTokenInput, ApiTokenScope, CreatedApiToken},
+    document::{DocumentInput, UserDocument},
+    identity::{LoginMethods, UserIdentity},
+    model::{RegistrationInput, User, UserInput, UserUpdate},
+    personal_data::DataExport,
+    registration::{EmailVerification, Registration},
+    two_factor::{TwoFactor, TwoFactorEnrollment},
+};
 
 #[derive(SimpleObject)]
 struct AuthPayload {
     token: String,
 }
 
+/// The new user, logged in with a session.
+#[derive(SimpleObject)]
+struct RegistrationPayload {
+    user: User,
+    token: String,
+    refresh_token: String,
+}
+
 #[derive(Default)]
 pub struct UserQuery;
 
@@ -32,21 +53,70 @@ impl UserQuery {
             let user = User::read_one(pool, &id).await?;
             Ok(user)
         } else {
-            return Err(
-                anyhow::Error::msg("User cannot know information about other users").into(),
-            );
+            Err(anyhow::Error::msg("User cannot know information about other users").into())
         }
     }
 
+    /// What is known about someone with a registration token, to prefill
+    /// their account.
+    async fn pending_registration(&self, ctx: &Context<'_>) -> FieldResult<Registration> {
+        let registration = ctx
+            .data_opt::<Registration>()
+            .ok_or(anyhow::Error::msg("No registration in progress"))?;
+        Ok(registration.clone())
+    }
+
+    /// The password and linked accounts the user can log in with.
+    async fn login_methods(&self, ctx: &Context<'_>) -> FieldResult<LoginMethods> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let user = User::read_one(pool, &user_id).await?;
+        Ok(LoginMethods {
+            password: user.password_hash.is_some(),
+            identities: UserIdentity::read_for_user(pool, &user_id).await?,
+            two_factor: TwoFactor::is_enabled(pool, &user_id).await?,
+        })
+    }
+
+    /// The user's personal API tokens, including expired and revoked ones.
+    async fn api_tokens(&self, ctx: &Context<'_>) -> FieldResult<Vec<ApiToken>> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let api_tokens = ApiToken::read_for_user(pool, &user_id).await?;
+        Ok(api_tokens)
+    }
+
+    /// Everything kept about the user: profile, memberships, reservations
+    /// and transactions they created. Also downloadable at /me/export.json.
+    async fn export_my_data(&self, ctx: &Context<'_>) -> FieldResult<Json<DataExport>> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let export = DataExport::collect(pool, &user_id, now).await?;
+        Ok(Json(export))
+    }
+
     async fn renew_token(&self, ctx: &Context<'_>) -> FieldResult<AuthPayload> {
         let claims = ctx.data::<Claims>()?;
         let user_id = &claims
             .sub
             .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
         let pool = ctx.data::<DB>().expect("DB pool not found");
-        let user = User::read_one(pool, &user_id).await?;
+        let user = User::read_one(pool, user_id).await?;
 
-        let token = get_token(Some(*user_id), user.email)
+        let config = ctx.data::<Config>()?;
+        let token = get_token(config, Some(*user_id), user.email, claims.sid)
             .map_err(|_| anyhow::Error::msg("Token creation failed"))?;
 
         Ok(AuthPayload { token })
@@ -59,16 +129,290 @@ pub struct UserMutation;
 #[Object(extends)]
 impl UserMutation {
     // Mutate user.
-    async fn create_own_user(&self, ctx: &Context<'_>, user_input: UserInput) -> FieldResult<User> {
-        let claims = ctx.data::<Claims>()?;
-        let user_email = &claims.email;
-        if &user_input.email != user_email {
-            return Err(anyhow::Error::msg("Unauthorized, please log in").into());
+    /// Creates the account of someone with a registration token, from an
+    /// OAuth login or an email/password registration, and logs them in.
+    async fn complete_registration(
+        &self,
+        ctx: &Context<'_>,
+        user_input: RegistrationInput,
+    ) -> FieldResult<RegistrationPayload> {
+        let registration = ctx
+            .data_opt::<Registration>()
+            .ok_or(anyhow::Error::msg("No registration in progress"))?;
+        let email = registration.email.clone().ok_or(anyhow::Error::msg(
+            "A verified email is required to register",
+        ))?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        // Passwords are only set through a verified registration.
+        let password_hash = EmailVerification::read_verified(pool, &email)
+            .await?
+            .map(|verification| verification.password_hash);
+        let user_input = UserInput {
+            password_hash,
+            name: user_input.name.or(registration.name.clone()),
+            email: Some(email.clone()),
+            profile_url: user_input.profile_url.or(registration.picture_url.clone()),
+            birthday: user_input.birthday,
+            address: user_input.address,
+            activity: user_input.activity,
+            personal_phone: user_input.personal_phone,
+            commercial_phone: user_input.commercial_phone,
+            uses_whatsapp: user_input.uses_whatsapp,
+            identities: user_input.identities,
+        };
+        // The user is only kept once their OAuth account is linked too.
+        let mut tx = pool.begin().await?;
+        let user = User::insert(&mut tx, user_input).await?;
+        EmailVerification::delete_for_email(&mut tx, &email).await?;
+        if let (Some(provider), Some(subject)) = (&registration.provider, &registration.subject) {
+            UserIdentity::link(&mut tx, &user.id, provider, subject, Some(&email), now).await?;
         }
+        tx.commit().await?;
+
+        let config = ctx.data::<Config>()?;
+        let (session, refresh_token) = Session::create(pool, &user.id, now).await?;
+        let token = get_token(config, Some(user.id), user.email.clone(), Some(session.id))
+            .map_err(|_| anyhow::Error::msg("Token creation failed"))?;
+        Ok(RegistrationPayload {
+            user,
+            token,
+            refresh_token,
+        })
+    }
+
+    /// Changes the password, logging out all other sessions.
+    async fn change_password(
+        &self,
+        ctx: &Context<'_>,
+        old_password: String,
+        new_password: String,
+    ) -> FieldResult<bool> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
 
         let pool = ctx.data::<DB>().expect("DB pool not found");
-        let user = User::create(pool, user_input).await?;
-        Ok(user)
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        User::change_password(
+            pool,
+            &user_id,
+            &old_password,
+            &new_password,
+            claims.sid,
+            now,
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Adds a password to an account that has none, e.g. created through
+    /// OAuth.
+    async fn set_password(&self, ctx: &Context<'_>, password: String) -> FieldResult<bool> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        User::set_password(pool, &user_id, &password).await?;
+        Ok(true)
+    }
+
+    /// Unlinks an OAuth account. The last login method cannot be removed.
+    async fn unlink_identity(&self, ctx: &Context<'_>, id: Uuid) -> FieldResult<bool> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        UserIdentity::unlink(pool, &user_id, &id).await?;
+        Ok(true)
+    }
+
+    /// Removes the password, leaving only linked accounts to log in with.
+    async fn remove_password(&self, ctx: &Context<'_>) -> FieldResult<bool> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        UserIdentity::remove_password(pool, &user_id).await?;
+        Ok(true)
+    }
+
+    /// Starts enabling two-factor authentication. The secret is added to an
+    /// authenticator app, then confirmed with `confirmTwoFactor`.
+    async fn enroll_two_factor(&self, ctx: &Context<'_>) -> FieldResult<TwoFactorEnrollment> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let user = User::read_one(pool, &user_id).await?;
+        let account = user.email.unwrap_or_else(|| user_id.to_string());
+        let enrollment = TwoFactor::enroll(pool, &user_id, &account).await?;
+        Ok(enrollment)
+    }
+
+    /// Enables two-factor authentication with a code from the authenticator
+    /// app, returning the recovery codes.
+    async fn confirm_two_factor(
+        &self,
+        ctx: &Context<'_>,
+        code: String,
+    ) -> FieldResult<Vec<String>> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let recovery_codes = TwoFactor::confirm(pool, &user_id, &code, claims.sid, now).await?;
+        Ok(recovery_codes)
+    }
+
+    async fn disable_two_factor(&self, ctx: &Context<'_>, code: String) -> FieldResult<bool> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        TwoFactor::disable(pool, &user_id, &code, now).await?;
+        Ok(true)
+    }
+
+    /// Replaces the recovery codes, e.g. after using some of them.
+    async fn regenerate_recovery_codes(
+        &self,
+        ctx: &Context<'_>,
+        code: String,
+    ) -> FieldResult<Vec<String>> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let recovery_codes =
+            TwoFactor::regenerate_recovery_codes(pool, &user_id, &code, now).await?;
+        Ok(recovery_codes)
+    }
+
+    /// Creates a personal API token for scripts and integrations. The token
+    /// is only returned here.
+    async fn create_api_token(
+        &self,
+        ctx: &Context<'_>,
+        input: ApiTokenInput,
+    ) -> FieldResult<CreatedApiToken> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let created = ApiToken::create(pool, &user_id, input, now).await?;
+        Ok(created)
+    }
+
+    async fn revoke_api_token(&self, ctx: &Context<'_>, id: Uuid) -> FieldResult<bool> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        let revoked = ApiToken::revoke(pool, &user_id, &id, now).await?;
+        Ok(revoked)
+    }
+
+    /// Adds an identity document, replacing the one of the same kind and
+    /// country. CPFs are checked against their check digits.
+    async fn set_document(
+        &self,
+        ctx: &Context<'_>,
+        input: DocumentInput,
+    ) -> FieldResult<UserDocument> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let document = UserDocument::set(pool, &user_id, input).await?;
+        Ok(document)
+    }
+
+    async fn remove_document(&self, ctx: &Context<'_>, id: Uuid) -> FieldResult<bool> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let removed = UserDocument::remove(pool, &user_id, &id).await?;
+        Ok(removed)
+    }
+
+    /// Sets the user's home address, which associations use to check they
+    /// live in the neighborhood.
+    async fn set_address(
+        &self,
+        ctx: &Context<'_>,
+        input: AddressInput,
+    ) -> FieldResult<UserAddress> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let address = UserAddress::set(pool, &user_id, input).await?;
+        Ok(address)
+    }
+
+    /// Deletes the account and anonymizes the user's personal data. Users
+    /// with a password must confirm it. Financial records are kept.
+    async fn request_account_deletion(
+        &self,
+        ctx: &Context<'_>,
+        password: Option<String>,
+    ) -> FieldResult<bool> {
+        let claims = ctx.data::<Claims>()?;
+        let user_id = claims
+            .sub
+            .ok_or(anyhow::Error::msg("Unauthorized, please log in"))?;
+        ApiTokenScope::deny(ctx)?;
+
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let now = ctx.data::<Arc<dyn Clock>>()?.now();
+        User::delete_account(pool, &user_id, password.as_deref(), now).await?;
+        Ok(true)
     }
 
     async fn toggle_pending_user(
@@ -91,7 +435,7 @@ impl UserMutation {
                 None => user_id,
             };
 
-            let toggle_user = User::toggle_approve(pool, &user_to_toggle, &association_id).await?;
+            let toggle_user = User::toggle_approve(pool, user_to_toggle, &association_id).await?;
             Ok(toggle_user)
         } else {
             Err(anyhow::Error::msg("Unauthorized, please log in").into())
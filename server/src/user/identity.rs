@@ -0,0 +1,168 @@
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::DB;
+
+/// A login method from an OAuth provider, linked to a user.
+#[derive(Debug, FromRow, SimpleObject)]
+pub struct UserIdentity {
+    pub id: Uuid,
+    #[graphql(skip)]
+    pub user_id: Uuid,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub linked_at: chrono::DateTime<Utc>,
+    #[graphql(skip)]
+    pub created_at: chrono::NaiveDateTime,
+    #[graphql(skip)]
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+/// The ways a user can log in.
+#[derive(Debug, SimpleObject)]
+pub struct LoginMethods {
+    pub password: bool,
+    pub identities: Vec<UserIdentity>,
+    pub two_factor: bool,
+}
+
+impl UserIdentity {
+    pub async fn find(
+        db: &DB,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<UserIdentity>, anyhow::Error> {
+        let identity = sqlx::query_as!(
+            UserIdentity,
+            r#"SELECT * FROM "UserIdentity" WHERE provider = $1 AND subject = $2"#,
+            provider,
+            subject
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(identity)
+    }
+
+    pub async fn read_for_user(
+        db: &DB,
+        user_id: &Uuid,
+    ) -> Result<Vec<UserIdentity>, anyhow::Error> {
+        let identities = sqlx::query_as!(
+            UserIdentity,
+            r#"SELECT * FROM "UserIdentity" WHERE user_id = $1 ORDER BY linked_at"#,
+            user_id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(identities)
+    }
+
+    /// Links a provider account to the user. An account can only be linked
+    /// to one user.
+    pub async fn link(
+        conn: &mut sqlx::PgConnection,
+        user_id: &Uuid,
+        provider: &str,
+        subject: &str,
+        email: Option<&str>,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<UserIdentity, anyhow::Error> {
+        let identity = sqlx::query_as!(
+            UserIdentity,
+            r#"INSERT INTO "UserIdentity" (user_id, provider, subject, email, linked_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (provider, subject) DO NOTHING
+            RETURNING *"#,
+            user_id,
+            provider,
+            subject,
+            email,
+            now
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        match identity {
+            Some(identity) => Ok(identity),
+            None => {
+                let existing = sqlx::query_as!(
+                    UserIdentity,
+                    r#"SELECT * FROM "UserIdentity" WHERE provider = $1 AND subject = $2"#,
+                    provider,
+                    subject
+                )
+                .fetch_optional(conn)
+                .await?
+                .ok_or(anyhow::anyhow!("Identity could not be linked"))?;
+                if &existing.user_id != user_id {
+                    return Err(anyhow::anyhow!("This account is linked to another user"));
+                }
+                Ok(existing)
+            }
+        }
+    }
+
+    /// Counts the user's login methods, locking the user so concurrent
+    /// unlinks cannot remove the last one.
+    async fn lock_login_methods(
+        tx: &mut sqlx::PgConnection,
+        user_id: &Uuid,
+    ) -> Result<i64, anyhow::Error> {
+        let has_password = sqlx::query_scalar!(
+            r#"SELECT password_hash IS NOT NULL AS "has_password!" FROM "User"
+            WHERE id = $1 FOR UPDATE"#,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let identities = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM "UserIdentity" WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        Ok(identities + has_password as i64)
+    }
+
+    /// Unlinks an identity of the user, unless it is their last login method.
+    pub async fn unlink(db: &DB, user_id: &Uuid, id: &Uuid) -> Result<(), anyhow::Error> {
+        let mut tx = db.begin().await?;
+        if UserIdentity::lock_login_methods(&mut tx, user_id).await? <= 1 {
+            return Err(anyhow::anyhow!("Cannot remove the last login method"));
+        }
+        let result = sqlx::query!(
+            r#"DELETE FROM "UserIdentity" WHERE id = $1 AND user_id = $2"#,
+            id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("Identity not found"));
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Removes the user's password, unless it is their last login method.
+    pub async fn remove_password(db: &DB, user_id: &Uuid) -> Result<(), anyhow::Error> {
+        let mut tx = db.begin().await?;
+        if UserIdentity::lock_login_methods(&mut tx, user_id).await? <= 1 {
+            return Err(anyhow::anyhow!("Cannot remove the last login method"));
+        }
+        let result = sqlx::query!(
+            r#"UPDATE "User" SET password_hash = NULL
+            WHERE id = $1 AND password_hash IS NOT NULL"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("User has no password"));
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
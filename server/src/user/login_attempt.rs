@@ -0,0 +1,121 @@
+use std::net::IpAddr;
+
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::{config::Config, DB};
+
+/// Why a password login failed, as kept in the audit trail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginFailure {
+    UnknownUser,
+    NoPassword,
+    WrongPassword,
+    // Wrong TOTP or recovery code, after a correct password.
+    WrongCode,
+    // Refused without checking the password. Not counted as a failure.
+    Throttled,
+}
+
+impl LoginFailure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginFailure::UnknownUser => "unknown_user",
+            LoginFailure::NoPassword => "no_password",
+            LoginFailure::WrongPassword => "wrong_password",
+            LoginFailure::WrongCode => "wrong_code",
+            LoginFailure::Throttled => "throttled",
+        }
+    }
+}
+
+pub struct LoginAttempt;
+
+impl LoginAttempt {
+    pub async fn record(
+        db: &DB,
+        email: &str,
+        user_id: Option<&Uuid>,
+        ip: Option<IpAddr>,
+        failure: Option<LoginFailure>,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"INSERT INTO "LoginAttempt" (email, user_id, ip, succeeded, reason, attempted_at)
+            VALUES ($1, $2, $3, $4, $5, $6)"#,
+            email,
+            user_id,
+            ip.map(|ip| ip.to_string()),
+            failure.is_none(),
+            failure.map(|failure| failure.as_str()),
+            now
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// How long a login for the email, from the IP, must wait. A single
+    /// failure is let through, so typos are not punished; each further one
+    /// since the last successful login doubles the wait, up to the lockout
+    /// after `login_max_attempts`. IPs are locked out after
+    /// `login_ip_max_attempts` failures on any emails.
+    pub async fn retry_after(
+        db: &DB,
+        config: &Config,
+        email: &str,
+        ip: Option<IpAddr>,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Option<Duration>, anyhow::Error> {
+        let window_start = now - config.login_lockout;
+        let email_failures = sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!", MAX(attempted_at) AS last_failure
+            FROM "LoginAttempt"
+            WHERE email = $1 AND NOT succeeded AND reason <> 'throttled'
+                AND attempted_at > $2
+                AND attempted_at > COALESCE(
+                    (SELECT MAX(attempted_at) FROM "LoginAttempt" WHERE email = $1 AND succeeded),
+                    '-infinity'
+                )"#,
+            email,
+            window_start
+        )
+        .fetch_one(db)
+        .await?;
+
+        let mut allowed_at = None;
+        if let Some(last_failure) = email_failures.last_failure {
+            let count = email_failures.count;
+            let wait = if count >= config.login_max_attempts {
+                config.login_lockout
+            } else if count > 1 {
+                (config.login_backoff * 2_i32.pow(count.min(30) as u32 - 2))
+                    .min(config.login_lockout)
+            } else {
+                Duration::zero()
+            };
+            allowed_at = Some(last_failure + wait);
+        }
+
+        if let Some(ip) = ip {
+            let ip_failures = sqlx::query!(
+                r#"SELECT COUNT(*) AS "count!", MAX(attempted_at) AS last_failure
+                FROM "LoginAttempt"
+                WHERE ip = $1 AND NOT succeeded AND reason <> 'throttled' AND attempted_at > $2"#,
+                ip.to_string(),
+                window_start
+            )
+            .fetch_one(db)
+            .await?;
+            if let Some(last_failure) = ip_failures.last_failure {
+                if ip_failures.count >= config.login_ip_max_attempts {
+                    allowed_at = allowed_at.max(Some(last_failure + config.login_lockout));
+                }
+            }
+        }
+
+        Ok(allowed_at
+            .map(|allowed_at| allowed_at - now)
+            .filter(|wait| *wait > Duration::zero()))
+    }
+}
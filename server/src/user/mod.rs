@@ -1,2 +1,13 @@
+pub mod address;
+pub mod api_token;
+pub mod document;
 pub mod graphql;
+pub mod identity;
+pub mod login_attempt;
 pub mod model;
+pub mod oauth_code;
+pub mod password;
+pub mod personal_data;
+pub mod phone;
+pub mod registration;
+pub mod two_factor;
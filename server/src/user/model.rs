@@ -1,19 +1,23 @@
 use async_graphql::{Context, InputObject, Object};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{FromRow, PgConnection};
 use uuid::Uuid;
 
 use crate::{
     association::model::Association,
     relations::model::{Relations, Role},
+    token::Claims,
     DB,
 };
 
+use super::{address::UserAddress, document::UserDocument, phone::normalize_phone};
+
 #[derive(Debug, FromRow, Deserialize, Serialize, Clone, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
     pub id: Uuid,
     pub name: String,
+    #[serde(skip_serializing)]
     pub password_hash: Option<String>,
     pub birthday: chrono::NaiveDate,
     pub address: String,
@@ -27,6 +31,8 @@ pub struct User {
     pub deleted: Option<bool>,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    // Set when the account was deleted and its personal data anonymized.
+    pub deleted_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Debug, InputObject, Deserialize)]
@@ -45,6 +51,22 @@ pub struct UserInput {
     pub identities: Option<String>,
 }
 
+/// The account of someone with a registration token. The email, password
+/// and linked account come from the registration.
+#[derive(Debug, InputObject, Deserialize)]
+pub struct RegistrationInput {
+    // Default to the profile from the OAuth provider.
+    pub name: Option<String>,
+    pub profile_url: Option<String>,
+    pub birthday: chrono::NaiveDate,
+    pub address: String,
+    pub activity: Option<String>,
+    pub personal_phone: Option<String>,
+    pub commercial_phone: Option<String>,
+    pub uses_whatsapp: bool,
+    pub identities: Option<String>,
+}
+
 #[derive(InputObject)]
 pub struct UserUpdate {
     pub id: Uuid,
@@ -57,7 +79,6 @@ pub struct UserUpdate {
     pub uses_whatsapp: bool,
     pub identities: Option<String>,
     pub profile_url: Option<String>,
-    pub deleted: Option<bool>,
 }
 
 #[Object]
@@ -98,6 +119,7 @@ impl User {
         self.uses_whatsapp
     }
 
+    #[graphql(deprecation = "Use documents")]
     pub async fn identities(&self) -> Option<String> {
         self.identities.to_owned()
     }
@@ -114,6 +136,46 @@ impl User {
         self.updated_at
     }
 
+    /// Identity documents. Only visible to the user and admins of their
+    /// associations.
+    pub async fn documents(&self, ctx: &Context<'_>) -> Result<Vec<UserDocument>, anyhow::Error> {
+        if !self.is_visible_to(ctx).await? {
+            return Ok(vec![]);
+        }
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let documents = UserDocument::read_for_user(pool, &self.id).await?;
+        Ok(documents)
+    }
+
+    /// Structured home address. Only visible to the user and admins of
+    /// their associations.
+    pub async fn postal_address(
+        &self,
+        ctx: &Context<'_>,
+    ) -> Result<Option<UserAddress>, anyhow::Error> {
+        if !self.is_visible_to(ctx).await? {
+            return Ok(None);
+        }
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let address = UserAddress::read(pool, &self.id).await?;
+        Ok(address)
+    }
+
+    /// Whether the user's address is in the association's neighborhood.
+    /// Unknown without an address.
+    pub async fn lives_in_neighborhood(
+        &self,
+        ctx: &Context<'_>,
+        association_id: Uuid,
+    ) -> Result<Option<bool>, anyhow::Error> {
+        let Some(address) = self.postal_address(ctx).await? else {
+            return Ok(None);
+        };
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let association = Association::read_one(pool, &association_id).await?;
+        Ok(Some(address.in_neighborhood(&association)))
+    }
+
     pub async fn associations(&self, ctx: &Context<'_>) -> Result<Vec<Association>, anyhow::Error> {
         let pool = ctx.data::<DB>().unwrap();
         let associations = sqlx::query_as!(
@@ -122,26 +184,34 @@ impl User {
         INNER JOIN "AssociationRoles" ar ON a.id = ar.association_id WHERE ar.user_id = $1 AND ar.role = 'member'"#,
             self.id
         )
-        .fetch_all(&*pool)
+        .fetch_all(pool)
         .await?;
         Ok(associations)
     }
 
-    pub async fn is_admin(
+    /// Whether the user holds the admin role, even if acting as admin needs
+    /// a session with a second factor.
+    #[graphql(name = "isAdmin")]
+    async fn has_admin_role(
         &self,
         ctx: &Context<'_>,
         association_id: Uuid,
     ) -> Result<bool, anyhow::Error> {
-        let role = Relations::get_role(ctx, &self.id, association_id, Role::Admin).await?;
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let role = Relations::read_role(pool, &self.id, association_id, Role::Admin).await?;
         Ok(role.is_some())
     }
 
-    pub async fn is_treasurer(
+    /// Whether the user holds the treasurer role, even if acting as treasurer
+    /// needs a session with a second factor.
+    #[graphql(name = "isTreasurer")]
+    async fn has_treasurer_role(
         &self,
         ctx: &Context<'_>,
         association_id: Uuid,
     ) -> Result<bool, anyhow::Error> {
-        let role = Relations::get_role(ctx, &self.id, association_id, Role::Treasurer).await?;
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let role = Relations::read_role(pool, &self.id, association_id, Role::Treasurer).await?;
         Ok(role.is_some())
     }
 
@@ -157,8 +227,84 @@ impl User {
 }
 
 impl User {
+    /// Whether the user may act as admin of the association, with the second
+    /// factor and API token scope that requires.
+    pub async fn is_admin(
+        &self,
+        ctx: &Context<'_>,
+        association_id: Uuid,
+    ) -> Result<bool, anyhow::Error> {
+        let role = Relations::get_role(ctx, &self.id, association_id, Role::Admin).await?;
+        Ok(role.is_some())
+    }
+
+    /// Whether the user may act as treasurer of the association, see
+    /// `is_admin`.
+    pub async fn is_treasurer(
+        &self,
+        ctx: &Context<'_>,
+        association_id: Uuid,
+    ) -> Result<bool, anyhow::Error> {
+        let role = Relations::get_role(ctx, &self.id, association_id, Role::Treasurer).await?;
+        Ok(role.is_some())
+    }
+
+    /// Whether the logged in user may see the private data of this user:
+    /// their own, or as admin of one of the user's associations.
+    async fn is_visible_to(&self, ctx: &Context<'_>) -> Result<bool, anyhow::Error> {
+        let Some(viewer_id) = ctx.data_opt::<Claims>().and_then(|claims| claims.sub) else {
+            return Ok(false);
+        };
+        if viewer_id == self.id {
+            return Ok(true);
+        }
+        let pool = ctx.data::<DB>().expect("DB pool not found");
+        let visible = sqlx::query_scalar!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM "AssociationRoles" admin
+                INNER JOIN "AssociationRoles" member
+                    ON member.association_id = admin.association_id
+                WHERE admin.user_id = $1 AND admin.role = 'admin' AND member.user_id = $2
+            ) AS "visible!""#,
+            viewer_id,
+            self.id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(visible)
+    }
+
     pub async fn create(db: &DB, user: UserInput) -> Result<User, anyhow::Error> {
         let mut tx = db.begin().await?;
+        let user = User::insert(&mut tx, user).await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    /// Inserts the user on the given connection, so it can be part of a
+    /// larger transaction. Emails already in use are refused.
+    pub async fn insert(conn: &mut PgConnection, user: UserInput) -> Result<User, anyhow::Error> {
+        let personal_phone = user
+            .personal_phone
+            .as_deref()
+            .map(normalize_phone)
+            .transpose()?;
+        let commercial_phone = user
+            .commercial_phone
+            .as_deref()
+            .map(normalize_phone)
+            .transpose()?;
+        if let Some(email) = &user.email {
+            let taken = sqlx::query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM "User" WHERE email = $1) AS "taken!""#,
+                email
+            )
+            .fetch_one(&mut *conn)
+            .await?;
+            if taken {
+                return Err(anyhow::anyhow!("User already exists"));
+            }
+        }
 
         let user = sqlx::query_as!(
             User,
@@ -172,36 +318,34 @@ impl User {
             user.address,
             user.activity,
             user.email,
-            user.personal_phone,
-            user.commercial_phone,
+            personal_phone,
+            commercial_phone,
             user.uses_whatsapp,
             user.identities,
             user.profile_url,
         )
-        .fetch_one(&mut *tx)
+        .fetch_one(conn)
         .await?;
-        tx.commit().await?;
-
         Ok(user)
     }
 
     pub async fn read_one(db: &DB, id: &Uuid) -> Result<User, anyhow::Error> {
         let user = sqlx::query_as!(User, r#"SELECT * FROM "User" WHERE id = $1"#, id)
-            .fetch_one(&*db)
+            .fetch_one(db)
             .await?;
         Ok(user)
     }
 
     pub async fn read_one_by_email(db: &DB, email: &str) -> Result<Option<User>, anyhow::Error> {
         let user = sqlx::query_as!(User, r#"SELECT * FROM "User" WHERE email = $1"#, email)
-            .fetch_optional(&*db)
+            .fetch_optional(db)
             .await?;
         Ok(user)
     }
 
     pub async fn read_all(db: &DB) -> Result<Vec<User>, anyhow::Error> {
         let users = sqlx::query_as!(User, r#"SELECT * FROM "User""#)
-            .fetch_all(&*db)
+            .fetch_all(db)
             .await?;
         Ok(users)
     }
@@ -229,6 +373,16 @@ impl User {
     }
 
     pub async fn update(db: &DB, user: UserUpdate) -> Result<User, anyhow::Error> {
+        let personal_phone = user
+            .personal_phone
+            .as_deref()
+            .map(normalize_phone)
+            .transpose()?;
+        let commercial_phone = user
+            .commercial_phone
+            .as_deref()
+            .map(normalize_phone)
+            .transpose()?;
         let mut tx: sqlx::Transaction<'_, sqlx::Postgres> = db.begin().await?;
         let new_user = sqlx::query_as!(
             User,
@@ -242,21 +396,19 @@ impl User {
                 commercial_phone = COALESCE($6, commercial_phone),
                 uses_whatsapp = COALESCE($7, uses_whatsapp),
                 identities = COALESCE($8, identities),
-                profile_url = COALESCE($9, profile_url),
-                deleted = COALESCE($10, deleted)
-            WHERE id = $11
+                profile_url = COALESCE($9, profile_url)
+            WHERE id = $10
             RETURNING *
             "#,
             user.name,
             user.birthday,
             user.address,
             user.activity,
-            user.personal_phone,
-            user.commercial_phone,
+            personal_phone,
+            commercial_phone,
             user.uses_whatsapp,
             user.identities,
             user.profile_url,
-            user.deleted,
             user.id
         )
         .fetch_one(&mut *tx)
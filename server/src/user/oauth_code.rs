@@ -0,0 +1,83 @@
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::{
+    token::{generate_opaque_token, hash_opaque_token},
+    user::registration::Registration,
+    DB,
+};
+
+/// How long the web app has to exchange the code after the redirect.
+const CODE_SECONDS: i64 = 60;
+
+/// Outcome of an OAuth login, handed to the web app as a single-use code
+/// instead of putting tokens in the redirect URL.
+#[derive(Debug)]
+pub struct OAuthCode {
+    pub user_id: Option<Uuid>,
+    pub email: Option<String>,
+    // What the provider told about new users.
+    pub provider: Option<String>,
+    pub subject: Option<String>,
+    pub name: Option<String>,
+    pub picture_url: Option<String>,
+}
+
+impl OAuthCode {
+    /// The code is only returned here, to be added to the redirect. New
+    /// users have no id, and the registration to prefill their account.
+    pub async fn create(
+        db: &DB,
+        user_id: Option<&Uuid>,
+        registration: &Registration,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<String, anyhow::Error> {
+        let code = generate_opaque_token();
+        sqlx::query!(
+            r#"INSERT INTO "OAuthCode"
+                (code_hash, user_id, email, provider, subject, name, picture_url, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"#,
+            hash_opaque_token(&code),
+            user_id,
+            registration.email,
+            registration.provider,
+            registration.subject,
+            registration.name,
+            registration.picture_url,
+            now + Duration::seconds(CODE_SECONDS)
+        )
+        .execute(db)
+        .await?;
+        Ok(code)
+    }
+
+    /// Uses up a code, which cannot be exchanged again.
+    pub async fn exchange(
+        db: &DB,
+        code: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<OAuthCode, anyhow::Error> {
+        let code = sqlx::query_as!(
+            OAuthCode,
+            r#"UPDATE "OAuthCode" SET used_at = $2
+            WHERE code_hash = $1 AND used_at IS NULL AND expires_at > $2
+            RETURNING user_id, email, provider, subject, name, picture_url"#,
+            hash_opaque_token(code),
+            now
+        )
+        .fetch_optional(db)
+        .await?
+        .ok_or(anyhow::anyhow!("Invalid or expired code"))?;
+        Ok(code)
+    }
+
+    pub fn registration(self) -> Registration {
+        Registration {
+            email: self.email,
+            provider: self.provider,
+            subject: self.subject,
+            name: self.name,
+            picture_url: self.picture_url,
+        }
+    }
+}
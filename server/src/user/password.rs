@@ -0,0 +1,144 @@
+use chrono::{Duration, Utc};
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::{
+    session::model::Session,
+    token::{generate_opaque_token, hash_opaque_token},
+    DB,
+};
+
+use super::{model::User, registration::check_password};
+
+/// How long a reset link stays valid.
+const RESET_MINUTES: i64 = 60;
+
+pub fn hash_password(password: &str) -> Result<String, anyhow::Error> {
+    check_password(password)?;
+    Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?)
+}
+
+pub struct PasswordReset;
+
+impl PasswordReset {
+    /// Creates a reset token for the user, invalidating earlier unused ones.
+    /// The token is only returned here, to be emailed.
+    pub async fn create(
+        db: &DB,
+        user_id: &Uuid,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<String, anyhow::Error> {
+        let token = generate_opaque_token();
+        let mut tx = db.begin().await?;
+        sqlx::query!(
+            r#"UPDATE "PasswordReset" SET used_at = $2 WHERE user_id = $1 AND used_at IS NULL"#,
+            user_id,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"INSERT INTO "PasswordReset" (user_id, token_hash, expires_at) VALUES ($1, $2, $3)"#,
+            user_id,
+            hash_opaque_token(&token),
+            now + Duration::minutes(RESET_MINUTES)
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    /// Sets a new password with a reset token and logs the user out of all
+    /// sessions.
+    pub async fn reset(
+        db: &DB,
+        token: &str,
+        password: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        let password_hash = hash_password(password)?;
+        let mut tx = db.begin().await?;
+        let user_id = sqlx::query_scalar!(
+            r#"UPDATE "PasswordReset" SET used_at = $2
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2
+            RETURNING user_id"#,
+            hash_opaque_token(token),
+            now
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(anyhow::anyhow!("Invalid or expired reset token"))?;
+        User::set_password_hash(&mut tx, &user_id, &password_hash).await?;
+        Session::revoke_all(&mut tx, &user_id, now).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+impl User {
+    pub(crate) async fn set_password_hash(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        password_hash: &str,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"UPDATE "User" SET password_hash = $2 WHERE id = $1"#,
+            user_id,
+            password_hash
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Changes the password of a user who knows the current one. Other
+    /// sessions are logged out.
+    pub async fn change_password(
+        db: &DB,
+        user_id: &Uuid,
+        old_password: &str,
+        new_password: &str,
+        current_session: Option<Uuid>,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        let user = User::read_one(db, user_id).await?;
+        let password_hash = user
+            .password_hash
+            .ok_or(anyhow::anyhow!("User has no password, use setPassword"))?;
+        if !bcrypt::verify(old_password, &password_hash)? {
+            return Err(anyhow::anyhow!("Wrong password"));
+        }
+
+        let new_hash = hash_password(new_password)?;
+        let mut tx = db.begin().await?;
+        User::set_password_hash(&mut tx, user_id, &new_hash).await?;
+        tx.commit().await?;
+        Session::revoke_others(db, user_id, current_session, now).await?;
+        Ok(())
+    }
+
+    /// Adds a password to an account created through OAuth.
+    pub async fn set_password(
+        db: &DB,
+        user_id: &Uuid,
+        password: &str,
+    ) -> Result<(), anyhow::Error> {
+        let password_hash = hash_password(password)?;
+        let mut tx = db.begin().await?;
+        let result = sqlx::query!(
+            r#"UPDATE "User" SET password_hash = $2 WHERE id = $1 AND password_hash IS NULL"#,
+            user_id,
+            password_hash
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!(
+                "User already has a password, use changePassword"
+            ));
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+use std::sync::Arc;
+
+use axum::{
+    http::header,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::Utc;
+use reqwest::StatusCode;
+use serde::Serialize;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::{
+    field::model::FieldReservation, relations::model::Role, session::model::Session, token::Claims,
+    transaction::model::Transaction, Clock, DB,
+};
+
+use super::{address::UserAddress, api_token::ApiTokenScope, document::UserDocument, model::User};
+
+/// Name kept on anonymized accounts, e.g. as creator of transactions.
+const DELETED_USER_NAME: &str = "Deleted user";
+
+/// A membership, as included in the data export.
+#[derive(Debug, FromRow, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedMembership {
+    pub association_id: Uuid,
+    pub association_name: String,
+    pub role: Role,
+    pub pending: bool,
+    pub start_date: Option<chrono::NaiveDate>,
+    pub end_date: Option<chrono::NaiveDate>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// Everything kept about a user, for them to download.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataExport {
+    pub exported_at: chrono::DateTime<Utc>,
+    pub profile: User,
+    pub documents: Vec<UserDocument>,
+    pub address: Option<UserAddress>,
+    pub memberships: Vec<ExportedMembership>,
+    pub reservations: Vec<FieldReservation>,
+    // Transactions the user created as treasurer.
+    pub transactions: Vec<Transaction>,
+}
+
+impl DataExport {
+    pub async fn collect(
+        db: &DB,
+        user_id: &Uuid,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<DataExport, anyhow::Error> {
+        let profile = User::read_one(db, user_id).await?;
+        let documents = UserDocument::read_for_user(db, user_id).await?;
+        let address = UserAddress::read(db, user_id).await?;
+        let memberships = sqlx::query_as!(
+            ExportedMembership,
+            r#"SELECT ar.association_id, a.name AS association_name, ar.role AS "role: Role",
+                ar.pending, ar.start_date, ar.end_date, ar.created_at
+            FROM "AssociationRoles" ar
+            INNER JOIN "Association" a ON a.id = ar.association_id
+            WHERE ar.user_id = $1
+            ORDER BY ar.created_at"#,
+            user_id
+        )
+        .fetch_all(db)
+        .await?;
+        let reservations = sqlx::query_as!(
+            FieldReservation,
+            r#"SELECT * FROM "FieldReservation" WHERE user_id = $1 ORDER BY start_date"#,
+            user_id
+        )
+        .fetch_all(db)
+        .await?;
+        let transactions = sqlx::query_as!(
+            Transaction,
+            r#"SELECT * FROM "Transaction" WHERE creator_id = $1 ORDER BY reference_date"#,
+            user_id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(DataExport {
+            exported_at: now,
+            profile,
+            documents,
+            address,
+            memberships,
+            reservations,
+            transactions,
+        })
+    }
+}
+
+/// Downloads the data export of the logged in user as a JSON file.
+pub async fn export_handler(
+    claims: Claims,
+    scope: Option<ApiTokenScope>,
+    Extension(db): Extension<DB>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let user_id = claims
+        .sub
+        .ok_or((StatusCode::UNAUTHORIZED, "Unauthorized, please log in"))?;
+    if scope.is_some() {
+        return Err((StatusCode::FORBIDDEN, "Not allowed with an API token"));
+    }
+    let export = DataExport::collect(&db, &user_id, clock.now())
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "DB error"))?;
+    Ok((
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"my-hood-data.json\"",
+        )],
+        Json(export),
+    )
+        .into_response())
+}
+
+impl User {
+    /// Deletes the account, anonymizing the user's personal data. The user
+    /// row is kept so transactions and charges still refer to it, while
+    /// documents, memberships, login methods and upcoming reservations are
+    /// removed.
+    /// Users with a password must confirm it.
+    pub async fn delete_account(
+        db: &DB,
+        user_id: &Uuid,
+        password: Option<&str>,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        let user = User::read_one(db, user_id).await?;
+        if user.deleted_at.is_some() {
+            return Err(anyhow::anyhow!("Account already deleted"));
+        }
+        if let Some(password_hash) = &user.password_hash {
+            let password = password.ok_or(anyhow::anyhow!("Confirm your password"))?;
+            if !bcrypt::verify(password, password_hash)? {
+                return Err(anyhow::anyhow!("Wrong password"));
+            }
+        }
+
+        // Associations must not be left without an admin.
+        let orphaned = sqlx::query_scalar!(
+            r#"SELECT a.name FROM "AssociationRoles" ar
+            INNER JOIN "Association" a ON a.id = ar.association_id
+            WHERE ar.user_id = $1 AND ar.role = 'admin' AND a.deleted IS NOT TRUE
+                AND NOT EXISTS (
+                    SELECT 1 FROM "AssociationRoles" other
+                    WHERE other.association_id = ar.association_id
+                        AND other.role = 'admin' AND other.user_id <> $1
+                )"#,
+            user_id
+        )
+        .fetch_optional(db)
+        .await?;
+        if let Some(association) = orphaned {
+            return Err(anyhow::anyhow!(
+                "Make someone else admin of {} before deleting your account",
+                association
+            ));
+        }
+
+        let mut tx = db.begin().await?;
+        // Upcoming reservations are cancelled, with their unpaid charges.
+        // Blocks made on behalf of the association stay.
+        sqlx::query!(
+            r#"WITH cancelled AS (
+                UPDATE "FieldReservation" SET deleted = true
+                WHERE user_id = $1 AND deleted = false AND blocked = false AND start_date > $2
+                RETURNING charge_id
+            )
+            UPDATE "Charge" SET deleted = true
+            WHERE settled_at IS NULL AND id IN (SELECT charge_id FROM cancelled)"#,
+            user_id,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"UPDATE "FieldReservationGuest" SET declined_at = $2
+            WHERE user_id = $1 AND declined_at IS NULL AND reservation_id IN (
+                SELECT id FROM "FieldReservation" WHERE start_date > $2
+            )"#,
+            user_id,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"UPDATE "FieldWaitlist" SET deleted = true
+            WHERE user_id = $1 AND deleted = false AND reservation_id IS NULL"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        for query in [
+            sqlx::query!(
+                r#"DELETE FROM "AssociationRoles" WHERE user_id = $1"#,
+                user_id
+            ),
+            sqlx::query!(r#"DELETE FROM "Notification" WHERE user_id = $1"#, user_id),
+            sqlx::query!(r#"DELETE FROM "UserIdentity" WHERE user_id = $1"#, user_id),
+            sqlx::query!(r#"DELETE FROM "UserDocument" WHERE user_id = $1"#, user_id),
+            sqlx::query!(r#"DELETE FROM "UserAddress" WHERE user_id = $1"#, user_id),
+            sqlx::query!(r#"DELETE FROM "RecoveryCode" WHERE user_id = $1"#, user_id),
+            sqlx::query!(r#"DELETE FROM "TwoFactor" WHERE user_id = $1"#, user_id),
+            sqlx::query!(r#"DELETE FROM "ApiToken" WHERE user_id = $1"#, user_id),
+            sqlx::query!(r#"DELETE FROM "PasswordReset" WHERE user_id = $1"#, user_id),
+            sqlx::query!(
+                r#"DELETE FROM "CalendarFeedToken" WHERE user_id = $1"#,
+                user_id
+            ),
+            sqlx::query!(r#"DELETE FROM "OAuthCode" WHERE user_id = $1"#, user_id),
+        ] {
+            query.execute(&mut *tx).await?;
+        }
+        if let Some(email) = &user.email {
+            sqlx::query!(
+                r#"DELETE FROM "LoginAttempt" WHERE user_id = $1 OR email = $2"#,
+                user_id,
+                email
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(r#"DELETE FROM "EmailVerification" WHERE email = $1"#, email)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        // The birthday cannot be null, so a placeholder is kept.
+        sqlx::query!(
+            r#"UPDATE "User" SET
+                name = $2,
+                password_hash = NULL,
+                birthday = DATE '1900-01-01',
+                address = '',
+                activity = NULL,
+                email = NULL,
+                personal_phone = NULL,
+                commercial_phone = NULL,
+                uses_whatsapp = false,
+                identities = NULL,
+                profile_url = NULL,
+                deleted = true,
+                deleted_at = $3
+            WHERE id = $1"#,
+            user_id,
+            DELETED_USER_NAME,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        Session::revoke_all(&mut tx, user_id, now).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+/// Country calling code of numbers given without one.
+const DEFAULT_COUNTRY_CODE: &str = "55";
+
+/// Normalizes a phone number to E.164, e.g. "(71) 99876-5432" to
+/// "+5571998765432". Numbers without a country code are taken as Brazilian,
+/// with the area code and an optional trunk prefix 0.
+pub fn normalize_phone(phone: &str) -> Result<String, anyhow::Error> {
+    let invalid = || anyhow::anyhow!("Invalid phone number: {}", phone);
+    let trimmed = phone.trim();
+    let valid_char = |(i, c): (usize, char)| {
+        c.is_ascii_digit() || matches!(c, ' ' | '-' | '.' | '(' | ')') || (c == '+' && i == 0)
+    };
+    if !trimmed.char_indices().all(valid_char) {
+        return Err(invalid());
+    }
+    let digits: String = trimmed.chars().filter(char::is_ascii_digit).collect();
+
+    let international = if trimmed.starts_with('+') {
+        Some(digits.as_str())
+    } else {
+        digits.strip_prefix("00")
+    };
+    let number = match international {
+        Some(number) => number.to_owned(),
+        None => {
+            let national = digits.strip_prefix('0').unwrap_or(&digits);
+            // Area code, then 8 digits for landlines or 9 for mobiles.
+            if !matches!(national.len(), 10 | 11) || national.starts_with('0') {
+                return Err(invalid());
+            }
+            format!("{}{}", DEFAULT_COUNTRY_CODE, national)
+        }
+    };
+    if !(8..=15).contains(&number.len()) || number.starts_with('0') {
+        return Err(invalid());
+    }
+    Ok(format!("+{}", number))
+}
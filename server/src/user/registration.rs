@@ -0,0 +1,198 @@
+use std::convert::Infallible;
+
+use async_graphql::SimpleObject;
+use axum::{extract::OptionalFromRequestParts, http::request::Parts};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    token::{decode_purpose_token, encode_purpose_token, generate_opaque_token, hash_opaque_token},
+    DB,
+};
+
+/// How long a verification link stays valid.
+const VERIFICATION_HOURS: i64 = 24;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// Registration tokens only allow creating the account with
+/// `completeRegistration`.
+const REGISTRATION_AUDIENCE: &str = "registration";
+const REGISTRATION_MINUTES: i64 = 60;
+
+/// Someone who verified an email or logged in with an OAuth provider, and
+/// has no user yet. Carried by a registration token until the account is
+/// created.
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleObject)]
+pub struct Registration {
+    pub email: Option<String>,
+    // Provider account to link to the new user.
+    pub provider: Option<String>,
+    #[graphql(skip)]
+    pub subject: Option<String>,
+    // Profile from the provider, to prefill the account.
+    pub name: Option<String>,
+    pub picture_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RegistrationClaims {
+    aud: String,
+    exp: usize,
+    #[serde(flatten)]
+    registration: Registration,
+}
+
+/// Optional extractor of the registration, set by the `Claims` extractor.
+impl<S> OptionalFromRequestParts<S> for Registration
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<Registration>().cloned())
+    }
+}
+
+impl Registration {
+    pub fn token(&self, config: &Config) -> Result<String, anyhow::Error> {
+        let claims = RegistrationClaims {
+            aud: REGISTRATION_AUDIENCE.to_owned(),
+            exp: (Utc::now() + Duration::minutes(REGISTRATION_MINUTES)).timestamp() as usize,
+            registration: self.clone(),
+        };
+        encode_purpose_token(config, &claims)
+    }
+
+    pub fn from_token(config: &Config, token: &str) -> Result<Registration, anyhow::Error> {
+        let claims: RegistrationClaims =
+            decode_purpose_token(config, token, REGISTRATION_AUDIENCE)?;
+        Ok(claims.registration)
+    }
+}
+
+/// A pending email/password registration.
+#[derive(Debug, FromRow)]
+pub struct EmailVerification {
+    pub id: Uuid,
+    pub email: String,
+    pub password_hash: String,
+    pub token_hash: String,
+    pub expires_at: chrono::DateTime<Utc>,
+    pub verified_at: Option<chrono::DateTime<Utc>>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+pub fn check_email(email: &str) -> Result<(), anyhow::Error> {
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !domain.contains('@')
+                && !email.contains(char::is_whitespace)
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(anyhow::anyhow!("Invalid email address"));
+    }
+    Ok(())
+}
+
+pub fn check_password(password: &str) -> Result<(), anyhow::Error> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(anyhow::anyhow!(
+            "Password must have at least {} characters",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+impl EmailVerification {
+    /// Starts a registration, replacing pending ones for the same email. The
+    /// token is only returned here, to be emailed.
+    pub async fn create(
+        db: &DB,
+        email: &str,
+        password_hash: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<String, anyhow::Error> {
+        let token = generate_opaque_token();
+        let mut tx = db.begin().await?;
+        sqlx::query!(r#"DELETE FROM "EmailVerification" WHERE email = $1"#, email)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            r#"INSERT INTO "EmailVerification" (email, password_hash, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4)"#,
+            email,
+            password_hash,
+            hash_opaque_token(&token),
+            now + Duration::hours(VERIFICATION_HOURS)
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(token)
+    }
+
+    /// Confirms the email the token was sent to.
+    pub async fn verify(
+        db: &DB,
+        token: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<EmailVerification, anyhow::Error> {
+        let mut tx = db.begin().await?;
+        let verification = sqlx::query_as!(
+            EmailVerification,
+            r#"UPDATE "EmailVerification" SET verified_at = COALESCE(verified_at, $2)
+            WHERE token_hash = $1 AND expires_at > $2
+            RETURNING *"#,
+            hash_opaque_token(token),
+            now
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(anyhow::anyhow!("Invalid or expired verification token"))?;
+        tx.commit().await?;
+        Ok(verification)
+    }
+
+    /// Verified registration for the email, holding the password for the
+    /// user about to be created.
+    pub async fn read_verified(
+        db: &DB,
+        email: &str,
+    ) -> Result<Option<EmailVerification>, anyhow::Error> {
+        let verification = sqlx::query_as!(
+            EmailVerification,
+            r#"SELECT * FROM "EmailVerification"
+            WHERE email = $1 AND verified_at IS NOT NULL
+            ORDER BY verified_at DESC LIMIT 1"#,
+            email
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(verification)
+    }
+
+    /// Forgets all registrations of the email once its user exists.
+    pub async fn delete_for_email(
+        conn: &mut PgConnection,
+        email: &str,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(r#"DELETE FROM "EmailVerification" WHERE email = $1"#, email)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
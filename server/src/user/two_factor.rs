@@ -0,0 +1,384 @@
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    session::model::Session,
+    token::{decode_purpose_token, encode_purpose_token, hash_opaque_token},
+    DB,
+};
+
+/// Name shown by authenticator apps.
+const TOTP_ISSUER: &str = "MyHood";
+const TOTP_STEP_SECS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Codes from the previous and next steps are accepted, for clock drift.
+const TOTP_SKEW: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+/// Pre-auth tokens only allow completing the login with a second factor.
+const PRE_AUTH_AUDIENCE: &str = "two-factor";
+const PRE_AUTH_MINUTES: i64 = 5;
+
+#[derive(Debug, FromRow)]
+pub struct TwoFactor {
+    pub user_id: Uuid,
+    // Base32, as entered in authenticator apps.
+    pub secret: String,
+    pub enabled_at: Option<chrono::DateTime<Utc>>,
+    pub last_used_step: i64,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, SimpleObject)]
+pub struct TwoFactorEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PreAuthClaims {
+    sub: Uuid,
+    aud: String,
+    exp: usize,
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    code % 10_u32.pow(TOTP_DIGITS)
+}
+
+fn totp_step(time: chrono::DateTime<Utc>) -> i64 {
+    time.timestamp() / TOTP_STEP_SECS
+}
+
+/// The TOTP code (RFC 6238, SHA-1, 6 digits, 30 seconds) of a base32
+/// secret at `time`.
+pub fn totp_code(secret: &str, time: chrono::DateTime<Utc>) -> Result<String, anyhow::Error> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes())?;
+    Ok(format!(
+        "{:0width$}",
+        hotp(&secret, totp_step(time) as u64),
+        width = TOTP_DIGITS as usize
+    ))
+}
+
+fn generate_secret() -> String {
+    // 160 bits, as recommended for HMAC-SHA1, from random UUIDs.
+    let bytes: Vec<u8> = [Uuid::new_v4(), Uuid::new_v4()]
+        .iter()
+        .flat_map(|uuid| *uuid.as_bytes())
+        .take(20)
+        .collect();
+    BASE32_NOPAD.encode(&bytes)
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Short-lived token proving the password was checked, exchanged for a
+/// session at `/auth/two-factor`.
+pub fn get_pre_auth_token(config: &Config, user_id: Uuid) -> Result<String, anyhow::Error> {
+    let claims = PreAuthClaims {
+        sub: user_id,
+        aud: PRE_AUTH_AUDIENCE.to_owned(),
+        exp: (Utc::now() + chrono::Duration::minutes(PRE_AUTH_MINUTES)).timestamp() as usize,
+    };
+    encode_purpose_token(config, &claims)
+}
+
+pub fn validate_pre_auth_token(config: &Config, token: &str) -> Result<Uuid, anyhow::Error> {
+    let claims: PreAuthClaims = decode_purpose_token(config, token, PRE_AUTH_AUDIENCE)?;
+    Ok(claims.sub)
+}
+
+impl TwoFactor {
+    pub async fn read(db: &DB, user_id: &Uuid) -> Result<Option<TwoFactor>, anyhow::Error> {
+        let two_factor = sqlx::query_as!(
+            TwoFactor,
+            r#"SELECT * FROM "TwoFactor" WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_optional(db)
+        .await?;
+        Ok(two_factor)
+    }
+
+    pub async fn is_enabled(db: &DB, user_id: &Uuid) -> Result<bool, anyhow::Error> {
+        let two_factor = TwoFactor::read(db, user_id).await?;
+        Ok(two_factor.is_some_and(|two_factor| two_factor.enabled_at.is_some()))
+    }
+
+    /// Starts enrollment with a new secret, replacing a pending one. It is
+    /// enabled by `confirm`.
+    pub async fn enroll(
+        db: &DB,
+        user_id: &Uuid,
+        account: &str,
+    ) -> Result<TwoFactorEnrollment, anyhow::Error> {
+        let secret = generate_secret();
+        let result = sqlx::query!(
+            r#"INSERT INTO "TwoFactor" (user_id, secret) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET secret = $2, last_used_step = 0
+            WHERE "TwoFactor".enabled_at IS NULL"#,
+            user_id,
+            secret
+        )
+        .execute(db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!(
+                "Two-factor authentication is already enabled"
+            ));
+        }
+
+        let otpauth_uri = format!(
+            "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+            issuer = TOTP_ISSUER,
+            account = account.replace(['/', '?', '#', '&', ':'], ""),
+            secret = secret,
+            digits = TOTP_DIGITS,
+            period = TOTP_STEP_SECS,
+        );
+        Ok(TwoFactorEnrollment {
+            secret,
+            otpauth_uri,
+        })
+    }
+
+    /// Checks a TOTP code, which cannot be used twice.
+    async fn verify_totp(
+        db: &DB,
+        two_factor: &TwoFactor,
+        code: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<bool, anyhow::Error> {
+        let secret = BASE32_NOPAD.decode(two_factor.secret.as_bytes())?;
+        let Ok(code) = code.trim().parse::<u32>() else {
+            return Ok(false);
+        };
+        let current = totp_step(now);
+        let step = (current - TOTP_SKEW..=current + TOTP_SKEW)
+            .find(|step| *step > two_factor.last_used_step && hotp(&secret, *step as u64) == code);
+        let Some(step) = step else {
+            return Ok(false);
+        };
+        let result = sqlx::query!(
+            r#"UPDATE "TwoFactor" SET last_used_step = $2
+            WHERE user_id = $1 AND last_used_step < $2"#,
+            two_factor.user_id,
+            step
+        )
+        .execute(db)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn use_recovery_code(
+        db: &DB,
+        user_id: &Uuid,
+        code: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query!(
+            r#"UPDATE "RecoveryCode" SET used_at = $3
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL"#,
+            user_id,
+            hash_opaque_token(&normalize_recovery_code(code)),
+            now
+        )
+        .execute(db)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Checks a TOTP or recovery code of a user with two-factor enabled.
+    pub async fn verify(
+        db: &DB,
+        user_id: &Uuid,
+        code: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<bool, anyhow::Error> {
+        let two_factor = match TwoFactor::read(db, user_id).await? {
+            Some(two_factor) if two_factor.enabled_at.is_some() => two_factor,
+            _ => return Ok(false),
+        };
+        if TwoFactor::verify_totp(db, &two_factor, code, now).await? {
+            return Ok(true);
+        }
+        TwoFactor::use_recovery_code(db, user_id, code, now).await
+    }
+
+    /// Replaces the user's recovery codes. The codes are only returned here.
+    async fn create_recovery_codes(db: &DB, user_id: &Uuid) -> Result<Vec<String>, anyhow::Error> {
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| {
+                // 80 bits from the leading bytes of two UUIDs, which are fully
+                // random, so leaked hashes cannot be brute-forced offline.
+                let bytes: Vec<u8> = [Uuid::new_v4(), Uuid::new_v4()]
+                    .iter()
+                    .flat_map(|uuid| uuid.as_bytes()[..5].to_vec())
+                    .collect();
+                let code = BASE32_NOPAD.encode(&bytes).to_lowercase();
+                format!(
+                    "{}-{}-{}-{}",
+                    &code[0..4],
+                    &code[4..8],
+                    &code[8..12],
+                    &code[12..16]
+                )
+            })
+            .collect();
+        let hashes: Vec<String> = codes
+            .iter()
+            .map(|code| hash_opaque_token(&normalize_recovery_code(code)))
+            .collect();
+
+        let mut tx = db.begin().await?;
+        sqlx::query!(r#"DELETE FROM "RecoveryCode" WHERE user_id = $1"#, user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            r#"INSERT INTO "RecoveryCode" (user_id, code_hash) SELECT $1, * FROM UNNEST($2::text[])"#,
+            user_id,
+            &hashes
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(codes)
+    }
+
+    /// Enables two-factor with the first code from the authenticator, and
+    /// returns the recovery codes. The current session counts as started
+    /// with a second factor.
+    pub async fn confirm(
+        db: &DB,
+        user_id: &Uuid,
+        code: &str,
+        session_id: Option<Uuid>,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        let two_factor = TwoFactor::read(db, user_id)
+            .await?
+            .ok_or(anyhow::anyhow!("Two-factor enrollment was not started"))?;
+        if two_factor.enabled_at.is_some() {
+            return Err(anyhow::anyhow!(
+                "Two-factor authentication is already enabled"
+            ));
+        }
+        if !TwoFactor::verify_totp(db, &two_factor, code, now).await? {
+            return Err(anyhow::anyhow!("Invalid code"));
+        }
+        sqlx::query!(
+            r#"UPDATE "TwoFactor" SET enabled_at = $2 WHERE user_id = $1"#,
+            user_id,
+            now
+        )
+        .execute(db)
+        .await?;
+        if let Some(session_id) = session_id {
+            Session::set_two_factor(db, &session_id).await?;
+        }
+        TwoFactor::create_recovery_codes(db, user_id).await
+    }
+
+    pub async fn regenerate_recovery_codes(
+        db: &DB,
+        user_id: &Uuid,
+        code: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<Vec<String>, anyhow::Error> {
+        if !TwoFactor::verify(db, user_id, code, now).await? {
+            return Err(anyhow::anyhow!("Invalid code"));
+        }
+        TwoFactor::create_recovery_codes(db, user_id).await
+    }
+
+    /// Disables two-factor, unless an association where the user is admin or
+    /// treasurer requires it.
+    pub async fn disable(
+        db: &DB,
+        user_id: &Uuid,
+        code: &str,
+        now: chrono::DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        let required = sqlx::query_scalar!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM "AssociationRoles" ar
+                JOIN "Association" a ON a.id = ar.association_id
+                WHERE ar.user_id = $1 AND ar.role IN ('admin', 'treasurer')
+                    AND a.require_two_factor
+            ) AS "required!""#,
+            user_id
+        )
+        .fetch_one(db)
+        .await?;
+        if required {
+            return Err(anyhow::anyhow!(
+                "An association you manage requires two-factor authentication"
+            ));
+        }
+        if !TwoFactor::verify(db, user_id, code, now).await? {
+            return Err(anyhow::anyhow!("Invalid code"));
+        }
+
+        let mut tx = db.begin().await?;
+        sqlx::query!(r#"DELETE FROM "RecoveryCode" WHERE user_id = $1"#, user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(r#"DELETE FROM "TwoFactor" WHERE user_id = $1"#, user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Errors if the association requires a second factor and the session
+    /// was not started with one.
+    pub async fn check_policy(
+        db: &DB,
+        association_id: &Uuid,
+        session_id: Option<Uuid>,
+    ) -> Result<(), anyhow::Error> {
+        let required = sqlx::query_scalar!(
+            r#"SELECT require_two_factor FROM "Association" WHERE id = $1"#,
+            association_id
+        )
+        .fetch_optional(db)
+        .await?
+        .unwrap_or(false);
+        if !required {
+            return Ok(());
+        }
+        let two_factor = match session_id {
+            Some(session_id) => Session::is_two_factor(db, &session_id).await?,
+            None => false,
+        };
+        if !two_factor {
+            return Err(anyhow::anyhow!(
+                "This association requires admins and treasurers to log in with two-factor authentication"
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1126 @@
+mod queries;
+mod test_utils;
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    extract::connect_info::MockConnectInfo,
+    routing::{get, post},
+    Extension, Router,
+};
+use chrono::TimeZone;
+use http::{header, Request, StatusCode};
+use jsonwebtoken::{decode_header, Algorithm, DecodingKey};
+#[cfg(test)]
+use my_hood_server::config::Config;
+use my_hood_server::{
+    config::JwtKeys,
+    graphql::{get_schema, graphql_handler},
+    mail::{Email, Mailer},
+    oauth::get_token,
+    rate_limit::rate_limited,
+    session::model::{Session, REFRESH_TOKEN_DAYS},
+    token::{
+        forgot_password_handler, login_handler, logout_handler, refresh_handler, register_handler,
+        reset_password_handler, two_factor_handler, validate_token, verify_email_handler, Claims,
+    },
+    user::{
+        model::{User, UserInput},
+        two_factor::totp_code,
+    },
+    Clock,
+};
+use serde_json::{json, Value};
+use test_utils::{FixedClock, TestDatabase};
+use tower::ServiceExt;
+
+/// Keeps sent emails for inspection.
+#[derive(Default)]
+struct RecordingMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+#[async_trait]
+impl Mailer for RecordingMailer {
+    async fn send(&self, email: Email) -> Result<(), anyhow::Error> {
+        self.sent.lock().unwrap().push(email);
+        Ok(())
+    }
+}
+
+fn auth_app(test_db: &TestDatabase, config: &Config, mailer: Arc<RecordingMailer>) -> Router {
+    auth_app_at(test_db, config, mailer, test_db.clock.clone())
+}
+
+fn auth_app_at(
+    test_db: &TestDatabase,
+    config: &Config,
+    mailer: Arc<RecordingMailer>,
+    clock: Arc<dyn Clock>,
+) -> Router {
+    Router::new()
+        .route("/auth", post(login_handler))
+        .route("/auth/two-factor", post(two_factor_handler))
+        .route("/auth/register", post(register_handler))
+        .route("/auth/verify-email", post(verify_email_handler))
+        .route("/auth/forgot-password", post(forgot_password_handler))
+        .route("/auth/reset-password", post(reset_password_handler))
+        .route("/auth/refresh", post(refresh_handler))
+        .route("/auth/logout", post(logout_handler))
+        .layer(Extension(test_db.pool.clone()))
+        .layer(Extension(clock))
+        .layer(Extension(config.clone()))
+        .layer(Extension(mailer as Arc<dyn Mailer>))
+        .layer(tower_cookies::CookieManagerLayer::new())
+}
+
+async fn post_json(
+    app: &Router,
+    uri: &str,
+    body: Value,
+    token: Option<&str>,
+) -> (StatusCode, Value) {
+    let mut request = Request::post(uri).header(header::CONTENT_TYPE, "application/json");
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let response = app
+        .clone()
+        .oneshot(request.body(Body::from(body.to_string())).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn test_refresh_and_logout() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+    let app = auth_app(&test_db, &config, Arc::default());
+
+    let user = User::create(
+        &test_db.pool,
+        UserInput {
+            name: Some("Session User".to_owned()),
+            birthday: "2000-01-01".parse().unwrap(),
+            address: "Rua A nr 1".to_owned(),
+            email: Some("session@test.com".to_owned()),
+            password_hash: Some(bcrypt::hash("secret", 4).unwrap()),
+            uses_whatsapp: false,
+            identities: None,
+            personal_phone: None,
+            commercial_phone: None,
+            activity: None,
+            profile_url: None,
+        },
+    )
+    .await
+    .unwrap();
+    let credentials = json!({ "email": "session@test.com", "password": "secret" });
+
+    // Logging in starts a session.
+    let (status, login) = post_json(&app, "/auth", credentials.clone(), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let token = login["token"].as_str().unwrap();
+    let refresh_token = login["refreshToken"].as_str().unwrap();
+    let claims = validate_token(&config, &test_db.pool, token, now)
+        .await
+        .unwrap();
+    assert_eq!(claims.sub, Some(user.id));
+    assert!(claims.sid.is_some());
+    // Access tokens stop working once their session expired.
+    let expired = now + chrono::Duration::days(REFRESH_TOKEN_DAYS);
+    assert!(validate_token(&config, &test_db.pool, token, expired)
+        .await
+        .is_err());
+
+    // Refreshing rotates the refresh token within the same session.
+    let (status, refreshed) = post_json(
+        &app,
+        "/auth/refresh",
+        json!({ "refreshToken": refresh_token }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let new_token = refreshed["token"].as_str().unwrap();
+    let new_refresh_token = refreshed["refreshToken"].as_str().unwrap();
+    assert_ne!(new_refresh_token, refresh_token);
+    let new_claims = validate_token(&config, &test_db.pool, new_token, now)
+        .await
+        .unwrap();
+    assert_eq!(new_claims.sid, claims.sid);
+
+    // Reusing a rotated refresh token revokes the whole session.
+    let (status, _) = post_json(
+        &app,
+        "/auth/refresh",
+        json!({ "refreshToken": refresh_token }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(validate_token(&config, &test_db.pool, new_token, now)
+        .await
+        .is_err());
+    let (status, _) = post_json(
+        &app,
+        "/auth/refresh",
+        json!({ "refreshToken": new_refresh_token }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Logging out revokes only the current session.
+    let (_, first) = post_json(&app, "/auth", credentials.clone(), None).await;
+    let (_, second) = post_json(&app, "/auth", credentials.clone(), None).await;
+    let first_token = first["token"].as_str().unwrap();
+    let second_token = second["token"].as_str().unwrap();
+    let (status, _) = post_json(&app, "/auth/logout", json!({}), Some(first_token)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(validate_token(&config, &test_db.pool, first_token, now)
+        .await
+        .is_err());
+    let (status, _) = post_json(
+        &app,
+        "/auth/refresh",
+        json!({ "refreshToken": first["refreshToken"] }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let second_claims = validate_token(&config, &test_db.pool, second_token, now)
+        .await
+        .unwrap();
+
+    // Logging out of all devices revokes the remaining sessions.
+    let schema = test_db.get_schema_for_tests(config.clone(), second_claims);
+    let response = schema.execute("mutation { logoutAllDevices }").await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert_eq!(response.data.into_json().unwrap()["logoutAllDevices"], 1);
+    assert!(validate_token(&config, &test_db.pool, second_token, now)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_jwt_key_rotation() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let old_config = Config {
+        jwt_keys: JwtKeys::hmac("2023", "old secret"),
+        ..config.clone()
+    };
+    // Tokens belong to a user session.
+    let (session, _) = Session::create(&test_db.pool, &test_db.admin.id, now)
+        .await
+        .unwrap();
+    let (sub, sid) = (Some(test_db.admin.id), Some(session.id));
+    let token = get_token(&old_config, sub, test_db.admin.email.clone(), sid).unwrap();
+    assert_eq!(decode_header(&token).unwrap().kid.as_deref(), Some("2023"));
+
+    // Lifetimes come from TOKEN_EXPIRED_IN.
+    let claims = validate_token(&old_config, &test_db.pool, &token, now)
+        .await
+        .unwrap();
+    let expires_in = claims.exp as i64 - chrono::Utc::now().timestamp();
+    assert!((expires_in - config.jwt_expires_in.num_seconds()).abs() < 5);
+
+    // After rotating, tokens signed with the previous key are only accepted
+    // while it is listed.
+    let rotated = Config {
+        jwt_keys: JwtKeys::hmac("2024", "new secret")
+            .with_accepted_key("2023", DecodingKey::from_secret(b"old secret")),
+        ..config.clone()
+    };
+    assert!(validate_token(&rotated, &test_db.pool, &token, now)
+        .await
+        .is_ok());
+    let new_token = get_token(&rotated, sub, None, sid).unwrap();
+    assert_eq!(
+        decode_header(&new_token).unwrap().kid.as_deref(),
+        Some("2024")
+    );
+    let retired = Config {
+        jwt_keys: JwtKeys::hmac("2024", "new secret"),
+        ..config.clone()
+    };
+    assert!(validate_token(&retired, &test_db.pool, &token, now)
+        .await
+        .is_err());
+    assert!(validate_token(&retired, &test_db.pool, &new_token, now)
+        .await
+        .is_ok());
+
+    // Asymmetric keys.
+    let eddsa = Config {
+        jwt_keys: JwtKeys::from_pem(
+            Algorithm::EdDSA,
+            "ed-1",
+            include_bytes!("fixtures/jwt_ed25519.pem"),
+            include_bytes!("fixtures/jwt_ed25519.pub.pem"),
+        )
+        .unwrap(),
+        ..config.clone()
+    };
+    let token = get_token(&eddsa, sub, None, sid).unwrap();
+    assert_eq!(decode_header(&token).unwrap().alg, Algorithm::EdDSA);
+    assert!(validate_token(&eddsa, &test_db.pool, &token, now)
+        .await
+        .is_ok());
+    assert!(validate_token(&retired, &test_db.pool, &token, now)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_email_registration() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+    let mailer = Arc::new(RecordingMailer::default());
+    let app = auth_app(&test_db, &config, mailer.clone());
+
+    let (status, _) = post_json(
+        &app,
+        "/auth/register",
+        json!({ "email": "not an email", "password": "long enough" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = post_json(
+        &app,
+        "/auth/register",
+        json!({ "email": "new@test.com", "password": "short" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let credentials = json!({ "email": "new@test.com", "password": "long enough" });
+    let (status, _) = post_json(&app, "/auth/register", credentials.clone(), None).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let email = mailer.sent.lock().unwrap().pop().unwrap();
+    assert_eq!(email.to, "new@test.com");
+    let verification_token = email.body.split("token=").nth(1).unwrap().to_owned();
+
+    // Unknown emails cannot log in.
+    let (status, _) = post_json(&app, "/auth", credentials.clone(), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = post_json(
+        &app,
+        "/auth/verify-email",
+        json!({ "token": "wrong" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, verified) = post_json(
+        &app,
+        "/auth/verify-email",
+        json!({ "token": verification_token }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(verified["registrationRequired"], true);
+    let registration_token = verified["registrationToken"].as_str().unwrap();
+    // Registration tokens are not access tokens.
+    assert!(validate_token(
+        &config,
+        &test_db.pool,
+        registration_token,
+        test_db.clock.now()
+    )
+    .await
+    .is_err());
+
+    // The verified email can create its user, with the registered password,
+    // and is logged in right away.
+    let graphql = graphql_app(&test_db, &config, test_db.clock.clone());
+    let (_, pending) = post_json(
+        &graphql,
+        "/",
+        json!({ "query": "{ pendingRegistration { email provider } }" }),
+        Some(registration_token),
+    )
+    .await;
+    assert_eq!(
+        pending["data"]["pendingRegistration"],
+        json!({ "email": "new@test.com", "provider": null })
+    );
+    let (_, registered) = post_json(
+        &graphql,
+        "/",
+        json!({ "query": r#"mutation { completeRegistration(userInput: {
+            name: "New User", birthday: "2000-01-01",
+            address: "Rua A nr 1", usesWhatsapp: false
+        }) { user { id email } token refreshToken } }"# }),
+        Some(registration_token),
+    )
+    .await;
+    let registered = &registered["data"]["completeRegistration"];
+    assert_eq!(registered["user"]["email"], "new@test.com");
+    let claims = validate_token(
+        &config,
+        &test_db.pool,
+        registered["token"].as_str().unwrap(),
+        test_db.clock.now(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        claims.sub.map(|id| id.to_string()).as_deref(),
+        registered["user"]["id"].as_str()
+    );
+    assert!(claims.sid.is_some());
+
+    // The registration cannot be completed twice.
+    let (_, registered) = post_json(
+        &graphql,
+        "/",
+        json!({ "query": r#"mutation { completeRegistration(userInput: {
+            birthday: "2000-01-01", address: "Rua A nr 1", usesWhatsapp: false
+        }) { token } }"# }),
+        Some(registration_token),
+    )
+    .await;
+    assert!(registered["errors"].is_array());
+    let (status, _) = post_json(&app, "/auth", credentials.clone(), None).await;
+    assert_eq!(status, StatusCode::OK);
+    // Emails are matched whatever their case.
+    let (status, _) = post_json(
+        &app,
+        "/auth",
+        json!({ "email": " New@Test.com", "password": "long enough" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Registering again only warns the account owner.
+    let (status, _) = post_json(&app, "/auth/register", credentials.clone(), None).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let email = mailer.sent.lock().unwrap().pop().unwrap();
+    assert_eq!(email.subject, "You already have an account");
+    assert!(!email.body.contains("token="));
+}
+
+#[tokio::test]
+async fn test_password_reset_and_change() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+    let mailer = Arc::new(RecordingMailer::default());
+    let app = auth_app(&test_db, &config, mailer.clone());
+
+    User::create(
+        &test_db.pool,
+        UserInput {
+            name: Some("Forgetful User".to_owned()),
+            birthday: "2000-01-01".parse().unwrap(),
+            address: "Rua A nr 1".to_owned(),
+            email: Some("forgetful@test.com".to_owned()),
+            password_hash: Some(bcrypt::hash("old password", 4).unwrap()),
+            uses_whatsapp: false,
+            identities: None,
+            personal_phone: None,
+            commercial_phone: None,
+            activity: None,
+            profile_url: None,
+        },
+    )
+    .await
+    .unwrap();
+    let (_, old_login) = post_json(
+        &app,
+        "/auth",
+        json!({ "email": "forgetful@test.com", "password": "old password" }),
+        None,
+    )
+    .await;
+    let old_token = old_login["token"].as_str().unwrap();
+
+    // Unknown emails get the same answer, but no email is sent.
+    let (status, _) = post_json(
+        &app,
+        "/auth/forgot-password",
+        json!({ "email": "nobody@test.com" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert!(mailer.sent.lock().unwrap().is_empty());
+
+    let (status, _) = post_json(
+        &app,
+        "/auth/forgot-password",
+        json!({ "email": "Forgetful@test.com" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let email = mailer.sent.lock().unwrap().pop().unwrap();
+    assert_eq!(email.to, "forgetful@test.com");
+    let reset_token = email.body.split("token=").nth(1).unwrap();
+    let reset_token = reset_token.split_whitespace().next().unwrap().to_owned();
+
+    let (status, _) = post_json(
+        &app,
+        "/auth/reset-password",
+        json!({ "token": reset_token, "password": "short" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = post_json(
+        &app,
+        "/auth/reset-password",
+        json!({ "token": reset_token, "password": "new password" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    // Reset tokens work once, and resetting logs out every session.
+    let (status, _) = post_json(
+        &app,
+        "/auth/reset-password",
+        json!({ "token": reset_token, "password": "another password" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(validate_token(&config, &test_db.pool, old_token, now)
+        .await
+        .is_err());
+    let (status, _) = post_json(
+        &app,
+        "/auth",
+        json!({ "email": "forgetful@test.com", "password": "old password" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (_, first) = post_json(
+        &app,
+        "/auth",
+        json!({ "email": "forgetful@test.com", "password": "new password" }),
+        None,
+    )
+    .await;
+    let (_, second) = post_json(
+        &app,
+        "/auth",
+        json!({ "email": "forgetful@test.com", "password": "new password" }),
+        None,
+    )
+    .await;
+    let first_token = first["token"].as_str().unwrap();
+    let second_token = second["token"].as_str().unwrap();
+    let claims = validate_token(&config, &test_db.pool, first_token, now)
+        .await
+        .unwrap();
+    let schema = test_db.get_schema_for_tests(config.clone(), claims);
+
+    // Changing the password needs the current one and keeps only this session.
+    let response = schema
+        .execute(
+            r#"mutation { changePassword(oldPassword: "wrong", newPassword: "newer password") }"#,
+        )
+        .await;
+    assert!(!response.errors.is_empty());
+    let response = schema
+        .execute(r#"mutation { changePassword(oldPassword: "new password", newPassword: "newer password") }"#)
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert!(validate_token(&config, &test_db.pool, first_token, now)
+        .await
+        .is_ok());
+    assert!(validate_token(&config, &test_db.pool, second_token, now)
+        .await
+        .is_err());
+    let (status, _) = post_json(
+        &app,
+        "/auth",
+        json!({ "email": "forgetful@test.com", "password": "newer password" }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Users with a password must change it instead of setting it.
+    let response = schema
+        .execute(r#"mutation { setPassword(password: "another password") }"#)
+        .await;
+    assert!(!response.errors.is_empty());
+
+    // Users without one, e.g. from OAuth, can add a password.
+    let admin_claims = Claims {
+        sub: Some(test_db.admin.id),
+        exp: 0,
+        sid: None,
+        email: test_db.admin.email.clone(),
+    };
+    let admin_schema = test_db.get_schema_for_tests(config.clone(), admin_claims);
+    let response = admin_schema
+        .execute(r#"mutation { setPassword(password: "admin password") }"#)
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let admin = User::read_one(&test_db.pool, &test_db.admin.id)
+        .await
+        .unwrap();
+    assert!(bcrypt::verify("admin password", admin.password_hash.as_deref().unwrap()).unwrap());
+}
+
+#[tokio::test]
+async fn test_login_throttling() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let mut config = Config::new();
+    config.login_max_attempts = 3;
+    config.login_ip_max_attempts = 6;
+    config.login_backoff = chrono::Duration::seconds(10);
+    config.login_lockout = chrono::Duration::minutes(15);
+    let app_at = |seconds: i64| {
+        let clock = Arc::new(FixedClock(now + chrono::Duration::seconds(seconds)));
+        auth_app_at(&test_db, &config, Arc::default(), clock)
+            .layer(MockConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))))
+    };
+
+    User::create(
+        &test_db.pool,
+        UserInput {
+            name: Some("Guessed User".to_owned()),
+            birthday: "2000-01-01".parse().unwrap(),
+            address: "Rua A nr 1".to_owned(),
+            email: Some("guessed@test.com".to_owned()),
+            password_hash: Some(bcrypt::hash("secret", 4).unwrap()),
+            uses_whatsapp: false,
+            identities: None,
+            personal_phone: None,
+            commercial_phone: None,
+            activity: None,
+            profile_url: None,
+        },
+    )
+    .await
+    .unwrap();
+    let wrong = json!({ "email": "guessed@test.com", "password": "guess" });
+    let right = json!({ "email": "guessed@test.com", "password": "secret" });
+
+    // A typo is let through, then failures double the wait.
+    let (status, _) = post_json(&app_at(0), "/auth", wrong.clone(), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post_json(&app_at(0), "/auth", wrong.clone(), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let response = app_at(5)
+        .oneshot(
+            Request::post("/auth")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(right.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+
+    // The last allowed failure locks the email out.
+    let (status, _) = post_json(&app_at(10), "/auth", wrong.clone(), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post_json(&app_at(600), "/auth", right.clone(), None).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _) = post_json(&app_at(10 + 900), "/auth", right.clone(), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Logging in resets the count.
+    let (status, _) = post_json(&app_at(1000), "/auth", wrong.clone(), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post_json(&app_at(1000), "/auth", right.clone(), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Guessing across emails locks the IP out.
+    for n in 0..5 {
+        let unknown = json!({ "email": format!("unknown{}@test.com", n), "password": "guess" });
+        let (status, _) = post_json(&app_at(1000), "/auth", unknown, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    let (status, _) = post_json(&app_at(1000), "/auth", right.clone(), None).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    // Failures are audited with their reason and IP.
+    let attempts = sqlx::query!(
+        r#"SELECT reason, ip FROM "LoginAttempt"
+        WHERE email = 'guessed@test.com' ORDER BY attempted_at, created_at"#
+    )
+    .fetch_all(&test_db.pool)
+    .await
+    .unwrap();
+    let reasons: Vec<_> = attempts
+        .iter()
+        .map(|attempt| attempt.reason.as_deref().unwrap_or("succeeded"))
+        .collect();
+    assert_eq!(
+        reasons,
+        [
+            "wrong_password",
+            "wrong_password",
+            "throttled",
+            "wrong_password",
+            "throttled",
+            "succeeded",
+            "wrong_password",
+            "succeeded",
+            "throttled",
+        ]
+    );
+    assert_eq!(attempts[0].ip.as_deref(), Some("10.0.0.1"));
+}
+
+#[tokio::test]
+async fn test_graphql_rate_limit() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let mut config = Config::new();
+    config.graphql_rate_limit = 2;
+    config.graphql_rate_limit_period = chrono::Duration::hours(1);
+    config.trust_forwarded_for = true;
+    let app = Router::new()
+        .route(
+            "/",
+            get(|| async { "playground" }).merge(rate_limited(post(|| async { "ok" }), &config)),
+        )
+        .layer(Extension(test_db.pool.clone()))
+        .layer(Extension(test_db.clock.clone()))
+        .layer(Extension(config.clone()))
+        .layer(tower_cookies::CookieManagerLayer::new());
+    let request = |ip: &str, token: Option<&str>| {
+        let mut request = Request::post("/").header("x-forwarded-for", ip);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    for _ in 0..2 {
+        let response = app
+            .clone()
+            .oneshot(request("10.0.0.1", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = app
+        .clone()
+        .oneshot(request("10.0.0.1", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "3600");
+
+    // Other clients keep their own limit, and the playground is not limited.
+    let response = app
+        .clone()
+        .oneshot(request("10.0.0.2", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .clone()
+        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Logged in users are counted apart from their IP.
+    let (session, _) = Session::create(&test_db.pool, &test_db.admin.id, now)
+        .await
+        .unwrap();
+    let token = get_token(
+        &config,
+        Some(test_db.admin.id),
+        test_db.admin.email.clone(),
+        Some(session.id),
+    )
+    .unwrap();
+    for _ in 0..2 {
+        let response = app
+            .clone()
+            .oneshot(request("10.0.0.1", Some(&token)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = app
+        .clone()
+        .oneshot(request("10.0.0.3", Some(&token)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_two_factor() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+    let app = auth_app(&test_db, &config, Arc::default());
+    let association = test_db
+        .create_association_admin_member_treasury_fields(0, 0, 0)
+        .await
+        .association;
+    User::set_password(&test_db.pool, &test_db.admin.id, "admin password")
+        .await
+        .unwrap();
+    let credentials = json!({ "email": "default_user@test.com", "password": "admin password" });
+
+    let (_, login) = post_json(&app, "/auth", credentials.clone(), None).await;
+    let token = login["token"].as_str().unwrap();
+    let claims = validate_token(&config, &test_db.pool, token, now)
+        .await
+        .unwrap();
+    let schema = test_db.get_schema_for_tests(config.clone(), claims);
+
+    // Enrolling needs a code from the authenticator app to take effect.
+    let response = schema
+        .execute("mutation { enrollTwoFactor { secret otpauthUri } }")
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let data = response.data.into_json().unwrap();
+    let secret = data["enrollTwoFactor"]["secret"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    assert!(data["enrollTwoFactor"]["otpauthUri"]
+        .as_str()
+        .unwrap()
+        .starts_with("otpauth://totp/MyHood:default_user@test.com?secret="));
+    let response = schema
+        .execute(r#"mutation { confirmTwoFactor(code: "000000") }"#)
+        .await;
+    assert!(!response.errors.is_empty());
+    let code = totp_code(&secret, now).unwrap();
+    let response = schema
+        .execute(format!(
+            r#"mutation {{ confirmTwoFactor(code: "{}") }}"#,
+            code
+        ))
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let data = response.data.into_json().unwrap();
+    let recovery_codes: Vec<String> =
+        serde_json::from_value(data["confirmTwoFactor"].clone()).unwrap();
+    assert_eq!(recovery_codes.len(), 10);
+    assert!(recovery_codes.iter().all(|code| code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .count()
+        == 16));
+
+    // The session that enabled it counts as started with a second factor.
+    let response = schema
+        .execute(format!(
+            r#"mutation {{ updateAssociation(associationId: "{}", association: {{ requireTwoFactor: true }}) {{ requireTwoFactor }} }}"#,
+            association.id
+        ))
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+    // Passwords alone now only get a challenge.
+    let (status, challenge) = post_json(&app, "/auth", credentials.clone(), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(challenge["twoFactorRequired"], true);
+    assert!(challenge.get("token").is_none());
+    let pre_auth_token = challenge["preAuthToken"].as_str().unwrap();
+    assert!(validate_token(&config, &test_db.pool, pre_auth_token, now)
+        .await
+        .is_err());
+
+    // TOTP codes cannot be replayed, recovery codes work once.
+    let (status, _) = post_json(
+        &app,
+        "/auth/two-factor",
+        json!({ "preAuthToken": pre_auth_token, "code": code }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, login) = post_json(
+        &app,
+        "/auth/two-factor",
+        json!({ "preAuthToken": pre_auth_token, "code": recovery_codes[0].to_uppercase() }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(login["token"].as_str().is_some());
+    let (status, _) = post_json(
+        &app,
+        "/auth/two-factor",
+        json!({ "preAuthToken": pre_auth_token, "code": recovery_codes[0] }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let later = now + chrono::Duration::seconds(30);
+    let later_app = auth_app_at(
+        &test_db,
+        &config,
+        Arc::default(),
+        Arc::new(FixedClock(later)),
+    );
+    let (status, login) = post_json(
+        &later_app,
+        "/auth/two-factor",
+        json!({ "preAuthToken": pre_auth_token, "code": totp_code(&secret, later).unwrap() }),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let two_factor_claims = validate_token(
+        &config,
+        &test_db.pool,
+        login["token"].as_str().unwrap(),
+        test_db.clock.now(),
+    )
+    .await
+    .unwrap();
+
+    // Admins of the association must use a session with a second factor.
+    let query = format!(
+        r#"{{ association(id: "{}") {{ occupancy(from: "2024-01-01T00:00:00Z", to: "2024-01-02T00:00:00Z") {{ reservations }} }} }}"#,
+        association.id
+    );
+    let no_two_factor = Claims {
+        sub: Some(test_db.admin.id),
+        exp: 0,
+        sid: None,
+        email: test_db.admin.email.clone(),
+    };
+    let response = test_db
+        .get_schema_for_tests(config.clone(), no_two_factor)
+        .execute(&query)
+        .await;
+    assert!(!response.errors.is_empty());
+    let two_factor_schema = test_db.get_schema_for_tests(config.clone(), two_factor_claims);
+    let response = two_factor_schema.execute(&query).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+    // And cannot turn it off while the association requires it.
+    let response = two_factor_schema
+        .execute(format!(
+            r#"mutation {{ disableTwoFactor(code: "{}") }}"#,
+            recovery_codes[1]
+        ))
+        .await;
+    assert!(!response.errors.is_empty());
+    let response = two_factor_schema
+        .execute("{ loginMethods { twoFactor } }")
+        .await;
+    assert_eq!(
+        response.data.into_json().unwrap()["loginMethods"]["twoFactor"],
+        true
+    );
+}
+
+fn graphql_app(test_db: &TestDatabase, config: &Config, clock: Arc<dyn Clock>) -> Router {
+    Router::new()
+        .route("/", post(graphql_handler))
+        .layer(Extension(get_schema(test_db.pool.clone(), config.clone())))
+        .layer(Extension(test_db.pool.clone()))
+        .layer(Extension(clock))
+        .layer(Extension(config.clone()))
+        .layer(tower_cookies::CookieManagerLayer::new())
+}
+
+#[tokio::test]
+async fn test_api_tokens() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+    let app = graphql_app(&test_db, &config, test_db.clock.clone());
+    let association = test_db
+        .create_association_admin_member_treasury_fields(0, 0, 0)
+        .await
+        .association;
+    let admin_claims = Claims {
+        sub: Some(test_db.admin.id),
+        exp: 0,
+        sid: None,
+        email: test_db.admin.email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), admin_claims);
+    let create_token = |input: String| {
+        let schema = &schema;
+        async move {
+            let response = schema
+                .execute(format!(
+                    "mutation {{ createApiToken(input: {}) {{ token apiToken {{ id prefix }} }} }}",
+                    input
+                ))
+                .await;
+            assert!(response.errors.is_empty(), "{:?}", response.errors);
+            let data = response.data.into_json().unwrap();
+            let token = data["createApiToken"]["token"].as_str().unwrap().to_owned();
+            assert!(token.starts_with(
+                data["createApiToken"]["apiToken"]["prefix"]
+                    .as_str()
+                    .unwrap()
+            ));
+            token
+        }
+    };
+    let update_association = json!({
+        "query": format!(
+            r#"mutation {{ updateAssociation(associationId: "{}", association: {{ public: false }}) {{ id }} }}"#,
+            association.id
+        )
+    });
+
+    // Read-only tokens can query, and record when they were used.
+    let read_only = create_token(r#"{ name: "Reports" }"#.to_owned()).await;
+    assert!(read_only.starts_with("mhk_"));
+    let (status, body) = post_json(
+        &app,
+        "/",
+        json!({ "query": "{ apiTokens { name readOnly lastUsedAt } }" }),
+        Some(&read_only),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["data"]["apiTokens"][0]["name"], "Reports");
+    assert_eq!(body["data"]["apiTokens"][0]["readOnly"], true);
+    assert!(body["data"]["apiTokens"][0]["lastUsedAt"].is_string());
+    let (_, body) = post_json(&app, "/", update_association.clone(), Some(&read_only)).await;
+    assert_eq!(body["errors"][0]["message"], "This API token is read-only");
+
+    // Tokens limited to an association only act with the given roles.
+    let member_only = create_token(format!(
+        r#"{{ name: "Member", readOnly: false, associationId: "{}", roles: [MEMBER] }}"#,
+        association.id
+    ))
+    .await;
+    let (_, body) = post_json(&app, "/", update_association.clone(), Some(&member_only)).await;
+    assert!(body["errors"].is_array(), "{}", body);
+    let admin = create_token(format!(
+        r#"{{ name: "Admin", readOnly: false, associationId: "{}", roles: [ADMIN] }}"#,
+        association.id
+    ))
+    .await;
+    let (_, body) = post_json(&app, "/", update_association.clone(), Some(&admin)).await;
+    assert!(body["errors"].is_null(), "{}", body);
+
+    // Treasurer and admin mutations are limited in the same way, also in
+    // other associations the user is admin of.
+    let other_association = test_db
+        .create_association_admin_member_treasury_fields(0, 0, 0)
+        .await
+        .association;
+    let set_treasurer = |association_id| {
+        json!({
+            "query": format!(
+                r#"mutation {{ createAssociationTreasurer(userIdTreasurer: "{}", associationId: "{}", startDate: "2024-01-01", endDate: "2024-12-31") {{ userId }} }}"#,
+                test_db.admin.id, association_id
+            )
+        })
+    };
+    let (_, body) = post_json(&app, "/", set_treasurer(other_association.id), Some(&admin)).await;
+    assert!(body["errors"].is_array(), "{}", body);
+    let (_, body) = post_json(&app, "/", set_treasurer(association.id), Some(&member_only)).await;
+    assert!(body["errors"].is_array(), "{}", body);
+    let (_, body) = post_json(&app, "/", set_treasurer(association.id), Some(&admin)).await;
+    assert!(body["errors"].is_null(), "{}", body);
+
+    // Tokens cannot manage tokens or account credentials.
+    let (_, body) = post_json(
+        &app,
+        "/",
+        json!({ "query": r#"mutation { createApiToken(input: { name: "More" }) { token } }"# }),
+        Some(&admin),
+    )
+    .await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        "Not allowed with an API token"
+    );
+    // Nor mint calendar feed tokens, which outlive the API token.
+    let (_, body) = post_json(
+        &app,
+        "/",
+        json!({ "query": "mutation { regenerateCalendarFeedToken }" }),
+        Some(&admin),
+    )
+    .await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        "Not allowed with an API token"
+    );
+    // Nor the user's documents and address.
+    let (_, body) = post_json(
+        &app,
+        "/",
+        json!({ "query": r#"mutation { setDocument(input: { kind: CPF, number: "52998224725" }) { id } }"# }),
+        Some(&admin),
+    )
+    .await;
+    assert_eq!(
+        body["errors"][0]["message"],
+        "Not allowed with an API token"
+    );
+
+    // Revoked and expired tokens are refused.
+    let response = schema.execute("{ apiTokens { id name } }").await;
+    let data = response.data.into_json().unwrap();
+    let admin_token_id = data["apiTokens"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|token| token["name"] == "Admin")
+        .unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let response = schema
+        .execute(format!(
+            r#"mutation {{ revokeApiToken(id: "{}") }}"#,
+            admin_token_id
+        ))
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let (status, _) = post_json(&app, "/", update_association.clone(), Some(&admin)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let later = now + chrono::Duration::days(91);
+    let later_app = graphql_app(&test_db, &config, Arc::new(FixedClock(later)));
+    let (status, _) = post_json(
+        &later_app,
+        "/",
+        json!({ "query": "{ apiTokens { name } }" }),
+        Some(&read_only),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let response = schema
+        .execute(r#"mutation { createApiToken(input: { name: "Forever", expiresAt: "2030-01-01T00:00:00Z" }) { token } }"#)
+        .await;
+    assert!(!response.errors.is_empty());
+}
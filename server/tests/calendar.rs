@@ -0,0 +1,169 @@
+mod queries;
+mod test_utils;
+
+use axum::{body::Body, routing::get, Extension, Router};
+use chrono::TimeZone;
+use http::{Request, StatusCode};
+#[cfg(test)]
+use my_hood_server::config::Config;
+use my_hood_server::{
+    calendar::{
+        ics::{field_calendar_handler, user_calendar_handler},
+        model::CalendarFeedToken,
+    },
+    field::model::FieldReservation,
+    token::Claims,
+};
+use queries::create_reservation;
+use test_utils::TestDatabase;
+use tower::ServiceExt;
+
+fn calendar_app(test_db: &TestDatabase) -> Router {
+    Router::new()
+        .route(
+            "/calendar/fields/{field_id}/reservations.ics",
+            get(field_calendar_handler),
+        )
+        .route("/calendar/me/reservations.ics", get(user_calendar_handler))
+        .layer(Extension(test_db.pool.clone()))
+        .layer(Extension(test_db.clock.clone()))
+}
+
+async fn get_calendar(app: &Router, uri: String) -> (StatusCode, String) {
+    let response = app
+        .clone()
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn test_reservation_calendar_feeds() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(2, 0, 1)
+        .await;
+    let field_id = test_data.fields[0].id;
+
+    let user_0_claim = Claims {
+        sub: Some(test_data.members[0].id),
+        exp: 0,
+        sid: None,
+        email: test_data.members[0].email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), user_0_claim);
+
+    let request = async_graphql::Request::new(create_reservation(
+        field_id,
+        "Beach tennis, doubles".to_owned(),
+        "2024-01-01T10:00:00Z".parse().unwrap(),
+        "2024-01-01T11:00:00Z".parse().unwrap(),
+    ));
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let reservation_id = response
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON")["createFieldReservation"]["id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+
+    let response = schema
+        .execute(async_graphql::Request::new(
+            "mutation { regenerateCalendarFeedToken }",
+        ))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let token = response
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON")["regenerateCalendarFeedToken"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+
+    let app = calendar_app(&test_db);
+
+    let (status, calendar) = get_calendar(
+        &app,
+        format!("/calendar/me/reservations.ics?token={}", token),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(calendar.starts_with("BEGIN:VCALENDAR\r\n"));
+    assert!(calendar.contains(&format!("UID:{}@myhood", reservation_id)));
+    assert!(calendar.contains("DTSTART:20240101T100000Z"));
+    assert!(calendar.contains("DESCRIPTION:Beach tennis\\, doubles"));
+    assert!(calendar.contains("STATUS:CONFIRMED"));
+
+    let (status, _) = get_calendar(
+        &app,
+        format!(
+            "/calendar/fields/{}/reservations.ics?token=invalid",
+            field_id
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Users outside the association cannot read the field feed.
+    let outsider = test_db.create_logins(1).await.remove(0);
+    let outsider_token = CalendarFeedToken::regenerate(&test_db.pool, &outsider.id)
+        .await
+        .unwrap();
+    let (status, _) = get_calendar(
+        &app,
+        format!(
+            "/calendar/fields/{}/reservations.ics?token={}",
+            field_id, outsider_token
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Cancelled reservations stay in the feed as cancelled events.
+    let delete_reservation_query = format!(
+        r#"mutation {{ deleteFieldReservation(id: "{}") {{ id }} }}"#,
+        reservation_id
+    );
+    let response = schema
+        .execute(async_graphql::Request::new(delete_reservation_query))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let (status, calendar) = get_calendar(
+        &app,
+        format!(
+            "/calendar/fields/{}/reservations.ics?token={}",
+            field_id, token
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(calendar.contains(&format!("UID:{}@myhood", reservation_id)));
+    assert!(calendar.contains("STATUS:CANCELLED"));
+
+    // The sequence follows the last change, so calendars apply updates.
+    let reservation = FieldReservation::get(&test_db.pool, &reservation_id.parse().unwrap())
+        .await
+        .unwrap();
+    assert!(reservation.updated_at > reservation.created_at);
+    assert!(calendar.contains(&format!(
+        "SEQUENCE:{}",
+        reservation.updated_at.and_utc().timestamp()
+    )));
+}
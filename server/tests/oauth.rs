@@ -0,0 +1,709 @@
+mod queries;
+mod test_utils;
+
+use axum::{
+    body::Body,
+    extract::State,
+    routing::{get, post},
+    Extension, Form, Json, Router,
+};
+use chrono::TimeZone;
+use http::{header, Request, Response, StatusCode};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+#[cfg(test)]
+use my_hood_server::config::Config;
+use my_hood_server::{
+    config::OidcProviderConfig,
+    oauth::{get_token, oauth_callback_handler, oauth_login_handler, oauth_token_handler},
+    oidc::OidcRegistry,
+    session::model::Session,
+    token::{validate_token, Claims},
+    user::{
+        identity::UserIdentity,
+        model::{User, UserInput},
+        registration::Registration,
+    },
+};
+use serde_json::{json, Value};
+use test_utils::TestDatabase;
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+
+const CLIENT_ID: &str = "my-hood";
+
+/// Ed25519 public key of `fixtures/jwt_ed25519.pem`.
+const PUBLIC_KEY_X: &str = "t-YheiGOlrYx7As-4y8rXuLBd6nTGoNG3jpTaeJtT8Q";
+
+/// The mock provider takes codes of the form `nonce:subject:email:verified`,
+/// so each test chooses what the ID token says.
+async fn mock_token(State(issuer): State<String>, Form(form): Form<Value>) -> Json<Value> {
+    assert_eq!(form["grant_type"], "authorization_code");
+    assert!(form["code_verifier"].is_string());
+    let code = form["code"].as_str().unwrap();
+    let mut parts = code.split(':');
+    let (nonce, subject, email, verified) = (
+        parts.next().unwrap(),
+        parts.next().unwrap(),
+        parts.next().unwrap(),
+        parts.next().unwrap(),
+    );
+
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some("mock-key".to_owned());
+    let key = EncodingKey::from_ed_pem(include_bytes!("fixtures/jwt_ed25519.pem")).unwrap();
+    let now = chrono::Utc::now().timestamp();
+    let id_token = encode(
+        &header,
+        &json!({
+            "iss": issuer,
+            "aud": CLIENT_ID,
+            "sub": subject,
+            "email": email,
+            "email_verified": verified == "true",
+            "nonce": nonce,
+            "name": format!("User {}", subject),
+            "picture": format!("https://pictures.example.com/{}.png", subject),
+            "iat": now,
+            "exp": now + 600,
+        }),
+        &key,
+    )
+    .unwrap();
+    Json(json!({
+        "access_token": "mock-access-token",
+        "token_type": "Bearer",
+        "id_token": id_token,
+    }))
+}
+
+/// Serves discovery, keys and tokens like an OpenID Connect provider.
+async fn start_mock_provider() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let discovery = json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+    });
+    let app = Router::new()
+        .route(
+            "/.well-known/openid-configuration",
+            get(move || async move { Json(discovery) }),
+        )
+        .route(
+            "/jwks",
+            get(|| async {
+                Json(json!({ "keys": [{
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "x": PUBLIC_KEY_X,
+                    "kid": "mock-key",
+                    "alg": "EdDSA",
+                    "use": "sig",
+                }] }))
+            }),
+        )
+        .route("/token", post(mock_token))
+        .with_state(issuer.clone());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    issuer
+}
+
+async fn get_request(app: &Router, uri: &str, cookies: &str) -> Response<Body> {
+    app.clone()
+        .oneshot(
+            Request::get(uri)
+                .header(header::COOKIE, cookies)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+fn location(response: &Response<Body>) -> reqwest::Url {
+    let location = response.headers()[header::LOCATION].to_str().unwrap();
+    reqwest::Url::parse(location).unwrap()
+}
+
+fn query_param(url: &reqwest::Url, name: &str) -> String {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+        .unwrap()
+}
+
+fn mock_app(test_db: &TestDatabase, issuer: &str) -> (Router, Config) {
+    let mut config = Config::new();
+    config.oauth_providers = vec![OidcProviderConfig {
+        name: "mock".to_owned(),
+        issuer: issuer.to_owned(),
+        client_id: CLIENT_ID.to_owned(),
+        client_secret: "mock-secret".to_owned(),
+        scopes: vec!["openid".to_owned(), "email".to_owned()],
+        redirect_url: "http://localhost:8000/oauth/mock/callback".to_owned(),
+    }];
+    let app = Router::new()
+        .route("/oauth/{provider}/login", get(oauth_login_handler))
+        .route("/oauth/{provider}/callback", get(oauth_callback_handler))
+        .route("/oauth/token", post(oauth_token_handler))
+        .layer(Extension(test_db.pool.clone()))
+        .layer(Extension(test_db.clock.clone()))
+        .layer(Extension(OidcRegistry::new(&config.oauth_providers)))
+        .layer(Extension(config.clone()))
+        .layer(tower_cookies::CookieManagerLayer::new());
+    (app, config)
+}
+
+/// Starts a login and returns the state, the nonce and the cookies to send
+/// back to the callback.
+async fn start_login(app: &Router, uri: &str, issuer: &str) -> (String, String, String) {
+    start_login_with_cookies(app, uri, issuer, "").await
+}
+
+async fn start_login_with_cookies(
+    app: &Router,
+    uri: &str,
+    issuer: &str,
+    cookies: &str,
+) -> (String, String, String) {
+    let response = get_request(app, uri, cookies).await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let url = location(&response);
+    assert!(url.as_str().starts_with(&format!("{}/authorize", issuer)));
+    assert_eq!(query_param(&url, "client_id"), CLIENT_ID);
+    assert_eq!(query_param(&url, "scope"), "openid email");
+    let cookies = set_cookies(&response)
+        .iter()
+        .map(|cookie| cookie.split(';').next().unwrap())
+        .filter(|cookie| !cookie.ends_with('='))
+        .collect::<Vec<_>>()
+        .join("; ");
+    (
+        query_param(&url, "state"),
+        query_param(&url, "nonce"),
+        cookies,
+    )
+}
+
+/// Logs in through the mock provider, which answers with `id_token` as
+/// `subject:email:verified`.
+async fn oauth_login(app: &Router, uri: &str, issuer: &str, id_token: &str) -> Response<Body> {
+    callback(app, start_login(app, uri, issuer).await, id_token).await
+}
+
+/// Links a provider account to the user logged in with `auth_token`.
+async fn oauth_link(
+    app: &Router,
+    auth_token: &str,
+    issuer: &str,
+    id_token: &str,
+) -> Response<Body> {
+    let auth_cookie = format!("auth_token={}", auth_token);
+    let login =
+        start_login_with_cookies(app, "/oauth/mock/login?link=true", issuer, &auth_cookie).await;
+    callback(app, login, id_token).await
+}
+
+async fn callback(
+    app: &Router,
+    (state, nonce, cookies): (String, String, String),
+    id_token: &str,
+) -> Response<Body> {
+    get_request(
+        app,
+        &format!(
+            "/oauth/mock/callback?code={}:{}&state={}",
+            nonce, id_token, state
+        ),
+        &cookies,
+    )
+    .await
+}
+
+/// Exchanges the code of an OAuth login, returning the status, the body and
+/// the cookies set.
+async fn exchange_code(app: &Router, code: &str) -> (StatusCode, Value, Vec<String>) {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/oauth/token")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({ "code": code }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let cookies = set_cookies(&response);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (
+        status,
+        serde_json::from_slice(&body).unwrap_or(Value::Null),
+        cookies,
+    )
+}
+
+fn set_cookies(response: &Response<Body>) -> Vec<String> {
+    response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .map(|cookie| cookie.to_str().unwrap().to_owned())
+        .collect()
+}
+
+async fn logged_in_user(
+    app: &Router,
+    config: &Config,
+    test_db: &TestDatabase,
+    response: &Response<Body>,
+) -> Claims {
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let code = query_param(&location(response), "code");
+    let (status, body, _) = exchange_code(app, &code).await;
+    assert_eq!(status, StatusCode::OK);
+    let token = body["token"].as_str().unwrap();
+    validate_token(config, &test_db.pool, token, test_db.clock.now())
+        .await
+        .unwrap()
+}
+
+/// Links the mock account `admin-sub` to the admin, as they would from their
+/// account settings.
+async fn link_admin(app: &Router, config: &Config, test_db: &TestDatabase, issuer: &str) -> Claims {
+    let (session, _) = Session::create(&test_db.pool, &test_db.admin.id, test_db.clock.now())
+        .await
+        .unwrap();
+    let admin_token = get_token(
+        config,
+        Some(test_db.admin.id),
+        test_db.admin.email.clone(),
+        Some(session.id),
+    )
+    .unwrap();
+    let response = oauth_link(
+        app,
+        &admin_token,
+        issuer,
+        "admin-sub:default_user@test.com:true",
+    )
+    .await;
+    logged_in_user(app, config, test_db, &response).await
+}
+
+/// The registration of a new user, from the registration token the code of
+/// the login is exchanged for.
+async fn pending_registration(
+    app: &Router,
+    config: &Config,
+    response: &Response<Body>,
+) -> Registration {
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let code = query_param(&location(response), "code");
+    let (status, body, cookies) = exchange_code(app, &code).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["registrationRequired"], true);
+    assert!(cookies.is_empty());
+    Registration::from_token(config, body["registrationToken"].as_str().unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn test_oidc_login() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let issuer = start_mock_provider().await;
+    let (app, config) = mock_app(&test_db, &issuer);
+
+    let response = get_request(&app, "/oauth/unknown/login", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // A verified email of a registered user does not log them in until they
+    // link the account.
+    let response = oauth_login(
+        &app,
+        "/oauth/mock/login",
+        &issuer,
+        "admin-sub:default_user@test.com:true",
+    )
+    .await;
+    let registration = pending_registration(&app, &config, &response).await;
+    assert_eq!(registration.subject.as_deref(), Some("admin-sub"));
+    assert!(UserIdentity::find(&test_db.pool, "mock", "admin-sub")
+        .await
+        .unwrap()
+        .is_none());
+
+    link_admin(&app, &config, &test_db, &issuer).await;
+    let response = oauth_login(
+        &app,
+        "/oauth/mock/login",
+        &issuer,
+        "admin-sub:default_user@test.com:true",
+    )
+    .await;
+    let claims = logged_in_user(&app, &config, &test_db, &response).await;
+    assert_eq!(claims.sub, Some(test_db.admin.id));
+    assert!(claims.sid.is_some());
+
+    // Unverified emails are ignored.
+    let response = oauth_login(
+        &app,
+        "/oauth/mock/login",
+        &issuer,
+        "other-sub:default_user@test.com:false",
+    )
+    .await;
+    let registration = pending_registration(&app, &config, &response).await;
+    assert_eq!(registration.email, None);
+
+    // ID tokens issued for another login are rejected.
+    let (state, _, cookies) = start_login(&app, "/oauth/mock/login", &issuer).await;
+    let response = get_request(
+        &app,
+        &format!(
+            "/oauth/mock/callback?code=other-nonce:admin-sub:default_user@test.com:true&state={}",
+            state
+        ),
+        &cookies,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // So are callbacks without the login state.
+    let (_, nonce, cookies) = start_login(&app, "/oauth/mock/login", &issuer).await;
+    let response = get_request(
+        &app,
+        &format!(
+            "/oauth/mock/callback?code={}:admin-sub:default_user@test.com:true&state=forged",
+            nonce
+        ),
+        &cookies,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_linked_identities() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let issuer = start_mock_provider().await;
+    let (app, config) = mock_app(&test_db, &issuer);
+
+    let admin_claims = link_admin(&app, &config, &test_db, &issuer).await;
+    assert_eq!(admin_claims.sub, Some(test_db.admin.id));
+
+    // Later logins find the user by subject, whatever the email.
+    let response = oauth_login(
+        &app,
+        "/oauth/mock/login",
+        &issuer,
+        "admin-sub:changed@test.com:false",
+    )
+    .await;
+    let claims = logged_in_user(&app, &config, &test_db, &response).await;
+    assert_eq!(claims.sub, Some(test_db.admin.id));
+
+    let admin_schema = test_db.get_schema_for_tests(config.clone(), admin_claims);
+    let response = admin_schema
+        .execute("query { loginMethods { password identities { id provider subject email } } }")
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let json = response.data.into_json().unwrap();
+    assert_eq!(json["loginMethods"]["password"], false);
+    let identities = json["loginMethods"]["identities"].as_array().unwrap();
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0]["provider"], "mock");
+    assert_eq!(identities[0]["subject"], "admin-sub");
+    assert_eq!(identities[0]["email"], "default_user@test.com");
+    let admin_identity = identities[0]["id"].as_str().unwrap().to_owned();
+
+    // The only login method cannot be removed.
+    let response = admin_schema
+        .execute(format!(
+            r#"mutation {{ unlinkIdentity(id: "{}") }}"#,
+            admin_identity
+        ))
+        .await;
+    assert!(!response.errors.is_empty());
+
+    // A password user links a provider account with a different email.
+    let user = User::create(
+        &test_db.pool,
+        UserInput {
+            name: Some("Linking User".to_owned()),
+            birthday: "2000-01-01".parse().unwrap(),
+            address: "Rua A nr 1".to_owned(),
+            email: Some("linking@test.com".to_owned()),
+            password_hash: Some(bcrypt::hash("secret", 4).unwrap()),
+            uses_whatsapp: false,
+            identities: None,
+            personal_phone: None,
+            commercial_phone: None,
+            activity: None,
+            profile_url: None,
+        },
+    )
+    .await
+    .unwrap();
+    let (session, _) = Session::create(&test_db.pool, &user.id, now).await.unwrap();
+    let user_token =
+        get_token(&config, Some(user.id), user.email.clone(), Some(session.id)).unwrap();
+
+    // Linking needs a logged in user, the token is not part of the URL.
+    let response = get_request(&app, "/oauth/mock/login?link=true", "").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = get_request(&app, "/oauth/mock/login?link=true", "auth_token=invalid").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = oauth_link(
+        &app,
+        &user_token,
+        &issuer,
+        "user-sub:elsewhere@test.com:false",
+    )
+    .await;
+    let claims = logged_in_user(&app, &config, &test_db, &response).await;
+    assert_eq!(claims.sub, Some(user.id));
+    let response = oauth_login(
+        &app,
+        "/oauth/mock/login",
+        &issuer,
+        "user-sub:elsewhere@test.com:false",
+    )
+    .await;
+    let user_claims = logged_in_user(&app, &config, &test_db, &response).await;
+    assert_eq!(user_claims.sub, Some(user.id));
+
+    // Accounts linked to someone else cannot be linked again.
+    let response = oauth_link(
+        &app,
+        &user_token,
+        &issuer,
+        "admin-sub:default_user@test.com:true",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // Methods can be removed while another one remains.
+    let user_schema = test_db.get_schema_for_tests(config.clone(), user_claims);
+    let response = user_schema.execute("mutation { removePassword }").await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let identities = UserIdentity::read_for_user(&test_db.pool, &user.id)
+        .await
+        .unwrap();
+    assert_eq!(identities.len(), 1);
+    let response = user_schema
+        .execute(format!(
+            r#"mutation {{ unlinkIdentity(id: "{}") }}"#,
+            identities[0].id
+        ))
+        .await;
+    assert!(!response.errors.is_empty());
+    let response = user_schema
+        .execute(r#"mutation { setPassword(password: "new password") }"#)
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let response = user_schema
+        .execute(format!(
+            r#"mutation {{ unlinkIdentity(id: "{}") }}"#,
+            identities[0].id
+        ))
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+    // Users cannot unlink identities of others.
+    let response = user_schema
+        .execute(format!(
+            r#"mutation {{ unlinkIdentity(id: "{}") }}"#,
+            admin_identity
+        ))
+        .await;
+    assert!(!response.errors.is_empty());
+}
+
+#[tokio::test]
+async fn test_oauth_code_exchange() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let issuer = start_mock_provider().await;
+    let (app, config) = mock_app(&test_db, &issuer);
+
+    // Only pages of the web app are allowed as redirects.
+    let response = get_request(
+        &app,
+        "/oauth/mock/login?redirect=https%3A%2F%2Fevil.example.com%2F",
+        "",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    link_admin(&app, &config, &test_db, &issuer).await;
+    let response = oauth_login(
+        &app,
+        "/oauth/mock/login?redirect=http%3A%2F%2Flocalhost%3A8081%2Fwelcome",
+        &issuer,
+        "admin-sub:default_user@test.com:true",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let url = location(&response);
+    assert!(url
+        .as_str()
+        .starts_with("http://localhost:8081/welcome?code="));
+    assert!(!url.query_pairs().any(|(key, _)| key == "token"));
+
+    // The login state cookies are cleared by the callback.
+    let cleared = set_cookies(&response);
+    for name in ["oauth_state", "pkce_verifier", "oauth_nonce"] {
+        assert!(
+            cleared
+                .iter()
+                .any(|cookie| cookie.starts_with(&format!("{}=;", name))
+                    && cookie.contains("Max-Age=0")),
+            "{} not cleared: {:?}",
+            name,
+            cleared
+        );
+    }
+
+    // The code gives tokens once, also as secure http-only cookies.
+    let code = query_param(&url, "code");
+    let (status, body, cookies) = exchange_code(&app, &code).await;
+    assert_eq!(status, StatusCode::OK);
+    let claims = validate_token(&config, &test_db.pool, body["token"].as_str().unwrap(), now)
+        .await
+        .unwrap();
+    assert_eq!(claims.sub, Some(test_db.admin.id));
+    assert!(body["refreshToken"].as_str().is_some());
+    let auth_cookie = cookies
+        .iter()
+        .find(|cookie| cookie.starts_with("auth_token="))
+        .unwrap();
+    assert!(auth_cookie.contains("HttpOnly"));
+    assert!(auth_cookie.contains("Secure"));
+    assert!(auth_cookie.contains("SameSite=Lax"));
+    let (status, _, _) = exchange_code(&app, &code).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _, _) = exchange_code(&app, "forged").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Accounts deleted before the exchange get no session.
+    let response = oauth_login(
+        &app,
+        "/oauth/mock/login",
+        &issuer,
+        "admin-sub:default_user@test.com:true",
+    )
+    .await;
+    let code = query_param(&location(&response), "code");
+    sqlx::query!(
+        r#"UPDATE "User" SET deleted = true WHERE id = $1"#,
+        test_db.admin.id
+    )
+    .execute(&test_db.pool)
+    .await
+    .unwrap();
+    let (status, body, _) = exchange_code(&app, &code).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body.get("token").is_none());
+}
+
+#[tokio::test]
+async fn test_oauth_registration() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let issuer = start_mock_provider().await;
+    let (app, config) = mock_app(&test_db, &issuer);
+
+    // New users get a registration prefilled with their provider profile.
+    let response = oauth_login(
+        &app,
+        "/oauth/mock/login",
+        &issuer,
+        "new-sub:newcomer@test.com:true",
+    )
+    .await;
+    let registration = pending_registration(&app, &config, &response).await;
+    assert_eq!(registration.email.as_deref(), Some("newcomer@test.com"));
+    assert_eq!(registration.provider.as_deref(), Some("mock"));
+    assert_eq!(registration.subject.as_deref(), Some("new-sub"));
+    assert_eq!(registration.name.as_deref(), Some("User new-sub"));
+
+    // Registration tokens give no access to anything else.
+    let claims = Claims {
+        sub: None,
+        exp: 0,
+        sid: None,
+        email: None,
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), claims);
+    let response = schema
+        .execute(async_graphql::Request::new("{ apiTokens { id } }").data(registration.clone()))
+        .await;
+    assert!(!response.errors.is_empty());
+
+    let response = schema
+        .execute(
+            async_graphql::Request::new(
+                r#"mutation { completeRegistration(userInput: {
+                    birthday: "2000-01-01", address: "Rua A nr 1", usesWhatsapp: true
+                }) { user { id name email profileUrl } token refreshToken } }"#,
+            )
+            .data(registration.clone()),
+        )
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let json = response.data.into_json().unwrap();
+    let registered = &json["completeRegistration"];
+    assert_eq!(registered["user"]["name"], "User new-sub");
+    assert_eq!(registered["user"]["email"], "newcomer@test.com");
+    assert_eq!(
+        registered["user"]["profileUrl"],
+        "https://pictures.example.com/new-sub.png"
+    );
+    assert!(registered["refreshToken"].as_str().is_some());
+
+    // The new user is logged in, and the provider account is linked.
+    let claims = validate_token(
+        &config,
+        &test_db.pool,
+        registered["token"].as_str().unwrap(),
+        now,
+    )
+    .await
+    .unwrap();
+    let user_id = claims.sub.unwrap();
+    assert_eq!(registered["user"]["id"], user_id.to_string());
+    let identity = UserIdentity::find(&test_db.pool, "mock", "new-sub")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(identity.user_id, user_id);
+
+    let response = oauth_login(
+        &app,
+        "/oauth/mock/login",
+        &issuer,
+        "new-sub:newcomer@test.com:true",
+    )
+    .await;
+    let claims = logged_in_user(&app, &config, &test_db, &response).await;
+    assert_eq!(claims.sub, Some(user_id));
+
+    // Only once.
+    let response = schema
+        .execute(
+            async_graphql::Request::new(
+                r#"mutation { completeRegistration(userInput: {
+                    birthday: "2000-01-01", address: "Rua A nr 1", usesWhatsapp: true
+                }) { token } }"#,
+            )
+            .data(registration),
+        )
+        .await;
+    assert!(!response.errors.is_empty());
+}
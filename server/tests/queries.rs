@@ -1,44 +1,57 @@
 #![allow(dead_code)]
 
 use chrono::{DateTime, NaiveDate};
+use my_hood_server::user::registration::Registration;
 use uuid::Uuid;
 
 pub fn create_users(n_users: u32) -> Vec<String> {
     (0..n_users)
-        .into_iter()
         .map(|id| {
             format!(
                 r#"mutation {{
-                    createOwnUser(userInput: {{
+                    completeRegistration(userInput: {{
                         name: "Test User {}",
-                        email: "test{}@gmail.com",
                         birthday: "2012-11-19",
                         address: "Rua A nr 1",
                         usesWhatsapp: true
                     }})
                     {{
-                        id,
-                        name,
-                        birthday,
-                        address,
-                        activity,
-                        email,
-                        personalPhone,
-                        commercialPhone,
-                        usesWhatsapp,
-                        identities,
-                        profileUrl,
-                        createdAt,
-                        updatedAt
+                        user {{
+                            id,
+                            name,
+                            birthday,
+                            address,
+                            activity,
+                            email,
+                            personalPhone,
+                            commercialPhone,
+                            usesWhatsapp,
+                            identities,
+                            profileUrl,
+                            createdAt,
+                            updatedAt
+                        }}
                     }}
                 }}
                 "#,
-                id, id
+                id
             )
         })
         .collect::<Vec<String>>()
 }
 
+/// Registration of the user created by the `create_users` mutation at the
+/// same index.
+pub fn test_registration(id: usize) -> Registration {
+    Registration {
+        email: Some(format!("test{}@gmail.com", id)),
+        provider: None,
+        subject: None,
+        name: None,
+        picture_url: None,
+    }
+}
+
 pub fn get_user(user_id: Uuid) -> String {
     format!(
         r#"query {{
@@ -107,7 +120,6 @@ pub fn create_fields(n_fields: u32, association_id: Uuid) -> Vec<String> {
     let json_rule = r#"{\"reservations_start_at_time_utc\":\"06:00:00\",\"max_duration_minutes\":60,\"max_reservations_per_period\":1,\"reservation_period\":\"Daily\"}"#;
 
     (0..n_fields)
-        .into_iter()
         .map(|id| {
             format!(
                 r#"mutation {{
@@ -126,6 +138,7 @@ pub fn create_fields(n_fields: u32, association_id: Uuid) -> Vec<String> {
                         reservationRules,
                         latitude,
                         longitude,
+                        capacity,
                         createdAt,
                         updatedAt
                     }}
@@ -165,6 +178,83 @@ pub fn create_reservation(
     )
 }
 
+pub fn join_waitlist(
+    field_id: Uuid,
+    description: String,
+    start_date: DateTime<chrono::Utc>,
+    end_date: DateTime<chrono::Utc>,
+) -> String {
+    format!(
+        r#"mutation {{
+            joinFieldWaitlist(fieldReservationInput: {{
+                fieldId: "{}",
+                description: "{}",
+                startDate: "{}",
+                endDate: "{}"
+            }})
+            {{
+                id,
+                fieldId,
+                userId,
+                reservationId
+            }}
+        }}"#,
+        field_id, description, start_date, end_date
+    )
+}
+
+pub fn field_reservations(
+    association_id: Uuid,
+    from_date: DateTime<chrono::Utc>,
+    to_date: DateTime<chrono::Utc>,
+) -> String {
+    format!(
+        r#"query {{
+            association(id: "{}") {{
+                fields {{
+                    id,
+                    reservations(fromDateTime: "{}", toDateTime: "{}") {{
+                        id,
+                        userId,
+                        startDate,
+                        endDate,
+                        seriesId
+                    }}
+                }}
+            }}
+        }}"#,
+        association_id, from_date, to_date
+    )
+}
+
+pub fn create_reservation_series(
+    field_id: Uuid,
+    weekdays: &str,
+    start_time: &str,
+    end_time: &str,
+    start_on: NaiveDate,
+    until: NaiveDate,
+) -> String {
+    format!(
+        r#"mutation {{
+            createFieldReservationSeries(fieldReservationSeriesInput: {{
+                fieldId: "{}",
+                description: "Training group",
+                weekdays: [{}],
+                startTime: "{}",
+                endTime: "{}",
+                startOn: "{}",
+                until: "{}"
+            }})
+            {{
+                id,
+                weekdays
+            }}
+        }}"#,
+        field_id, weekdays, start_time, end_time, start_on, until
+    )
+}
+
 pub fn create_association(
     name: String,
     neighborhood: String,
@@ -190,6 +280,8 @@ pub fn create_association(
             address,
             identity,
             public,
+            timezone,
+            requireTwoFactor,
             createdAt,
             updatedAt,
         }}
@@ -1,18 +1,30 @@
 mod queries;
 mod test_utils;
 
-use chrono::TimeZone;
+use std::sync::Arc;
+
+use chrono::{NaiveDate, NaiveTime, TimeZone};
 #[cfg(test)]
 use my_hood_server::config::Config;
-use my_hood_server::{field::model::FieldReservation, token::Claims};
-use queries::create_reservation;
-use test_utils::TestDatabase;
+use my_hood_server::{
+    field::{
+        model::{Field, FieldInput, FieldReservation},
+        moderation::FieldReservationModeration,
+        rules::{ReservationPeriod, ReservationRule, ReservationRules},
+    },
+    relations::model::Role,
+    session::model::Session,
+    token::Claims,
+    transaction::model::Charge,
+    Clock,
+};
+use queries::{create_reservation, create_reservation_series, field_reservations, join_waitlist};
+use test_utils::{FixedClock, TestDatabase};
+use uuid::Uuid;
 
 #[tokio::test]
 async fn test_create_reservation() {
-    let now = chrono::Utc
-        .with_ymd_and_hms(2024, 01, 01, 07, 0, 0)
-        .unwrap();
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
     let test_db = TestDatabase::new(now).await;
     let config = Config::new();
 
@@ -28,6 +40,7 @@ async fn test_create_reservation() {
     let user_0_claim = Claims {
         sub: Some(test_data.members[0].id),
         exp: 0,
+        sid: None,
         email: test_data.members[0].email.clone(),
     };
     let schema = test_db.get_schema_for_tests(config.clone(), user_0_claim);
@@ -96,9 +109,7 @@ async fn test_create_reservation() {
 
 #[tokio::test]
 async fn test_create_reservation_before_rule_time() {
-    let now = chrono::Utc
-        .with_ymd_and_hms(2024, 01, 01, 05, 0, 0)
-        .unwrap();
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 5, 0, 0).unwrap();
     let test_db = TestDatabase::new(now).await;
     let config = Config::new();
 
@@ -114,6 +125,7 @@ async fn test_create_reservation_before_rule_time() {
     let user_0_claim = Claims {
         sub: Some(test_data.members[0].id),
         exp: 0,
+        sid: None,
         email: test_data.members[0].email.clone(),
     };
     let schema = test_db.get_schema_for_tests(config.clone(), user_0_claim);
@@ -127,9 +139,7 @@ async fn test_create_reservation_before_rule_time() {
 
 #[tokio::test]
 async fn test_create_reservation_tomorrow() {
-    let now = chrono::Utc
-        .with_ymd_and_hms(2024, 01, 01, 07, 0, 0)
-        .unwrap();
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
     let test_db = TestDatabase::new(now).await;
     let config = Config::new();
 
@@ -145,6 +155,7 @@ async fn test_create_reservation_tomorrow() {
     let user_0_claim = Claims {
         sub: Some(test_data.members[0].id),
         exp: 0,
+        sid: None,
         email: test_data.members[0].email.clone(),
     };
     let schema = test_db.get_schema_for_tests(config.clone(), user_0_claim);
@@ -155,3 +166,1518 @@ async fn test_create_reservation_tomorrow() {
     let response = schema.execute(request).await;
     assert!(response.is_err());
 }
+
+#[tokio::test]
+async fn test_waitlist_promotion() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(10, 2, 1)
+        .await;
+
+    let field_id = test_data.fields[0].id;
+    let description = "Test reservation for beach tennis".to_owned();
+    let start_date = "2024-01-01T10:00:00Z".to_string().parse().unwrap();
+    let end_date = "2024-01-01T11:00:00Z".to_string().parse().unwrap();
+
+    let user_0_claim = Claims {
+        sub: Some(test_data.members[0].id),
+        exp: 0,
+        sid: None,
+        email: test_data.members[0].email.clone(),
+    };
+    let user_1_claim = Claims {
+        sub: Some(test_data.members[1].id),
+        exp: 0,
+        sid: None,
+        email: test_data.members[1].email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), user_0_claim);
+
+    let reservation_query = create_reservation(field_id, description.clone(), start_date, end_date);
+    let response = schema
+        .execute(async_graphql::Request::new(reservation_query.clone()))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let reservation = serde_json::from_value::<FieldReservation>(
+        response
+            .data
+            .into_json()
+            .expect("Failed to convert response to JSON")["createFieldReservation"]
+            .clone(),
+    )
+    .expect("Failed to deserialize reservation");
+
+    // The slot is taken, so user 1 cannot reserve it but can queue for it.
+    let request = async_graphql::Request::new(reservation_query).data(user_1_claim.clone());
+    assert!(schema.execute(request).await.is_err());
+
+    let request = async_graphql::Request::new(join_waitlist(
+        field_id,
+        description.clone(),
+        start_date,
+        end_date,
+    ))
+    .data(user_1_claim.clone());
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let response = response
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    assert!(response["joinFieldWaitlist"]["reservationId"].is_null());
+
+    // Cancelling the reservation promotes user 1 into the slot.
+    let delete_reservation_query = format!(
+        r#"mutation {{ deleteFieldReservation(id: "{}") {{ id }} }}"#,
+        reservation.id
+    );
+    let response = schema
+        .execute(async_graphql::Request::new(
+            delete_reservation_query.clone(),
+        ))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+
+    // It cannot be cancelled twice, which would run the promotion again.
+    let response = schema
+        .execute(async_graphql::Request::new(delete_reservation_query))
+        .await;
+    assert_eq!(response.errors[0].message, "Reservation was cancelled");
+
+    let from_date = "2024-01-01T00:00:00Z".to_string().parse().unwrap();
+    let to_date = "2024-01-02T00:00:00Z".to_string().parse().unwrap();
+    let request = async_graphql::Request::new(field_reservations(
+        test_data.association.id,
+        from_date,
+        to_date,
+    ))
+    .data(user_1_claim.clone());
+    let response = schema
+        .execute(request)
+        .await
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    let reservations = response["association"]["fields"][0]["reservations"]
+        .as_array()
+        .expect("Should get reservations");
+    assert_eq!(reservations.len(), 1);
+    assert_eq!(
+        reservations[0]["userId"].as_str().unwrap(),
+        test_data.members[1].id.to_string()
+    );
+
+    let request =
+        async_graphql::Request::new("query { notifications { message, read } }").data(user_1_claim);
+    let response = schema
+        .execute(request)
+        .await
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    let notifications = response["notifications"]
+        .as_array()
+        .expect("Should get notifications");
+    assert_eq!(notifications.len(), 1);
+    assert_eq!(notifications[0]["read"], false);
+}
+
+#[tokio::test]
+async fn test_reservation_series() {
+    // 2024-01-02 is a Tuesday.
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 2, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(10, 2, 1)
+        .await;
+    let field_id = test_data.fields[0].id;
+
+    let admin_claim = Claims {
+        sub: Some(test_db.admin.id),
+        exp: 0,
+        sid: None,
+        email: test_db.admin.email.clone(),
+    };
+    let user_0_claim = Claims {
+        sub: Some(test_data.members[0].id),
+        exp: 0,
+        sid: None,
+        email: test_data.members[0].email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), admin_claim.clone());
+
+    // Members cannot create series.
+    let series_query = create_reservation_series(
+        field_id,
+        "TUESDAY",
+        "19:00:00",
+        "20:00:00",
+        NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+        NaiveDate::from_ymd_opt(2024, 1, 31).unwrap(),
+    );
+    let request = async_graphql::Request::new(series_query.clone()).data(user_0_claim.clone());
+    assert!(schema.execute(request).await.is_err());
+
+    let response = schema
+        .execute(async_graphql::Request::new(series_query))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let response = response
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    let series_id = response["createFieldReservationSeries"]["id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+
+    // Reservation queries are limited to ranges shorter than 30 days.
+    let from_date = "2024-01-02T00:00:00Z".to_string().parse().unwrap();
+    let to_date = "2024-01-31T00:00:00Z".to_string().parse().unwrap();
+    let request = async_graphql::Request::new(field_reservations(
+        test_data.association.id,
+        from_date,
+        to_date,
+    ));
+    let response = schema
+        .execute(request)
+        .await
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    let occurrences = response["association"]["fields"][0]["reservations"]
+        .as_array()
+        .expect("Should get reservations")
+        .clone();
+    // Tuesdays 2, 9, 16, 23 and 30 of January.
+    assert_eq!(occurrences.len(), 5);
+
+    // Occurrences block member bookings.
+    let start_date = "2024-01-02T19:00:00Z".to_string().parse().unwrap();
+    let end_date = "2024-01-02T20:00:00Z".to_string().parse().unwrap();
+    let reservation_query = create_reservation(field_id, "Tennis".to_owned(), start_date, end_date);
+    let request = async_graphql::Request::new(reservation_query.clone()).data(user_0_claim.clone());
+    assert!(schema.execute(request).await.is_err());
+
+    // The occurrence's owner does not get a quota hit: the admin can still book.
+    let other_start = "2024-01-02T10:00:00Z".to_string().parse().unwrap();
+    let other_end = "2024-01-02T11:00:00Z".to_string().parse().unwrap();
+    let request = async_graphql::Request::new(create_reservation(
+        field_id,
+        "Admin match".to_owned(),
+        other_start,
+        other_end,
+    ));
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+
+    // Cancelling a single occurrence frees the slot for members.
+    let delete_reservation_query = format!(
+        r#"mutation {{ deleteFieldReservation(id: "{}") {{ id }} }}"#,
+        occurrences[0]["id"].as_str().unwrap()
+    );
+    let request =
+        async_graphql::Request::new(delete_reservation_query.clone()).data(user_0_claim.clone());
+    assert!(schema.execute(request).await.is_err());
+    let response = schema
+        .execute(async_graphql::Request::new(delete_reservation_query))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let request = async_graphql::Request::new(reservation_query).data(user_0_claim.clone());
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+
+    // Cancelling the series removes the remaining occurrences.
+    let delete_series_query = format!(
+        r#"mutation {{ deleteFieldReservationSeries(id: "{}") {{ id }} }}"#,
+        series_id
+    );
+    let response = schema
+        .execute(async_graphql::Request::new(delete_series_query))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let request = async_graphql::Request::new(field_reservations(
+        test_data.association.id,
+        from_date,
+        to_date,
+    ));
+    let response = schema
+        .execute(request)
+        .await
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    let reservations = response["association"]["fields"][0]["reservations"]
+        .as_array()
+        .expect("Should get reservations");
+    assert_eq!(reservations.len(), 2);
+    assert!(reservations.iter().all(|r| r["seriesId"].is_null()));
+}
+
+#[tokio::test]
+async fn test_reservation_series_local_time() {
+    // 2024-01-02 is a Tuesday.
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 2, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(10, 2, 1)
+        .await;
+    sqlx::query!(
+        r#"UPDATE "Association" SET timezone = 'America/Sao_Paulo' WHERE id = $1"#,
+        test_data.association.id
+    )
+    .execute(&test_db.pool)
+    .await
+    .unwrap();
+    let admin_claim = Claims {
+        sub: Some(test_db.admin.id),
+        exp: 0,
+        sid: None,
+        email: test_db.admin.email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), admin_claim);
+
+    let response = schema
+        .execute(async_graphql::Request::new(create_reservation_series(
+            test_data.fields[0].id,
+            "TUESDAY",
+            "19:00:00",
+            "20:00:00",
+            NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+            NaiveDate::from_ymd_opt(2024, 1, 10).unwrap(),
+        )))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+
+    // Occurrences are at 19:00 in São Paulo, which is 22:00 UTC.
+    let from_date = "2024-01-02T00:00:00Z".to_string().parse().unwrap();
+    let to_date = "2024-01-11T00:00:00Z".to_string().parse().unwrap();
+    let response = schema
+        .execute(async_graphql::Request::new(field_reservations(
+            test_data.association.id,
+            from_date,
+            to_date,
+        )))
+        .await
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    let occurrences = response["association"]["fields"][0]["reservations"]
+        .as_array()
+        .expect("Should get reservations");
+    assert_eq!(occurrences.len(), 2);
+    let start_date: chrono::DateTime<chrono::Utc> = occurrences[0]["startDate"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(
+        start_date,
+        chrono::Utc.with_ymd_and_hms(2024, 1, 2, 22, 0, 0).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_check_in_and_no_show_block() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(10, 2, 0)
+        .await;
+    let rules = ReservationRules::new(
+        NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+        60,
+        1,
+        ReservationPeriod::Daily,
+    )
+    .with_no_show_limit(1, 30);
+    let field = Field::create(
+        &test_db.pool,
+        FieldInput {
+            association_id: test_data.association.id,
+            name: "Tennis court".to_owned(),
+            description: None,
+            reservation_rules: Some(rules.to_json().unwrap()),
+            latitude: "-16.42".parse().unwrap(),
+            longitude: "-39.07".parse().unwrap(),
+            capacity: None,
+        },
+    )
+    .await
+    .unwrap();
+
+    let admin_claim = Claims {
+        sub: Some(test_db.admin.id),
+        exp: 0,
+        sid: None,
+        email: test_db.admin.email.clone(),
+    };
+    let user_0_claim = Claims {
+        sub: Some(test_data.members[0].id),
+        exp: 0,
+        sid: None,
+        email: test_data.members[0].email.clone(),
+    };
+    let user_1_claim = Claims {
+        sub: Some(test_data.members[1].id),
+        exp: 0,
+        sid: None,
+        email: test_data.members[1].email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), user_0_claim.clone());
+
+    let mut reservation_ids = vec![];
+    for (claim, start, end) in [
+        (
+            &user_0_claim,
+            "2024-01-01T10:00:00Z",
+            "2024-01-01T11:00:00Z",
+        ),
+        (
+            &user_1_claim,
+            "2024-01-01T11:00:00Z",
+            "2024-01-01T12:00:00Z",
+        ),
+    ] {
+        let request = async_graphql::Request::new(create_reservation(
+            field.id,
+            "Tennis".to_owned(),
+            start.parse().unwrap(),
+            end.parse().unwrap(),
+        ))
+        .data(claim.clone());
+        let response = schema.execute(request).await;
+        if response.is_err() {
+            panic!("Error executing request: {:?}", response);
+        }
+        let response = response
+            .data
+            .into_json()
+            .expect("Failed to convert response to JSON");
+        reservation_ids.push(
+            response["createFieldReservation"]["id"]
+                .as_str()
+                .unwrap()
+                .to_owned(),
+        );
+    }
+
+    // Too early to check in.
+    let check_in_query = format!(
+        r#"mutation {{ checkInFieldReservation(id: "{}") {{ checkedInAt }} }}"#,
+        reservation_ids[0]
+    );
+    let request = async_graphql::Request::new(check_in_query.clone());
+    assert!(schema.execute(request).await.is_err());
+
+    let check_in_time = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 10, 5, 0).unwrap();
+    let request = async_graphql::Request::new(check_in_query)
+        .data(Arc::new(FixedClock(check_in_time)) as Arc<dyn Clock>);
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+
+    // Only user 1 did not show up.
+    let job_time = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 13, 0, 0).unwrap();
+    let no_shows = FieldReservation::mark_no_shows(&test_db.pool, job_time)
+        .await
+        .unwrap();
+    assert_eq!(no_shows.len(), 1);
+    assert_eq!(no_shows[0].id.to_string(), reservation_ids[1]);
+
+    // User 1 is blocked the next day until an admin lifts the block.
+    let next_day = chrono::Utc.with_ymd_and_hms(2024, 1, 2, 7, 0, 0).unwrap();
+    let next_day_clock = Arc::new(FixedClock(next_day)) as Arc<dyn Clock>;
+    let reservation_query = create_reservation(
+        field.id,
+        "Tennis".to_owned(),
+        "2024-01-02T10:00:00Z".parse().unwrap(),
+        "2024-01-02T11:00:00Z".parse().unwrap(),
+    );
+    let request = async_graphql::Request::new(reservation_query.clone())
+        .data(user_1_claim.clone())
+        .data(next_day_clock.clone());
+    assert!(schema.execute(request).await.is_err());
+
+    let lift_query = format!(
+        r#"mutation {{ liftNoShowBlock(associationId: "{}", targetUserId: "{}") }}"#,
+        test_data.association.id, test_data.members[1].id
+    );
+    let request = async_graphql::Request::new(lift_query.clone()).data(user_0_claim);
+    assert!(schema.execute(request).await.is_err());
+    let request = async_graphql::Request::new(lift_query).data(admin_claim);
+    let response = schema
+        .execute(request)
+        .await
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    assert_eq!(response["liftNoShowBlock"], 1);
+
+    let request = async_graphql::Request::new(reservation_query)
+        .data(user_1_claim)
+        .data(next_day_clock);
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+}
+
+#[tokio::test]
+async fn test_paid_reservation() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(4, 1, 0)
+        .await;
+    let rules = ReservationRules::new(
+        NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+        120,
+        1,
+        ReservationPeriod::Daily,
+    )
+    .with_price(Some("10".parse().unwrap()), Some("20".parse().unwrap()), 30);
+    let field = Field::create(
+        &test_db.pool,
+        FieldInput {
+            association_id: test_data.association.id,
+            name: "Tennis court".to_owned(),
+            description: None,
+            reservation_rules: Some(rules.to_json().unwrap()),
+            latitude: "-16.42".parse().unwrap(),
+            longitude: "-39.07".parse().unwrap(),
+            capacity: None,
+        },
+    )
+    .await
+    .unwrap();
+
+    let claim = |user: &my_hood_server::user::model::User| Claims {
+        sub: Some(user.id),
+        exp: 0,
+        sid: None,
+        email: user.email.clone(),
+    };
+    let treasurer_claim = claim(&test_data.treasurers[0]);
+    let user_2_claim = claim(&test_data.members[2]);
+    let user_3_claim = claim(&test_data.members[3]);
+    let schema = test_db.get_schema_for_tests(config.clone(), user_2_claim.clone());
+
+    let start = "2024-01-01T10:00:00Z".parse().unwrap();
+    let end = "2024-01-01T11:30:00Z".parse().unwrap();
+    let request = async_graphql::Request::new(create_reservation(
+        field.id,
+        "Tennis".to_owned(),
+        start,
+        end,
+    ));
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let response = response
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    let reservation_id =
+        Uuid::parse_str(response["createFieldReservation"]["id"].as_str().unwrap()).unwrap();
+
+    let reservation = FieldReservation::get(&test_db.pool, &reservation_id)
+        .await
+        .unwrap();
+    assert!(reservation.tentative);
+    let charge = Charge::read_one(&test_db.pool, &reservation.charge_id.unwrap())
+        .await
+        .unwrap();
+    assert_eq!(charge.amount, "40".parse().unwrap());
+    assert_eq!(charge.user_id, Some(test_data.members[2].id));
+
+    // Admin moves charge the price of the new slot.
+    let moved_end = "2024-01-01T11:00:00Z".parse().unwrap();
+    let request = async_graphql::Request::new(format!(
+        r#"mutation {{ moveFieldReservation(id: "{}", startDate: "{}", endDate: "{}", reason: "Shorter slot") {{ id }} }}"#,
+        reservation_id, start, moved_end
+    ))
+    .data(claim(&test_db.admin));
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let charge = Charge::read_one(&test_db.pool, &charge.id).await.unwrap();
+    assert_eq!(charge.amount, rules.price(start, moved_end).unwrap());
+    assert_ne!(charge.amount, "40".parse().unwrap());
+
+    let request =
+        async_graphql::Request::new(join_waitlist(field.id, "Tennis".to_owned(), start, end))
+            .data(user_3_claim);
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+
+    // Nothing expires within the payment window.
+    let released = FieldReservation::release_expired(
+        &test_db.pool,
+        chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 20, 0).unwrap(),
+    )
+    .await
+    .unwrap();
+    assert!(released.is_empty());
+
+    // The unpaid reservation is released and the waitlisted member promoted
+    // with a charge of their own.
+    let expiry_time = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 31, 0).unwrap();
+    let released = FieldReservation::release_expired(&test_db.pool, expiry_time)
+        .await
+        .unwrap();
+    assert_eq!(released.len(), 1);
+    assert_eq!(released[0].id, reservation_id);
+    let charge = Charge::read_one(&test_db.pool, &charge.id).await.unwrap();
+    assert!(charge.deleted);
+
+    let promoted = sqlx::query_as!(
+        FieldReservation,
+        r#"SELECT * FROM "FieldReservation" WHERE user_id = $1 AND deleted = false"#,
+        test_data.members[3].id
+    )
+    .fetch_one(&test_db.pool)
+    .await
+    .unwrap();
+    assert!(promoted.tentative);
+    let promoted_charge_id = promoted.charge_id.unwrap();
+
+    // Only treasurers can settle charges.
+    let settle_query = format!(
+        r#"mutation {{ settleCharge(id: "{}") {{ id, settledAt }} }}"#,
+        promoted_charge_id
+    );
+    let expiry_clock = Arc::new(FixedClock(expiry_time)) as Arc<dyn Clock>;
+    let request = async_graphql::Request::new(settle_query.clone()).data(expiry_clock.clone());
+    assert!(schema.execute(request).await.is_err());
+
+    // Treasurers of associations requiring it need a second factor.
+    sqlx::query!(
+        r#"UPDATE "Association" SET require_two_factor = true WHERE id = $1"#,
+        test_data.association.id
+    )
+    .execute(&test_db.pool)
+    .await
+    .unwrap();
+    let request = async_graphql::Request::new(settle_query.clone())
+        .data(treasurer_claim.clone())
+        .data(expiry_clock.clone());
+    assert!(schema.execute(request).await.is_err());
+    let (session, _) = Session::create(&test_db.pool, &test_data.treasurers[0].id, now)
+        .await
+        .unwrap();
+    Session::set_two_factor(&test_db.pool, &session.id)
+        .await
+        .unwrap();
+    let two_factor_claim = Claims {
+        sid: Some(session.id),
+        ..treasurer_claim
+    };
+
+    let request = async_graphql::Request::new(settle_query)
+        .data(two_factor_claim)
+        .data(expiry_clock);
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+
+    let promoted = FieldReservation::get(&test_db.pool, &promoted.id)
+        .await
+        .unwrap();
+    assert!(!promoted.tentative);
+    let income = sqlx::query_scalar!(
+        r#"SELECT count(*) AS "count!" FROM "Transaction" WHERE association_id = $1"#,
+        test_data.association.id
+    )
+    .fetch_one(&test_db.pool)
+    .await
+    .unwrap();
+    assert_eq!(income, 1);
+}
+
+#[tokio::test]
+async fn test_admin_moderation() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(3, 0, 1)
+        .await;
+    let field_id = test_data.fields[0].id;
+
+    let claim = |user: &my_hood_server::user::model::User| Claims {
+        sub: Some(user.id),
+        exp: 0,
+        sid: None,
+        email: user.email.clone(),
+    };
+    let admin_claim = claim(&test_db.admin);
+    let user_0_claim = claim(&test_data.members[0]);
+    let user_1_claim = claim(&test_data.members[1]);
+    let schema = test_db.get_schema_for_tests(config.clone(), admin_claim.clone());
+
+    let mut reservation_ids = vec![];
+    for (claim, start, end) in [
+        (
+            &user_0_claim,
+            "2024-01-01T10:00:00Z",
+            "2024-01-01T11:00:00Z",
+        ),
+        (
+            &user_1_claim,
+            "2024-01-01T11:00:00Z",
+            "2024-01-01T12:00:00Z",
+        ),
+    ] {
+        let request = async_graphql::Request::new(create_reservation(
+            field_id,
+            "Tennis".to_owned(),
+            start.parse().unwrap(),
+            end.parse().unwrap(),
+        ))
+        .data(claim.clone());
+        let response = schema.execute(request).await;
+        if response.is_err() {
+            panic!("Error executing request: {:?}", response);
+        }
+        let response = response
+            .data
+            .into_json()
+            .expect("Failed to convert response to JSON");
+        reservation_ids.push(
+            Uuid::parse_str(response["createFieldReservation"]["id"].as_str().unwrap()).unwrap(),
+        );
+    }
+
+    // Members cannot moderate and admins must give a reason.
+    let move_query = |reason: &str| {
+        format!(
+            r#"mutation {{ moveFieldReservation(id: "{}", startDate: "2024-01-01T16:00:00Z", endDate: "2024-01-01T17:00:00Z", reason: "{}") {{ id, startDate }} }}"#,
+            reservation_ids[0], reason
+        )
+    };
+    let request = async_graphql::Request::new(move_query("Maintenance")).data(user_1_claim.clone());
+    assert!(schema.execute(request).await.is_err());
+    let request = async_graphql::Request::new(move_query(" "));
+    assert!(schema.execute(request).await.is_err());
+    // Nor can reservations be moved to the past.
+    let request = async_graphql::Request::new(format!(
+        r#"mutation {{ moveFieldReservation(id: "{}", startDate: "2024-01-01T05:00:00Z", endDate: "2024-01-01T06:00:00Z", reason: "Earlier") {{ id }} }}"#,
+        reservation_ids[0]
+    ));
+    let response = schema.execute(request).await;
+    assert_eq!(
+        response.errors[0].message,
+        "Reservations cannot be moved to the past"
+    );
+
+    let request = async_graphql::Request::new(move_query("Net maintenance"));
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let moderations =
+        FieldReservationModeration::read_by_reservation(&test_db.pool, &reservation_ids[0])
+            .await
+            .unwrap();
+    assert_eq!(moderations.len(), 1);
+    assert_eq!(moderations[0].action, "moved");
+    assert_eq!(
+        moderations[0].previous_start_date,
+        Some("2024-01-01T10:00:00Z".parse().unwrap())
+    );
+
+    // A tournament block cancels the member reservation in its slot.
+    let block_query = format!(
+        r#"mutation {{ blockField(fieldReservationInput: {{ fieldId: "{}", description: "Tournament", startDate: "2024-01-01T10:00:00Z", endDate: "2024-01-01T15:00:00Z" }}, reason: "Summer tournament") {{ id, blocked }} }}"#,
+        field_id
+    );
+    let response = schema
+        .execute(async_graphql::Request::new(block_query))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let response = response
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    assert_eq!(response["blockField"]["blocked"], true);
+
+    let cancelled = FieldReservation::get(&test_db.pool, &reservation_ids[1])
+        .await
+        .unwrap();
+    assert!(cancelled.deleted);
+    let moved = FieldReservation::get(&test_db.pool, &reservation_ids[0])
+        .await
+        .unwrap();
+    assert!(!moved.deleted);
+
+    // Cancelled reservations cannot be cancelled again, which would notify
+    // the member twice.
+    let request = async_graphql::Request::new(format!(
+        r#"mutation {{ cancelFieldReservationAsAdmin(id: "{}", reason: "Again") {{ id }} }}"#,
+        reservation_ids[1]
+    ));
+    let response = schema.execute(request).await;
+    assert_eq!(response.errors[0].message, "Reservation was cancelled");
+    let moderations =
+        FieldReservationModeration::read_by_reservation(&test_db.pool, &reservation_ids[1])
+            .await
+            .unwrap();
+    assert_eq!(moderations.len(), 1);
+
+    for (claim, expected) in [
+        (user_0_claim, "Net maintenance"),
+        (user_1_claim, "Summer tournament"),
+    ] {
+        let request =
+            async_graphql::Request::new("query { notifications { message } }").data(claim);
+        let response = schema
+            .execute(request)
+            .await
+            .data
+            .into_json()
+            .expect("Failed to convert response to JSON");
+        let notifications = response["notifications"]
+            .as_array()
+            .expect("Should get notifications");
+        assert_eq!(notifications.len(), 1);
+        assert!(notifications[0]["message"]
+            .as_str()
+            .unwrap()
+            .ends_with(expected));
+    }
+}
+
+#[tokio::test]
+async fn test_update_reservation() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(2, 0, 0)
+        .await;
+    let rules = ReservationRules::new(
+        NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+        60,
+        1,
+        ReservationPeriod::Daily,
+    );
+    let field = Field::create(
+        &test_db.pool,
+        FieldInput {
+            association_id: test_data.association.id,
+            name: "Tennis court".to_owned(),
+            description: None,
+            reservation_rules: Some(rules.to_json().unwrap()),
+            latitude: "-16.42".parse().unwrap(),
+            longitude: "-39.07".parse().unwrap(),
+            capacity: None,
+        },
+    )
+    .await
+    .unwrap();
+
+    let claim = |user: &my_hood_server::user::model::User| Claims {
+        sub: Some(user.id),
+        exp: 0,
+        sid: None,
+        email: user.email.clone(),
+    };
+    let user_0_claim = claim(&test_data.members[0]);
+    let user_1_claim = claim(&test_data.members[1]);
+    let schema = test_db.get_schema_for_tests(config.clone(), user_0_claim.clone());
+
+    let mut reservation_ids = vec![];
+    for (claim, start, end) in [
+        (
+            &user_0_claim,
+            "2024-01-01T10:00:00Z",
+            "2024-01-01T11:00:00Z",
+        ),
+        (
+            &user_1_claim,
+            "2024-01-01T12:00:00Z",
+            "2024-01-01T13:00:00Z",
+        ),
+    ] {
+        let request = async_graphql::Request::new(create_reservation(
+            field.id,
+            "Tennis".to_owned(),
+            start.parse().unwrap(),
+            end.parse().unwrap(),
+        ))
+        .data(claim.clone());
+        let response = schema.execute(request).await;
+        if response.is_err() {
+            panic!("Error executing request: {:?}", response);
+        }
+        let response = response
+            .data
+            .into_json()
+            .expect("Failed to convert response to JSON");
+        reservation_ids.push(
+            Uuid::parse_str(response["createFieldReservation"]["id"].as_str().unwrap()).unwrap(),
+        );
+    }
+
+    let update_query = |start: &str, end: &str| {
+        format!(
+            r#"mutation {{ updateFieldReservation(fieldReservationUpdate: {{ id: "{}", description: "Doubles", startDate: "{}", endDate: "{}" }}) {{ id, description, startDate, endDate }} }}"#,
+            reservation_ids[0], start, end
+        )
+    };
+
+    // Only the owner can change a reservation.
+    let request =
+        async_graphql::Request::new(update_query("2024-01-01T10:30:00Z", "2024-01-01T11:30:00Z"))
+            .data(user_1_claim);
+    assert!(schema.execute(request).await.is_err());
+
+    // Shifting into its own slot neither overlaps nor exceeds the daily quota.
+    let request =
+        async_graphql::Request::new(update_query("2024-01-01T10:30:00Z", "2024-01-01T11:30:00Z"));
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let response = response
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    assert_eq!(response["updateFieldReservation"]["description"], "Doubles");
+
+    // Rejected changes keep the original slot.
+    let request =
+        async_graphql::Request::new(update_query("2024-01-01T11:30:00Z", "2024-01-01T12:30:00Z"));
+    assert!(schema.execute(request).await.is_err());
+    let request =
+        async_graphql::Request::new(update_query("2024-01-01T10:30:00Z", "2024-01-01T12:00:00Z"));
+    assert!(schema.execute(request).await.is_err());
+
+    let reservation = FieldReservation::get(&test_db.pool, &reservation_ids[0])
+        .await
+        .unwrap();
+    assert!(!reservation.deleted);
+    assert_eq!(
+        reservation.start_date,
+        "2024-01-01T10:30:00Z"
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap()
+    );
+    assert_eq!(
+        reservation.end_date,
+        "2024-01-01T11:30:00Z"
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_local_time_rules() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(1, 0, 0)
+        .await;
+    let admin_claim = Claims {
+        sub: Some(test_db.admin.id),
+        exp: 0,
+        sid: None,
+        email: test_db.admin.email.clone(),
+    };
+    let user_0_claim = Claims {
+        sub: Some(test_data.members[0].id),
+        exp: 0,
+        sid: None,
+        email: test_data.members[0].email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), admin_claim);
+
+    let update_query = |timezone: &str| {
+        format!(
+            r#"mutation {{ updateAssociation(associationId: "{}", association: {{ timezone: "{}" }}) {{ timezone }} }}"#,
+            test_data.association.id, timezone
+        )
+    };
+    let request = async_graphql::Request::new(update_query("Mars/Olympus_Mons"));
+    assert!(schema.execute(request).await.is_err());
+    let request = async_graphql::Request::new(update_query("America/Sao_Paulo"));
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+
+    // Bookings open at 07:00 in São Paulo, i.e. 10:00 UTC.
+    let rules = ReservationRules::new(
+        NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+        60,
+        1,
+        ReservationPeriod::Daily,
+    );
+    let field = Field::create(
+        &test_db.pool,
+        FieldInput {
+            association_id: test_data.association.id,
+            name: "Tennis court".to_owned(),
+            description: None,
+            reservation_rules: Some(rules.to_json().unwrap()),
+            latitude: "-16.42".parse().unwrap(),
+            longitude: "-39.07".parse().unwrap(),
+            capacity: None,
+        },
+    )
+    .await
+    .unwrap();
+
+    let reserve = |clock_time: &str, start: &str, end: &str| {
+        async_graphql::Request::new(create_reservation(
+            field.id,
+            "Tennis".to_owned(),
+            start.parse().unwrap(),
+            end.parse().unwrap(),
+        ))
+        .data(user_0_claim.clone())
+        .data(Arc::new(FixedClock(clock_time.parse().unwrap())) as Arc<dyn Clock>)
+    };
+
+    // 06:30 local is before the opening time.
+    let request = reserve(
+        "2024-01-01T09:30:00Z",
+        "2024-01-01T20:00:00Z",
+        "2024-01-01T21:00:00Z",
+    );
+    assert!(schema.execute(request).await.is_err());
+
+    // 22:00 local is still today although it is tomorrow in UTC.
+    let request = reserve(
+        "2024-01-01T10:30:00Z",
+        "2024-01-02T01:00:00Z",
+        "2024-01-02T02:00:00Z",
+    );
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+
+    // The daily quota is counted per local day.
+    let request = reserve(
+        "2024-01-01T10:30:00Z",
+        "2024-01-01T15:00:00Z",
+        "2024-01-01T16:00:00Z",
+    );
+    assert!(schema.execute(request).await.is_err());
+    let request = reserve(
+        "2024-01-02T10:30:00Z",
+        "2024-01-02T15:00:00Z",
+        "2024-01-02T16:00:00Z",
+    );
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+}
+
+#[tokio::test]
+async fn test_quota_per_association() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    // Fields of both associations allow one reservation a day.
+    let first = test_db
+        .create_association_admin_member_treasury_fields(1, 0, 1)
+        .await;
+    let second = test_db
+        .create_association_admin_member_treasury_fields(0, 0, 1)
+        .await;
+    let member = &first.members[0];
+    sqlx::query!(
+        r#"INSERT INTO "AssociationRoles" (user_id, association_id, role, pending)
+        VALUES ($1, $2, 'member', false)"#,
+        member.id,
+        second.association.id
+    )
+    .execute(&test_db.pool)
+    .await
+    .unwrap();
+    let claim = Claims {
+        sub: Some(member.id),
+        exp: 0,
+        sid: None,
+        email: member.email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), claim);
+
+    let reserve = |field_id, start: &str, end: &str| {
+        async_graphql::Request::new(create_reservation(
+            field_id,
+            "Tennis".to_owned(),
+            start.parse().unwrap(),
+            end.parse().unwrap(),
+        ))
+    };
+    let response = schema
+        .execute(reserve(
+            first.fields[0].id,
+            "2024-01-01T10:00:00Z",
+            "2024-01-01T11:00:00Z",
+        ))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let response = schema
+        .execute(reserve(
+            first.fields[0].id,
+            "2024-01-01T12:00:00Z",
+            "2024-01-01T13:00:00Z",
+        ))
+        .await;
+    assert!(response.is_err());
+
+    // Reservations in one association do not count towards the other's quota.
+    let response = schema
+        .execute(reserve(
+            second.fields[0].id,
+            "2024-01-01T10:00:00Z",
+            "2024-01-01T11:00:00Z",
+        ))
+        .await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+}
+
+#[tokio::test]
+async fn test_rule_engine() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(2, 1, 0)
+        .await;
+    let admin_claim = Claims {
+        sub: Some(test_db.admin.id),
+        exp: 0,
+        sid: None,
+        email: test_db.admin.email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), admin_claim);
+
+    let validate_query = |rules: &str, sample: &str| {
+        format!(
+            r#"query {{ validateReservationRules(reservationRules: {}{}) {{ valid, errors, normalized, violations {{ rule, message }} }} }}"#,
+            serde_json::to_string(rules).unwrap(),
+            sample
+        )
+    };
+    let validate = async |query: String| {
+        let response = schema.execute(async_graphql::Request::new(query)).await;
+        if response.is_err() {
+            panic!("Error executing request: {:?}", response);
+        }
+        response
+            .data
+            .into_json()
+            .expect("Failed to convert response to JSON")["validateReservationRules"]
+            .clone()
+    };
+
+    let response = validate(validate_query(
+        r#"{"version":2,"rules":[{"kind":"role_restricted","roles":[]}]}"#,
+        "",
+    ))
+    .await;
+    assert_eq!(response["valid"], false);
+    assert_eq!(response["errors"].as_array().unwrap().len(), 1);
+    let response = validate(validate_query(r#"{"version":3,"rules":[]}"#, "")).await;
+    assert_eq!(response["valid"], false);
+
+    let rules = ReservationRules::from_rules(vec![
+        ReservationRule::MinNotice { minutes: 60 },
+        ReservationRule::MaxAdvance { days: 7 },
+        ReservationRule::MaxDuration { minutes: 60 },
+        ReservationRule::MaxConcurrentFutureBookings { max: 1 },
+        ReservationRule::RoleRestricted {
+            roles: vec![Role::Treasurer],
+        },
+    ]);
+    let field = Field::create(
+        &test_db.pool,
+        FieldInput {
+            association_id: test_data.association.id,
+            name: "Tennis court".to_owned(),
+            description: None,
+            reservation_rules: Some(rules.to_json().unwrap()),
+            latitude: "-16.42".parse().unwrap(),
+            longitude: "-39.07".parse().unwrap(),
+            capacity: None,
+        },
+    )
+    .await
+    .unwrap();
+
+    // A dry run reports every rule the admin's own booking would break.
+    let sample = format!(
+        r#", fieldReservationInput: {{ fieldId: "{}", startDate: "2024-01-01T07:30:00Z", endDate: "2024-01-01T09:00:00Z" }}"#,
+        field.id
+    );
+    let response = validate(validate_query(&rules.to_json().unwrap(), &sample)).await;
+    assert_eq!(response["valid"], true);
+    let mut violated: Vec<&str> = response["violations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| v["rule"].as_str().unwrap())
+        .collect();
+    violated.sort();
+    assert_eq!(violated, ["max_duration", "min_notice", "role_restricted"]);
+
+    let reserve = |user: &my_hood_server::user::model::User, start: &str, end: &str| {
+        async_graphql::Request::new(create_reservation(
+            field.id,
+            "Tennis".to_owned(),
+            start.parse().unwrap(),
+            end.parse().unwrap(),
+        ))
+        .data(Claims {
+            sub: Some(user.id),
+            exp: 0,
+            sid: None,
+            email: user.email.clone(),
+        })
+    };
+    let treasurer = &test_data.treasurers[0];
+    let request = reserve(treasurer, "2024-01-03T10:00:00Z", "2024-01-03T11:00:00Z");
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let request = reserve(treasurer, "2024-01-04T10:00:00Z", "2024-01-04T11:00:00Z");
+    assert!(schema.execute(request).await.is_err());
+    let request = reserve(treasurer, "2024-01-10T10:00:00Z", "2024-01-10T11:00:00Z");
+    assert!(schema.execute(request).await.is_err());
+    let request = reserve(
+        &test_data.members[1],
+        "2024-01-05T10:00:00Z",
+        "2024-01-05T11:00:00Z",
+    );
+    assert!(schema.execute(request).await.is_err());
+}
+
+#[tokio::test]
+async fn test_capacity_and_guests() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(4, 0, 0)
+        .await;
+    let rules = ReservationRules::new(
+        NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+        60,
+        1,
+        ReservationPeriod::Daily,
+    );
+    let field = Field::create(
+        &test_db.pool,
+        FieldInput {
+            association_id: test_data.association.id,
+            name: "Barbecue area".to_owned(),
+            description: None,
+            reservation_rules: Some(rules.to_json().unwrap()),
+            latitude: "-16.42".parse().unwrap(),
+            longitude: "-39.07".parse().unwrap(),
+            capacity: Some(2),
+        },
+    )
+    .await
+    .unwrap();
+
+    let claims: Vec<Claims> = test_data
+        .members
+        .iter()
+        .map(|user| Claims {
+            sub: Some(user.id),
+            exp: 0,
+            sid: None,
+            email: user.email.clone(),
+        })
+        .collect();
+    let schema = test_db.get_schema_for_tests(config.clone(), claims[0].clone());
+    let reserve = |member: usize, start: &str, end: &str, guests: &[usize]| {
+        let guest_ids: Vec<String> = guests
+            .iter()
+            .map(|guest| format!(r#""{}""#, test_data.members[*guest].id))
+            .collect();
+        async_graphql::Request::new(format!(
+            r#"mutation {{ createFieldReservation(fieldReservationInput: {{ fieldId: "{}", startDate: "{}", endDate: "{}", guestIds: [{}] }}) {{ id, guests {{ userId }} }} }}"#,
+            field.id,
+            start,
+            end,
+            guest_ids.join(", ")
+        ))
+        .data(claims[member].clone())
+    };
+
+    let request = reserve(0, "2024-01-01T10:00:00Z", "2024-01-01T11:00:00Z", &[0]);
+    assert!(schema.execute(request).await.is_err());
+    let request = reserve(0, "2024-01-01T10:00:00Z", "2024-01-01T11:00:00Z", &[1]);
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let response = response
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    let reservation_id = response["createFieldReservation"]["id"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    assert_eq!(
+        response["createFieldReservation"]["guests"][0]["userId"],
+        test_data.members[1].id.to_string()
+    );
+
+    let request = async_graphql::Request::new("query { fieldReservationInvitations { id } }")
+        .data(claims[1].clone());
+    let response = schema
+        .execute(request)
+        .await
+        .data
+        .into_json()
+        .expect("Failed to convert response to JSON");
+    assert_eq!(
+        response["fieldReservationInvitations"][0]["id"],
+        reservation_id
+    );
+
+    // The invitation counts towards the guest's daily quota.
+    let request = reserve(1, "2024-01-01T12:00:00Z", "2024-01-01T13:00:00Z", &[]);
+    assert!(schema.execute(request).await.is_err());
+
+    // Two groups fit at once, a third one does not.
+    let request = reserve(2, "2024-01-01T10:00:00Z", "2024-01-01T11:00:00Z", &[]);
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let request = reserve(3, "2024-01-01T10:30:00Z", "2024-01-01T11:30:00Z", &[]);
+    assert!(schema.execute(request).await.is_err());
+    let request = reserve(3, "2024-01-01T11:00:00Z", "2024-01-01T12:00:00Z", &[]);
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+
+    // Declining frees the guest's quota.
+    let request = async_graphql::Request::new(format!(
+        r#"mutation {{ respondToFieldReservationInvitation(reservationId: "{}", accept: false) {{ declinedAt }} }}"#,
+        reservation_id
+    ))
+    .data(claims[1].clone());
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+    let request = reserve(1, "2024-01-01T12:00:00Z", "2024-01-01T13:00:00Z", &[]);
+    let response = schema.execute(request).await;
+    if response.is_err() {
+        panic!("Error executing request: {:?}", response);
+    }
+}
+
+#[tokio::test]
+async fn test_occupancy_report() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 3, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(2, 0, 0)
+        .await;
+    let field = Field::create(
+        &test_db.pool,
+        FieldInput {
+            association_id: test_data.association.id,
+            name: "Tennis court".to_owned(),
+            description: None,
+            reservation_rules: None,
+            latitude: "-16.42".parse().unwrap(),
+            longitude: "-39.07".parse().unwrap(),
+            capacity: None,
+        },
+    )
+    .await
+    .unwrap();
+
+    // 2024-01-01 is a Monday.
+    let member_0 = test_data.members[0].id;
+    let member_1 = test_data.members[1].id;
+    let reservations = [
+        (
+            member_0,
+            "2024-01-01T10:00:00Z",
+            "2024-01-01T11:00:00Z",
+            false,
+            true,
+            false,
+        ),
+        (
+            member_0,
+            "2024-01-01T14:30:00Z",
+            "2024-01-01T15:30:00Z",
+            false,
+            false,
+            false,
+        ),
+        (
+            member_1,
+            "2024-01-01T16:00:00Z",
+            "2024-01-01T18:00:00Z",
+            false,
+            false,
+            false,
+        ),
+        (
+            member_1,
+            "2024-01-01T12:00:00Z",
+            "2024-01-01T13:00:00Z",
+            true,
+            false,
+            false,
+        ),
+        (
+            test_db.admin.id,
+            "2024-01-01T20:00:00Z",
+            "2024-01-02T02:00:00Z",
+            false,
+            false,
+            true,
+        ),
+    ];
+    for (user_id, start, end, deleted, no_show, blocked) in reservations {
+        sqlx::query!(
+            r#"INSERT INTO "FieldReservation" (field_id, user_id, start_date, end_date, deleted, no_show, blocked)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+            field.id,
+            user_id,
+            start.parse::<chrono::DateTime<chrono::Utc>>().unwrap(),
+            end.parse::<chrono::DateTime<chrono::Utc>>().unwrap(),
+            deleted,
+            no_show,
+            blocked
+        )
+        .execute(&test_db.pool)
+        .await
+        .unwrap();
+    }
+
+    let occupancy_query = format!(
+        r#"query {{ association(id: "{}") {{
+            occupancy(from: "2024-01-01T00:00:00Z", to: "2024-01-02T00:00:00Z") {{
+                bookedHours availableHours occupancyRate reservations cancellations
+                cancellationRate noShows noShowRate
+                peakHours {{ weekday hour bookedHours }}
+                topUsers {{ userId reservations bookedHours }}
+            }}
+        }} }}"#,
+        test_data.association.id
+    );
+
+    let admin_claim = Claims {
+        sub: Some(test_db.admin.id),
+        exp: 0,
+        sid: None,
+        email: test_db.admin.email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), admin_claim);
+    let response = schema.execute(occupancy_query.clone()).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let json = response.data.into_json().unwrap();
+    let report = &json["association"]["occupancy"];
+    assert_eq!(report["bookedHours"], 4.0);
+    // The block takes four hours of the day.
+    assert_eq!(report["availableHours"], 20.0);
+    assert_eq!(report["occupancyRate"], 0.2);
+    assert_eq!(report["reservations"], 3);
+    assert_eq!(report["cancellations"], 1);
+    assert_eq!(report["cancellationRate"], 0.25);
+    assert_eq!(report["noShows"], 1);
+    assert_eq!(report["peakHours"].as_array().unwrap().len(), 5);
+    assert_eq!(report["peakHours"][0]["weekday"], "MONDAY");
+    assert_eq!(report["peakHours"][0]["hour"], 10);
+    assert_eq!(report["peakHours"][1]["hour"], 14);
+    assert_eq!(report["peakHours"][1]["bookedHours"], 0.5);
+    let top_users = report["topUsers"].as_array().unwrap();
+    assert_eq!(top_users.len(), 2);
+    assert_eq!(top_users[0]["userId"], member_0.to_string());
+    assert_eq!(top_users[0]["reservations"], 2);
+
+    // Peak hours follow the association's timezone.
+    sqlx::query!(
+        r#"UPDATE "Association" SET timezone = 'America/Sao_Paulo' WHERE id = $1"#,
+        test_data.association.id
+    )
+    .execute(&test_db.pool)
+    .await
+    .unwrap();
+    let response = schema.execute(occupancy_query.clone()).await;
+    let json = response.data.into_json().unwrap();
+    assert_eq!(json["association"]["occupancy"]["peakHours"][0]["hour"], 7);
+
+    // Members cannot see reports.
+    let user_0_claim = Claims {
+        sub: Some(member_0),
+        exp: 0,
+        sid: None,
+        email: test_data.members[0].email.clone(),
+    };
+    let schema = test_db.get_schema_for_tests(config.clone(), user_0_claim);
+    let response = schema.execute(occupancy_query).await;
+    assert!(!response.errors.is_empty());
+}
@@ -0,0 +1,130 @@
+mod queries;
+mod test_utils;
+
+use chrono::TimeZone;
+#[cfg(test)]
+use my_hood_server::config::Config;
+use my_hood_server::{
+    field::model::{Field, FieldInput},
+    token::Claims,
+};
+use test_utils::TestDatabase;
+
+fn nearby_fields(lat: f64, lng: f64, radius_km: f64) -> String {
+    format!(
+        r#"query {{ nearbyFields(lat: {}, lng: {}, radiusKm: {}) {{ field {{ id name }} distanceKm }} }}"#,
+        lat, lng, radius_km
+    )
+}
+
+fn nearby_associations(lat: f64, lng: f64, radius_km: f64) -> String {
+    format!(
+        r#"query {{ nearbyAssociations(lat: {}, lng: {}, radiusKm: {}) {{ association {{ id }} distanceKm }} }}"#,
+        lat, lng, radius_km
+    )
+}
+
+#[tokio::test]
+async fn test_nearby_search() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = TestDatabase::new(now).await;
+    let config = Config::new();
+
+    let test_data = test_db
+        .create_association_admin_member_treasury_fields(1, 0, 1)
+        .await;
+    // About 9 km south of the first field.
+    let far_field = Field::create(
+        &test_db.pool,
+        FieldInput {
+            association_id: test_data.association.id,
+            name: "Far court".to_owned(),
+            description: None,
+            reservation_rules: None,
+            latitude: "-16.50".parse().unwrap(),
+            longitude: "-39.07".parse().unwrap(),
+            capacity: None,
+        },
+    )
+    .await
+    .unwrap();
+
+    let member_claim = Claims {
+        sub: Some(test_data.members[0].id),
+        exp: 0,
+        sid: None,
+        email: test_data.members[0].email.clone(),
+    };
+    let anonymous_claim = Claims {
+        sub: None,
+        exp: 0,
+        sid: None,
+        email: None,
+    };
+    let member_schema = test_db.get_schema_for_tests(config.clone(), member_claim);
+    let anonymous_schema = test_db.get_schema_for_tests(config.clone(), anonymous_claim);
+
+    // Members see their association's fields, closest first.
+    let response = member_schema
+        .execute(nearby_fields(-16.42, -39.07, 20.0))
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let json = response.data.into_json().unwrap();
+    let fields = json["nearbyFields"].as_array().unwrap();
+    assert_eq!(fields.len(), 2);
+    assert_eq!(fields[0]["field"]["id"], test_data.fields[0].id.to_string());
+    assert_eq!(fields[1]["field"]["id"], far_field.id.to_string());
+    let distance = fields[1]["distanceKm"].as_f64().unwrap();
+    assert!((distance - 8.9).abs() < 0.1, "distance was {}", distance);
+
+    // The far field is outside a smaller radius.
+    let response = member_schema
+        .execute(nearby_fields(-16.42, -39.07, 5.0))
+        .await;
+    let json = response.data.into_json().unwrap();
+    assert_eq!(json["nearbyFields"].as_array().unwrap().len(), 1);
+
+    // Private associations are hidden from anonymous callers.
+    let response = anonymous_schema
+        .execute(nearby_fields(-16.42, -39.07, 20.0))
+        .await;
+    let json = response.data.into_json().unwrap();
+    assert!(json["nearbyFields"].as_array().unwrap().is_empty());
+    let response = anonymous_schema
+        .execute(nearby_associations(-16.42, -39.07, 20.0))
+        .await;
+    let json = response.data.into_json().unwrap();
+    assert!(json["nearbyAssociations"].as_array().unwrap().is_empty());
+
+    sqlx::query!(
+        r#"UPDATE "Association" SET public = true WHERE id = $1"#,
+        test_data.association.id
+    )
+    .execute(&test_db.pool)
+    .await
+    .unwrap();
+
+    // Associations are listed once, at the distance of their closest field.
+    let response = anonymous_schema
+        .execute(nearby_associations(-16.50, -39.07, 20.0))
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let json = response.data.into_json().unwrap();
+    let associations = json["nearbyAssociations"].as_array().unwrap();
+    assert_eq!(associations.len(), 1);
+    assert_eq!(
+        associations[0]["association"]["id"],
+        test_data.association.id.to_string()
+    );
+    assert!(associations[0]["distanceKm"].as_f64().unwrap() < 0.01);
+
+    // Coordinates and radius are validated.
+    let response = anonymous_schema
+        .execute(nearby_fields(-95.0, -39.07, 20.0))
+        .await;
+    assert!(!response.errors.is_empty());
+    let response = anonymous_schema
+        .execute(nearby_fields(-16.42, -39.07, 1000.0))
+        .await;
+    assert!(!response.errors.is_empty());
+}
@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 
+#[allow(clippy::duplicate_mod)]
 #[path = "queries.rs"]
 mod queries;
 
@@ -25,6 +26,7 @@ use my_hood_server::{
 };
 use queries::{
     create_association, create_fields, create_treasurers, create_user_membership, create_users,
+    test_registration,
 };
 use reqwest::Url;
 use sqlx::Executor;
@@ -59,7 +61,7 @@ impl TestDatabase {
                     &self.pool,
                     UserInput {
                         name: Some(name.clone()),
-                        birthday: birthday.clone(),
+                        birthday,
                         address: address.clone(),
                         email: Some(email.clone()),
                         password_hash: Some(password_hash),
@@ -76,7 +78,7 @@ impl TestDatabase {
             })
             .collect::<Vec<_>>();
         let all = join_all(users).await;
-        return all;
+        all
     }
 
     pub async fn new(now: DateTime<Utc>) -> Self {
@@ -96,7 +98,6 @@ impl TestDatabase {
 
         let db_url = url.to_string();
 
-        env::set_var("DATABASE_URL", &db_url);
         let pool = DB::connect(&db_url)
             .await
             .expect("Failed to connect to test database");
@@ -148,6 +149,7 @@ impl TestDatabase {
         let admin_claim = Claims {
             sub: Some(self.admin.id),
             exp: 0,
+            sid: None,
             email: self.admin.email.clone(),
         };
 
@@ -156,8 +158,10 @@ impl TestDatabase {
         let users_json = create_users(n_member);
         let user_id_futures = users_json
             .iter()
-            .map(async |user| {
-                let request = async_graphql::Request::new(user.to_string());
+            .enumerate()
+            .map(async |(i, user)| {
+                let request =
+                    async_graphql::Request::new(user.to_string()).data(test_registration(i));
                 let response = schema.execute(request).await;
                 if response.is_err() {
                     panic!("Error executing request: {:?}", response);
@@ -165,7 +169,8 @@ impl TestDatabase {
                 let response = &response
                     .data
                     .into_json()
-                    .expect("Something went wrong parsing the response")["createOwnUser"];
+                    .expect("Something went wrong parsing the response")["completeRegistration"]
+                    ["user"];
 
                 let user: User =
                     serde_json::from_value(response.clone()).expect("Should deserialize user");
@@ -187,6 +192,7 @@ impl TestDatabase {
         let admin_claim = Claims {
             sub: Some(self.admin.id),
             exp: 0,
+            sid: None,
             email: self.admin.email.clone(),
         };
         println!(
@@ -203,12 +209,8 @@ impl TestDatabase {
             .iter()
             .enumerate()
             .map(async |(i, user)| {
-                let claim = Claims {
-                    sub: None,
-                    exp: 0,
-                    email: Some(format!("test{}@gmail.com", i)),
-                };
-                let request = async_graphql::Request::new(user.to_string()).data(claim);
+                let request =
+                    async_graphql::Request::new(user.to_string()).data(test_registration(i));
                 let response = schema.execute(request).await;
                 if response.is_err() {
                     panic!("Error executing request: {:?}", response);
@@ -216,7 +218,8 @@ impl TestDatabase {
                 let response = &response
                     .data
                     .into_json()
-                    .expect("Something went wrong parsing the response")["createOwnUser"];
+                    .expect("Something went wrong parsing the response")["completeRegistration"]
+                    ["user"];
 
                 let user: User =
                     serde_json::from_value(response.clone()).expect("Should deserialize user");
@@ -250,14 +253,15 @@ impl TestDatabase {
         let create_treasurer_requests = create_treasurers(
             user_ids[..n_treasurer as usize].to_vec(),
             association_id,
-            NaiveDate::from_ymd_opt(2020, 01, 01).unwrap(),
-            NaiveDate::from_ymd_opt(2100, 01, 01).unwrap(),
+            NaiveDate::from_ymd_opt(2020, 1, 1).unwrap(),
+            NaiveDate::from_ymd_opt(2100, 1, 1).unwrap(),
         );
 
         for (idx, user_memberships_request) in user_memberships_request.iter().enumerate() {
             let user_claim = Claims {
                 sub: Some(user_ids[idx]),
                 exp: 0,
+                sid: None,
                 email: Some(format!("test{}@gmail.com", idx)),
             };
 
@@ -373,7 +377,7 @@ pub fn app() -> Router {
         .route("/requires-connect-info", get(|| async move {}))
 }
 
-pub struct FixedClock(DateTime<Utc>);
+pub struct FixedClock(pub DateTime<Utc>);
 
 #[async_trait]
 impl Clock for FixedClock {
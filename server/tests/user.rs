@@ -1,61 +1,81 @@
 mod queries;
 mod test_utils;
 
+use axum::{body::Body, routing::get, Extension, Router};
 use chrono::TimeZone;
+use http::{header, Request, StatusCode};
 #[cfg(test)]
 use my_hood_server::config::Config;
-use my_hood_server::{token::Claims, user::model::User};
-use queries::{create_users, get_user};
+use my_hood_server::{
+    oauth::get_token,
+    session::model::Session,
+    token::{validate_token, Claims},
+    user::{
+        api_token::{ApiToken, ApiTokenInput},
+        document::is_valid_cpf,
+        model::User,
+        personal_data::export_handler,
+        phone::normalize_phone,
+        registration::Registration,
+    },
+};
+use queries::{create_users, get_user, test_registration};
+use serde_json::{json, Value};
+use tower::ServiceExt;
 
 #[tokio::test]
 async fn test_create_user() {
-    let now = chrono::Utc
-        .with_ymd_and_hms(2024, 01, 01, 07, 0, 0)
-        .unwrap();
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
     let test_db = test_utils::TestDatabase::new(now).await;
     let config = Config::new();
 
     let claims = Claims {
-        sub: Some(test_db.admin.id.clone()),
+        sub: Some(test_db.admin.id),
         exp: 0,
+        sid: None,
         email: test_db.admin.email.clone(),
     };
     let schema = test_db.get_schema_for_tests(config.clone(), claims);
 
     let create_user_mutation = r#"mutation {
-            createOwnUser(userInput: {
+            completeRegistration(userInput: {
                 name: "Test User",
-                email: "test@gmail.com",
                 birthday: "2012-11-19",
                 address: "Rua A nr 1",
                 usesWhatsapp: true
             }) {
-                name,
-                email,
-                birthday,
-                address,
-                usesWhatsapp,
+                user {
+                    name,
+                    email,
+                    birthday,
+                    address,
+                    usesWhatsapp,
+                }
             }
         }
         "#;
 
-    let claim = Claims {
-        sub: None,
-        exp: 0,
+    let registration = Registration {
         email: Some("test@gmail.com".to_owned()),
+        provider: None,
+        subject: None,
+        name: None,
+        picture_url: None,
     };
 
-    let request = async_graphql::Request::new(create_user_mutation.to_string()).data(claim);
+    let request = async_graphql::Request::new(create_user_mutation.to_string()).data(registration);
     let response = schema.execute(request).await.data.into_value();
 
     let expected_response = serde_json::from_str(
         r#"{
-                "createOwnUser": {
-                    "name": "Test User",
-                    "email": "test@gmail.com",
-                    "birthday": "2012-11-19",
-                    "address": "Rua A nr 1",
-                    "usesWhatsapp": true
+                "completeRegistration": {
+                    "user": {
+                        "name": "Test User",
+                        "email": "test@gmail.com",
+                        "birthday": "2012-11-19",
+                        "address": "Rua A nr 1",
+                        "usesWhatsapp": true
+                    }
                 }
             }"#,
     )
@@ -65,15 +85,14 @@ async fn test_create_user() {
 
 #[tokio::test]
 async fn test_get_user() {
-    let now = chrono::Utc
-        .with_ymd_and_hms(2024, 01, 01, 07, 0, 0)
-        .unwrap();
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
     let test_db = test_utils::TestDatabase::new(now).await;
     let config = Config::new();
 
     let claims = Claims {
         sub: Some(test_db.admin.id),
         exp: 0,
+        sid: None,
         email: test_db.admin.email.clone(),
     };
     let schema = test_db.get_schema_for_tests(config.clone(), claims);
@@ -110,15 +129,14 @@ async fn test_get_user() {
 
 #[tokio::test]
 async fn test_create_association() {
-    let now = chrono::Utc
-        .with_ymd_and_hms(2024, 01, 01, 07, 0, 0)
-        .unwrap();
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
     let test_db = test_utils::TestDatabase::new(now).await;
     let config = Config::new();
 
     let claims = Claims {
         sub: Some(test_db.admin.id),
         exp: 0,
+        sid: None,
         email: test_db.admin.email.clone(),
     };
     let schema = test_db.get_schema_for_tests(config.clone(), claims);
@@ -160,39 +178,35 @@ async fn test_create_association() {
 
 #[tokio::test]
 async fn test_users_association() {
-    let now = chrono::Utc
-        .with_ymd_and_hms(2024, 01, 01, 07, 0, 0)
-        .unwrap();
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
     let test_db = test_utils::TestDatabase::new(now).await;
     let config = Config::new();
 
     let claims = Claims {
         sub: Some(test_db.admin.id),
         exp: 0,
+        sid: None,
         email: test_db.admin.email.clone(),
     };
     let schema = test_db.get_schema_for_tests(config.clone(), claims);
 
     let users = create_users(10);
     for (i, create_user) in users.iter().enumerate() {
-        let claim = Claims {
-            sub: None,
-            exp: 0,
-            email: Some(format!("test{}@gmail.com", i)),
-        };
-        let request = async_graphql::Request::new(create_user.to_string()).data(claim);
+        let request =
+            async_graphql::Request::new(create_user.to_string()).data(test_registration(i));
         let response = &schema
             .execute(request)
             .await
             .data
             .into_json()
-            .expect("Failed to convert response to JSON")["createOwnUser"];
+            .expect("Failed to convert response to JSON")["completeRegistration"]["user"];
         let user =
             serde_json::from_value::<User>(response.clone()).expect("Failed to deserialize user");
         let get_user_query = get_user(user.id);
         let claim = Claims {
             sub: Some(user.id),
             exp: 0,
+            sid: None,
             email: user.email.clone(),
         };
         let request = async_graphql::Request::new(get_user_query.to_string()).data(claim);
@@ -208,3 +222,367 @@ async fn test_users_association() {
         assert_eq!(user, get_user);
     }
 }
+
+#[tokio::test]
+async fn test_account_deletion_and_export() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = test_utils::TestDatabase::new(now).await;
+    let config = Config::new();
+    let association_users = test_db
+        .create_association_admin_member_treasury_fields(0, 1, 1)
+        .await;
+    let treasurer = &association_users.treasurers[0];
+    let field = &association_users.fields[0];
+    User::set_password(&test_db.pool, &treasurer.id, "long enough")
+        .await
+        .unwrap();
+
+    let transaction_id = sqlx::query_scalar!(
+        r#"INSERT INTO "Transaction" (association_id, creator_id, details, amount, reference_date)
+        VALUES ($1, $2, 'Field paint', 120.50, '2023-12-20') RETURNING id"#,
+        association_users.association.id,
+        treasurer.id
+    )
+    .fetch_one(&test_db.pool)
+    .await
+    .unwrap();
+    let reservation_id = sqlx::query_scalar!(
+        r#"INSERT INTO "FieldReservation" (field_id, user_id, start_date, end_date)
+        VALUES ($1, $2, $3, $4) RETURNING id"#,
+        field.id,
+        treasurer.id,
+        now + chrono::Duration::days(2),
+        now + chrono::Duration::days(2) + chrono::Duration::hours(1)
+    )
+    .fetch_one(&test_db.pool)
+    .await
+    .unwrap();
+
+    let (session, _) = Session::create(&test_db.pool, &treasurer.id, now)
+        .await
+        .unwrap();
+    let token = get_token(
+        &config,
+        Some(treasurer.id),
+        treasurer.email.clone(),
+        Some(session.id),
+    )
+    .unwrap();
+    let claims = validate_token(&config, &test_db.pool, &token, now)
+        .await
+        .unwrap();
+    let schema = test_db.get_schema_for_tests(config.clone(), claims);
+
+    // The export has the profile, memberships, reservations and transactions,
+    // without the password hash.
+    let response = schema.execute("{ exportMyData }").await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let export = &response.data.into_json().unwrap()["exportMyData"];
+    assert_eq!(export["profile"]["email"], "test0@gmail.com");
+    assert!(export["profile"].get("passwordHash").is_none());
+    let memberships = export["memberships"].as_array().unwrap();
+    assert!(memberships
+        .iter()
+        .any(|membership| membership["role"] == "treasurer"
+            && membership["associationName"] == "Test Association"));
+    assert_eq!(export["reservations"][0]["id"], reservation_id.to_string());
+    assert_eq!(export["transactions"][0]["id"], transaction_id.to_string());
+
+    // Also as a file download.
+    let app = Router::new()
+        .route("/me/export.json", get(export_handler))
+        .layer(Extension(test_db.pool.clone()))
+        .layer(Extension(test_db.clock.clone()))
+        .layer(Extension(config.clone()))
+        .layer(tower_cookies::CookieManagerLayer::new());
+    let response = app
+        .clone()
+        .oneshot(
+            Request::get("/me/export.json")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()[header::CONTENT_DISPOSITION]
+        .to_str()
+        .unwrap()
+        .starts_with("attachment"));
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let download: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(download["profile"]["id"], treasurer.id.to_string());
+
+    // The last admin of an association cannot leave it without one.
+    let admin_schema = test_db.get_schema_for_tests(
+        config.clone(),
+        Claims {
+            sub: Some(test_db.admin.id),
+            exp: 0,
+            sid: None,
+            email: test_db.admin.email.clone(),
+        },
+    );
+    let response = admin_schema
+        .execute("mutation { requestAccountDeletion }")
+        .await;
+    assert!(!response.errors.is_empty());
+
+    // Password users confirm the deletion with it.
+    let response = schema
+        .execute(r#"mutation { requestAccountDeletion(password: "wrong password") }"#)
+        .await;
+    assert!(!response.errors.is_empty());
+    let response = schema
+        .execute(r#"mutation { requestAccountDeletion(password: "long enough") }"#)
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+    // Personal data is gone and the user is logged out.
+    let user = User::read_one(&test_db.pool, &treasurer.id).await.unwrap();
+    assert_eq!(user.name, "Deleted user");
+    assert_eq!(user.email, None);
+    assert_eq!(user.password_hash, None);
+    assert_eq!(user.personal_phone, None);
+    assert_eq!(user.deleted_at, Some(now));
+    assert!(validate_token(&config, &test_db.pool, &token, now)
+        .await
+        .is_err());
+    // Sessions and API tokens left over, e.g. from a concurrent login, are
+    // refused too.
+    sqlx::query!(
+        r#"UPDATE "Session" SET revoked_at = NULL WHERE id = $1"#,
+        session.id
+    )
+    .execute(&test_db.pool)
+    .await
+    .unwrap();
+    assert!(validate_token(&config, &test_db.pool, &token, now)
+        .await
+        .is_err());
+    let api_token = ApiToken::create(
+        &test_db.pool,
+        &treasurer.id,
+        ApiTokenInput {
+            name: "Left over".to_owned(),
+            read_only: true,
+            association_id: None,
+            roles: None,
+            expires_at: None,
+        },
+        now,
+    )
+    .await
+    .unwrap();
+    assert!(ApiToken::authenticate(&test_db.pool, &api_token.token, now)
+        .await
+        .unwrap()
+        .is_none());
+    let roles = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM "AssociationRoles" WHERE user_id = $1"#,
+        treasurer.id
+    )
+    .fetch_one(&test_db.pool)
+    .await
+    .unwrap();
+    assert_eq!(roles, 0);
+
+    // Upcoming reservations are cancelled, financial records kept.
+    let deleted = sqlx::query_scalar!(
+        r#"SELECT deleted FROM "FieldReservation" WHERE id = $1"#,
+        reservation_id
+    )
+    .fetch_one(&test_db.pool)
+    .await
+    .unwrap();
+    assert!(deleted);
+    let creator_id = sqlx::query_scalar!(
+        r#"SELECT creator_id FROM "Transaction" WHERE id = $1"#,
+        transaction_id
+    )
+    .fetch_one(&test_db.pool)
+    .await
+    .unwrap();
+    assert_eq!(creator_id, treasurer.id);
+}
+
+#[tokio::test]
+async fn test_structured_profile() {
+    let now = chrono::Utc.with_ymd_and_hms(2024, 1, 1, 7, 0, 0).unwrap();
+    let test_db = test_utils::TestDatabase::new(now).await;
+    let config = Config::new();
+
+    // Phones are normalized to E.164, taking numbers without a country code
+    // as Brazilian.
+    assert_eq!(
+        normalize_phone("(71) 99876-5432").unwrap(),
+        "+5571998765432"
+    );
+    assert_eq!(normalize_phone("071 3333-4444").unwrap(), "+557133334444");
+    assert_eq!(
+        normalize_phone("+1 (415) 555-2671").unwrap(),
+        "+14155552671"
+    );
+    assert_eq!(
+        normalize_phone("0044 20 7946 0958").unwrap(),
+        "+442079460958"
+    );
+    for invalid in ["12345", "71 9987a-5432", "71+998765432", "+0 71 99876 5432"] {
+        assert!(normalize_phone(invalid).is_err(), "{}", invalid);
+    }
+    assert!(is_valid_cpf("52998224725"));
+    assert!(!is_valid_cpf("52998224726"));
+    assert!(!is_valid_cpf("11111111111"));
+
+    let association_users = test_db
+        .create_association_admin_member_treasury_fields(2, 0, 0)
+        .await;
+    let association_id = association_users.association.id;
+    let member = &association_users.members[0];
+    let other_member = &association_users.members[1];
+    let schema_for = |user_id: uuid::Uuid| {
+        test_db.get_schema_for_tests(
+            config.clone(),
+            Claims {
+                sub: Some(user_id),
+                exp: 0,
+                sid: None,
+                email: None,
+            },
+        )
+    };
+    let member_schema = schema_for(member.id);
+
+    let response = member_schema
+        .execute(format!(
+            r#"mutation {{ update(userUpdate: {{
+                id: "{}", birthday: "2012-11-19", address: "Rua A nr 1", usesWhatsapp: true,
+                personalPhone: "(71) 99876-5432"
+            }}) {{ personalPhone }} }}"#,
+            member.id
+        ))
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert_eq!(
+        response.data.into_json().unwrap()["update"]["personalPhone"],
+        "+5571998765432"
+    );
+    let response = member_schema
+        .execute(format!(
+            r#"mutation {{ update(userUpdate: {{
+                id: "{}", birthday: "2012-11-19", address: "Rua A nr 1", usesWhatsapp: true,
+                commercialPhone: "not a phone"
+            }}) {{ id }} }}"#,
+            member.id
+        ))
+        .await;
+    assert!(!response.errors.is_empty());
+
+    // CPFs are stored as digits, once their check digits are verified.
+    let response = member_schema
+        .execute(
+            r#"mutation { setDocument(input: { kind: CPF, number: "529.982.247-26" }) { id } }"#,
+        )
+        .await;
+    assert!(!response.errors.is_empty());
+    let response = member_schema
+        .execute(
+            r#"mutation { setDocument(input: { kind: CPF, number: "529.982.247-25" }) {
+                kind number country
+            } }"#,
+        )
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert_eq!(
+        response.data.into_json().unwrap()["setDocument"],
+        json!({ "kind": "CPF", "number": "52998224725", "country": "BR" })
+    );
+    let response = member_schema
+        .execute(
+            r#"mutation { setDocument(input: { kind: PASSPORT, number: "FX123456" }) { id } }"#,
+        )
+        .await;
+    assert!(!response.errors.is_empty());
+    let response = member_schema
+        .execute(
+            r#"mutation { setDocument(input: { kind: PASSPORT, number: "fx123456", country: "pt" }) {
+                number country
+            } }"#,
+        )
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+    // A document belongs to a single user.
+    let response = schema_for(other_member.id)
+        .execute(r#"mutation { setDocument(input: { kind: CPF, number: "52998224725" }) { id } }"#)
+        .await;
+    assert!(!response.errors.is_empty());
+
+    let response = member_schema
+        .execute(
+            r#"mutation { setAddress(input: {
+                street: "Rua  das Flores", number: "12", unit: "Apto 3",
+                neighborhood: "neighborhood", city: "Salvador", state: "ba",
+                postalCode: "40000000"
+            }) { state postalCode country } }"#,
+        )
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert_eq!(
+        response.data.into_json().unwrap()["setAddress"],
+        json!({ "state": "BA", "postalCode": "40000-000", "country": "BR" })
+    );
+    let response = member_schema
+        .execute(
+            r#"mutation { setAddress(input: {
+                street: "Rua A", number: "1", neighborhood: "Centro", city: "Salvador",
+                state: "XX", postalCode: "40000000"
+            }) { state } }"#,
+        )
+        .await;
+    assert!(!response.errors.is_empty());
+    let user = User::read_one(&test_db.pool, &member.id).await.unwrap();
+    assert_eq!(
+        user.address,
+        "Rua das Flores, 12 Apto 3, neighborhood, Salvador - BA, 40000-000"
+    );
+
+    // Admins see the documents and address of members, to check they live
+    // in the neighborhood. Other members do not.
+    let members_query = format!(
+        r#"{{ association(id: "{}") {{ members(onlyAdmin: false) {{
+            id documents {{ kind }} postalAddress {{ city }}
+            livesInNeighborhood(associationId: "{}")
+        }} }} }}"#,
+        association_id, association_id
+    );
+    let member_view = |json: Value| {
+        json["association"]["members"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|user| user["id"] == member.id.to_string())
+            .unwrap()
+            .clone()
+    };
+    let response = schema_for(test_db.admin.id)
+        .execute(members_query.as_str())
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let view = member_view(response.data.into_json().unwrap());
+    assert_eq!(view["documents"].as_array().unwrap().len(), 2);
+    assert_eq!(view["postalAddress"]["city"], "Salvador");
+    assert_eq!(view["livesInNeighborhood"], true);
+
+    let response = schema_for(other_member.id)
+        .execute(members_query.as_str())
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    let view = member_view(response.data.into_json().unwrap());
+    assert_eq!(view["documents"], json!([]));
+    assert_eq!(view["postalAddress"], Value::Null);
+    assert_eq!(view["livesInNeighborhood"], Value::Null);
+}